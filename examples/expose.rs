@@ -0,0 +1,43 @@
+//! 使用库中的客户端连接服务端, 把本地的一个端口暴露在服务端的访问端口上,
+//! 握手, `Bind` 与 `Map` 的处理都由 `using_penetrate` 完成
+//!
+//! ```text
+//! cargo run --example expose -- <server> <visit_port> <local>
+//! cargo run --example expose -- 127.0.0.1:6722 8080 127.0.0.1:80
+//! ```
+
+use std::time::Duration;
+
+use fuso::{penetrate::PenetrateRsaAndAesHandshake, Addr, Socket, TokioPenetrateConnector};
+
+#[tokio::main]
+async fn main() -> fuso::Result<()> {
+    let mut args = std::env::args().skip(1);
+
+    let server = args.next().unwrap_or_else(|| "127.0.0.1:6722".into());
+    let visit_port = args.next().unwrap_or_else(|| "8080".into());
+    let local = args.next().unwrap_or_else(|| "127.0.0.1:80".into());
+
+    let server = server.parse::<Addr>()?;
+    let local = local.parse::<Addr>()?;
+    let visit_port = visit_port
+        .parse::<u16>()
+        .map_err(|_| format!("invalid port `{}`", visit_port))?;
+
+    // 与 `fuc` 的默认参数相同: 转发数据使用aes, 控制连接优先使用aes-gcm, 不压缩
+    let handshake = PenetrateRsaAndAesHandshake::Client(
+        vec!["aes".parse()?],
+        vec!["none".parse()?],
+        vec!["aes-gcm".parse()?, "aes".parse()?],
+    );
+
+    fuso::builder_client_with_tokio()
+        .using_handshake(handshake)
+        .using_penetrate(Socket::tcp(([0, 0, 0, 0], visit_port)), Socket::tcp(local))
+        .set_name(String::from("expose"))
+        .heartbeat_delay(Duration::from_secs(30))
+        .maximum_retries(None)
+        .build(Socket::tcp(server), TokioPenetrateConnector::new().await?)
+        .run()
+        .await
+}
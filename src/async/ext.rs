@@ -192,6 +192,10 @@ where
         let mut writer = this.writer;
         let offset = this.offset;
 
+        if this.buf.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
         loop {
             match Pin::new(&mut **writer).poll_write(cx, &this.buf[*offset..])? {
                 Poll::Pending => break Poll::Pending,
@@ -230,6 +234,6 @@ where
     type Output = crate::Result<()>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
-        Pin::new(&mut *self.writer).poll_close(cx)
+        Pin::new(&mut *self.writer).poll_flush(cx)
     }
 }
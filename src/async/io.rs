@@ -1,10 +1,17 @@
-use std::{future::Future, ops::Deref, pin::Pin, sync::Arc, task::Poll};
-
-use crate::{
-    ext::{AsyncReadExt, AsyncWriteExt},
-    AsyncRead, AsyncWrite, NetSocket,
+use std::{
+    future::Future,
+    ops::Deref,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Poll, Waker},
+    time::{Duration, Instant},
 };
 
+use crate::{time, AsyncRead, AsyncWrite, Kind, NetSocket, Stream};
+
 type BoxedFuture = Pin<Box<dyn Future<Output = crate::Result<()>> + Send + 'static>>;
 
 macro_rules! unwrap {
@@ -16,8 +23,44 @@ macro_rules! unwrap {
     };
 }
 
+/// 两个方向独立转发, 一个方向读到EOF时只关闭对端的写入,
+/// 另一个方向继续转发, 两个方向都结束或出错时才结束
 pub struct Forward {
-    futures: Vec<BoxedFuture>,
+    copies: Vec<BoxedFuture>,
+    watchers: Vec<BoxedFuture>,
+}
+
+/// 转发时的复制方式, 默认使用1500字节的缓冲区在用户态复制
+#[derive(Debug, Clone, Copy)]
+pub struct ForwardStrategy {
+    buffer_size: usize,
+    splice: bool,
+}
+
+/// 转发结束的原因, 由转发的结果区分
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Closed {
+    /// 两个方向都读到EOF, 正常关闭
+    Clean,
+    /// 连接被对端重置或中断, 或被主动重置
+    Reset,
+    /// 超过空闲时间没有数据
+    Timeout,
+    /// 超过最长存活时间
+    Lifetime,
+    /// 其他错误
+    Error,
+}
+
+/// 一次转发两个方向上的字节数, `rx` 为 s1 到 s2, `tx` 为 s2 到 s1
+#[derive(Debug, Default)]
+pub struct Traffic {
+    rx: AtomicU64,
+    tx: AtomicU64,
+    first_tx: std::sync::OnceLock<Instant>,
+    finished: AtomicBool,
+    reset: AtomicBool,
+    waker: std::sync::Mutex<Option<Waker>>,
 }
 
 pub struct Inner<S>(std::sync::Mutex<S>);
@@ -71,19 +114,26 @@ impl Future for Forward {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
-        let mut futures = Vec::new();
-        while let Some(mut future) = self.futures.pop() {
-            match Pin::new(&mut future).poll(cx) {
-                Poll::Pending => futures.push(future),
-                Poll::Ready(r) => {
-                    return Poll::Ready(r);
+        for watcher in self.watchers.iter_mut() {
+            if let Poll::Ready(r) = Pin::new(watcher).poll(cx) {
+                return Poll::Ready(r);
+            }
+        }
+
+        let mut copies = Vec::new();
+        while let Some(mut copy) = self.copies.pop() {
+            match Pin::new(&mut copy).poll(cx) {
+                Poll::Pending => copies.push(copy),
+                Poll::Ready(Ok(())) => log::trace!("one direction is half closed"),
+                Poll::Ready(Err(e)) => {
+                    return Poll::Ready(Err(e));
                 }
             }
         }
 
-        drop(std::mem::replace(&mut self.futures, futures));
+        drop(std::mem::replace(&mut self.copies, copies));
 
-        if self.futures.is_empty() {
+        if self.copies.is_empty() {
             Poll::Ready(Ok(()))
         } else {
             Poll::Pending
@@ -92,6 +142,134 @@ impl Future for Forward {
 }
 
 pub fn forward<S1, S2>(s1: S1, s2: S2) -> Forward
+where
+    S1: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S2: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    forward_with_idle(s1, s2, None)
+}
+
+/// 超过 `idle` 时间两个方向都没有数据时关闭连接, 为None时不限制
+pub fn forward_with_idle<S1, S2>(s1: S1, s2: S2, idle: Option<Duration>) -> Forward
+where
+    S1: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S2: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    forward_with_traffic(s1, s2, idle, Default::default())
+}
+
+impl Forward {
+    /// 转发开始后超过 `lifetime` 时无论是否有数据都结束转发, None 表示不限制.
+    /// 结束后 `Forward` 被丢弃, 两端的连接随之关闭
+    pub fn max_lifetime(mut self, lifetime: Option<Duration>) -> Self {
+        if let Some(lifetime) = lifetime {
+            self.watchers.push(Box::pin(watch_lifetime(lifetime)));
+        }
+        self
+    }
+}
+
+impl Default for ForwardStrategy {
+    fn default() -> Self {
+        Self {
+            buffer_size: 1500,
+            splice: false,
+        }
+    }
+}
+
+impl ForwardStrategy {
+    /// 每个方向上复制使用的缓冲区大小, 使用 `splice` 时为单次移动的最大字节数
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size.max(1);
+        self
+    }
+
+    /// 在linux上两端都是未经加密或压缩的tcp连接时使用 `splice(2)` 零拷贝转发,
+    /// 其他情况仍然使用缓冲区复制
+    pub fn splice(mut self, splice: bool) -> Self {
+        self.splice = splice;
+        self
+    }
+}
+
+/// 与 `forward_with_idle` 相同, 同时将转发的字节数累加到 `traffic`
+pub fn forward_with_traffic<S1, S2>(
+    s1: S1,
+    s2: S2,
+    idle: Option<Duration>,
+    traffic: Arc<Traffic>,
+) -> Forward
+where
+    S1: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S2: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let active = Arc::new(std::sync::Mutex::new(Instant::now()));
+    let copies = buffered_copies(s1, s2, 1500, active.clone(), traffic.clone());
+    with_watchers(copies, idle, active, traffic)
+}
+
+/// 与 `forward_with_traffic` 相同, 按 `strategy` 选择复制方式
+pub fn forward_with_strategy<S1, S2>(
+    s1: S1,
+    s2: S2,
+    idle: Option<Duration>,
+    traffic: Arc<Traffic>,
+    strategy: ForwardStrategy,
+) -> Forward
+where
+    S1: Stream + Send + 'static,
+    S2: Stream + Send + 'static,
+{
+    let active = Arc::new(std::sync::Mutex::new(Instant::now()));
+
+    #[cfg(all(target_os = "linux", feature = "fuso-rt-tokio"))]
+    if strategy.splice {
+        if let (Some(fd1), Some(fd2)) = (s1.raw_fd(), s2.raw_fd()) {
+            match crate::runtime::splice(fd1, fd2, strategy.buffer_size) {
+                Ok((s1_to_s2, s2_to_s1)) => {
+                    // 复制的描述符共享同一个连接, 转发结束前保留原连接
+                    let guard = Arc::new(std::sync::Mutex::new((s1, s2)));
+                    let copies = vec![
+                        splice_copy(
+                            s1_to_s2,
+                            guard.clone(),
+                            active.clone(),
+                            traffic.clone(),
+                            Traffic::add_rx,
+                        ),
+                        splice_copy(
+                            s2_to_s1,
+                            guard,
+                            active.clone(),
+                            traffic.clone(),
+                            Traffic::add_tx,
+                        ),
+                    ];
+                    return with_watchers(copies, idle, active, traffic);
+                }
+                Err(e) => log::debug!("splice is not available, {}", e),
+            }
+        }
+    }
+
+    let copies = buffered_copies(
+        s1,
+        s2,
+        strategy.buffer_size,
+        active.clone(),
+        traffic.clone(),
+    );
+    with_watchers(copies, idle, active, traffic)
+}
+
+fn buffered_copies<S1, S2>(
+    s1: S1,
+    s2: S2,
+    buffer_size: usize,
+    active: Arc<std::sync::Mutex<Instant>>,
+    traffic: Arc<Traffic>,
+) -> Vec<BoxedFuture>
 where
     S1: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     S2: AsyncRead + AsyncWrite + Unpin + Send + 'static,
@@ -99,52 +277,308 @@ where
     let (s1_reader, s1_writer) = split(s1);
     let (s2_reader, s2_writer) = split(s2);
 
-    fn copy<R, W>(mut reader: R, mut writer: W) -> BoxedFuture
-    where
-        R: AsyncRead + Unpin + Send + 'static,
-        W: AsyncWrite + Unpin + Send + 'static,
-    {
-        Box::pin(async move {
-            let mut buf = unsafe {
-                let mut buf = Vec::with_capacity(1500);
-                buf.set_len(1500);
-                buf
-            };
-
-            loop {
-                let r = reader.read(&mut buf).await;
-
-                if r.is_err() {
-                    return Err(unsafe { r.unwrap_err_unchecked() });
-                }
+    let s1_to_s2 = copy(
+        s1_reader,
+        s2_writer,
+        buffer_size,
+        active.clone(),
+        traffic.clone(),
+        Traffic::add_rx,
+    );
+    let s2_to_s1 = copy(
+        s2_reader,
+        s1_writer,
+        buffer_size,
+        active,
+        traffic,
+        Traffic::add_tx,
+    );
+
+    vec![s1_to_s2, s2_to_s1]
+}
 
-                let n = unsafe { r.unwrap_unchecked() };
+fn with_watchers(
+    copies: Vec<BoxedFuture>,
+    idle: Option<Duration>,
+    active: Arc<std::sync::Mutex<Instant>>,
+    traffic: Arc<Traffic>,
+) -> Forward {
+    let mut watchers: Vec<BoxedFuture> = vec![Box::pin(watch_reset(traffic))];
 
-                if n == 0 {
-                    let _ = writer.flush().await;
-                    return writer.close().await;
-                }
+    if let Some(idle) = idle {
+        watchers.push(Box::pin(watch_idle(idle, active)));
+    }
+
+    Forward { copies, watchers }
+}
+
+impl Closed {
+    pub fn of(result: &crate::Result<()>) -> Self {
+        let e = match result {
+            Ok(()) => return Self::Clean,
+            Err(e) => e,
+        };
+
+        match e.kind() {
+            Kind::Timeout(_) => Self::Timeout,
+            Kind::IO(e) if e.get_ref().is_some_and(|e| e.is::<LifetimeExceeded>()) => {
+                Self::Lifetime
+            }
+            Kind::IO(e) => match e.kind() {
+                std::io::ErrorKind::TimedOut => Self::Timeout,
+                std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::UnexpectedEof => Self::Reset,
+                _ => Self::Error,
+            },
+            _ => Self::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Clean => "clean close",
+            Self::Reset => "reset",
+            Self::Timeout => "timeout",
+            Self::Lifetime => "max lifetime",
+            Self::Error => "error",
+        })
+    }
+}
+
+impl Traffic {
+    pub fn rx_bytes(&self) -> u64 {
+        self.rx.load(Ordering::Relaxed)
+    }
+
+    pub fn tx_bytes(&self) -> u64 {
+        self.tx.load(Ordering::Relaxed)
+    }
+
+    /// s2 到 s1 方向首次写入的时间, 映射中即后端的首个响应
+    pub fn first_tx(&self) -> Option<Instant> {
+        self.first_tx.get().copied()
+    }
+
+    fn add_rx(&self, n: usize) {
+        self.rx.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    fn add_tx(&self, n: usize) {
+        self.tx.fetch_add(n as u64, Ordering::Relaxed);
+        let _ = self.first_tx.set(Instant::now());
+    }
 
-                log::trace!("forward {}bytes data", n);
+    /// 转发结束后标记, 统计数据仍然可以读取
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Release);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Ordering::Acquire)
+    }
 
-                let r = writer.write_all(&buf[..n]).await;
+    /// 强制结束对应的转发, 重复调用无副作用
+    pub fn reset(&self) {
+        self.reset.store(true, Ordering::Release);
 
-                if r.is_err() {
-                    return Err(unsafe {
-                        let err = r.unwrap_err_unchecked();
-                        log::trace!("forward error {}", err);
-                        err
-                    });
+        if let Some(waker) = self.waker.lock().ok().and_then(|mut waker| waker.take()) {
+            waker.wake();
+        }
+    }
+
+    pub fn is_reset(&self) -> bool {
+        self.reset.load(Ordering::Acquire)
+    }
+}
+
+fn copy<R, W>(
+    reader: R,
+    writer: W,
+    buffer_size: usize,
+    active: Arc<std::sync::Mutex<Instant>>,
+    traffic: Arc<Traffic>,
+    counter: fn(&Traffic, usize),
+) -> BoxedFuture
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    Box::pin(Pump {
+        reader,
+        writer,
+        buf: vec![0u8; buffer_size].into_boxed_slice(),
+        pos: 0,
+        cap: 0,
+        eof: false,
+        need_flush: false,
+        active,
+        traffic,
+        counter,
+    })
+}
+
+/// 单个方向的转发, 缓冲区中的数据全部写出后才继续读取, 写入跟不上时读取随之暂停,
+/// 每个方向最多缓存一个缓冲区的数据. 读取暂时没有数据时刷新写入端, 数据不会滞留在写入端自身的缓冲中
+struct Pump<R, W> {
+    reader: R,
+    writer: W,
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+    eof: bool,
+    need_flush: bool,
+    active: Arc<std::sync::Mutex<Instant>>,
+    traffic: Arc<Traffic>,
+    counter: fn(&Traffic, usize),
+}
+
+impl<R, W> Future for Pump<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    type Output = crate::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        loop {
+            if this.pos == this.cap && !this.eof {
+                let mut buf = crate::ReadBuf::new(&mut this.buf);
+                match Pin::new(&mut this.reader).poll_read(cx, &mut buf) {
+                    Poll::Ready(Ok(0)) => this.eof = true,
+                    Poll::Ready(Ok(n)) => {
+                        log::trace!("forward {}bytes data", n);
+                        crate::metrics::metrics().on_forward(n);
+                        this.pos = 0;
+                        this.cap = n;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending if this.need_flush => {
+                        match Pin::new(&mut this.writer).poll_flush(cx) {
+                            Poll::Ready(r) => unwrap!(r),
+                            Poll::Pending => return Poll::Pending,
+                        }
+                        this.need_flush = false;
+                        return Poll::Pending;
+                    }
+                    Poll::Pending => return Poll::Pending,
                 }
             }
-        })
+
+            while this.pos < this.cap {
+                let buf = &this.buf[this.pos..this.cap];
+                let n = match Pin::new(&mut this.writer).poll_write(cx, buf) {
+                    Poll::Ready(Ok(0)) => {
+                        let e = std::io::Error::from(std::io::ErrorKind::WriteZero);
+                        return Poll::Ready(Err(e.into()));
+                    }
+                    Poll::Ready(Ok(n)) => n,
+                    Poll::Ready(Err(e)) => {
+                        log::trace!("forward error {}", e);
+                        return Poll::Ready(Err(e));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                };
+
+                this.pos += n;
+                this.need_flush = true;
+                (this.counter)(&this.traffic, n);
+                *unwrap!(this.active.lock()) = Instant::now();
+            }
+
+            if this.eof {
+                if this.need_flush {
+                    if Pin::new(&mut this.writer).poll_flush(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    this.need_flush = false;
+                }
+
+                return Pin::new(&mut this.writer).poll_close(cx);
+            }
+        }
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "fuso-rt-tokio"))]
+fn splice_copy<G>(
+    splice: crate::runtime::Splice,
+    guard: Arc<G>,
+    active: Arc<std::sync::Mutex<Instant>>,
+    traffic: Arc<Traffic>,
+    counter: fn(&Traffic, usize),
+) -> BoxedFuture
+where
+    G: Send + Sync + 'static,
+{
+    Box::pin(async move {
+        let _guard = guard;
+
+        splice
+            .copy(|n| {
+                crate::metrics::metrics().on_forward(n);
+                counter(&traffic, n);
+                if let Ok(mut active) = active.lock() {
+                    *active = Instant::now();
+                }
+            })
+            .await
+    })
+}
+
+async fn watch_idle(idle: Duration, active: Arc<std::sync::Mutex<Instant>>) -> crate::Result<()> {
+    loop {
+        let elapsed = active.lock()?.elapsed();
+
+        if elapsed >= idle {
+            log::debug!("no data within {:?}, close the connection", idle);
+            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "idle timeout").into());
+        }
+
+        time::sleep(idle - elapsed).await;
     }
+}
+
+#[derive(Debug)]
+struct LifetimeExceeded(Duration);
 
-    Forward {
-        futures: vec![copy(s1_reader, s2_writer), copy(s2_reader, s1_writer)],
+impl std::fmt::Display for LifetimeExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "max lifetime {:?} exceeded", self.0)
     }
 }
 
+impl std::error::Error for LifetimeExceeded {}
+
+async fn watch_lifetime(lifetime: Duration) -> crate::Result<()> {
+    time::sleep(lifetime).await;
+    log::debug!("exceeded max lifetime {:?}, close the connection", lifetime);
+    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, LifetimeExceeded(lifetime)).into())
+}
+
+async fn watch_reset(traffic: Arc<Traffic>) -> crate::Result<()> {
+    std::future::poll_fn(|cx| {
+        *traffic.waker.lock()? = Some(cx.waker().clone());
+
+        if traffic.is_reset() {
+            log::debug!("the connection was reset");
+            Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionReset,
+                "reset",
+            )
+            .into()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
 impl<T> Deref for Inner<T> {
     type Target = std::sync::Mutex<T>;
 
@@ -197,6 +631,7 @@ where
     }
 }
 
+/// 拆分为读写两半, 两半共享同一个连接, 拆分后仍然可以通过 `NetSocket` 获取连接的地址
 pub fn split<T>(t: T) -> (ReadHalf<T>, WriteHalf<T>)
 where
     T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
@@ -217,3 +652,317 @@ impl<T> Clone for WriteHalf<T> {
         Self(self.0.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_forward_idle_timeout() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s1, mut visitor) = tokio::io::duplex(64);
+                let (s2, mut client) = tokio::io::duplex(64);
+
+                let forward = tokio::spawn(super::forward_with_idle(
+                    s1,
+                    s2,
+                    Some(Duration::from_millis(300)),
+                ));
+
+                let mut buf = [0u8; 4];
+                for _ in 0..5 {
+                    visitor.write_all(b"ping").await.unwrap();
+                    client.read_exact(&mut buf).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(150)).await;
+                }
+
+                assert!(!forward.is_finished());
+
+                tokio::time::sleep(Duration::from_millis(400)).await;
+                assert!(forward.await.unwrap().is_err());
+            });
+    }
+
+    #[test]
+    fn test_forward_max_lifetime() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s1, mut visitor) = tokio::io::duplex(64);
+                let (s2, mut client) = tokio::io::duplex(64);
+
+                let forward = tokio::spawn(
+                    super::forward_with_idle(s1, s2, Some(Duration::from_millis(200)))
+                        .max_lifetime(Some(Duration::from_millis(320))),
+                );
+
+                // 一直有数据时空闲超时不会触发
+                let mut buf = [0u8; 4];
+                for _ in 0..3 {
+                    visitor.write_all(b"ping").await.unwrap();
+                    client.read_exact(&mut buf).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(80)).await;
+                }
+
+                let result = forward.await.unwrap();
+                assert_eq!(super::Closed::of(&result), super::Closed::Lifetime);
+
+                // 转发结束后两端的连接已经关闭
+                assert_eq!(visitor.read(&mut buf).await.unwrap(), 0);
+                assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+            });
+    }
+
+    #[test]
+    fn test_forward_traffic() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s1, mut visitor) = tokio::io::duplex(64);
+                let (s2, mut client) = tokio::io::duplex(64);
+
+                let traffic = Arc::new(super::Traffic::default());
+                let forward =
+                    tokio::spawn(super::forward_with_traffic(s1, s2, None, traffic.clone()));
+
+                let mut buf = [0u8; 5];
+                visitor.write_all(b"hello").await.unwrap();
+                client.read_exact(&mut buf).await.unwrap();
+                client.write_all(b"hi").await.unwrap();
+                visitor.read_exact(&mut buf[..2]).await.unwrap();
+
+                drop(visitor);
+                drop(client);
+                let _ = forward.await.unwrap();
+
+                assert_eq!(traffic.rx_bytes(), 5);
+                assert_eq!(traffic.tx_bytes(), 2);
+            });
+    }
+
+    #[test]
+    fn test_forward_backpressure() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s1, visitor) = tokio::io::duplex(64);
+                let (s2, mut client) = tokio::io::duplex(64);
+
+                let traffic = Arc::new(super::Traffic::default());
+                let forward =
+                    tokio::spawn(super::forward_with_traffic(s1, s2, None, traffic.clone()));
+
+                let (mut visitor_reader, mut visitor_writer) = tokio::io::split(visitor);
+                let written = Arc::new(AtomicUsize::new(0));
+
+                let sender = tokio::spawn({
+                    let written = written.clone();
+                    async move {
+                        for _ in 0..16 {
+                            visitor_writer.write_all(&[7u8; 256]).await.unwrap();
+                            written.fetch_add(256, Ordering::Relaxed);
+                        }
+                        visitor_writer.shutdown().await.unwrap();
+                    }
+                });
+
+                // 客户端不读取时发送方被阻塞, 两端的管道与一个转发缓冲区之外不再缓存数据
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(!sender.is_finished());
+                assert!(written.load(Ordering::Relaxed) <= 64 + 1500 + 64);
+                assert!(traffic.rx_bytes() <= 64);
+
+                // 反方向不受影响
+                let mut buf = [0u8; 4];
+                client.write_all(b"pong").await.unwrap();
+                let read = tokio::time::timeout(
+                    Duration::from_secs(1),
+                    visitor_reader.read_exact(&mut buf),
+                );
+                read.await.unwrap().unwrap();
+                assert_eq!(&buf, b"pong");
+
+                // 慢速读取, 数据完整
+                let mut received = 0;
+                let mut buf = [0u8; 64];
+                loop {
+                    match client.read(&mut buf).await.unwrap() {
+                        0 => break,
+                        n => received += n,
+                    }
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+
+                sender.await.unwrap();
+                assert_eq!(received, 16 * 256);
+                assert_eq!(traffic.rx_bytes(), 16 * 256);
+
+                drop(client);
+                drop(visitor_reader);
+                let r = tokio::time::timeout(Duration::from_secs(1), forward).await;
+                assert!(r.unwrap().is_ok());
+            });
+    }
+
+    #[test]
+    fn test_split_addr() {
+        use crate::{Address, NetSocket, Socket};
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let server_addr = listener.local_addr().unwrap();
+                let tcp = tokio::net::TcpStream::connect(server_addr).await.unwrap();
+                let client_addr = tcp.local_addr().unwrap();
+
+                let (reader, writer) = super::split(tcp);
+
+                let addr = |addr| Address::One(Socket::tcp(addr));
+                assert_eq!(reader.peer_addr().unwrap(), addr(server_addr));
+                assert_eq!(writer.peer_addr().unwrap(), addr(server_addr));
+                assert_eq!(reader.local_addr().unwrap(), addr(client_addr));
+                assert_eq!(writer.local_addr().unwrap(), addr(client_addr));
+            });
+    }
+
+    #[test]
+    fn test_forward_half_close() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s1, mut visitor) = tokio::io::duplex(64);
+                let (s2, mut client) = tokio::io::duplex(64);
+
+                let forward = tokio::spawn(super::forward(s1, s2));
+
+                visitor.write_all(b"request").await.unwrap();
+                visitor.shutdown().await.unwrap();
+
+                // 对端读到EOF后才回复
+                let mut request = Vec::new();
+                client.read_to_end(&mut request).await.unwrap();
+                assert_eq!(request, b"request");
+                assert!(!forward.is_finished());
+
+                client.write_all(b"response").await.unwrap();
+                client.shutdown().await.unwrap();
+
+                let mut response = Vec::new();
+                visitor.read_to_end(&mut response).await.unwrap();
+                assert_eq!(response, b"response");
+
+                let r = tokio::time::timeout(Duration::from_secs(1), forward).await;
+                let r = r.unwrap().unwrap();
+                assert!(r.is_ok());
+                assert_eq!(super::Closed::of(&r), super::Closed::Clean);
+            });
+    }
+
+    #[test]
+    fn test_forward_closed() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s1, _visitor) = tokio::io::duplex(64);
+                let (s2, _client) = tokio::io::duplex(64);
+
+                let idle = Some(Duration::from_millis(50));
+                let r = super::forward_with_idle(s1, s2, idle).await;
+                assert_eq!(super::Closed::of(&r), super::Closed::Timeout);
+
+                let (s1, _visitor) = tokio::io::duplex(64);
+                let (s2, _client) = tokio::io::duplex(64);
+
+                let traffic = Arc::new(super::Traffic::default());
+                let forward = super::forward_with_traffic(s1, s2, None, traffic.clone());
+                let forward = tokio::spawn(forward);
+                traffic.reset();
+
+                let r = forward.await.unwrap();
+                assert_eq!(super::Closed::of(&r), super::Closed::Reset);
+            });
+    }
+
+    #[test]
+    fn test_forward_strategy() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                async fn pair() -> (tokio::net::TcpStream, tokio::net::TcpStream) {
+                    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                    let addr = listener.local_addr().unwrap();
+                    let (s1, s2) =
+                        tokio::join!(tokio::net::TcpStream::connect(addr), listener.accept());
+                    (s1.unwrap(), s2.unwrap().0)
+                }
+
+                for splice in [false, true] {
+                    let (mut visitor, s1) = pair().await;
+                    let (s2, mut client) = pair().await;
+
+                    let traffic = Arc::new(super::Traffic::default());
+                    let strategy = super::ForwardStrategy::default()
+                        .buffer_size(4096)
+                        .splice(splice);
+                    let forward = tokio::spawn(super::forward_with_strategy(
+                        s1,
+                        s2,
+                        None,
+                        traffic.clone(),
+                        strategy,
+                    ));
+
+                    let request = vec![7u8; 100 * 1024];
+                    visitor.write_all(&request).await.unwrap();
+                    visitor.shutdown().await.unwrap();
+
+                    let mut received = Vec::new();
+                    client.read_to_end(&mut received).await.unwrap();
+                    assert_eq!(received, request);
+
+                    client.write_all(b"response").await.unwrap();
+                    client.shutdown().await.unwrap();
+
+                    let mut response = Vec::new();
+                    visitor.read_to_end(&mut response).await.unwrap();
+                    assert_eq!(response, b"response");
+
+                    let r = tokio::time::timeout(Duration::from_secs(1), forward).await;
+                    assert!(r.unwrap().unwrap().is_ok());
+                    assert_eq!(traffic.rx_bytes(), request.len() as u64);
+                    assert_eq!(traffic.tx_bytes(), 8);
+                    assert!(traffic.first_tx().is_some());
+                }
+            });
+    }
+
+    #[test]
+    fn test_forward_reset() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s1, _visitor) = tokio::io::duplex(64);
+                let (s2, _client) = tokio::io::duplex(64);
+
+                let traffic = Arc::new(super::Traffic::default());
+                let forward =
+                    tokio::spawn(super::forward_with_traffic(s1, s2, None, traffic.clone()));
+
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(!forward.is_finished());
+
+                traffic.reset();
+                traffic.reset();
+
+                let r = tokio::time::timeout(Duration::from_secs(1), forward).await;
+                assert!(r.unwrap().unwrap().is_err());
+            });
+    }
+}
@@ -1,8 +1,11 @@
-use std::net::IpAddr;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    ops::RangeInclusive,
+};
 
 use clap::ArgAction;
 use clap::Parser;
-use fuso::Socket;
+use fuso::{compress::Compression, encryption::Cipher, Socket};
 
 #[derive(Parser)]
 #[clap(author, version, about)]
@@ -13,6 +16,12 @@ struct FusoArgs {
     /// 映射名称
     #[clap(short, long, default_value = "anonymous", display_order = 1)]
     name: String,
+    /// 服务端要求的token
+    #[clap(long, display_order = 1)]
+    token: Option<String>,
+    /// 从文件读取token, 去掉末尾的换行, 优先于 `--token`, 避免token出现在进程的命令行与shell历史中
+    #[clap(long, display_order = 1)]
+    token_file: Option<String>,
     /// 启用socks
     #[clap(long, default_value = "false", action = ArgAction::SetTrue, display_order=2)]
     socks: bool,
@@ -25,6 +34,9 @@ struct FusoArgs {
         display_order = 9
     )]
     visit_bind_port: u16,
+    /// 服务端监听访问端口的地址, 需要是服务端本机的地址, 默认监听所有地址
+    #[clap(long, visible_alias = "bh", display_order = 9)]
+    visit_bind_host: Option<IpAddr>,
     /// 桥接监听地址
     #[clap(
         long,
@@ -57,6 +69,70 @@ struct FusoArgs {
     /// 转发端口
     #[clap(long, default_value = "80", visible_alias = "fp", display_order = 8)]
     forward_port: u16,
+    /// 转发到unix域套接字, 指定后忽略 `--forward-host` 与 `--forward-port`, windows上不支持
+    #[clap(long, display_order = 8)]
+    forward_unix: Option<String>,
+    /// 额外的转发目标 `host:port`, 逗号分隔, 与 `--forward-host` 和 `--forward-port` 一起分配连接
+    #[clap(long, value_delimiter = ',', display_order = 8)]
+    forward_to: Vec<fuso::Addr>,
+    /// 多个转发目标之间分配连接的方式 [round-robin, least-conn]
+    #[clap(long, default_value = "round-robin", display_order = 8)]
+    balance: fuso::penetrate::BalanceStrategy,
+    /// 按访问者的tls SNI转发, 格式 `host=addr:port`, 逗号分隔, 没有匹配时使用默认转发地址
+    #[clap(long, value_parser = parse_route, value_delimiter = ',', display_order = 8)]
+    sni_route: Vec<(String, fuso::Addr)>,
+    /// 按访问者的http `Host` 转发, 格式 `host=addr:port`, 逗号分隔, 没有匹配时使用默认转发地址
+    #[clap(long, value_parser = parse_route, value_delimiter = ',', display_order = 8)]
+    host_route: Vec<(String, fuso::Addr)>,
+    /// `--host-route` 没有匹配时回复404, 而不是使用默认转发地址
+    #[clap(long, action = ArgAction::SetTrue, display_order = 8)]
+    host_404: bool,
+    /// 在访问者的第一个http请求中添加 `X-Forwarded-For`, 只用于转发到http服务的映射
+    #[clap(long, action = ArgAction::SetTrue, display_order = 8)]
+    xff: bool,
+    /// 在同一个连接上注册其他服务, 格式 `name:port=addr:port`, 逗号分隔,
+    /// 服务端监听 `port` 并转发到 `addr:port`, 与默认映射一起建立与关闭
+    #[clap(long, value_parser = parse_service, value_delimiter = ',', display_order = 8)]
+    service: Vec<(String, u16, fuso::Addr)>,
+    /// 连接转发目标时使用的本地地址, 多网卡时用于选择出口
+    #[clap(long, display_order = 8)]
+    bind_from: Option<IpAddr>,
+    /// 连接转发目标时使用的源端口范围, 格式 `40000-40100`, 用于配合防火墙的出站规则
+    #[clap(long, value_parser = parse_port_range, display_order = 8)]
+    bind_ports: Option<RangeInclusive<u16>>,
+    /// 转发目标为域名且有ipv4与ipv6地址时并发连接, 值为第一个协议族领先的毫秒数, 0为不启用
+    #[clap(long, display_order = 8)]
+    happy_eyeballs: Option<u64>,
+    /// 单次连接转发目标的超时时间(秒), 有多个转发目标时每个目标单独计时, 默认由系统决定
+    #[clap(long, display_order = 8)]
+    connect_timeout: Option<u64>,
+    /// 经过上游socks5代理连接tcp转发目标, 格式 `host:port` 或 `user:pass@host:port`
+    #[clap(long, display_order = 8)]
+    socks_upstream: Option<fuso::socks::Upstream>,
+    /// 同一个转发目标连续连接失败的次数达到该值后熔断, 冷却期内直接拒绝访问者, 0为不启用
+    #[clap(long, default_value = "0", display_order = 8)]
+    breaker_threshold: usize,
+    /// 熔断的冷却时间(秒), 之后放行一个连接探测目标是否恢复
+    #[clap(long, default_value = "10", display_order = 8)]
+    breaker_cooldown: u64,
+    /// 所有tcp连接设置TCP_NODELAY, 降低ssh, rdp等交互式协议的延迟
+    #[clap(long, action = ArgAction::SetTrue, display_order = 8)]
+    nodelay: bool,
+    /// 映射的连接通过同一个连接多路复用, 服务端不支持时为每个映射创建连接
+    #[clap(long, action = ArgAction::SetTrue, display_order = 8)]
+    mux: bool,
+    /// 映射的连接使用的密钥, 与服务端为该映射名称设置的 `--tenant-key` 一致
+    #[clap(long, display_order = 8)]
+    tenant_key: Option<String>,
+    /// 映射的连接与转发目标之间每个方向的缓冲区大小(字节)
+    #[clap(long, default_value = "1500", display_order = 8)]
+    forward_buffer: usize,
+    /// 在linux上两端都是未加密的tcp连接时使用splice零拷贝转发, 其他情况仍然使用缓冲区复制
+    #[clap(long, action = ArgAction::SetTrue, display_order = 8)]
+    splice: bool,
+    /// 与服务端及转发目标的tcp连接设置DSCP标记(0-63), 用于网络中的QoS
+    #[clap(long, value_parser = clap::value_parser!(u8).range(..64), display_order = 8)]
+    dscp: Option<u8>,
     /// 是否启用socks5 udp转发, 默认不启用
     #[clap(long, default_value = "false", visible_alias = "su", action = ArgAction::SetTrue, display_order=2)]
     socks_udp: bool,
@@ -66,18 +142,80 @@ struct FusoArgs {
     /// socks5密码
     #[clap(long, visible_alias = "s5p", display_order = 4)]
     socks_password: Option<String>,
+    /// socks5 udp转发时目标域名解析结果的缓存时间(秒)
+    #[clap(long, default_value = "60", display_order = 5)]
+    dns_cache_ttl: u64,
     /// 最大等待读取时间
     #[clap(long, default_value = "5", display_order = 11)]
     maximum_rtime: u64,
     /// 最大等待写入时间
     #[clap(long, default_value = "5", display_order = 12)]
     maximum_wtime: u64,
+    /// 接收的单个数据包的最大长度(字节), 超过时断开连接
+    #[clap(long, default_value = "16777216", display_order = 12)]
+    max_frame_size: usize,
     /// 最大等待建立连接时间
     #[clap(long, default_value = "10", display_order = 13)]
     maximum_wctime: u64,
     /// 发送心跳延时
     #[clap(long, default_value = "30", display_order = 14)]
     heartbeat_delay: u64,
+    /// 与服务端断开后的首次重连延时(秒), 最大为2秒
+    #[clap(long, default_value = "1", display_order = 14)]
+    reconnect_min: u64,
+    /// 连续重连失败时延时加倍的上限(秒), 应小于服务端的 `--session-timeout` 以保留访问端口
+    #[clap(long, default_value = "20", display_order = 14)]
+    reconnect_max: u64,
+    /// 重连延时随机减少的最大比例 [0, 1]
+    #[clap(long, default_value = "0.2", display_order = 14)]
+    reconnect_jitter: f64,
+    /// 映射的存活时间(秒), 到期后服务端关闭映射和访问端口, 客户端随即退出
+    #[clap(long, display_order = 14)]
+    ttl: Option<u64>,
+    /// 加密方式, 逗号分隔, 按优先级排列 [aes, aes-gcm, chacha20, none]
+    #[clap(long, default_value = "aes", value_delimiter = ',', display_order = 15)]
+    cipher: Vec<Cipher>,
+    /// 控制连接的加密方式, 逗号分隔, 按优先级排列 [aes, aes-gcm, chacha20], 与转发数据分开协商
+    #[clap(
+        long,
+        default_value = "aes-gcm,chacha20,aes",
+        value_parser = fuso::cli::parse_control_cipher,
+        value_delimiter = ',',
+        display_order = 15
+    )]
+    control_cipher: Vec<Cipher>,
+    /// 转发数据的压缩方式, 逗号分隔, 按优先级排列 [lz4, none], 适合文本为主的低带宽链路
+    #[clap(
+        long,
+        default_value = "none",
+        value_delimiter = ',',
+        display_order = 15
+    )]
+    compress: Vec<Compression>,
+    /// 使用websocket连接服务端, 用于穿过cdn或反向代理
+    #[clap(long, visible_alias = "ws", action = ArgAction::SetTrue, display_order = 16)]
+    websocket: bool,
+    /// websocket请求的Host, 默认使用服务端地址
+    #[clap(long, display_order = 17)]
+    ws_host: Option<String>,
+    /// websocket请求路径
+    #[clap(long, default_value = "/", display_order = 18)]
+    ws_path: String,
+    /// 使用tls连接服务端
+    #[clap(long, action = ArgAction::SetTrue, display_order = 19)]
+    tls: bool,
+    /// 用于校验服务端证书的ca文件(pem), 默认使用内置的根证书
+    #[clap(long, display_order = 20)]
+    tls_ca: Option<String>,
+    /// 校验证书时使用的域名, 默认使用服务端地址
+    #[clap(long, display_order = 21)]
+    tls_server_name: Option<String>,
+    /// 握手之前校验服务端发送的问候, 与服务端的 `--banner` 一致
+    #[clap(long, display_order = 22)]
+    banner: Option<String>,
+    /// 握手之前发送给服务端的数据, 与服务端的 `--knock` 一致
+    #[clap(long, display_order = 22)]
+    knock: Option<String>,
     /// 日志级别
     #[cfg(debug_assertions)]
     #[cfg(feature = "fuso-log")]
@@ -88,6 +226,105 @@ struct FusoArgs {
     #[cfg(feature = "fuso-log")]
     #[clap(long, default_value = "info", display_order = 10, possible_values = ["info", "warn", "error", "debug", "trace", "off"])]
     log_level: log::LevelFilter,
+    /// 日志格式 [text, json]
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, default_value = "text", display_order = 10)]
+    log_format: fuso::logger::LogFormat,
+    /// 日志写入该文件, 由后台线程写入, 磁盘较慢时丢弃日志而不阻塞转发
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, display_order = 10)]
+    log_file: Option<String>,
+    /// 日志文件的轮转方式 [never, hourly, daily], 或按文件大小轮转, 如 `100MB`
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, default_value = "never", display_order = 10)]
+    log_rotate: fuso::logger::LogRotation,
+    /// 保留的已轮转日志文件数量
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, default_value = "5", display_order = 10)]
+    log_keep: usize,
+    /// 写入日志文件时同时输出到stderr
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, action = ArgAction::SetTrue, display_order = 10)]
+    log_tee: bool,
+}
+
+impl FusoArgs {
+    /// 每个参数都可以通过 `FUSO_` 前缀的环境变量设置, 如 `--token` 对应 `FUSO_TOKEN`,
+    /// 命令行优先, 避免token等密钥出现在进程的命令行中
+    fn load() -> Self {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let command = fuso::cli::with_env(Self::command());
+
+        Self::from_arg_matches(&command.get_matches())
+            .unwrap_or_else(|e| e.exit())
+            .with_token_file()
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            })
+    }
+
+    /// `--token-file` 指定的文件不存在或为空时在连接服务端之前退出
+    fn with_token_file(mut self) -> Result<Self, String> {
+        if let Some(path) = self.token_file.as_ref() {
+            let token = fuso::cli::read_token_file(path)
+                .map_err(|e| format!("failed to read token file {}", e))?;
+            self.token = Some(token);
+        }
+
+        Ok(self)
+    }
+}
+
+fn parse_route(route: &str) -> Result<(String, fuso::Addr), String> {
+    let invalid = || format!("invalid route `{}`, expect `host=addr:port`", route);
+
+    match route.split_once('=') {
+        Some((host, addr)) if !host.trim().is_empty() => Ok((
+            host.trim().to_ascii_lowercase(),
+            addr.trim().parse().map_err(|_| invalid())?,
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_port_range(ports: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("invalid port range `{}`, expect `start-end`", ports);
+
+    let (start, end) = match ports.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => (ports.trim(), ports.trim()),
+    };
+
+    let start = start.parse::<u16>().map_err(|_| invalid())?;
+    let end = end.parse::<u16>().map_err(|_| invalid())?;
+
+    if start == 0 || start > end {
+        return Err(invalid());
+    }
+
+    Ok(start..=end)
+}
+
+fn parse_service(service: &str) -> Result<(String, u16, fuso::Addr), String> {
+    let invalid = || {
+        format!(
+            "invalid service `{}`, expect `name:port=addr:port`",
+            service
+        )
+    };
+
+    let (bind, addr) = service.split_once('=').ok_or_else(invalid)?;
+
+    match bind.split_once(':') {
+        Some((name, port)) if !name.trim().is_empty() => Ok((
+            name.trim().to_owned(),
+            port.trim().parse().map_err(|_| invalid())?,
+            addr.trim().parse().map_err(|_| invalid())?,
+        )),
+        _ => Err(invalid()),
+    }
 }
 
 #[cfg(feature = "fuso-rt-tokio")]
@@ -95,27 +332,131 @@ struct FusoArgs {
 async fn main() -> fuso::Result<()> {
     use std::time::Duration;
 
-    use fuso::{penetrate::PenetrateRsaAndAesHandshake, TokioAccepter, TokioPenetrateConnector};
+    use fuso::{
+        io::ForwardStrategy,
+        penetrate::{
+            parse_banner, Banner, CircuitBreaker, PenetrateBannerHandshake,
+            PenetrateRsaAndAesHandshake, PenetrateTlsHandshake, PenetrateWebSocketHandshake,
+            TenantKey,
+        },
+        resolver::CachedResolver,
+        BuildErr, TokioAccepter, TokioPenetrateConnector, TokioResolver, WrappedProvider,
+    };
 
-    let args = FusoArgs::parse();
+    let args = FusoArgs::load();
 
     #[cfg(feature = "fuso-log")]
-    env_logger::builder()
-        .filter_module("fuso", args.log_level)
-        .default_format()
-        .format_module_path(false)
-        .init();
+    match args.log_file.as_ref() {
+        None => fuso::logger::init(args.log_level, args.log_format, true),
+        Some(path) => {
+            let log_file = fuso::logger::LogFile {
+                path: path.into(),
+                rotation: args.log_rotate,
+                keep: args.log_keep,
+                tee: args.log_tee,
+            };
+
+            if let Err(e) =
+                fuso::logger::init_with_file(args.log_level, args.log_format, true, log_file)
+            {
+                eprintln!("failed to open log file: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let handshake =
+        PenetrateRsaAndAesHandshake::Client(args.cipher, args.compress, args.control_cipher);
+
+    let handshake = if args.websocket {
+        let host = args.ws_host.unwrap_or_else(|| args.server_host.clone());
+        WrappedProvider::wrap(PenetrateWebSocketHandshake::client(
+            host,
+            args.ws_path,
+            handshake,
+        ))
+    } else {
+        WrappedProvider::wrap(handshake)
+    };
+
+    let handshake = if args.tls {
+        let config = fuso::tls::client_config(args.tls_ca.as_deref())?;
+        let server_name = args
+            .tls_server_name
+            .unwrap_or_else(|| args.server_host.clone());
+        let server_name = fuso::tls::server_name(&server_name)?;
+        WrappedProvider::wrap(PenetrateTlsHandshake::client(
+            config,
+            server_name,
+            handshake,
+        ))
+    } else {
+        WrappedProvider::wrap(handshake)
+    };
+
+    let banner = Banner::default()
+        .greeting(
+            args.banner
+                .as_deref()
+                .map(parse_banner)
+                .transpose()
+                .map_err(|e| BuildErr::InvalidOption("banner", e))?,
+        )
+        .knock(
+            args.knock
+                .as_deref()
+                .map(parse_banner)
+                .transpose()
+                .map_err(|e| BuildErr::InvalidOption("knock", e))?,
+        );
+
+    let handshake = match banner.is_empty() {
+        true => handshake,
+        false => WrappedProvider::wrap(PenetrateBannerHandshake::client(banner, handshake)),
+    };
+
+    let tenant_key = args.tenant_key.as_ref().map(TenantKey::new).transpose()?;
+
+    let resolver = CachedResolver::new(TokioResolver, Duration::from_secs(args.dns_cache_ttl));
 
     let fuso = fuso::builder_client_with_tokio()
-        .using_handshake(PenetrateRsaAndAesHandshake::Client)
+        .nodelay(args.nodelay)
+        .dscp(args.dscp)
+        .using_handshake(handshake)
         .using_penetrate(
-            Socket::tcp(args.visit_bind_port),
-            Socket::tcp((args.forward_host, args.forward_port)),
+            Socket::tcp((
+                args.visit_bind_host.unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+                args.visit_bind_port,
+            )),
+            match args.forward_unix {
+                Some(path) => Socket::tcp(fuso::Addr::unix(path)),
+                None => Socket::tcp((args.forward_host, args.forward_port)),
+            },
         )
         .maximum_retries(None)
         .heartbeat_delay(Duration::from_secs(args.heartbeat_delay))
+        .reconnect_delay(Duration::from_secs(args.reconnect_min))
+        .reconnect_max_delay(Duration::from_secs(args.reconnect_max))
+        .reconnect_jitter(args.reconnect_jitter)
         .maximum_wait(Duration::from_secs(args.maximum_wctime))
         .set_name(args.name)
+        .set_token(args.token)
+        .set_ttl(args.ttl.map(Duration::from_secs))
+        .add_forward_targets(args.forward_to.into_iter().map(Socket::tcp).collect())
+        .balance_strategy(args.balance)
+        .add_sni_routes(args.sni_route)
+        .add_host_routes(args.host_route)
+        .host_not_found(args.host_404)
+        .forwarded_for(args.xff)
+        .add_services(args.service)
+        .using_mux(args.mux)
+        .tenant_key(tenant_key)
+        .forward_strategy(
+            ForwardStrategy::default()
+                .buffer_size(args.forward_buffer)
+                .splice(args.splice),
+        )
+        .max_frame_size(args.max_frame_size)
         .enable_kcp(args.kcp)
         .enable_socks5(args.socks)
         .enable_socks5_udp(args.socks_udp)
@@ -123,13 +464,33 @@ async fn main() -> fuso::Result<()> {
         .set_socks5_username(args.socks_username)
         .build(
             Socket::tcp((args.server_host, args.server_port)),
-            TokioPenetrateConnector::new().await?,
+            CircuitBreaker::new(
+                TokioPenetrateConnector::new()
+                    .await?
+                    .using_resolver(resolver)
+                    .using_bind_from(args.bind_from)
+                    .using_bind_ports(args.bind_ports)
+                    .using_happy_eyeballs(
+                        args.happy_eyeballs
+                            .filter(|delay| *delay > 0)
+                            .map(Duration::from_millis),
+                    )
+                    .using_connect_timeout(args.connect_timeout.map(Duration::from_secs))
+                    .using_upstream(args.socks_upstream)
+                    .using_nodelay(args.nodelay)
+                    .using_dscp(args.dscp),
+            )
+            .threshold(args.breaker_threshold)
+            .cooldown(Duration::from_secs(args.breaker_cooldown)),
         );
 
     let fuso = match args.bridge_port {
         None => fuso.run(),
         Some(port) => fuso
-            .using_bridge(Socket::tcp((args.bridge_listen, port)), TokioAccepter)
+            .using_bridge(
+                Socket::tcp((args.bridge_listen, port)),
+                TokioAccepter::default(),
+            )
             .run(),
     };
 
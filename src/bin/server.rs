@@ -1,6 +1,11 @@
-use std::{net::IpAddr, str::FromStr};
+use std::{net::IpAddr, ops::RangeInclusive, str::FromStr};
 
 use clap::Parser;
+use fuso::{
+    acl::{Cidr, EgressRule},
+    compress::Compression,
+    encryption::Cipher,
+};
 
 pub enum Kind {
     Proxy,
@@ -9,18 +14,118 @@ pub enum Kind {
 
 #[derive(Parser)]
 pub struct FusoArgs {
-    /// 监听的端口
-    #[clap(short, long, default_value = "6722")]
-    port: u16,
-    /// 监听的地址
+    /// toml配置文件, 命令行参数优先于配置文件
+    #[cfg(feature = "fuso-toml")]
+    #[clap(short, long)]
+    config: Option<String>,
+    /// 监听的端口, 可重复指定或逗号分隔, 每个端口独立运行
+    #[clap(short, long, default_value = "6722", value_delimiter = ',')]
+    port: Vec<u16>,
+    /// 监听的地址, 支持ipv6, 如 `::` 或 `[fe80::1%eth0]`
     #[clap(short, long, default_value = "0.0.0.0")]
-    listen: IpAddr,
+    listen: String,
     /// 启用udp转发
     #[clap(long, default_value = "false")]
     enable_ufd: bool,
     /// 启用socks5
     #[clap(long, default_value = "false")]
     enable_socks: bool,
+    /// socks5用户名密码, 格式 `user:pass`, 可指定多个
+    #[clap(long, value_parser = parse_socks_auth)]
+    socks_auth: Vec<(String, String)>,
+    /// 允许客户端监听的端口范围, 格式 `10000-20000`, 默认不限制
+    #[clap(long, value_parser = parse_port_range)]
+    allowed_bind_ports: Option<RangeInclusive<u16>>,
+    /// 允许客户端监听的地址, 可指定多个或逗号分隔, 需要列出 `0.0.0.0` 才能监听所有地址, 默认允许本机的任意地址
+    #[clap(long, value_delimiter = ',')]
+    allowed_bind_hosts: Vec<IpAddr>,
+    /// 禁止客户端使用随机端口(不指定访问端口)
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    deny_random_port: bool,
+    /// 只接受来自这些网段的客户端, 如 `10.0.0.0/8`, 可指定多个或逗号分隔, 默认不限制
+    #[clap(long, value_delimiter = ',')]
+    allow_ip: Vec<Cidr>,
+    /// 拒绝来自这些网段的客户端, 优先于 `--allow-ip`
+    #[clap(long, value_delimiter = ',')]
+    deny_ip: Vec<Cidr>,
+    /// 每秒接受的连接数, 所有来源与监听共享, 超过后在握手之前直接关闭, 默认不限制
+    #[clap(long)]
+    accept_rate: Option<u32>,
+    /// 全局可以积攒的连接数, 默认与 `--accept-rate` 相同
+    #[clap(long)]
+    accept_burst: Option<u32>,
+    /// 每个来源ip每秒接受的连接数, ipv6按/64网段计算, 超过后在握手之前直接关闭, 默认不限制
+    #[clap(long)]
+    ip_accept_rate: Option<u32>,
+    /// 每个来源ip可以积攒的连接数, 默认与 `--ip-accept-rate` 相同
+    #[clap(long)]
+    ip_accept_burst: Option<u32>,
+    /// 访问者通过socks5与http代理可以连接的目标, 网段或域名(`*.example.com` 匹配子域名), 可指定多个或逗号分隔, 默认不限制
+    #[clap(long, value_delimiter = ',')]
+    egress_allow: Vec<EgressRule>,
+    /// 监听时设置SO_REUSEADDR, 服务重启后可以立即监听处于TIME_WAIT的端口, 非windows平台默认开启, `--reuse-addr=false` 关闭
+    #[clap(
+        long,
+        min_values = 0,
+        multiple_values = false,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    reuse_addr: Option<bool>,
+    /// 监听时设置SO_REUSEPORT, 允许多个进程监听同一个端口, 不支持的平台上忽略
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    reuse_port: bool,
+    /// 监听时设置IP_FREEBIND, 允许监听本机还没有的地址(如keepalived的虚拟ip), 只支持linux, 其他平台上忽略
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    freebind: bool,
+    /// 服务端与映射的监听的等待队列长度, 连接突增时调大, 会被系统的上限截断(linux为net.core.somaxconn, macos为kern.ipc.somaxconn)
+    #[clap(long, default_value = "1024", value_parser = clap::value_parser!(u32).range(1..))]
+    backlog: u32,
+    /// 所有tcp连接设置TCP_NODELAY, 降低ssh, rdp等交互式协议的延迟
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    nodelay: bool,
+    /// 所有tcp连接设置DSCP标记(0-63), 用于网络中的QoS, 不支持的平台上忽略
+    #[clap(long, value_parser = clap::value_parser!(u8).range(..64))]
+    dscp: Option<u8>,
+    /// 客户端的访问端口被占用时的重试次数, 每次重试的间隔加倍
+    #[clap(long, default_value = "0")]
+    bind_retries: usize,
+    /// 转发时向本地服务发送PROXY protocol v2头, 携带访问者的真实地址
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    proxy_protocol: bool,
+    /// 不识别websocket客户端, 握手前不再预读连接的数据
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    no_ws: bool,
+    /// websocket升级请求允许的最大请求头长度(字节), 经过cdn或携带大量cookie时需要调大
+    #[clap(long, default_value = "8192")]
+    ws_max_head: usize,
+    /// 控制连接与udp转发接收的单个数据包的最大长度(字节), 超过时断开连接
+    #[clap(long, default_value = "16777216")]
+    max_frame_size: usize,
+    /// 访问者与客户端之间转发时每个方向的缓冲区大小(字节)
+    #[clap(long, default_value = "1500")]
+    forward_buffer: usize,
+    /// 在linux上两端都是未加密的tcp连接时使用splice零拷贝转发, 其他情况仍然使用缓冲区复制
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    splice: bool,
+    /// socks5 udp中继socket的接收缓冲区大小(字节), 突发的udp流量丢包时调大, 默认使用系统设置
+    #[clap(long)]
+    udp_recv_buffer: Option<usize>,
+    /// socks5 udp中继socket的发送缓冲区大小(字节), 默认使用系统设置
+    #[clap(long)]
+    udp_send_buffer: Option<usize>,
+    /// socks5 udp中继应答给访问者的地址, 服务端在NAT之后时设置为公网地址, 默认应答未指定地址(0.0.0.0或::)
+    #[clap(long)]
+    udp_public_ip: Option<IpAddr>,
+    /// 访问者发往socks5 udp中继的数据包携带的数据的最大长度(字节, 不包括socks5头部), 超过后丢弃, 不截断也不分片
+    #[clap(long, default_value = "1500", value_parser = clap::value_parser!(u16).range(1..))]
+    udp_max_datagram: u16,
+    /// 每个客户端同时存在的socks5 udp中继数, 超过后拒绝新的 `UDP ASSOCIATE`, 默认不限制
+    #[clap(long)]
+    max_udp_associations: Option<usize>,
+    /// 以debug级别输出认证, 监听以及每个映射的握手, 建立映射, 任务调度与首字节的耗时
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    trace_timing: bool,
     /// 日志级别
     #[cfg(debug_assertions)]
     #[cfg(feature = "fuso-log")]
@@ -31,54 +136,1116 @@ pub struct FusoArgs {
     #[cfg(feature = "fuso-log")]
     #[clap(long, default_value = "info")]
     log_level: log::LevelFilter,
+    /// 日志格式 [text, json]
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, default_value = "text")]
+    log_format: fuso::logger::LogFormat,
+    /// 日志写入该文件, 由后台线程写入, 磁盘较慢时丢弃日志而不阻塞转发
+    #[cfg(feature = "fuso-log")]
+    #[clap(long)]
+    log_file: Option<String>,
+    /// 日志文件的轮转方式 [never, hourly, daily], 或按文件大小轮转, 如 `100MB`
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, default_value = "never")]
+    log_rotate: fuso::logger::LogRotation,
+    /// 保留的已轮转日志文件数量
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, default_value = "5")]
+    log_keep: usize,
+    /// 写入日志文件时同时输出到stderr
+    #[cfg(feature = "fuso-log")]
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    log_tee: bool,
+    /// 握手与等待客户端首个请求的超时时间(秒), 两个阶段分别计时, 0为不限制
+    #[clap(long, default_value = "30")]
+    handshake_timeout: u64,
     /// 发送心跳延时
     #[clap(long, default_value = "30")]
     heartbeat_delay: u64,
+    /// 超过该时间未收到客户端心跳则断开连接
+    #[clap(long, default_value = "90")]
+    keepalive_timeout: u64,
+    /// 客户端断开后保留监听的时间, 期间同名客户端重连将继续使用原来的端口
+    #[clap(long, default_value = "30")]
+    session_timeout: u64,
+    /// 转发的连接超过该时间(秒)没有数据时关闭, 默认不限制
+    #[clap(long)]
+    idle_timeout: Option<u64>,
+    /// 转发的连接从建立起超过该时间(秒)后强制关闭, 即使仍有数据, 默认不限制
+    #[clap(long)]
+    max_lifetime: Option<u64>,
+    /// 定时(秒)向客户端推送每个转发连接的流量统计, 默认不推送
+    #[clap(long)]
+    stats_interval: Option<u64>,
+    /// 每个客户端同时存在的最大连接数, 超过后拒绝新的访问, 默认不限制
+    #[clap(long)]
+    max_connections: Option<usize>,
+    /// 整个服务端同时存在的最大连接数, 所有客户端共享, 默认不限制
+    #[clap(long)]
+    max_convs: Option<usize>,
+    /// 可用内存低于该值(MB)时拒绝新的连接与访问, 默认不检查
+    #[clap(long)]
+    min_free_memory: Option<u64>,
+    /// 距离文件描述符上限少于该数量时拒绝新的连接与访问, 默认不检查
+    #[clap(long)]
+    min_free_fds: Option<u64>,
+    /// prometheus指标监听端口, 默认不开启
+    #[clap(long)]
+    metrics_port: Option<u16>,
+    /// 健康检查监听端口, `/healthz` 在服务监听后返回200, `/readyz` 在开始关闭后返回503, 默认不开启
+    #[clap(long)]
+    health_port: Option<u16>,
+    /// 管理服务监听地址, 如 `127.0.0.1:6724`, 支持 `list`, `kill <conv> [cid]`, `drain <client>`, `connect <client> <addr>` 与 `loglevel [level]` 命令, 默认不开启
+    #[clap(long)]
+    admin_socket: Option<String>,
+    /// 收到退出信号后等待转发结束的时间, 再次收到信号立即退出
+    #[clap(long, default_value = "10")]
+    shutdown_grace: u64,
+    /// 允许的加密方式, 逗号分隔 [aes, aes-gcm, chacha20, none]
+    #[clap(long, default_value = "aes,aes-gcm,chacha20", value_delimiter = ',')]
+    cipher: Vec<Cipher>,
+    /// 控制连接允许的加密方式, 逗号分隔 [aes, aes-gcm, chacha20], 与转发数据分开协商
+    #[clap(
+        long,
+        default_value = "aes-gcm,chacha20,aes",
+        value_parser = fuso::cli::parse_control_cipher,
+        value_delimiter = ','
+    )]
+    control_cipher: Vec<Cipher>,
+    /// 允许的压缩方式, 逗号分隔 [lz4, none], 由客户端选择
+    #[clap(long, default_value = "lz4,none", value_delimiter = ',')]
+    compress: Vec<Compression>,
+    /// tls证书文件(pem), 指定后只接受tls连接
+    #[clap(long)]
+    tls_cert: Option<String>,
+    /// tls私钥文件(pem), 需与 `--tls-cert` 同时指定
+    #[clap(long)]
+    tls_key: Option<String>,
+    /// 连接建立后在握手之前发送的问候, 用于伪装成其他协议, 支持 `\r\n` 与 `\xHH` 转义,
+    /// 如 `SSH-2.0-OpenSSH_8.9\r\n`, 客户端需要使用相同的 `--banner`
+    #[clap(long)]
+    banner: Option<String>,
+    /// 客户端在握手之前需要发送的数据, 不一致时断开连接, 格式同 `--banner`
+    #[clap(long)]
+    knock: Option<String>,
+    /// 不是fuso客户端的连接握手失败或超时时回复的内容, 让端口看起来像普通的web服务,
+    /// `404` 为内置的nginx 404页面, 其他值按 `--banner` 的格式转义后原样回复
+    #[clap(long)]
+    decoy: Option<String>,
+    /// 从文件读取 `--decoy` 的内容, 原样回复, 优先于 `--decoy`
+    #[clap(long)]
+    decoy_file: Option<String>,
+    /// 客户端连接时需要提供的token, 默认不校验
+    #[clap(long)]
+    token: Option<String>,
+    /// 从文件读取token, 去掉末尾的换行, 优先于 `--token`, 避免token出现在进程的命令行与shell历史中
+    #[clap(long)]
+    token_file: Option<String>,
+    /// 按客户端名设置映射连接的密钥, 格式 `name=passphrase`, 可指定多个, 客户端需要使用相同的 `--tenant-key`
+    #[clap(long, value_parser = parse_tenant_key)]
+    tenant_key: Vec<(String, String)>,
+    /// 客户端名没有对应的密钥时使用的密钥, 未指定时拒绝未知的客户端
+    #[clap(long)]
+    tenant_default_key: Option<String>,
+    /// 只检查参数与配置文件并输出将要监听的地址, 不监听任何端口
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    check: bool,
+}
+
+/// 配置文件, 字段名与命令行参数一致, 如 `heartbeat-delay = 30`
+#[cfg(feature = "fuso-toml")]
+#[derive(Default, serde::Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct FusoConfig {
+    port: Option<Ports>,
+    listen: Option<String>,
+    enable_ufd: Option<bool>,
+    enable_socks: Option<bool>,
+    log_level: Option<String>,
+    log_format: Option<String>,
+    log_file: Option<String>,
+    log_rotate: Option<String>,
+    log_keep: Option<usize>,
+    log_tee: Option<bool>,
+    handshake_timeout: Option<u64>,
+    heartbeat_delay: Option<u64>,
+    keepalive_timeout: Option<u64>,
+    session_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    max_lifetime: Option<u64>,
+    stats_interval: Option<u64>,
+    max_connections: Option<usize>,
+    max_convs: Option<usize>,
+    min_free_memory: Option<u64>,
+    min_free_fds: Option<u64>,
+    metrics_port: Option<u16>,
+    health_port: Option<u16>,
+    admin_socket: Option<String>,
+    shutdown_grace: Option<u64>,
+    cipher: Option<Vec<String>>,
+    control_cipher: Option<Vec<String>>,
+    compress: Option<Vec<String>>,
+    socks_auth: Option<Vec<String>>,
+    allowed_bind_ports: Option<String>,
+    allowed_bind_hosts: Option<Vec<IpAddr>>,
+    deny_random_port: Option<bool>,
+    allow_ip: Option<Vec<String>>,
+    deny_ip: Option<Vec<String>>,
+    accept_rate: Option<u32>,
+    accept_burst: Option<u32>,
+    ip_accept_rate: Option<u32>,
+    ip_accept_burst: Option<u32>,
+    egress_allow: Option<Vec<String>>,
+    reuse_addr: Option<bool>,
+    reuse_port: Option<bool>,
+    freebind: Option<bool>,
+    backlog: Option<u32>,
+    nodelay: Option<bool>,
+    dscp: Option<u8>,
+    bind_retries: Option<usize>,
+    proxy_protocol: Option<bool>,
+    no_ws: Option<bool>,
+    ws_max_head: Option<usize>,
+    max_frame_size: Option<usize>,
+    forward_buffer: Option<usize>,
+    splice: Option<bool>,
+    udp_recv_buffer: Option<usize>,
+    udp_send_buffer: Option<usize>,
+    udp_public_ip: Option<IpAddr>,
+    udp_max_datagram: Option<u16>,
+    max_udp_associations: Option<usize>,
+    trace_timing: Option<bool>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    banner: Option<String>,
+    knock: Option<String>,
+    decoy: Option<String>,
+    decoy_file: Option<String>,
+    token: Option<String>,
+    token_file: Option<String>,
+    tenant_key: Option<Vec<String>>,
+    tenant_default_key: Option<String>,
+}
+
+/// `port = 6722` 或 `port = [6722, 6723]`
+#[cfg(feature = "fuso-toml")]
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Ports {
+    One(u16),
+    Many(Vec<u16>),
+}
+
+#[cfg(feature = "fuso-toml")]
+impl FusoConfig {
+    fn load(path: &str) -> fuso::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&text)?)
+    }
+
+    fn merge(self, args: &mut FusoArgs, matches: &clap::ArgMatches) -> fuso::Result<()> {
+        let unset = |field: &str| {
+            !matches!(
+                matches.value_source(field.replace('_', "-")),
+                Some(clap::ValueSource::CommandLine | clap::ValueSource::EnvVariable)
+            )
+        };
+
+        macro_rules! merge {
+            ($($field:ident),*) => {
+                $(
+                    if let Some(value) = self.$field.filter(|_| unset(stringify!($field))) {
+                        args.$field = value;
+                    }
+                )*
+            };
+        }
+
+        merge!(
+            listen,
+            enable_ufd,
+            enable_socks,
+            deny_random_port,
+            reuse_port,
+            freebind,
+            backlog,
+            nodelay,
+            bind_retries,
+            proxy_protocol,
+            no_ws,
+            ws_max_head,
+            max_frame_size,
+            forward_buffer,
+            splice,
+            udp_max_datagram,
+            trace_timing,
+            handshake_timeout,
+            heartbeat_delay,
+            keepalive_timeout,
+            session_timeout,
+            shutdown_grace
+        );
+
+        if let Some(ports) = self.port.filter(|_| unset("port")) {
+            args.port = match ports {
+                Ports::One(port) => vec![port],
+                Ports::Many(ports) => ports,
+            };
+        }
+
+        if let Some(reuse) = self.reuse_addr.filter(|_| unset("reuse_addr")) {
+            args.reuse_addr = Some(reuse);
+        }
+
+        if let Some(timeout) = self.idle_timeout.filter(|_| unset("idle_timeout")) {
+            args.idle_timeout = Some(timeout);
+        }
+
+        if let Some(lifetime) = self.max_lifetime.filter(|_| unset("max_lifetime")) {
+            args.max_lifetime = Some(lifetime);
+        }
+
+        if let Some(interval) = self.stats_interval.filter(|_| unset("stats_interval")) {
+            args.stats_interval = Some(interval);
+        }
+
+        if let Some(max) = self.max_connections.filter(|_| unset("max_connections")) {
+            args.max_connections = Some(max);
+        }
+
+        if let Some(max) = self.max_convs.filter(|_| unset("max_convs")) {
+            args.max_convs = Some(max);
+        }
+
+        if let Some(mb) = self.min_free_memory.filter(|_| unset("min_free_memory")) {
+            args.min_free_memory = Some(mb);
+        }
+
+        if let Some(fds) = self.min_free_fds.filter(|_| unset("min_free_fds")) {
+            args.min_free_fds = Some(fds);
+        }
+
+        if let Some(max) = self
+            .max_udp_associations
+            .filter(|_| unset("max_udp_associations"))
+        {
+            args.max_udp_associations = Some(max);
+        }
+
+        if let Some(size) = self.udp_recv_buffer.filter(|_| unset("udp_recv_buffer")) {
+            args.udp_recv_buffer = Some(size);
+        }
+
+        if let Some(size) = self.udp_send_buffer.filter(|_| unset("udp_send_buffer")) {
+            args.udp_send_buffer = Some(size);
+        }
+
+        if let Some(ip) = self.udp_public_ip.filter(|_| unset("udp_public_ip")) {
+            args.udp_public_ip = Some(ip);
+        }
+
+        if let Some(port) = self.metrics_port.filter(|_| unset("metrics_port")) {
+            args.metrics_port = Some(port);
+        }
+
+        if let Some(port) = self.health_port.filter(|_| unset("health_port")) {
+            args.health_port = Some(port);
+        }
+
+        if let Some(addr) = self.admin_socket.filter(|_| unset("admin_socket")) {
+            args.admin_socket = Some(addr);
+        }
+
+        if let Some(dscp) = self.dscp.filter(|_| unset("dscp")) {
+            if dscp > 63 {
+                let reason = format!("`{}`, expect 0-63", dscp);
+                return Err(fuso::BuildErr::InvalidOption("dscp", reason).into());
+            }
+
+            args.dscp = Some(dscp);
+        }
+
+        if let Some(ports) = self
+            .allowed_bind_ports
+            .filter(|_| unset("allowed_bind_ports"))
+        {
+            args.allowed_bind_ports =
+                Some(parse_port_range(&ports).map_err(invalid_option("allowed-bind-ports"))?);
+        }
+
+        if let Some(hosts) = self
+            .allowed_bind_hosts
+            .filter(|_| unset("allowed_bind_hosts"))
+        {
+            args.allowed_bind_hosts = hosts;
+        }
+
+        if let Some(allow_ip) = self.allow_ip.filter(|_| unset("allow_ip")) {
+            args.allow_ip = allow_ip
+                .iter()
+                .map(|cidr| cidr.parse())
+                .collect::<fuso::Result<_>>()
+                .map_err(invalid_option("allow-ip"))?;
+        }
+
+        if let Some(deny_ip) = self.deny_ip.filter(|_| unset("deny_ip")) {
+            args.deny_ip = deny_ip
+                .iter()
+                .map(|cidr| cidr.parse())
+                .collect::<fuso::Result<_>>()
+                .map_err(invalid_option("deny-ip"))?;
+        }
+
+        if let Some(rate) = self.accept_rate.filter(|_| unset("accept_rate")) {
+            args.accept_rate = Some(rate);
+        }
+
+        if let Some(burst) = self.accept_burst.filter(|_| unset("accept_burst")) {
+            args.accept_burst = Some(burst);
+        }
+
+        if let Some(rate) = self.ip_accept_rate.filter(|_| unset("ip_accept_rate")) {
+            args.ip_accept_rate = Some(rate);
+        }
+
+        if let Some(burst) = self.ip_accept_burst.filter(|_| unset("ip_accept_burst")) {
+            args.ip_accept_burst = Some(burst);
+        }
+
+        if let Some(rules) = self.egress_allow.filter(|_| unset("egress_allow")) {
+            args.egress_allow = rules
+                .iter()
+                .map(|rule| rule.parse())
+                .collect::<fuso::Result<_>>()
+                .map_err(invalid_option("egress-allow"))?;
+        }
+
+        if let Some(cert) = self.tls_cert.filter(|_| unset("tls_cert")) {
+            args.tls_cert = Some(cert);
+        }
+
+        if let Some(key) = self.tls_key.filter(|_| unset("tls_key")) {
+            args.tls_key = Some(key);
+        }
+
+        if let Some(banner) = self.banner.filter(|_| unset("banner")) {
+            args.banner = Some(banner);
+        }
+
+        if let Some(knock) = self.knock.filter(|_| unset("knock")) {
+            args.knock = Some(knock);
+        }
+
+        if let Some(decoy) = self.decoy.filter(|_| unset("decoy")) {
+            args.decoy = Some(decoy);
+        }
+
+        if let Some(path) = self.decoy_file.filter(|_| unset("decoy_file")) {
+            args.decoy_file = Some(path);
+        }
+
+        if let Some(token) = self.token.filter(|_| unset("token")) {
+            args.token = Some(token);
+        }
+
+        if let Some(path) = self.token_file.filter(|_| unset("token_file")) {
+            args.token_file = Some(path);
+        }
+
+        if let Some(tenant_key) = self.tenant_key.filter(|_| unset("tenant_key")) {
+            args.tenant_key = tenant_key
+                .iter()
+                .map(|key| parse_tenant_key(key))
+                .collect::<Result<_, _>>()
+                .map_err(invalid_option("tenant-key"))?;
+        }
+
+        if let Some(key) = self
+            .tenant_default_key
+            .filter(|_| unset("tenant_default_key"))
+        {
+            args.tenant_default_key = Some(key);
+        }
+
+        if let Some(cipher) = self.cipher.filter(|_| unset("cipher")) {
+            args.cipher = cipher
+                .iter()
+                .map(|cipher| cipher.parse())
+                .collect::<fuso::Result<_>>()
+                .map_err(invalid_option("cipher"))?;
+        }
+
+        if let Some(cipher) = self.control_cipher.filter(|_| unset("control_cipher")) {
+            args.control_cipher = cipher
+                .iter()
+                .map(|cipher| fuso::cli::parse_control_cipher(cipher))
+                .collect::<Result<_, String>>()
+                .map_err(invalid_option("control-cipher"))?;
+        }
+
+        if let Some(compress) = self.compress.filter(|_| unset("compress")) {
+            args.compress = compress
+                .iter()
+                .map(|compress| compress.parse())
+                .collect::<fuso::Result<_>>()
+                .map_err(invalid_option("compress"))?;
+        }
+
+        if let Some(socks_auth) = self.socks_auth.filter(|_| unset("socks_auth")) {
+            args.socks_auth = socks_auth
+                .iter()
+                .map(|auth| parse_socks_auth(auth))
+                .collect::<Result<_, _>>()
+                .map_err(invalid_option("socks-auth"))?;
+        }
+
+        #[cfg(feature = "fuso-log")]
+        if let Some(level) = self.log_level.filter(|_| unset("log_level")) {
+            args.log_level = level
+                .parse()
+                .map_err(|_| fuso::BuildErr::InvalidOption("log-level", format!("`{}`", level)))?;
+        }
+
+        #[cfg(feature = "fuso-log")]
+        if let Some(format) = self.log_format.filter(|_| unset("log_format")) {
+            args.log_format = format.parse().map_err(invalid_option("log-format"))?;
+        }
+
+        #[cfg(feature = "fuso-log")]
+        if let Some(path) = self.log_file.filter(|_| unset("log_file")) {
+            args.log_file = Some(path);
+        }
+
+        #[cfg(feature = "fuso-log")]
+        if let Some(rotation) = self.log_rotate.filter(|_| unset("log_rotate")) {
+            args.log_rotate = rotation.parse().map_err(invalid_option("log-rotate"))?;
+        }
+
+        #[cfg(feature = "fuso-log")]
+        if let Some(keep) = self.log_keep.filter(|_| unset("log_keep")) {
+            args.log_keep = keep;
+        }
+
+        #[cfg(feature = "fuso-log")]
+        if let Some(tee) = self.log_tee.filter(|_| unset("log_tee")) {
+            args.log_tee = tee;
+        }
+
+        Ok(())
+    }
+}
+
+impl FusoArgs {
+    /// 每个参数都可以通过 `FUSO_` 前缀的环境变量设置, 如 `--heartbeat-delay` 对应
+    /// `FUSO_HEARTBEAT_DELAY`, 避免token等密钥出现在进程的命令行中, 帮助信息中不显示环境变量的值
+    fn command_with_env() -> clap::Command<'static> {
+        use clap::CommandFactory;
+
+        fuso::cli::with_env(Self::command())
+    }
+
+    #[cfg(not(feature = "fuso-toml"))]
+    fn load() -> fuso::Result<Self> {
+        use clap::FromArgMatches;
+
+        let matches = Self::command_with_env().get_matches();
+        Ok(Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+    }
+
+    /// `--token-file` 指定的文件不存在或为空时在监听之前退出
+    fn with_token_file(mut self) -> Result<Self, fuso::BuildErr> {
+        if let Some(path) = self.token_file.as_ref() {
+            let token = fuso::cli::read_token_file(path).map_err(invalid_option("token-file"))?;
+            self.token = Some(token);
+        }
+
+        Ok(self)
+    }
+
+    /// 优先级为 命令行 > 环境变量 > 配置文件 > 默认值
+    #[cfg(feature = "fuso-toml")]
+    fn load() -> fuso::Result<Self> {
+        use clap::FromArgMatches;
+
+        let matches = Self::command_with_env().get_matches();
+        let mut args = Self::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+        if let Some(path) = args.config.clone() {
+            FusoConfig::load(&path)
+                .and_then(|config| config.merge(&mut args, &matches))
+                .map_err(|e| fuso::BuildErr::InvalidOption("config", format!("{}: {}", path, e)))?;
+        }
+
+        Ok(args)
+    }
+}
+
+/// 只指定了 `--tls-cert` 与 `--tls-key` 其中之一
+fn tls_pair() -> fuso::BuildErr {
+    let reason = String::from("--tls-cert and --tls-key must be specified together");
+    fuso::BuildErr::InvalidOption("tls-key", reason)
+}
+
+/// 选项的值无效, `option` 为命令行参数名
+fn invalid_option<E: std::fmt::Display>(option: &'static str) -> impl FnOnce(E) -> fuso::BuildErr {
+    move |e| fuso::BuildErr::InvalidOption(option, e.to_string())
+}
+
+fn parse_socks_auth(auth: &str) -> Result<(String, String), String> {
+    match auth.split_once(':') {
+        Some((username, password)) if !username.is_empty() => {
+            Ok((username.to_string(), password.to_string()))
+        }
+        _ => Err(format!("`{}`, expect `user:pass`", auth)),
+    }
+}
+
+fn parse_tenant_key(key: &str) -> Result<(String, String), String> {
+    match key.split_once('=') {
+        Some((name, passphrase)) if !name.is_empty() && !passphrase.is_empty() => {
+            Ok((name.to_string(), passphrase.to_string()))
+        }
+        _ => Err(format!("`{}`, expect `name=passphrase`", key)),
+    }
+}
+
+fn parse_port_range(ports: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("`{}`, expect `start-end`", ports);
+
+    let (start, end) = match ports.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => (ports.trim(), ports.trim()),
+    };
+
+    let start = start.parse::<u16>().map_err(|_| invalid())?;
+    let end = end.parse::<u16>().map_err(|_| invalid())?;
+
+    if start == 0 || start > end {
+        return Err(invalid());
+    }
+
+    Ok(start..=end)
+}
+
+/// `--check` 时调用, 校验加载配置时无法发现的错误, 不创建任何socket
+fn check(args: &FusoArgs) -> Result<(), fuso::BuildErr> {
+    use fuso::BuildErr;
+    use std::net::ToSocketAddrs;
+
+    let duplicated = |port: u16| format!("{} is specified more than once", port);
+
+    if args.port.is_empty() {
+        return Err(BuildErr::InvalidOption("port", "no port specified".into()));
+    }
+
+    for (i, port) in args.port.iter().enumerate() {
+        if args.port[..i].contains(port)
+            || args.metrics_port == Some(*port)
+            || args.health_port == Some(*port)
+        {
+            return Err(BuildErr::InvalidOption("port", duplicated(*port)));
+        }
+
+        let addr = fuso::Addr::from((args.listen.clone(), *port));
+
+        if addr.is_domain() {
+            (args.listen.as_str(), *port)
+                .to_socket_addrs()
+                .map_err(|e| BuildErr::InvalidOption("listen", format!("{}: {}", addr, e)))?;
+        }
+
+        println!("listen on {}", addr);
+    }
+
+    if let Some(port) = args.metrics_port {
+        println!(
+            "metrics on {}",
+            fuso::Addr::from((args.listen.clone(), port))
+        );
+    }
+
+    if let Some(port) = args.health_port {
+        if args.metrics_port == Some(port) {
+            return Err(BuildErr::InvalidOption("health-port", duplicated(port)));
+        }
+
+        println!(
+            "health check on {}",
+            fuso::Addr::from((args.listen.clone(), port))
+        );
+    }
+
+    if let Some(addr) = &args.admin_socket {
+        let addr = addr
+            .parse::<fuso::Addr>()
+            .map_err(|e| BuildErr::InvalidOption("admin-socket", format!("{}: {}", addr, e)))?;
+
+        println!("admin on {}", addr);
+    }
+
+    match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            fuso::tls::server_config(cert, key)
+                .map_err(|e| BuildErr::InvalidOption("tls-cert", format!("{}: {}", cert, e)))?;
+            println!("tls enabled, certificate {}", cert);
+        }
+        (None, None) => {}
+        _ => return Err(tls_pair()),
+    }
+
+    if let Some(path) = &args.decoy_file {
+        std::fs::read(path)
+            .map_err(|e| BuildErr::InvalidOption("decoy-file", format!("{}: {}", path, e)))?;
+    }
+
+    if args.max_frame_size == 0 {
+        let reason = String::from("must be greater than 0");
+        return Err(BuildErr::InvalidOption("max-frame-size", reason));
+    }
+
+    if args.cipher.is_empty() {
+        let reason = String::from("no cipher allowed");
+        return Err(BuildErr::InvalidOption("cipher", reason));
+    }
+
+    if args.control_cipher.is_empty() {
+        let reason = String::from("no control cipher allowed");
+        return Err(BuildErr::InvalidOption("control-cipher", reason));
+    }
+
+    if args.compress.is_empty() {
+        let reason = String::from("no compression allowed");
+        return Err(BuildErr::InvalidOption("compress", reason));
+    }
+
+    if let Some(ports) = &args.allowed_bind_ports {
+        println!("allowed bind ports {}-{}", ports.start(), ports.end());
+    }
+
+    if !args.allowed_bind_hosts.is_empty() {
+        println!("allowed bind hosts {:?}", args.allowed_bind_hosts);
+    }
+
+    if !args.allow_ip.is_empty() || !args.deny_ip.is_empty() {
+        println!("allow ip {:?}, deny ip {:?}", args.allow_ip, args.deny_ip);
+    }
+
+    if !args.egress_allow.is_empty() {
+        println!("egress allow {:?}", args.egress_allow);
+    }
+
+    println!(
+        "token {}",
+        if args.token.is_some() {
+            "required"
+        } else {
+            "not required"
+        }
+    );
+
+    Ok(())
 }
 
 #[cfg(feature = "fuso-log")]
-fn init_logger(log_level: log::LevelFilter) {
-    let is_info_log = log_level.eq(&log::LevelFilter::Info);
-    env_logger::builder()
-        .filter_module("fuso", log_level)
-        .default_format()
-        .format_timestamp_millis()
-        .format_target(!is_info_log)
-        .init();
+fn init_logger(args: &FusoArgs) -> fuso::Result<()> {
+    let is_info_log = args.log_level.eq(&log::LevelFilter::Info);
+
+    match args.log_file.as_ref() {
+        None => fuso::logger::init(args.log_level, args.log_format, !is_info_log),
+        Some(path) => fuso::logger::init_with_file(
+            args.log_level,
+            args.log_format,
+            !is_info_log,
+            fuso::logger::LogFile {
+                path: path.into(),
+                rotation: args.log_rotate,
+                keep: args.log_keep,
+                tee: args.log_tee,
+            },
+        )?,
+    }
+
+    Ok(())
 }
 
 #[cfg(feature = "fuso-rt-tokio")]
 #[tokio::main]
 async fn main() -> fuso::Result<()> {
     use fuso::{
-        penetrate::PenetrateRsaAndAesHandshake, Socket, TokioExecutor, TokioUdpServerProvider,
-        UdpForwardProvider,
+        acl::{AccessControl, Egress},
+        admin::Admin,
+        health::Health,
+        io::ForwardStrategy,
+        limit::{AcceptLimiter, RateLimit},
+        penetrate::{
+            parse_banner, Banner, PenetrateBannerHandshake, PenetrateDecoyHandshake,
+            PenetrateRsaAndAesHandshake, PenetrateTlsHandshake, PenetrateWebSocketHandshake,
+            TenantKey, DECOY_NOT_FOUND, DECOY_WRITE_TIMEOUT,
+        },
+        resource::ResourceGuard,
+        BuildErr, Socket, TokioAccepter, TokioExecutor, TokioUdpServerProvider, UdpForwardProvider,
+        WrappedProvider,
     };
     use std::time::Duration;
 
-    let args = FusoArgs::parse();
-    
+    let args = match FusoArgs::load().and_then(|args| Ok(args.with_token_file()?)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if args.check {
+        if let Err(e) = check(&args) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+
+        println!("configuration ok");
+        return Ok(());
+    }
+
     #[cfg(feature = "fuso-log")]
-    init_logger(args.log_level);
-
-    fuso::builder_server_with_tokio(())
-        .using_handshake(PenetrateRsaAndAesHandshake::Server)
-        .using_kcp(TokioUdpServerProvider, TokioExecutor)
-        .using_penetrate()
-        .heartbeat_timeout(Duration::from_secs(args.heartbeat_delay))
-        .using_adapter()
-        .using_direct()
-        .using_socks()
-        .using_udp_forward(UdpForwardProvider)
-        .build()
-        .bind(Socket::tcp((args.listen, args.port)))
-        .run()
-        .await
-        .expect("server start failed");
+    if let Err(e) = init_logger(&args) {
+        eprintln!("failed to open log file: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(port) = args.metrics_port {
+        let bind = Socket::tcp((args.listen.clone(), port));
+        tokio::spawn(async move {
+            if let Err(e) = fuso::metrics::serve(TokioAccepter::default(), bind).await {
+                log::error!("metrics server stopped {}", e);
+            }
+        });
+    }
+
+    let health = Health::default();
+
+    if let Some(port) = args.health_port {
+        let bind = Socket::tcp((args.listen.clone(), port));
+        let health = health.clone();
+        tokio::spawn(async move {
+            if let Err(e) =
+                fuso::health::serve(TokioAccepter::default(), bind, health, TokioExecutor).await
+            {
+                log::error!("health check server stopped {}", e);
+            }
+        });
+    }
+
+    // 没有管理服务时不记录正在转发的连接
+    let admin = args.admin_socket.as_ref().map(|_| Admin::default());
+
+    if let (Some(addr), Some(admin)) = (args.admin_socket.as_deref(), admin.clone()) {
+        let bind = addr
+            .parse::<fuso::Addr>()
+            .map_err(|e| BuildErr::InvalidOption("admin-socket", format!("{}: {}", addr, e)))?;
+        let bind = Socket::tcp(bind);
+        tokio::spawn(async move {
+            if let Err(e) =
+                fuso::admin::serve(TokioAccepter::default(), bind, admin, TokioExecutor).await
+            {
+                log::error!("admin server stopped {}", e);
+            }
+        });
+    }
+
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let config = fuso::tls::server_config(&cert, &key)
+                .map_err(|e| BuildErr::InvalidOption("tls-cert", format!("{}: {}", cert, e)))?;
+            log::info!("tls enabled, certificate {}", cert);
+            Some(config)
+        }
+        (None, None) => None,
+        _ => {
+            eprintln!("{}", tls_pair());
+            std::process::exit(1);
+        }
+    };
+
+    let tenant_keys = args
+        .tenant_key
+        .iter()
+        .map(|(name, passphrase)| Ok((name.clone(), TenantKey::new(passphrase)?)))
+        .collect::<fuso::Result<Vec<_>>>()
+        .map_err(invalid_option("tenant-key"))?;
+
+    let default_tenant_key = args
+        .tenant_default_key
+        .as_ref()
+        .map(TenantKey::new)
+        .transpose()
+        .map_err(invalid_option("tenant-default-key"))?;
+
+    let banner = Banner::default()
+        .greeting(
+            args.banner
+                .as_deref()
+                .map(parse_banner)
+                .transpose()
+                .map_err(|e| BuildErr::InvalidOption("banner", e))?,
+        )
+        .knock(
+            args.knock
+                .as_deref()
+                .map(parse_banner)
+                .transpose()
+                .map_err(|e| BuildErr::InvalidOption("knock", e))?,
+        );
+
+    let decoy = match (args.decoy.as_deref(), args.decoy_file.as_ref()) {
+        (_, Some(path)) => match std::fs::read(path) {
+            Ok(decoy) => Some(decoy),
+            Err(e) => {
+                let reason = format!("{}: {}", path, e);
+                eprintln!("{}", BuildErr::InvalidOption("decoy-file", reason));
+                std::process::exit(1);
+            }
+        },
+        (Some("404"), None) => Some(DECOY_NOT_FOUND.to_vec()),
+        (Some(decoy), None) => {
+            Some(parse_banner(decoy).map_err(|e| BuildErr::InvalidOption("decoy", e))?)
+        }
+        (None, None) => None,
+    };
+
+    let handshake_timeout = Some(args.handshake_timeout)
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+
+    // 设置了 `--decoy` 时由伪装层先超时并回复, 外层多留出回复的时间
+    let server_handshake_timeout = match decoy {
+        None => handshake_timeout,
+        Some(_) => handshake_timeout.map(|timeout| timeout + DECOY_WRITE_TIMEOUT),
+    };
+
+    // 所有端口共享, 全局限制对所有端口生效
+    let accept_limiter = AcceptLimiter::new()
+        .global(
+            args.accept_rate
+                .map(|rate| RateLimit::new(rate, args.accept_burst.unwrap_or(0))),
+        )
+        .per_ip(
+            args.ip_accept_rate
+                .map(|rate| RateLimit::new(rate, args.ip_accept_burst.unwrap_or(0))),
+        );
+
+    // 所有端口与转发共享采样结果
+    let resource_guard = ResourceGuard::new()
+        .min_available_memory(args.min_free_memory.map(|mb| mb * 1024 * 1024))
+        .min_free_fds(args.min_free_fds);
+
+    // 由systemd socket activation启动时使用传递的监听, 忽略 `--listen` 与 `--port`
+    let inherited = fuso::systemd::listen_fds();
+
+    let binds = if inherited.is_empty() {
+        args.port
+            .iter()
+            .map(|port| Socket::tcp((args.listen.clone(), *port)))
+            .collect::<Vec<_>>()
+    } else {
+        let binds = inherited
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .map(Socket::tcp)
+            .collect::<Vec<_>>();
+
+        println!("{} listeners passed by systemd", binds.len());
+
+        fuso::systemd::adopt(inherited);
+
+        binds
+    };
+
+    let mut servers = Vec::new();
+
+    // 每个端口使用独立的处理链, 共享同一个运行时
+    for bind in binds {
+        let handshake = PenetrateRsaAndAesHandshake::Server(
+            args.cipher.clone(),
+            args.compress.clone(),
+            args.control_cipher.clone(),
+        );
+
+        let handshake = match args.no_ws {
+            true => WrappedProvider::wrap(handshake),
+            false => WrappedProvider::wrap(
+                PenetrateWebSocketHandshake::server(handshake)
+                    .max_head_len(args.ws_max_head)
+                    .decoy(decoy.clone()),
+            ),
+        };
+
+        // 在websocket之后, 不使用websocket时同样回复伪装内容
+        let handshake = match decoy.clone() {
+            None => handshake,
+            Some(decoy) => WrappedProvider::wrap(
+                PenetrateDecoyHandshake::server(decoy, handshake).timeout(handshake_timeout),
+            ),
+        };
+
+        let handshake = match tls.clone() {
+            Some(config) => WrappedProvider::wrap(PenetrateTlsHandshake::server(config, handshake)),
+            None => handshake,
+        };
+
+        // 不设置时不读写任何数据, websocket的识别不受影响
+        let handshake = match banner.is_empty() {
+            true => handshake,
+            false => {
+                WrappedProvider::wrap(PenetrateBannerHandshake::server(banner.clone(), handshake))
+            }
+        };
+
+        let server = fuso::builder_server_with_tokio(());
+
+        // 不指定时使用各平台的默认值
+        let server = match args.reuse_addr {
+            Some(reuse) => server.reuse_addr(reuse),
+            None => server,
+        };
+
+        let server = server
+            .reuse_port(args.reuse_port)
+            .freebind(args.freebind)
+            .backlog(args.backlog)
+            .nodelay(args.nodelay)
+            .dscp(args.dscp)
+            .using_handshake(handshake)
+            .using_kcp(TokioUdpServerProvider, TokioExecutor)
+            .using_penetrate()
+            .heartbeat_timeout(Duration::from_secs(args.heartbeat_delay))
+            .keepalive_timeout(Duration::from_secs(args.keepalive_timeout))
+            .session_timeout(Duration::from_secs(args.session_timeout))
+            .idle_timeout(
+                args.idle_timeout
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs),
+            )
+            .max_lifetime(
+                args.max_lifetime
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs),
+            )
+            .forward_strategy(
+                ForwardStrategy::default()
+                    .buffer_size(args.forward_buffer)
+                    .splice(args.splice),
+            )
+            .trace_timing(args.trace_timing)
+            .stats_interval(
+                args.stats_interval
+                    .filter(|secs| *secs > 0)
+                    .map(Duration::from_secs),
+            )
+            .max_connections(args.max_connections.filter(|max| *max > 0))
+            .max_convs(args.max_convs.filter(|max| *max > 0))
+            .resource_guard(resource_guard.clone())
+            .admin(admin.clone())
+            .socks5_credentials(args.socks_auth.clone())
+            .allowed_bind_ports(args.allowed_bind_ports.clone())
+            .allowed_bind_hosts(args.allowed_bind_hosts.clone())
+            .allow_random_bind_port(!args.deny_random_port)
+            .egress(Egress::new(args.egress_allow.clone()))
+            .udp_public_ip(args.udp_public_ip)
+            .udp_max_datagram(args.udp_max_datagram as usize)
+            .max_frame_size(args.max_frame_size)
+            .max_udp_associations(args.max_udp_associations.filter(|max| *max > 0))
+            .bind_retries(args.bind_retries)
+            .proxy_protocol(args.proxy_protocol)
+            .token(args.token.clone())
+            .tenant_keys(tenant_keys.clone())
+            .default_tenant_key(default_tenant_key.clone())
+            .using_adapter()
+            .using_direct()
+            .using_socks()
+            .using_udp_forward(
+                UdpForwardProvider::default()
+                    .recv_buffer_size(args.udp_recv_buffer.filter(|size| *size > 0))
+                    .send_buffer_size(args.udp_send_buffer.filter(|size| *size > 0)),
+            )
+            .using_http_connect()
+            .using_sni()
+            .using_http_host()
+            .build()
+            .bind(bind)
+            .grace_period(Duration::from_secs(args.shutdown_grace))
+            .health(health.clone())
+            .handshake_timeout(server_handshake_timeout)
+            .access_control(AccessControl::new(
+                args.allow_ip.clone(),
+                args.deny_ip.clone(),
+            ))
+            .accept_limiter(accept_limiter.clone())
+            .resource_guard(resource_guard.clone());
+
+        servers.push(server);
+    }
+
+    let shutdowns = servers
+        .iter()
+        .map(|server| server.shutdown())
+        .collect::<Vec<_>>();
+
+    // 所有端口开始接受连接后通知systemd, 不是由systemd以 `Type=notify` 启动时忽略
+    health.on_bound(servers.len(), || {
+        if let Err(e) = fuso::systemd::notify("READY=1") {
+            log::warn!("failed to notify systemd {}", e);
+        }
+    });
+
+    tokio::spawn(async move {
+        wait_signal().await;
+        log::info!("received exit signal, shutting down");
+        let _ = fuso::systemd::notify("STOPPING=1");
+        shutdowns.iter().for_each(|shutdown| shutdown.shutdown());
+        wait_signal().await;
+        log::warn!("received exit signal again, force exit");
+        std::process::exit(1);
+    });
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    for server in servers {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let _ = tx.send(server.run().await);
+        });
+    }
+
+    drop(tx);
+
+    while let Some(result) = rx.recv().await {
+        if let Err(e) = result {
+            eprintln!("server start failed: {}", e);
+            std::process::exit(1);
+        }
+    }
 
     Ok(())
 }
 
+#[cfg(feature = "fuso-rt-tokio")]
+async fn wait_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut term = signal(SignalKind::terminate()).expect("failed to listen SIGTERM");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = term.recv() => {},
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 #[cfg(feature = "fuso-web")]
 #[tokio::main]
 async fn main() {}
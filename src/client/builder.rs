@@ -5,13 +5,15 @@ use crate::{
     Socket, Stream, WrappedProvider,
 };
 
-use super::Client;
+use super::{Backoff, Client};
 
 type BoxedFuture<T> = Pin<Box<dyn Future<Output = crate::Result<T>> + Send + 'static>>;
 
 pub struct ClientBuilder<E, P, S> {
     pub(crate) executor: E,
     pub(crate) retry_delay: Option<Duration>,
+    pub(crate) retry_max_delay: Option<Duration>,
+    pub(crate) retry_jitter: f64,
     pub(crate) maximum_retries: Option<usize>,
     pub(crate) handshake: Option<WrappedProvider<S, (S, Option<DecorateProvider<S>>)>>,
     pub(crate) client_provider: ClientProvider<P>,
@@ -34,6 +36,22 @@ where
         self
     }
 
+    /// 使用调用者提供的执行器运行连接任务, 可与应用共用同一个运行时
+    pub fn with_executor<E2>(self, executor: E2) -> ClientBuilder<E2, P, S>
+    where
+        E2: Executor + 'static,
+    {
+        ClientBuilder {
+            executor,
+            retry_delay: self.retry_delay,
+            retry_max_delay: self.retry_max_delay,
+            retry_jitter: self.retry_jitter,
+            maximum_retries: self.maximum_retries,
+            handshake: self.handshake,
+            client_provider: self.client_provider,
+        }
+    }
+
     pub fn build<A: Into<Socket>, H, G>(self, socket: A, handler: H) -> Fuso<Client<E, H, P, S>>
     where
         G: Generator<Output = Option<BoxedFuture<()>>> + Unpin + Send + 'static,
@@ -43,11 +61,16 @@ where
             + 'static,
     {
         let socket = socket.into();
+        let retry_delay = self.retry_delay.unwrap_or(Duration::from_secs(5));
 
         Fuso(Client {
             socket: socket.clone(),
             maximum_retries: self.maximum_retries,
-            retry_delay: self.retry_delay.unwrap_or(Duration::from_secs(5)),
+            backoff: Backoff {
+                min: retry_delay,
+                max: self.retry_max_delay.unwrap_or(retry_delay),
+                jitter: self.retry_jitter,
+            },
             handler: Arc::new(handler),
             executor: Arc::new(self.executor),
             handshake: self.handshake,
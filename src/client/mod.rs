@@ -6,6 +6,7 @@ pub use builder::*;
 
 use crate::{
     generator::{Generator, GeneratorEx},
+    protocol::ErrorCode,
     time, ClientProvider, DecorateProvider, Executor, Fuso, Kind, Processor, Provider, Serve,
     Socket, Stream, WrappedProvider,
 };
@@ -17,12 +18,34 @@ pub enum Route<S> {
     Provider(WrappedProvider<S, ()>),
 }
 
+/// 重连延时, 每次失败后从 `min` 开始加倍直到 `max`,
+/// 再随机减少最多 `jitter` 比例, 避免大量客户端同时重连
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+    pub jitter: f64,
+}
+
+impl Backoff {
+    pub fn delay(&self, attempts: usize) -> Duration {
+        let max = self.max.max(self.min);
+
+        let delay = self
+            .min
+            .checked_mul(1 << attempts.min(31))
+            .map_or(max, |delay| delay.min(max));
+
+        delay.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * rand::random::<f64>())
+    }
+}
+
 pub struct Client<E, H, P, S> {
     pub(crate) socket: Socket,
     pub(crate) executor: Arc<E>,
     pub(crate) handler: Arc<H>,
     pub(crate) maximum_retries: Option<usize>,
-    pub(crate) retry_delay: Duration,
+    pub(crate) backoff: Backoff,
     pub(crate) handshake: Option<WrappedProvider<S, (S, Option<DecorateProvider<S>>)>>,
     pub(crate) client_provider: ClientProvider<P>,
 }
@@ -44,6 +67,8 @@ where
         let handshake = self.handshake;
         let mut retries_count = 0;
         let maximum_retries = self.maximum_retries;
+        // 映射排空后等待正在转发的连接结束
+        let forwards = crate::server::Shutdown::default();
 
         loop {
             let socket = self.socket.clone();
@@ -55,7 +80,7 @@ where
                 }
                 Err(e) => {
                     log::warn!("connect to {} failed err: {}", self.socket, e);
-                    time::sleep(self.retry_delay).await;
+                    time::sleep(self.backoff.delay(retries_count)).await;
 
                     if let Some(retries) = maximum_retries {
                         if retries > retries_count {
@@ -90,20 +115,39 @@ where
                 Ok(generate) => generate,
                 Err(e) => {
                     log::warn!("processing failed ! err: {}", e);
-                    time::sleep(self.retry_delay).await;
+                    time::sleep(self.backoff.delay(retries_count)).await;
+                    retries_count += 1;
                     continue;
                 }
             };
 
+            retries_count = 0;
+
             loop {
                 match generate.next().await {
                     Ok(None) => break,
                     Ok(Some(fut)) => {
-                        executor.spawn(fut);
+                        executor.spawn(forwards.track(fut));
+                    }
+                    Err(e) if ErrorCode::from(&e) == ErrorCode::Expired => {
+                        log::info!("the mapping has expired, stop reconnecting");
+                        return Ok(());
+                    }
+                    Err(e) if ErrorCode::from(&e) == ErrorCode::Drained => {
+                        log::info!(
+                            "the mapping was drained, wait for {} forwards",
+                            forwards.inflight()
+                        );
+
+                        forwards.idle().await;
+
+                        log::info!("all forwards finished, stop reconnecting");
+                        return Ok(());
                     }
                     Err(e) => {
                         log::error!("encountered an error err: {}", e);
-                        time::sleep(self.retry_delay).await;
+                        time::sleep(self.backoff.delay(retries_count)).await;
+                        retries_count += 1;
                         break;
                     }
                 }
@@ -129,3 +173,35 @@ where
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Backoff;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            min: Duration::from_secs(1),
+            max: Duration::from_secs(20),
+            jitter: 0.0,
+        };
+
+        assert_eq!(backoff.delay(0), Duration::from_secs(1));
+        assert_eq!(backoff.delay(3), Duration::from_secs(8));
+        assert_eq!(backoff.delay(5), Duration::from_secs(20));
+        assert_eq!(backoff.delay(usize::MAX), Duration::from_secs(20));
+
+        let backoff = Backoff {
+            jitter: 0.5,
+            ..backoff
+        };
+
+        for attempts in 0..10 {
+            let delay = backoff.delay(attempts);
+            let max = Duration::from_secs(1 << attempts.min(5)).min(Duration::from_secs(20));
+            assert!(delay <= max && delay >= max / 2);
+        }
+    }
+}
@@ -9,6 +9,12 @@ pub trait NetSocket {
     fn peer_addr(&self) -> crate::Result<Address>;
 
     fn local_addr(&self) -> crate::Result<Address>;
+
+    /// 未经加密或压缩的系统连接返回其文件描述符, 用于 `splice` 零拷贝转发
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
 }
 
 pub trait Accepter: NetSocket {
@@ -0,0 +1,242 @@
+use std::{net::IpAddr, str::FromStr};
+
+use crate::{Addr, Address, InnerAddr};
+
+/// ip网段, 如 `10.0.0.0/8`, `fe80::/10`, 不带前缀时为单个地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// 按客户端来源ip过滤连接, 拒绝列表优先,
+/// 允许列表不为空时只接受其中的地址
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+/// 出站规则, ip网段或域名, `*.example.com` 与 `.example.com` 匹配所有子域名
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EgressRule {
+    Cidr(Cidr),
+    Domain(String),
+}
+
+/// 访问者通过服务端(socks, http代理等)可以到达的目标, 为空时不限制.
+/// ip目标只匹配网段, 域名目标只匹配域名规则, 服务端不解析域名
+#[derive(Debug, Clone, Default)]
+pub struct Egress {
+    rules: Vec<EgressRule>,
+}
+
+/// ipv4映射的ipv6地址转换为ipv4, 监听 `::` 时ipv4客户端的地址为此格式
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = crate::Error;
+
+    fn from_str(cidr: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cidr `{}`", cidr);
+
+        let (addr, prefix) = match cidr.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (cidr.trim(), None),
+        };
+
+        let addr = canonical(addr.parse::<IpAddr>().map_err(|_| invalid())?);
+
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix.parse::<u8>().map_err(|_| invalid())?,
+        };
+
+        if prefix > max {
+            return Err(invalid().into());
+        }
+
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl FromStr for EgressRule {
+    type Err = crate::Error;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let rule = rule.trim();
+
+        if let Ok(cidr) = rule.parse::<Cidr>() {
+            return Ok(Self::Cidr(cidr));
+        }
+
+        let domain = rule.trim_end_matches('.').to_ascii_lowercase();
+        let name = domain
+            .strip_prefix("*.")
+            .or_else(|| domain.strip_prefix('.'))
+            .unwrap_or(&domain);
+
+        let valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty()
+                    && label
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+
+        match valid {
+            true => Ok(Self::Domain(domain)),
+            false => Err(format!("invalid egress rule `{}`", rule).into()),
+        }
+    }
+}
+
+impl EgressRule {
+    fn matches(&self, addr: &Addr) -> bool {
+        match (self, addr.inner()) {
+            (Self::Cidr(cidr), InnerAddr::Socket(addr)) => cidr.contains(addr.ip()),
+            (Self::Domain(rule), InnerAddr::Domain(domain, _)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+
+                match rule.strip_prefix("*.").or_else(|| rule.strip_prefix('.')) {
+                    None => domain == *rule,
+                    Some(parent) => domain
+                        .strip_suffix(parent)
+                        .is_some_and(|sub| sub.ends_with('.')),
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Egress {
+    pub fn new(rules: Vec<EgressRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// 规则为空时允许所有目标, unix域套接字不匹配任何规则
+    pub fn is_allowed(&self, addr: &Addr) -> bool {
+        self.is_empty() || self.rules.iter().any(|rule| rule.matches(addr))
+    }
+}
+
+impl AccessControl {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// 返回被拒绝的ip, 无法取得ip的地址不做限制
+    pub fn check(&self, addr: &Address) -> Option<IpAddr> {
+        match addr {
+            Address::One(socket) => match socket.inner() {
+                InnerAddr::Socket(addr) if !self.is_allowed(addr.ip()) => Some(addr.ip()),
+                _ => None,
+            },
+            Address::Many(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessControl, Cidr, Egress, EgressRule};
+    use crate::Addr;
+
+    #[test]
+    fn test_access_control() {
+        let cidr = |cidr: &str| cidr.parse::<Cidr>().unwrap();
+
+        assert!(cidr("10.0.0.0/8").contains("10.2.3.4".parse().unwrap()));
+        assert!(!cidr("10.0.0.0/8").contains("11.0.0.1".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains("::ffff:1.2.3.4".parse().unwrap()));
+        assert!(cidr("fe80::/10").contains("fe80::1".parse().unwrap()));
+        assert!(!cidr("fe80::/10").contains("10.0.0.1".parse().unwrap()));
+        assert!(cidr("::1").contains("::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("fuso/8".parse::<Cidr>().is_err());
+
+        let acl = AccessControl::new(
+            vec![cidr("192.168.0.0/16"), cidr("2001:db8::/32")],
+            vec![cidr("192.168.1.0/24")],
+        );
+
+        assert!(acl.is_allowed("192.168.2.1".parse().unwrap()));
+        assert!(acl.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!acl.is_allowed("192.168.1.1".parse().unwrap()));
+        assert!(!acl.is_allowed("8.8.8.8".parse().unwrap()));
+
+        let acl = AccessControl::new(vec![], vec![cidr("8.8.8.8")]);
+        assert!(acl.is_allowed("1.1.1.1".parse().unwrap()));
+        assert!(!acl.is_allowed("8.8.8.8".parse().unwrap()));
+        assert!(AccessControl::default().is_empty());
+    }
+
+    #[test]
+    fn test_egress() {
+        let rule = |rule: &str| rule.parse::<EgressRule>().unwrap();
+        let addr = |host: &str| Addr::from((String::from(host), 443));
+
+        let egress = Egress::new(vec![
+            rule("10.0.0.0/8"),
+            rule("example.com"),
+            rule("*.fuso.dev"),
+        ]);
+
+        assert!(egress.is_allowed(&addr("10.1.2.3")));
+        assert!(!egress.is_allowed(&addr("192.168.1.1")));
+        assert!(egress.is_allowed(&addr("Example.COM.")));
+        assert!(!egress.is_allowed(&addr("www.example.com")));
+        assert!(egress.is_allowed(&addr("api.fuso.dev")));
+        assert!(!egress.is_allowed(&addr("fuso.dev")));
+        assert!(!egress.is_allowed(&addr("evilfuso.dev")));
+        assert!(!egress.is_allowed(&Addr::unix("/tmp/fuso.sock")));
+
+        assert!("exa mple.com".parse::<EgressRule>().is_err());
+        assert!("*.".parse::<EgressRule>().is_err());
+        assert!(Egress::default().is_allowed(&addr("192.168.1.1")));
+    }
+}
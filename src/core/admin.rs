@@ -0,0 +1,500 @@
+use std::{
+    fmt::Write,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::json::json_string;
+use crate::{
+    ext::{AsyncReadExt, AsyncWriteExt},
+    io::Traffic,
+    time, Accepter, AccepterExt, Addr, Address, Executor, FusoStream, Provider, Socket, Stream,
+    ToBoxStream,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+const MAX_COMMAND_LEN: usize = 256;
+
+/// 命令只有一行, 读取超时后直接断开
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Inner {
+    tunnels: Mutex<Vec<Tunnel>>,
+    clients: Mutex<Vec<Client>>,
+}
+
+/// 管理服务查询与操作的状态, 由服务端记录已连接的客户端与正在转发的连接.
+/// 服务端与管理服务共享同一个, 服务端没有设置时不记录
+#[derive(Default, Clone)]
+pub struct Admin(Arc<Inner>);
+
+/// 正在转发的连接
+pub struct Tunnel {
+    pub cid: Arc<str>,
+    pub conv: u32,
+    pub name: String,
+    /// 访问端口的监听地址
+    pub bind_addr: Address,
+    /// 客户端地址
+    pub client_addr: Address,
+    /// 访问者地址
+    pub visitor_addr: Address,
+    pub traffic: Arc<Traffic>,
+    pub started: Instant,
+}
+
+/// 已连接的客户端, 由服务端把管理连接交给客户端映射到指定地址
+pub struct Client {
+    pub cid: Arc<str>,
+    pub name: String,
+    /// 客户端断开后关闭
+    pub push: async_channel::Sender<(FusoStream, Socket)>,
+    /// 要求服务端排空该客户端的映射
+    pub drain: async_channel::Sender<()>,
+}
+
+fn addr_string(addr: &Address) -> String {
+    match addr {
+        Address::One(socket) => socket.as_string(),
+        Address::Many(sockets) => sockets
+            .iter()
+            .map(|socket| socket.as_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+fn json_error(message: &str) -> String {
+    let mut out = String::from("{\"error\":");
+    json_string(&mut out, message);
+    out.push('}');
+    out
+}
+
+impl std::fmt::Debug for Admin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tunnels = self.0.tunnels.lock().map(|tunnels| tunnels.len());
+        f.debug_struct("Admin")
+            .field("tunnels", &tunnels.ok())
+            .finish()
+    }
+}
+
+impl Admin {
+    /// 记录一个开始转发的连接, 同时清理已经结束的连接
+    pub fn register(&self, tunnel: Tunnel) {
+        if let Ok(mut tunnels) = self.0.tunnels.lock() {
+            tunnels.retain(|tunnel| !tunnel.traffic.is_finished());
+            tunnels.push(tunnel);
+        }
+    }
+
+    /// 记录一个已连接的客户端, 同时清理已经断开的客户端
+    pub fn register_client(&self, client: Client) {
+        if let Ok(mut clients) = self.0.clients.lock() {
+            clients.retain(|client| !client.push.is_closed());
+            clients.push(client);
+        }
+    }
+
+    /// 以json数组输出正在转发的连接
+    pub fn list(&self) -> String {
+        let mut out = String::from("[");
+
+        let tunnels = match self.0.tunnels.lock() {
+            Ok(tunnels) => tunnels,
+            Err(_) => return json_error("lock poisoned"),
+        };
+
+        let active = tunnels
+            .iter()
+            .filter(|tunnel| !tunnel.traffic.is_finished());
+
+        for (i, tunnel) in active.enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+
+            out.push_str("{\"cid\":");
+            json_string(&mut out, &tunnel.cid);
+            let _ = write!(out, ",\"conv\":{},\"name\":", tunnel.conv);
+            json_string(&mut out, &tunnel.name);
+            out.push_str(",\"bind\":");
+            json_string(&mut out, &addr_string(&tunnel.bind_addr));
+            out.push_str(",\"client\":");
+            json_string(&mut out, &addr_string(&tunnel.client_addr));
+            out.push_str(",\"visitor\":");
+            json_string(&mut out, &addr_string(&tunnel.visitor_addr));
+            let _ = write!(
+                out,
+                ",\"rx_bytes\":{},\"tx_bytes\":{},\"uptime\":{}}}",
+                tunnel.traffic.rx_bytes(),
+                tunnel.traffic.tx_bytes(),
+                tunnel.started.elapsed().as_secs()
+            );
+        }
+
+        out.push(']');
+        out
+    }
+
+    /// 强制关闭指定conv的连接, 不同客户端的conv可能重复, 此时需要指定cid
+    pub fn kill(&self, conv: u32, cid: Option<&str>) -> Result<(), String> {
+        let tunnels = self
+            .0
+            .tunnels
+            .lock()
+            .map_err(|_| String::from("lock poisoned"))?;
+
+        let matched = tunnels
+            .iter()
+            .filter(|tunnel| tunnel.conv == conv && !tunnel.traffic.is_finished())
+            .filter(|tunnel| match cid {
+                None => true,
+                Some(cid) => &*tunnel.cid == cid,
+            })
+            .collect::<Vec<_>>();
+
+        match matched.as_slice() {
+            [] => Err(format!("conv {} does not exist", conv)),
+            [tunnel] => {
+                log::info!(cid = &*tunnel.cid, conv = conv; "connection reset by admin");
+                tunnel.traffic.reset();
+                Ok(())
+            }
+            _ => Err(format!("conv {} is ambiguous, specify the cid", conv)),
+        }
+    }
+
+    /// 处理一行命令: `list`, `kill <conv> [cid]`, `drain <client>` 或 `loglevel [level]`
+    pub fn execute(&self, command: &str) -> String {
+        let mut args = command.split_whitespace();
+
+        match (args.next(), args.next(), args.next()) {
+            (Some("list"), None, None) => self.list(),
+            (Some("drain"), Some(client), None) => match self.drain(client) {
+                Ok(()) => String::from("{\"ok\":true}"),
+                Err(e) => json_error(&e),
+            },
+            (Some("kill"), Some(conv), cid) => match conv.parse::<u32>() {
+                Err(_) => json_error(&format!("invalid conv `{}`", conv)),
+                Ok(conv) => match self.kill(conv, cid) {
+                    Ok(()) => String::from("{\"ok\":true}"),
+                    Err(e) => json_error(&e),
+                },
+            },
+            (Some("loglevel"), level, None) => match loglevel(level) {
+                Ok(out) => out,
+                Err(e) => json_error(&e),
+            },
+            _ => json_error(
+                "unknown command, expect `list`, `kill <conv> [cid]`, `drain <client>`, `connect <client> <addr>` or `loglevel [level]`",
+            ),
+        }
+    }
+
+    /// 查找名称或cid为 `client` 的客户端, 同名的客户端有多个时需要指定cid
+    fn find_client<R>(&self, client: &str, f: impl FnOnce(&Client) -> R) -> Result<R, String> {
+        let clients = self
+            .0
+            .clients
+            .lock()
+            .map_err(|_| String::from("lock poisoned"))?;
+
+        let matched = clients
+            .iter()
+            .filter(|c| !c.push.is_closed() && (c.name == client || &*c.cid == client))
+            .collect::<Vec<_>>();
+
+        match matched.as_slice() {
+            [] => Err(format!("client {} does not exist", client)),
+            [c] => Ok(f(c)),
+            _ => Err(format!("client {} is ambiguous, specify the cid", client)),
+        }
+    }
+
+    /// 让名称或cid为 `client` 的客户端连接 `target`, 并与 `stream` 转发
+    pub fn connect(&self, client: &str, target: Socket, stream: FusoStream) -> Result<(), String> {
+        push(self.find_client(client, push_of)?, target, stream)
+    }
+
+    /// 服务端停止接受名称或cid为 `client` 的客户端的新访问, 正在转发的连接不受影响,
+    /// 等待中的访问处理完后结束映射, 客户端不再重连
+    pub fn drain(&self, client: &str) -> Result<(), String> {
+        let (cid, drain) = self.find_client(client, |c| (c.cid.clone(), c.drain.clone()))?;
+
+        log::info!(cid = &*cid; "admin drains the client");
+
+        // 已经断开的客户端不会被找到, 发送失败只可能是已经请求过排空
+        let _ = drain.try_send(());
+
+        Ok(())
+    }
+}
+
+type Push = (Arc<str>, async_channel::Sender<(FusoStream, Socket)>);
+
+fn push(client: Push, target: Socket, stream: FusoStream) -> Result<(), String> {
+    let (cid, push) = client;
+
+    log::info!(cid = &*cid; "admin connects to {}", target);
+
+    push.try_send((stream, target))
+        .map_err(|_| format!("client {} has disconnected", cid))
+}
+
+fn push_of(client: &Client) -> Push {
+    (client.cid.clone(), client.push.clone())
+}
+
+/// 修改日志级别, 级别与 `-l` 参数相同, 不指定时只返回当前级别
+#[cfg(feature = "fuso-log")]
+pub fn loglevel(level: Option<&str>) -> Result<String, String> {
+    use crate::logger;
+
+    if let Some(level) = level {
+        let level = level
+            .parse::<log::LevelFilter>()
+            .map_err(|_| format!("invalid log level `{}`", level))?;
+
+        logger::set_level(level);
+
+        log::warn!("log level changed to {} by admin", level);
+    }
+
+    let mut out = String::from("{\"level\":");
+    json_string(&mut out, &logger::level().to_string().to_lowercase());
+    out.push('}');
+
+    Ok(out)
+}
+
+#[cfg(not(feature = "fuso-log"))]
+pub fn loglevel(_: Option<&str>) -> Result<String, String> {
+    Err(String::from("logger is not enabled"))
+}
+
+/// 启动管理服务, 每个连接读取一行命令, 返回一行json后关闭.
+/// `connect <client> <addr>` 成功时返回后不关闭, 之后的数据由客户端转发到 `addr`
+pub async fn serve<P, A, S, E>(
+    provider: P,
+    bind: Socket,
+    admin: Admin,
+    executor: E,
+) -> crate::Result<()>
+where
+    P: Provider<Socket, Output = BoxedFuture<A>>,
+    A: Accepter<Stream = S> + Unpin,
+    S: Stream + Send + 'static,
+    E: Executor,
+{
+    let mut accepter = provider.call(bind).await?;
+
+    log::info!("admin listens on {}", accepter.local_addr()?);
+
+    loop {
+        let stream = accepter.accept().await?;
+        let admin = admin.clone();
+
+        // 每个连接单独处理, 避免慢的连接阻塞其他命令
+        executor.spawn(async move {
+            if let Err(e) = respond(stream, admin).await {
+                log::debug!("admin request failed {}", e);
+            }
+        });
+    }
+}
+
+async fn respond<S>(stream: S, admin: Admin) -> crate::Result<()>
+where
+    S: Stream + Send + 'static,
+{
+    let (mut stream, command) = time::wait_for(READ_TIMEOUT, async move {
+        let mut stream = stream;
+        let mut command = Vec::new();
+        let mut buf = [0u8; 1];
+
+        // 逐字节读取, 不读取命令之后需要转发的数据
+        while !command.ends_with(b"\n") && command.len() < MAX_COMMAND_LEN {
+            match stream.read(&mut buf).await? {
+                0 => break,
+                _ => command.push(buf[0]),
+            }
+        }
+
+        Ok::<_, crate::Error>((stream, command))
+    })
+    .await??;
+
+    let command = String::from_utf8_lossy(&command);
+    let command = command.lines().next().unwrap_or_default();
+
+    log::debug!("admin command `{}`", command);
+
+    let mut args = command.split_whitespace();
+
+    let target = match (args.next(), args.next(), args.next(), args.next()) {
+        (Some("connect"), Some(client), Some(addr), None) => match addr.parse::<Addr>() {
+            Ok(addr) => Some((client.to_owned(), Socket::tcp(addr))),
+            Err(_) => return reply(stream, json_error(&format!("invalid addr `{}`", addr))).await,
+        },
+        _ => None,
+    };
+
+    let (client, target) = match target.map(|(c, t)| (admin.find_client(&c, push_of), t)) {
+        Some((Ok(client), target)) => (client, target),
+        Some((Err(e), _)) => return reply(stream, json_error(&e)).await,
+        None => return reply(stream, admin.execute(command)).await,
+    };
+
+    time::wait_for(WRITE_TIMEOUT, async move {
+        stream.write_all(b"{\"ok\":true}\n").await?;
+
+        if let Err(e) = push(client, target, stream.into_boxed_stream()) {
+            log::warn!("admin failed to connect {}", e);
+        }
+
+        Ok(())
+    })
+    .await?
+}
+
+/// 返回一行json后关闭
+async fn reply<S>(mut stream: S, mut response: String) -> crate::Result<()>
+where
+    S: Stream + Send + 'static,
+{
+    response.push('\n');
+
+    time::wait_for(WRITE_TIMEOUT, async move {
+        stream.write_all(response.as_bytes()).await?;
+        stream.close().await
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Instant};
+
+    use super::{Admin, Client, Tunnel};
+    use crate::{io::Traffic, Address, MemoryStream, Socket, ToBoxStream};
+
+    #[test]
+    fn test_admin_commands() {
+        let admin = Admin::default();
+
+        let tunnel = |cid: &str, conv: u32| {
+            let traffic = Arc::new(Traffic::default());
+            admin.register(Tunnel {
+                cid: Arc::from(cid),
+                conv,
+                name: String::from("fu\"so"),
+                bind_addr: Address::One(Socket::tcp(8080)),
+                client_addr: Address::One(Socket::tcp(([127, 0, 0, 1], 6000))),
+                visitor_addr: Address::One(Socket::tcp(([127, 0, 0, 1], 6001))),
+                traffic: traffic.clone(),
+                started: Instant::now(),
+            });
+            traffic
+        };
+
+        let t1 = tunnel("aaaaaaaa", 7);
+        let t2 = tunnel("bbbbbbbb", 7);
+        let t3 = tunnel("bbbbbbbb", 8);
+
+        assert!(admin
+            .list()
+            .contains("\"cid\":\"aaaaaaaa\",\"conv\":7,\"name\":\"fu\\\"so\""));
+        assert!(admin.list().contains("\"bind\":\"0.0.0.0:8080\""));
+
+        assert!(admin.kill(7, None).unwrap_err().contains("ambiguous"));
+        assert_eq!(admin.execute("kill 7 bbbbbbbb"), "{\"ok\":true}");
+        assert!(t2.is_reset() && !t1.is_reset());
+
+        t3.finish();
+        assert!(!admin.list().contains("\"conv\":8"));
+        assert!(admin.execute("kill 8").contains("does not exist"));
+        assert!(admin.execute("kill x").contains("invalid conv"));
+        assert!(admin.execute("stop").contains("unknown command"));
+
+        // 其他实例记录的连接互不可见
+        assert_eq!(Admin::default().list(), "[]");
+
+        #[cfg(feature = "fuso-log")]
+        {
+            let level = crate::logger::level();
+            assert_eq!(admin.execute("loglevel debug"), "{\"level\":\"debug\"}");
+            assert_eq!(admin.execute("loglevel"), "{\"level\":\"debug\"}");
+            assert!(admin
+                .execute("loglevel verbose")
+                .contains("invalid log level"));
+            crate::logger::set_level(level);
+        }
+    }
+
+    #[test]
+    fn test_admin_connect() {
+        let admin = Admin::default();
+
+        let client = |cid: &str, name: &str| {
+            let (push, pushed) = async_channel::unbounded();
+            let (drain, drained) = async_channel::bounded(1);
+            admin.register_client(Client {
+                cid: Arc::from(cid),
+                name: String::from(name),
+                push,
+                drain,
+            });
+            (pushed, drained)
+        };
+
+        let stream = || {
+            let (stream, _) =
+                MemoryStream::pair(([127, 0, 0, 1], 9000).into(), ([127, 0, 0, 1], 9001).into());
+            stream.into_boxed_stream()
+        };
+
+        let (web1, _) = client("cccccccc", "web");
+        let (web2, drained) = client("dddddddd", "web");
+        let (db, _) = client("eeeeeeee", "db");
+
+        let target = Socket::tcp(([10, 0, 0, 1], 22));
+
+        assert!(admin
+            .connect("web", target.clone(), stream())
+            .unwrap_err()
+            .contains("ambiguous"));
+        assert!(admin
+            .connect("ssh", target.clone(), stream())
+            .unwrap_err()
+            .contains("does not exist"));
+
+        admin.connect("dddddddd", target.clone(), stream()).unwrap();
+        assert!(web1.try_recv().is_err());
+        assert_eq!(web2.try_recv().unwrap().1, target);
+
+        // 其他实例记录的客户端互不可见
+        assert!(Admin::default()
+            .connect("dddddddd", target.clone(), stream())
+            .unwrap_err()
+            .contains("does not exist"));
+
+        assert!(admin.execute("drain web").contains("ambiguous"));
+        assert_eq!(admin.execute("drain dddddddd"), "{\"ok\":true}");
+        assert_eq!(admin.execute("drain dddddddd"), "{\"ok\":true}");
+        assert!(drained.try_recv().is_ok() && drained.try_recv().is_err());
+
+        // 客户端断开后不再匹配
+        drop(db);
+        assert!(admin
+            .connect("db", target.clone(), stream())
+            .unwrap_err()
+            .contains("does not exist"));
+    }
+}
@@ -27,6 +27,11 @@ impl NetSocket for FusoStream {
     fn local_addr(&self) -> crate::Result<Address> {
         self.0.local_addr()
     }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.0.raw_fd()
+    }
 }
 
 impl AsyncWrite for FusoStream {
@@ -0,0 +1,98 @@
+use crate::encryption::Cipher;
+
+/// 每个参数都可以通过 `FUSO_` 前缀的环境变量设置, 如 `--heartbeat-delay` 对应
+/// `FUSO_HEARTBEAT_DELAY`, 命令行优先, 避免token等密钥出现在进程的命令行中,
+/// 帮助信息中不显示环境变量的值
+pub fn with_env(command: clap::Command<'static>) -> clap::Command<'static> {
+    let ids = command
+        .get_arguments()
+        .map(|arg| arg.get_id())
+        .filter(|id| !matches!(*id, "help" | "version"))
+        .collect::<Vec<_>>();
+
+    ids.into_iter().fold(command, |command, id| {
+        // 只在启动时调用一次, 环境变量名需要与 `Command` 一样是 `'static`
+        let env = format!("FUSO_{}", id.to_uppercase().replace('-', "_"));
+        let env = Box::leak(env.into_boxed_str());
+        command.mut_arg(id, |arg| arg.env(env).hide_env_values(true))
+    })
+}
+
+/// 读取 `--token-file` 指定的token, 去掉末尾的换行, 文件无法读取或为空时返回错误,
+/// 在绑定端口与连接服务端之前调用
+pub fn read_token_file(path: &str) -> Result<String, String> {
+    let token = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let token = token.trim_end_matches(['\r', '\n']);
+
+    if token.is_empty() {
+        return Err(format!("{} is empty", path));
+    }
+
+    Ok(token.to_owned())
+}
+
+/// 解析控制连接的加密方式, 控制连接传输token, 不允许 `none`
+pub fn parse_control_cipher(cipher: &str) -> Result<Cipher, String> {
+    match cipher.parse::<Cipher>() {
+        Ok(Cipher::None) => Err(String::from(
+            "the control channel carries the token, cipher `none` is not allowed",
+        )),
+        Ok(cipher) => Ok(cipher),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encryption::Cipher;
+
+    #[test]
+    fn test_parse_control_cipher() {
+        assert_eq!(super::parse_control_cipher("aes").unwrap(), Cipher::Aes);
+        assert!(super::parse_control_cipher("none")
+            .unwrap_err()
+            .contains("not allowed"));
+        assert!(super::parse_control_cipher("xor").is_err());
+    }
+
+    #[test]
+    fn test_read_token_file() {
+        let path = std::env::temp_dir().join(format!("fuso-token-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "secret\r\n").unwrap();
+        assert_eq!(super::read_token_file(path).unwrap(), "secret");
+
+        std::fs::write(path, "\n").unwrap();
+        assert!(super::read_token_file(path)
+            .unwrap_err()
+            .ends_with("is empty"));
+
+        std::fs::remove_file(path).unwrap();
+        assert!(super::read_token_file(path).is_err());
+    }
+
+    #[test]
+    fn test_with_env() {
+        let command = clap::Command::new("fuso").arg(
+            clap::Arg::new("heartbeat-delay")
+                .long("heartbeat-delay")
+                .takes_value(true),
+        );
+
+        let command = super::with_env(command);
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "heartbeat-delay")
+            .unwrap();
+
+        assert_eq!(
+            arg.get_env(),
+            Some(std::ffi::OsStr::new("FUSO_HEARTBEAT_DELAY"))
+        );
+        assert!(command
+            .get_arguments()
+            .filter(|arg| matches!(arg.get_id(), "help" | "version"))
+            .all(|arg| arg.get_env().is_none()));
+    }
+}
@@ -0,0 +1,345 @@
+use std::{pin::Pin, task::Poll};
+
+use crate::{guard::buffer::Buffer, AsyncRead, AsyncWrite, Lz4Err, NetSocket, ReadBuf};
+
+use super::{
+    super::{Decoder, Encoder},
+    third_party,
+};
+
+// frame: | len: u32 (le) | flag: u8 | payload: [u8; len - 1] |
+
+const HEAD_LEN: usize = 4;
+const MAX_BLOCK_LEN: usize = 16 * 1024;
+const MAX_FRAME_LEN: usize = 1 + MAX_BLOCK_LEN;
+
+const FLAG_RAW: u8 = 0;
+const FLAG_LZ4: u8 = 1;
+
+/// 小于该长度的数据直接发送
+const MIN_COMPRESS_LEN: usize = 64;
+/// 压缩后没有节省1/8以上时视为无法压缩
+const MIN_SAVING: usize = 8;
+/// 遇到无法压缩的数据后跳过的块数, 连续失败时加倍
+const MAX_SKIP_BLOCKS: usize = 64;
+
+/// 按块压缩, 每个块独立压缩, 已压缩或加密过的数据会被识别并原样发送
+pub struct Lz4BlockCompress<T> {
+    target: T,
+    lz4_ebuf: Option<Vec<u8>>,
+    lz4_epos: usize,
+    lz4_elen: usize,
+    lz4_skip: usize,
+    lz4_misses: usize,
+    lz4_dbuf: Buffer<u8>,
+    lz4_rbuf: Vec<u8>,
+    lz4_rpos: usize,
+    lz4_dinit: bool,
+}
+
+impl<T> Lz4BlockCompress<T> {
+    pub fn new(target: T) -> Self {
+        Self {
+            target,
+            lz4_ebuf: Default::default(),
+            lz4_epos: Default::default(),
+            lz4_elen: Default::default(),
+            lz4_skip: Default::default(),
+            lz4_misses: Default::default(),
+            lz4_dbuf: Default::default(),
+            lz4_rbuf: vec![0; HEAD_LEN],
+            lz4_rpos: Default::default(),
+            lz4_dinit: Default::default(),
+        }
+    }
+
+    fn compress(&mut self, buf: &[u8]) -> Option<Vec<u8>> {
+        if buf.len() < MIN_COMPRESS_LEN {
+            return None;
+        }
+
+        if self.lz4_skip > 0 {
+            self.lz4_skip -= 1;
+            return None;
+        }
+
+        let bound = unsafe { third_party::LZ4_compressBound(buf.len() as i32) };
+        let mut compressed = vec![0u8; bound as usize];
+
+        let n = unsafe {
+            third_party::LZ4_compress_default(
+                buf.as_ptr(),
+                compressed.as_mut_ptr(),
+                buf.len() as i32,
+                bound,
+            )
+        };
+
+        if n <= 0 || n as usize > buf.len() - buf.len() / MIN_SAVING {
+            self.lz4_misses = (self.lz4_misses + 1).min(MAX_SKIP_BLOCKS.trailing_zeros() as usize);
+            self.lz4_skip = 1 << self.lz4_misses;
+            return None;
+        }
+
+        self.lz4_misses = 0;
+        compressed.truncate(n as usize);
+
+        Some(compressed)
+    }
+
+    fn seal(&mut self, buf: &[u8]) -> Vec<u8> {
+        let (flag, payload) = match self.compress(buf) {
+            Some(compressed) => (FLAG_LZ4, compressed),
+            None => (FLAG_RAW, buf.to_vec()),
+        };
+
+        let frame_len = (1 + payload.len()) as u32;
+        let mut frame = Vec::with_capacity(HEAD_LEN + frame_len as usize);
+
+        frame.extend_from_slice(&frame_len.to_le_bytes());
+        frame.push(flag);
+        frame.extend_from_slice(&payload);
+
+        frame
+    }
+
+    fn open(frame: &[u8]) -> crate::Result<Vec<u8>> {
+        let (flag, payload) = (frame[0], &frame[1..]);
+
+        match flag {
+            FLAG_RAW => Ok(payload.to_vec()),
+            FLAG_LZ4 => {
+                let mut decompressed = vec![0u8; MAX_BLOCK_LEN];
+
+                let n = unsafe {
+                    third_party::LZ4_decompress_safe(
+                        payload.as_ptr(),
+                        decompressed.as_mut_ptr(),
+                        payload.len() as i32,
+                        MAX_BLOCK_LEN as i32,
+                    )
+                };
+
+                if n < 0 {
+                    return Err(Lz4Err::Decompress.into());
+                }
+
+                decompressed.truncate(n as usize);
+
+                Ok(decompressed)
+            }
+            _ => Err(Lz4Err::Decompress.into()),
+        }
+    }
+}
+
+impl<T> NetSocket for Lz4BlockCompress<T>
+where
+    T: NetSocket,
+{
+    fn peer_addr(&self) -> crate::Result<crate::Address> {
+        self.target.peer_addr()
+    }
+
+    fn local_addr(&self) -> crate::Result<crate::Address> {
+        self.target.local_addr()
+    }
+}
+
+impl<T> AsyncRead for Lz4BlockCompress<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        if !self.lz4_dbuf.is_empty() {
+            let n = self.lz4_dbuf.read_to_buffer(buf.initialize_unfilled());
+            buf.advance(n);
+            Poll::Ready(Ok(n))
+        } else {
+            self.poll_decode_read(cx, buf)
+        }
+    }
+}
+
+impl<T> AsyncWrite for Lz4BlockCompress<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        self.poll_encode_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.target).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.target).poll_close(cx)
+    }
+}
+
+impl<T> Encoder for Lz4BlockCompress<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_encode_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        if buf.is_empty() && self.lz4_ebuf.is_none() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let ebuf = match self.lz4_ebuf.take() {
+            Some(ebuf) => ebuf,
+            None => {
+                let block = &buf[..buf.len().min(MAX_BLOCK_LEN)];
+                self.lz4_epos = 0;
+                self.lz4_elen = block.len();
+                self.seal(block)
+            }
+        };
+
+        loop {
+            let epos = self.lz4_epos;
+            match Pin::new(&mut self.target).poll_write(cx, &ebuf[epos..])? {
+                Poll::Ready(0) => break Poll::Ready(Ok(0)),
+                Poll::Ready(n) => {
+                    self.lz4_epos += n;
+                    if self.lz4_epos == ebuf.len() {
+                        break Poll::Ready(Ok(self.lz4_elen));
+                    }
+                }
+                Poll::Pending => {
+                    self.lz4_ebuf = Some(ebuf);
+                    break Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<T> Decoder for Lz4BlockCompress<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_decode_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        let mut rbuf = std::mem::take(&mut self.lz4_rbuf);
+
+        let poll = loop {
+            let rpos = self.lz4_rpos;
+
+            if !self.lz4_dinit && rpos == HEAD_LEN {
+                let len = u32::from_le_bytes([rbuf[0], rbuf[1], rbuf[2], rbuf[3]]) as usize;
+
+                if !(1..=MAX_FRAME_LEN).contains(&len) {
+                    break Poll::Ready(Err(Lz4Err::Decompress.into()));
+                }
+
+                rbuf.resize(len, 0);
+                self.lz4_rpos = 0;
+                self.lz4_dinit = true;
+                continue;
+            } else if self.lz4_dinit && rpos == rbuf.len() {
+                let decompressed = match Self::open(&rbuf) {
+                    Ok(decompressed) => decompressed,
+                    Err(e) => break Poll::Ready(Err(e)),
+                };
+
+                rbuf.resize(HEAD_LEN, 0);
+                self.lz4_rpos = 0;
+                self.lz4_dinit = false;
+
+                // 空块不能返回0, 否则会被当作连接关闭
+                if decompressed.is_empty() {
+                    continue;
+                }
+
+                let unfilled = buf.initialize_unfilled();
+                let n = unfilled.len().min(decompressed.len());
+                unfilled[..n].copy_from_slice(&decompressed[..n]);
+                buf.advance(n);
+
+                if n < decompressed.len() {
+                    self.lz4_dbuf.push_back(&decompressed[n..]);
+                }
+
+                break Poll::Ready(Ok(n));
+            }
+
+            let mut read_buf = ReadBuf::new(&mut rbuf[rpos..]);
+            match Pin::new(&mut self.target).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(0)) if rpos == 0 && !self.lz4_dinit => break Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(0)) => {
+                    break Poll::Ready(Err(
+                        std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+                    ))
+                }
+                Poll::Ready(Ok(n)) => self.lz4_rpos += n,
+                Poll::Ready(Err(e)) => break Poll::Ready(Err(e)),
+                Poll::Pending => break Poll::Pending,
+            }
+        };
+
+        self.lz4_rbuf = rbuf;
+
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+    use super::Lz4BlockCompress;
+
+    #[test]
+    fn test_lz4_block() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let text = "GET / HTTP/1.1\r\nHost: fuso\r\n\r\n".repeat(1024);
+                let random = (0..text.len()).map(|_| rand::random()).collect::<Vec<u8>>();
+
+                let (w, r) = tokio::io::duplex(64 * 1024);
+                let mut w = Lz4BlockCompress::new(w);
+                let mut r = Lz4BlockCompress::new(r);
+
+                for data in [text.as_bytes(), &random, b"fuso"] {
+                    let mut buf = vec![0u8; data.len()];
+                    let (sent, recv) = tokio::join!(w.write_all(data), r.read_exact(&mut buf));
+                    sent.unwrap();
+                    recv.unwrap();
+                    assert_eq!(buf, data);
+                }
+
+                let mut w = Lz4BlockCompress::new(());
+                let frame = w.seal(&text.as_bytes()[..super::MAX_BLOCK_LEN]);
+                assert_eq!(frame[4], super::FLAG_LZ4);
+                assert!(frame.len() < super::MAX_BLOCK_LEN / 2);
+
+                let frame = w.seal(&random[..super::MAX_BLOCK_LEN]);
+                assert_eq!(frame[4], super::FLAG_RAW);
+                assert!(w.lz4_skip > 0);
+            });
+    }
+}
@@ -1,5 +1,8 @@
+mod block;
 mod third_party;
 
+pub use block::Lz4BlockCompress;
+
 use std::{ffi::c_void, pin::Pin, task::Poll};
 
 use crate::{guard::buffer::Buffer, AsyncRead, AsyncWrite, Lz4Err, NetSocket, ReadBuf};
@@ -1,11 +1,27 @@
 mod lz4;
-pub use lz4::Lz4Compress;
+pub use lz4::{Lz4BlockCompress, Lz4Compress};
 
 use std::{
+    fmt::Display,
     pin::Pin,
+    str::FromStr,
     task::{Context, Poll},
 };
 
+use serde::{Deserialize, Serialize};
+
+use crate::CompressErr;
+
+/// 转发数据的压缩方式, 与加密方式一同协商
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    /// 不压缩
+    #[default]
+    None,
+    /// lz4块压缩, 无法压缩的数据原样发送
+    Lz4,
+}
+
 pub trait Encoder {
     fn poll_encode_write(
         self: Pin<&mut Self>,
@@ -21,3 +37,26 @@ pub trait Decoder {
         buf: &mut crate::ReadBuf<'_>,
     ) -> Poll<crate::Result<usize>>;
 }
+
+impl FromStr for Compression {
+    type Err = crate::Error;
+
+    fn from_str(compression: &str) -> Result<Self, Self::Err> {
+        Ok(match compression {
+            "none" => Self::None,
+            "lz4" => Self::Lz4,
+            compression => return Err(CompressErr::Unknown(compression.to_owned()).into()),
+        })
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", {
+            match self {
+                Compression::None => "none",
+                Compression::Lz4 => "lz4",
+            }
+        })
+    }
+}
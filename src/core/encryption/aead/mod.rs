@@ -0,0 +1,378 @@
+use std::{pin::Pin, task::Poll};
+
+use aes_gcm::{
+    aead::{consts::U12, generic_array::GenericArray, Aead, AeadCore, KeyInit},
+    Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+
+use crate::{
+    guard::buffer::Buffer, metrics::metrics, Address, AsyncRead, AsyncWrite, NetSocket, ReadBuf,
+};
+
+use super::{AuthFailure, Decrypt, Encrypt};
+
+// frame: | len: u32 (le) | nonce: [u8; 12] | ciphertext + tag: [u8; len - 12] |
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEAD_LEN: usize = 4;
+const MAX_PAYLOAD_LEN: usize = 16 * 1024;
+const MAX_FRAME_LEN: usize = NONCE_LEN + MAX_PAYLOAD_LEN + TAG_LEN;
+
+const PBKDF2_SALT: &[u8] = b"fuso-aead";
+const PBKDF2_ROUNDS: u32 = 10_000;
+
+pub type AESGcmEncryptor<T> = AeadEncryptor<T, Aes256Gcm>;
+pub type ChaCha20Encryptor<T> = AeadEncryptor<T, ChaCha20Poly1305>;
+
+/// 通过pbkdf2从口令派生32字节的密钥
+pub fn derive_key(passphrase: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(passphrase, PBKDF2_SALT, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+pub struct AeadEncryptor<T, C> {
+    target: T,
+    cipher: C,
+    aead_ebuf: Option<Vec<u8>>,
+    aead_epos: usize,
+    aead_elen: usize,
+    aead_dbuf: Buffer<u8>,
+    aead_rbuf: Vec<u8>,
+    aead_rpos: usize,
+    aead_dinit: bool,
+    /// 认证失败时记录在日志中
+    peer: Option<Address>,
+}
+
+impl<T, C> AeadEncryptor<T, C>
+where
+    C: KeyInit,
+{
+    pub fn new(target: T, key: [u8; 32]) -> Self {
+        Self {
+            target,
+            cipher: C::new(GenericArray::from_slice(&key)),
+            aead_ebuf: Default::default(),
+            aead_epos: Default::default(),
+            aead_elen: Default::default(),
+            aead_dbuf: Default::default(),
+            aead_rbuf: vec![0; HEAD_LEN],
+            aead_rpos: Default::default(),
+            aead_dinit: Default::default(),
+            peer: None,
+        }
+    }
+
+    /// 对端的地址, 认证失败时输出到日志
+    pub fn peer(mut self, peer: Option<Address>) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    pub fn with_passphrase(target: T, passphrase: &[u8]) -> Self {
+        Self::new(target, derive_key(passphrase))
+    }
+}
+
+impl<T, C> AeadEncryptor<T, C>
+where
+    C: Aead + AeadCore<NonceSize = U12>,
+{
+    fn seal(&self, buf: &[u8]) -> crate::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce.fill_with(rand::random);
+
+        let encrypted = self
+            .cipher
+            .encrypt(GenericArray::from_slice(&nonce), buf)
+            .map_err(|_| invalid_data("aead encrypt failed"))?;
+
+        let frame_len = (NONCE_LEN + encrypted.len()) as u32;
+        let mut frame = Vec::with_capacity(HEAD_LEN + frame_len as usize);
+
+        frame.extend_from_slice(&frame_len.to_le_bytes());
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&encrypted);
+
+        Ok(frame)
+    }
+
+    fn open(&self, frame: &[u8]) -> crate::Result<Vec<u8>> {
+        let (nonce, encrypted) = frame.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(GenericArray::from_slice(nonce), encrypted)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, AuthFailure).into())
+    }
+}
+
+fn invalid_data(msg: &str) -> crate::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg).into()
+}
+
+impl<T, C> NetSocket for AeadEncryptor<T, C>
+where
+    T: NetSocket,
+{
+    fn peer_addr(&self) -> crate::Result<crate::Address> {
+        self.target.peer_addr()
+    }
+
+    fn local_addr(&self) -> crate::Result<crate::Address> {
+        self.target.local_addr()
+    }
+}
+
+impl<T, C> AsyncRead for AeadEncryptor<T, C>
+where
+    T: AsyncRead + Unpin,
+    C: Aead + AeadCore<NonceSize = U12> + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        if !self.aead_dbuf.is_empty() {
+            let n = self.aead_dbuf.read_to_buffer(buf.initialize_unfilled());
+            buf.advance(n);
+            Poll::Ready(Ok(n))
+        } else {
+            self.poll_decrypt_read(cx, buf)
+        }
+    }
+}
+
+impl<T, C> AsyncWrite for AeadEncryptor<T, C>
+where
+    T: AsyncWrite + Unpin,
+    C: Aead + AeadCore<NonceSize = U12> + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        self.poll_encrypt_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.target).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.target).poll_close(cx)
+    }
+}
+
+impl<T, C> Encrypt for AeadEncryptor<T, C>
+where
+    T: AsyncWrite + Unpin,
+    C: Aead + AeadCore<NonceSize = U12> + Unpin,
+{
+    fn poll_encrypt_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        if buf.is_empty() && self.aead_ebuf.is_none() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let ebuf = match self.aead_ebuf.take() {
+            Some(ebuf) => ebuf,
+            None => {
+                let plain = &buf[..buf.len().min(MAX_PAYLOAD_LEN)];
+                self.aead_epos = 0;
+                self.aead_elen = plain.len();
+                self.seal(plain)?
+            }
+        };
+
+        loop {
+            let epos = self.aead_epos;
+            match Pin::new(&mut self.target).poll_write(cx, &ebuf[epos..])? {
+                Poll::Ready(0) => break Poll::Ready(Ok(0)),
+                Poll::Ready(n) => {
+                    self.aead_epos += n;
+                    if self.aead_epos == ebuf.len() {
+                        break Poll::Ready(Ok(self.aead_elen));
+                    }
+                }
+                Poll::Pending => {
+                    self.aead_ebuf = Some(ebuf);
+                    break Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+impl<T, C> Decrypt for AeadEncryptor<T, C>
+where
+    T: AsyncRead + Unpin,
+    C: Aead + AeadCore<NonceSize = U12> + Unpin,
+{
+    fn poll_decrypt_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        let mut rbuf = std::mem::take(&mut self.aead_rbuf);
+
+        let poll = loop {
+            let rpos = self.aead_rpos;
+
+            if !self.aead_dinit && rpos == HEAD_LEN {
+                let len = u32::from_le_bytes([rbuf[0], rbuf[1], rbuf[2], rbuf[3]]) as usize;
+
+                if !(NONCE_LEN + TAG_LEN..=MAX_FRAME_LEN).contains(&len) {
+                    break Poll::Ready(Err(invalid_data("aead bad frame length")));
+                }
+
+                rbuf.resize(len, 0);
+                self.aead_rpos = 0;
+                self.aead_dinit = true;
+                continue;
+            } else if self.aead_dinit && rpos == rbuf.len() {
+                let decrypted = match self.open(&rbuf) {
+                    Ok(decrypted) => decrypted,
+                    Err(e) => {
+                        metrics().on_decrypt_failed();
+
+                        match self.peer.as_ref() {
+                            Some(peer) => log::warn!("aead authentication failed from {}", peer),
+                            None => log::warn!("aead authentication failed"),
+                        }
+
+                        break Poll::Ready(Err(e));
+                    }
+                };
+
+                rbuf.resize(HEAD_LEN, 0);
+                self.aead_rpos = 0;
+                self.aead_dinit = false;
+
+                let unfilled = buf.initialize_unfilled();
+                let n = unfilled.len().min(decrypted.len());
+                unfilled[..n].copy_from_slice(&decrypted[..n]);
+                buf.advance(n);
+
+                if n < decrypted.len() {
+                    self.aead_dbuf.push_back(&decrypted[n..]);
+                }
+
+                break Poll::Ready(Ok(n));
+            }
+
+            let mut read_buf = ReadBuf::new(&mut rbuf[rpos..]);
+            match Pin::new(&mut self.target).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(0)) if rpos == 0 && !self.aead_dinit => break Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(0)) => {
+                    break Poll::Ready(Err(
+                        std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+                    ))
+                }
+                Poll::Ready(Ok(n)) => self.aead_rpos += n,
+                Poll::Ready(Err(e)) => break Poll::Ready(Err(e)),
+                Poll::Pending => break Poll::Pending,
+            }
+        };
+
+        self.aead_rbuf = rbuf;
+
+        poll
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        encryption::is_auth_failure,
+        ext::{AsyncReadExt, AsyncWriteExt},
+        metrics::metrics,
+    };
+
+    use super::{derive_key, AESGcmEncryptor, ChaCha20Encryptor};
+
+    fn is_invalid_data(err: &crate::Error) -> bool {
+        matches!(err.kind(), crate::Kind::IO(e) if e.kind() == std::io::ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn test_aes_gcm_frame() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let key = derive_key(b"fuso");
+                let mut buf = [0u8; 10];
+
+                let (w, r) = tokio::io::duplex(1024);
+                let mut w = AESGcmEncryptor::new(w, key);
+                let mut r = AESGcmEncryptor::new(r, key);
+
+                w.write_all(b"hello fuso").await.unwrap();
+                r.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello fuso");
+
+                let frame = AESGcmEncryptor::new((), key).seal(b"hello fuso").unwrap();
+
+                // tampered frame
+                let (mut w, r) = tokio::io::duplex(1024);
+                let mut r = AESGcmEncryptor::new(r, key);
+                let mut tampered = frame.clone();
+                *tampered.last_mut().unwrap() ^= 1;
+                w.write_all(&tampered).await.unwrap();
+                let err = r.read(&mut buf).await.unwrap_err();
+                assert!(is_invalid_data(&err));
+                assert!(is_auth_failure(&err));
+                assert!(metrics().render().lines().any(|line| {
+                    line.strip_prefix("fuso_decrypt_failures_total ")
+                        .is_some_and(|count| count != "0")
+                }));
+
+                // short read mid-frame
+                let (mut w, r) = tokio::io::duplex(1024);
+                let mut r = AESGcmEncryptor::new(r, key);
+                w.write_all(&frame[..frame.len() - 1]).await.unwrap();
+                drop(w);
+                let err = r.read(&mut buf).await.unwrap_err();
+                assert!(!is_auth_failure(&err));
+            });
+    }
+
+    #[test]
+    fn test_chacha20_frame() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let key = derive_key(b"fuso");
+                let mut buf = [0u8; 10];
+
+                let (w, r) = tokio::io::duplex(1024);
+                let mut w = ChaCha20Encryptor::new(w, key);
+                let mut r = ChaCha20Encryptor::new(r, key);
+
+                w.write_all(b"hello fuso").await.unwrap();
+                r.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello fuso");
+
+                let mut frame = ChaCha20Encryptor::new((), key).seal(b"hello fuso").unwrap();
+                frame[8] ^= 1;
+
+                let (mut w, r) = tokio::io::duplex(1024);
+                let mut r = ChaCha20Encryptor::new(r, key);
+                w.write_all(&frame).await.unwrap();
+                let err = r.read(&mut buf).await.unwrap_err();
+                assert!(is_invalid_data(&err));
+            });
+    }
+}
@@ -1,14 +1,61 @@
 mod aes;
 mod rsa;
 
+#[cfg(feature = "fuso-crypt-aead")]
+mod aead;
+
 pub use crate::core::encryption::{aes::AESEncryptor, rsa::RSAEncryptor};
 
+#[cfg(feature = "fuso-crypt-aead")]
+pub use crate::core::encryption::aead::{
+    derive_key, AESGcmEncryptor, AeadEncryptor, ChaCha20Encryptor,
+};
+
 use std::{
+    fmt::Display,
     pin::Pin,
+    str::FromStr,
     task::{Context, Poll},
 };
 
-use crate::ReadBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::{EncryptionErr, ReadBuf};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Cipher {
+    /// aes-128-cbc
+    #[default]
+    Aes,
+    /// 不加密, 需要双方同时允许
+    None,
+    /// aes-256-gcm
+    #[cfg(feature = "fuso-crypt-aead")]
+    AesGcm,
+    /// chacha20-poly1305
+    #[cfg(feature = "fuso-crypt-aead")]
+    ChaCha20,
+}
+
+/// aead帧认证失败时错误中携带的原因, 由 `is_auth_failure` 识别
+#[derive(Debug)]
+pub(crate) struct AuthFailure;
+
+/// 解密时帧认证失败, 说明双方的密钥不一致或数据被篡改, 其他错误返回false
+pub fn is_auth_failure(err: &crate::Error) -> bool {
+    matches!(
+        err.kind(),
+        crate::Kind::IO(e) if e.get_ref().is_some_and(|e| e.is::<AuthFailure>())
+    )
+}
+
+impl Display for AuthFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("aead tag mismatch")
+    }
+}
+
+impl std::error::Error for AuthFailure {}
 
 pub trait Decrypt {
     fn poll_decrypt_read(
@@ -25,3 +72,34 @@ pub trait Encrypt {
         buf: &[u8],
     ) -> Poll<crate::Result<usize>>;
 }
+
+impl FromStr for Cipher {
+    type Err = crate::Error;
+
+    fn from_str(cipher: &str) -> Result<Self, Self::Err> {
+        Ok(match cipher {
+            "aes" => Self::Aes,
+            "none" => Self::None,
+            #[cfg(feature = "fuso-crypt-aead")]
+            "aes-gcm" => Self::AesGcm,
+            #[cfg(feature = "fuso-crypt-aead")]
+            "chacha20" => Self::ChaCha20,
+            cipher => return Err(EncryptionErr::UnknownCipher(cipher.to_owned()).into()),
+        })
+    }
+}
+
+impl Display for Cipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", {
+            match self {
+                Cipher::Aes => "aes",
+                Cipher::None => "none",
+                #[cfg(feature = "fuso-crypt-aead")]
+                Cipher::AesGcm => "aes-gcm",
+                #[cfg(feature = "fuso-crypt-aead")]
+                Cipher::ChaCha20 => "chacha20",
+            }
+        })
+    }
+}
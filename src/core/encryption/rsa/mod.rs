@@ -1,6 +1,6 @@
 use std::{pin::Pin, task::Poll};
 
-use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
+use rsa::{PaddingScheme, PublicKey, PublicKeyParts, RsaPrivateKey, RsaPublicKey};
 
 use crate::{guard::buffer::Buffer, AsyncRead, AsyncWrite, NetSocket, ReadBuf};
 
@@ -12,6 +12,7 @@ pub struct RSAEncryptor<T> {
     rbuf: Option<Vec<u8>>,
     wbuf: Option<Vec<u8>>,
     wpos: usize,
+    wlen: usize,
     rpos: usize,
     dinit: bool,
     rsa_priv: RsaPrivateKey,
@@ -28,10 +29,17 @@ impl<T> RSAEncryptor<T> {
             rbuf: Default::default(),
             wbuf: Default::default(),
             wpos: Default::default(),
+            wlen: Default::default(),
             rpos: Default::default(),
             dinit: Default::default(),
         }
     }
+
+    /// 取回底层连接, 只能在一条消息读写完成且对端等待回应时调用,
+    /// 否则已经读取但未消费的数据会丢失
+    pub fn into_inner(self) -> T {
+        self.target
+    }
 }
 
 impl<T> NetSocket for RSAEncryptor<T>
@@ -88,7 +96,7 @@ where
                     Poll::Ready(n) => {
                         self.wpos += n;
                         if self.wpos == wbuf.len() {
-                            break Poll::Ready(Ok(buf.len()));
+                            break Poll::Ready(Ok(self.wlen));
                         }
                     }
                     Poll::Pending => {
@@ -126,6 +134,8 @@ where
         cx: &mut std::task::Context<'_>,
         buf: &[u8],
     ) -> std::task::Poll<crate::Result<usize>> {
+        // pkcs1v15填充占用11字节, 超过时分多次加密
+        let buf = &buf[..buf.len().min(self.rsa_publ.size() - 11)];
         let mut rng = rand::thread_rng();
         let ps = PaddingScheme::new_pkcs1v15_encrypt();
         let encrypted_data = self.rsa_publ.encrypt(&mut rng, ps, buf)?;
@@ -148,6 +158,7 @@ where
                 }
                 std::task::Poll::Pending => {
                     self.wpos = 0;
+                    self.wlen = buf.len();
                     drop(std::mem::replace(
                         &mut self.wbuf,
                         Some(encrypted_buf[pos..].to_vec()),
@@ -237,3 +248,36 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rsa::{RsaPrivateKey, RsaPublicKey};
+
+    use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+    use super::RSAEncryptor;
+
+    #[test]
+    fn test_write_larger_than_block() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let priv_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+                let publ_key = RsaPublicKey::from(&priv_key);
+
+                let (w, r) = tokio::io::duplex(4096);
+                let mut w = RSAEncryptor::new(w, publ_key.clone(), priv_key.clone());
+                let mut r = RSAEncryptor::new(r, publ_key, priv_key);
+
+                // 1024位的密钥每次最多加密117字节
+                let data = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+                let mut buf = vec![0u8; data.len()];
+
+                let (written, read) = tokio::join!(w.write_all(&data), r.read_exact(&mut buf));
+
+                written.unwrap();
+                read.unwrap();
+                assert_eq!(buf, data);
+            });
+    }
+}
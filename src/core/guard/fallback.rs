@@ -92,6 +92,18 @@ where
     fn local_addr(&self) -> crate::Result<crate::Address> {
         self.target.local_addr()
     }
+
+    /// 还有未读取的回退数据时不能绕过缓冲直接转发
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        let buffered = |buf: &Option<Buffer<u8>>| buf.as_ref().is_some_and(|buf| !buf.is_empty());
+
+        if buffered(&self.backed_buf) || buffered(&self.marked_buf) {
+            None
+        } else {
+            self.target.raw_fd()
+        }
+    }
 }
 
 impl<T> AsyncRead for Fallback<T>
@@ -103,6 +115,9 @@ where
         cx: &mut Context<'_>,
         buf: &mut crate::ReadBuf<'_>,
     ) -> std::task::Poll<crate::Result<usize>> {
+        // 只记录本次读取的数据, `read_exact` 会多次使用同一个buf
+        let filled = buf.iter_mut().len();
+
         let poll = {
             match self.backed_buf.take() {
                 None => Pin::new(&mut self.target).poll_read(cx, buf),
@@ -123,7 +138,7 @@ where
             Poll::Ready(Ok(n)) => match self.marked_buf.as_mut() {
                 None => Poll::Ready(Ok(n)),
                 Some(marked) => {
-                    marked.push_back(buf.iter_mut());
+                    marked.push_back(&buf.iter_mut()[filled..]);
                     Poll::Ready(Ok(n))
                 }
             },
@@ -197,14 +212,16 @@ where
         _: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Self::Output> {
         let this = &mut self.0;
-        if let Some(mut marked) = this.marked_buf.take() {
+        // 标记后没有读取数据时不产生空的缓冲, 否则下次读取会被当作已关闭
+        if let Some(mut marked) = this.marked_buf.take().filter(|marked| !marked.is_empty()) {
             let backed_buf = if let Some(mut backed) = this.backed_buf.take() {
                 let mut buf = Vec::with_capacity(marked.len());
                 unsafe{
                     buf.set_len(marked.len());
                 }
                 marked.read_to_buffer(&mut buf);
-                backed.push_all(buf);
+                // 标记后读取的数据在未读取的数据之前
+                backed.push_front(&buf);
                 backed
             }else{
                 marked
@@ -215,3 +232,68 @@ where
         Poll::Ready(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+    use super::Fallback;
+
+    #[test]
+    fn test_backward_after_partial_reads() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (mut w, r) = tokio::io::duplex(1024);
+                let mut r = Fallback::new(r, true);
+
+                w.write_all(b"ab").await.unwrap();
+
+                // `read_exact` 分两次读取, 每次只记录新读取的数据
+                r.mark().await.unwrap();
+                let mut buf = [0u8; 4];
+                let write = async {
+                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                    w.write_all(b"cd").await.unwrap();
+                };
+                let (read, _) = tokio::join!(r.read_exact(&mut buf), write);
+                read.unwrap();
+                r.backward().await.unwrap();
+
+                // 回退的数据没有读完时再次回退, 顺序不变
+                r.mark().await.unwrap();
+                let mut buf = [0u8; 1];
+                r.read_exact(&mut buf).await.unwrap();
+                r.backward().await.unwrap();
+
+                let mut buf = [0u8; 4];
+                r.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"abcd");
+            });
+    }
+
+    #[test]
+    fn test_backward_without_read() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (mut w, r) = tokio::io::duplex(1024);
+                let mut r = Fallback::new(r, true);
+
+                w.write_all(b"\x05\x01\x00").await.unwrap();
+
+                // 前面的处理没有读取数据就放弃了
+                r.mark().await.unwrap();
+                r.backward().await.unwrap();
+
+                r.mark().await.unwrap();
+                let mut buf = [0u8; 1];
+                r.read_exact(&mut buf).await.unwrap();
+                r.backward().await.unwrap();
+
+                let mut buf = [0u8; 3];
+                r.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"\x05\x01\x00");
+            });
+    }
+}
@@ -2,7 +2,7 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::{future::Future, pin::Pin, task::Poll, time::Duration};
 
-use crate::{AsyncRead, AsyncWrite, BoxedFuture};
+use crate::{Address, AsyncRead, AsyncWrite, BoxedFuture, NetSocket};
 
 pub struct Timer<T> {
     target: Rc<RefCell<T>>,
@@ -94,6 +94,20 @@ where
     }
 }
 
+/// 设置超时后仍然可以获取连接的地址
+impl<T> NetSocket for Timer<T>
+where
+    T: NetSocket,
+{
+    fn peer_addr(&self) -> crate::Result<Address> {
+        self.target.try_borrow()?.peer_addr()
+    }
+
+    fn local_addr(&self) -> crate::Result<Address> {
+        self.target.try_borrow()?.local_addr()
+    }
+}
+
 #[allow(unused)]
 impl<T> AsyncWrite for Timer<T>
 where
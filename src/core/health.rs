@@ -0,0 +1,282 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    ext::{AsyncReadExt, AsyncWriteExt},
+    time, Accepter, AccepterExt, AsyncRead, AsyncWrite, Executor, Provider, Socket,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+/// 探测请求很短, 读取超时后直接断开, 不让空闲的连接占用资源
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+type OnBound = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Default)]
+struct Inner {
+    bound: AtomicUsize,
+    accepting: AtomicUsize,
+    /// 监听的服务达到数量后调用一次
+    on_bound: Mutex<Option<(usize, OnBound)>>,
+}
+
+/// 服务的健康状态, 多个服务可以共享同一个, 由服务的接受循环更新
+#[derive(Default, Clone)]
+pub struct Health(Arc<Inner>);
+
+/// 接受新连接期间持有, 停止接受时释放
+pub(crate) struct Accepting(Arc<Inner>);
+
+impl Health {
+    /// 至少有一个服务已经监听
+    pub fn is_live(&self) -> bool {
+        self.0.bound.load(Ordering::SeqCst) > 0
+    }
+
+    /// 已经监听的服务数量
+    pub fn bound(&self) -> usize {
+        self.0.bound.load(Ordering::SeqCst)
+    }
+
+    /// 所有已监听的服务都在接受新连接, 任意一个开始关闭后不再就绪
+    pub fn is_ready(&self) -> bool {
+        let bound = self.0.bound.load(Ordering::SeqCst);
+        bound > 0 && self.0.accepting.load(Ordering::SeqCst) == bound
+    }
+
+    /// `count` 个服务监听后在监听的服务中调用一次 `f`, 已经达到时立即调用,
+    /// 只保留最后一次设置的回调
+    pub fn on_bound<F>(&self, count: usize, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Ok(mut on_bound) = self.0.on_bound.lock() {
+            *on_bound = Some((count, Box::new(f)));
+        }
+
+        self.fire_bound();
+    }
+
+    pub(crate) fn accepting(&self) -> Accepting {
+        self.0.bound.fetch_add(1, Ordering::SeqCst);
+        self.0.accepting.fetch_add(1, Ordering::SeqCst);
+        self.fire_bound();
+        Accepting(self.0.clone())
+    }
+
+    fn fire_bound(&self) {
+        let bound = self.bound();
+
+        let f = match self.0.on_bound.lock() {
+            Ok(mut on_bound) => match on_bound.take() {
+                Some((count, f)) if bound >= count => Some(f),
+                other => {
+                    *on_bound = other;
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        // 不持有锁调用, 回调中可以再次设置
+        if let Some(f) = f {
+            f();
+        }
+    }
+
+    fn respond(&self, path: &str) -> (&'static str, &'static str) {
+        let ok = match path {
+            "/healthz" => self.is_live(),
+            "/readyz" => self.is_ready(),
+            _ => return ("404 Not Found", "not found\n"),
+        };
+
+        match ok {
+            true => ("200 OK", "ok\n"),
+            false => ("503 Service Unavailable", "unavailable\n"),
+        }
+    }
+}
+
+impl Drop for Accepting {
+    fn drop(&mut self) {
+        self.0.accepting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// 启动一个简单的http服务, `/healthz` 在服务监听后返回200,
+/// `/readyz` 在服务接受新连接时返回200, 开始关闭后立即返回503.
+/// 每个连接单独处理, 一个空闲的连接不会阻塞其他探测
+pub async fn serve<P, A, S, E>(
+    provider: P,
+    bind: Socket,
+    health: Health,
+    executor: E,
+) -> crate::Result<()>
+where
+    P: Provider<Socket, Output = BoxedFuture<A>>,
+    A: Accepter<Stream = S> + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    E: Executor,
+{
+    let mut accepter = provider.call(bind).await?;
+
+    log::info!("health check listens on {}", accepter.local_addr()?);
+
+    loop {
+        let stream = accepter.accept().await?;
+        let health = health.clone();
+
+        executor.spawn(async move {
+            if let Err(e) = respond(stream, health).await {
+                log::debug!("failed to respond health check {}", e);
+            }
+        });
+    }
+}
+
+async fn respond<S>(stream: S, health: Health) -> crate::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut stream, request) = time::wait_for(READ_TIMEOUT, async move {
+        let mut stream = stream;
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf).await?;
+        Ok::<_, crate::Error>((stream, buf[..n].to_vec()))
+    })
+    .await??;
+
+    // 只关心请求行中的路径, 如 `GET /healthz HTTP/1.1`
+    let path = String::from_utf8_lossy(&request)
+        .split_whitespace()
+        .nth(1)
+        .map(|path| path.split('?').next().unwrap_or(path).to_owned())
+        .unwrap_or_default();
+
+    let (status, body) = health.respond(&path);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    time::wait_for(Duration::from_secs(5), async move {
+        stream.write_all(response.as_bytes()).await?;
+        stream.close().await
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Health;
+    use crate::{
+        ext::{AsyncReadExt, AsyncWriteExt},
+        TokioAccepter, TokioExecutor,
+    };
+
+    #[test]
+    fn test_health_state() {
+        let health = Health::default();
+        assert!(!health.is_live());
+        assert!(!health.is_ready());
+        assert_eq!(health.respond("/healthz").0, "503 Service Unavailable");
+
+        let first = health.accepting();
+        let second = health.accepting();
+        assert!(health.is_live());
+        assert!(health.is_ready());
+        assert_eq!(health.respond("/readyz").0, "200 OK");
+        assert_eq!(health.respond("/metrics").0, "404 Not Found");
+
+        drop(first);
+        assert!(health.is_live());
+        assert!(!health.is_ready());
+        assert_eq!(health.respond("/healthz").0, "200 OK");
+        assert_eq!(health.respond("/readyz").0, "503 Service Unavailable");
+
+        drop(second);
+        assert!(!health.is_ready());
+    }
+
+    #[test]
+    fn test_health_on_bound() {
+        let health = Health::default();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let notify = tx.clone();
+        health.on_bound(2, move || notify.send(2).unwrap());
+
+        let _first = health.accepting();
+        assert!(rx.try_recv().is_err());
+
+        // 第二个服务监听时调用, 之后不再调用
+        let _second = health.accepting();
+        assert_eq!(rx.try_recv(), Ok(2));
+
+        let _third = health.accepting();
+        assert!(rx.try_recv().is_err());
+
+        // 已经达到数量时立即调用
+        health.on_bound(3, move || tx.send(3).unwrap());
+        assert_eq!(rx.try_recv(), Ok(3));
+    }
+
+    #[test]
+    fn test_health_idle_connection() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+
+                let health = Health::default();
+                let _accepting = health.accepting();
+
+                tokio::spawn(super::serve(
+                    TokioAccepter::default(),
+                    crate::Socket::tcp(([127, 0, 0, 1], port)),
+                    health,
+                    TokioExecutor,
+                ));
+
+                tokio::time::sleep(Duration::from_millis(100)).await;
+
+                // 一个不发送请求的连接不应阻塞之后的探测
+                let _idle = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                    .await
+                    .unwrap();
+
+                let mut probe = tokio::net::TcpStream::connect(("127.0.0.1", port))
+                    .await
+                    .unwrap();
+
+                probe
+                    .write_all(b"GET /healthz HTTP/1.1\r\n\r\n")
+                    .await
+                    .unwrap();
+
+                let mut buf = [0u8; 15];
+                tokio::time::timeout(Duration::from_millis(300), probe.read_exact(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(&buf, b"HTTP/1.1 200 OK");
+            });
+    }
+}
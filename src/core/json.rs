@@ -0,0 +1,34 @@
+use std::fmt::Write;
+
+/// 输出带引号并转义的json字符串, 管理服务的应答与日志的json格式都使用这里
+pub(crate) fn json_string(out: &mut String, value: &str) {
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_string;
+
+    #[test]
+    fn test_json_string() {
+        let mut out = String::new();
+        json_string(&mut out, "say \"hi\"\n\\\u{1}");
+        assert_eq!(out, r#""say \"hi\"\n\\\u0001""#);
+    }
+}
@@ -0,0 +1,313 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{Address, InnerAddr};
+
+/// 默认跟踪的来源ip数量上限
+const DEFAULT_MAX_TRACKED_IPS: usize = 4096;
+
+/// 每秒补充 `rate` 个令牌, 最多积攒 `burst` 个, 每个连接消耗一个
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    rate: u32,
+    burst: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// 按最近一次连接排序的来源, 超过上限时淘汰最久没有连接的来源
+#[derive(Debug, Default)]
+struct Sources {
+    buckets: HashMap<IpAddr, (Bucket, u64)>,
+    order: BTreeMap<u64, IpAddr>,
+    seq: u64,
+}
+
+/// 连接被限制的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limited {
+    Global,
+    Ip(IpAddr),
+}
+
+/// 接受连接的速率限制, 在握手之前检查, 超过速率的连接直接关闭.
+/// 同一个实例可以在多个监听之间共享, 全局限制对所有监听生效.
+/// 来源ip的数量有上限, 超过后淘汰最久没有连接的ip, 限制器本身占用的内存不会无限增长.
+/// ipv6按/64网段计算, 同一个网段内的地址共享一个来源的速率
+#[derive(Debug, Clone)]
+pub struct AcceptLimiter {
+    global: Option<(RateLimit, Arc<Mutex<Bucket>>)>,
+    per_ip: Option<RateLimit>,
+    sources: Arc<Mutex<Sources>>,
+    max_tracked: usize,
+}
+
+impl RateLimit {
+    /// `burst` 为0时与 `rate` 相同
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: if burst == 0 { rate } else { burst },
+        }
+    }
+}
+
+impl Bucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate as f64).min(limit.burst as f64);
+        self.last = now;
+    }
+
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        self.refill(limit, now);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+
+        true
+    }
+}
+
+impl Default for AcceptLimiter {
+    fn default() -> Self {
+        Self {
+            global: None,
+            per_ip: None,
+            sources: Default::default(),
+            max_tracked: DEFAULT_MAX_TRACKED_IPS,
+        }
+    }
+}
+
+impl AcceptLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 所有来源共享的速率, None 表示不限制
+    pub fn global(mut self, limit: Option<RateLimit>) -> Self {
+        self.global = limit.filter(|limit| limit.rate > 0).map(|limit| {
+            let bucket = Bucket::full(&limit, Instant::now());
+            (limit, Arc::new(Mutex::new(bucket)))
+        });
+        self
+    }
+
+    /// 每个来源ip的速率, None 表示不限制
+    pub fn per_ip(mut self, limit: Option<RateLimit>) -> Self {
+        self.per_ip = limit.filter(|limit| limit.rate > 0);
+        self
+    }
+
+    /// 同时跟踪的来源ip数量, 默认4096
+    pub fn max_tracked(mut self, max: usize) -> Self {
+        self.max_tracked = max.max(1);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.global.is_none() && self.per_ip.is_none()
+    }
+
+    /// 先检查来源ip再检查全局, 被单个ip限制的连接不消耗全局的令牌,
+    /// 无法取得ip的地址只受全局限制
+    pub fn check(&self, addr: &Address) -> Option<Limited> {
+        let now = Instant::now();
+
+        if let Some(ip) = ip_of(addr) {
+            if !self.take_ip(ip, now) {
+                return Some(Limited::Ip(ip));
+            }
+        }
+
+        let (limit, bucket) = self.global.as_ref()?;
+
+        match bucket.lock() {
+            Ok(mut bucket) => match bucket.take(limit, now) {
+                true => None,
+                false => Some(Limited::Global),
+            },
+            Err(_) => None,
+        }
+    }
+
+    fn take_ip(&self, ip: IpAddr, now: Instant) -> bool {
+        let limit = match self.per_ip.as_ref() {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        match self.sources.lock() {
+            Ok(mut sources) => sources.take(source_of(ip), limit, now, self.max_tracked),
+            Err(_) => true,
+        }
+    }
+}
+
+impl Sources {
+    /// 取出来源的一个令牌并把来源移到最近使用的位置, 插入和淘汰都是O(log n)
+    fn take(&mut self, source: IpAddr, limit: &RateLimit, now: Instant, max: usize) -> bool {
+        self.seq += 1;
+
+        match self.buckets.get(&source) {
+            Some((_, used)) => {
+                self.order.remove(used);
+            }
+            None if self.buckets.len() >= max => {
+                let oldest = self.order.keys().next().copied();
+
+                if let Some(source) = oldest.and_then(|used| self.order.remove(&used)) {
+                    self.buckets.remove(&source);
+                }
+            }
+            None => {}
+        }
+
+        self.order.insert(self.seq, source);
+
+        let (bucket, used) = self
+            .buckets
+            .entry(source)
+            .or_insert_with(|| (Bucket::full(limit, now), 0));
+
+        *used = self.seq;
+
+        bucket.take(limit, now)
+    }
+}
+
+/// ipv4映射的ipv6地址按ipv4处理, 其他ipv6地址只保留/64网段,
+/// 单个主机通常分配到整个/64网段, 按完整地址计算时可以任意更换来源
+fn source_of(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => {
+                let prefix = u128::from(v6) & !(u64::MAX as u128);
+                IpAddr::V6(Ipv6Addr::from(prefix))
+            }
+        },
+    }
+}
+
+fn ip_of(addr: &Address) -> Option<IpAddr> {
+    match addr {
+        Address::One(socket) => match socket.inner() {
+            InnerAddr::Socket(addr) => Some(addr.ip()),
+            _ => None,
+        },
+        Address::Many(_) => None,
+    }
+}
+
+impl std::fmt::Display for Limited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limited::Global => write!(f, "accept rate exceeded"),
+            Limited::Ip(ip) => write!(f, "accept rate of {} exceeded", ip),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use super::{AcceptLimiter, Limited, RateLimit};
+    use crate::{Address, Socket};
+
+    fn addr(ip: [u8; 4]) -> Address {
+        Address::One(Socket::tcp((ip, 50000)))
+    }
+
+    #[test]
+    fn test_accept_limiter() {
+        let limiter = AcceptLimiter::new()
+            .global(Some(RateLimit::new(100, 3)))
+            .per_ip(Some(RateLimit::new(100, 2)));
+
+        assert!(limiter.check(&addr([10, 0, 0, 1])).is_none());
+        assert!(limiter.check(&addr([10, 0, 0, 1])).is_none());
+        assert_eq!(
+            limiter.check(&addr([10, 0, 0, 1])),
+            Some(Limited::Ip(IpAddr::from([10, 0, 0, 1])))
+        );
+
+        // 被单个ip限制的连接不消耗全局的令牌
+        assert!(limiter.check(&addr([10, 0, 0, 2])).is_none());
+        assert_eq!(limiter.check(&addr([10, 0, 0, 3])), Some(Limited::Global));
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(limiter.check(&addr([10, 0, 0, 1])).is_none());
+
+        assert!(AcceptLimiter::new().is_empty());
+        assert!(AcceptLimiter::new()
+            .global(Some(RateLimit::new(0, 10)))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_accept_limiter_bounded() {
+        let limiter = AcceptLimiter::new()
+            .per_ip(Some(RateLimit::new(1, 1)))
+            .max_tracked(2);
+
+        for ip in 1..=3 {
+            assert!(limiter.check(&addr([10, 0, 0, ip])).is_none());
+        }
+
+        let tracked = |ip: [u8; 4]| {
+            let sources = limiter.sources.lock().unwrap();
+            assert_eq!(sources.buckets.len(), sources.order.len());
+            sources.buckets.contains_key(&IpAddr::from(ip))
+        };
+
+        // 最久没有连接的ip被淘汰
+        assert!(!tracked([10, 0, 0, 1]));
+        assert!(limiter.check(&addr([10, 0, 0, 3])).is_some());
+
+        // 再次连接的ip移到最近使用的位置, 淘汰的是另一个
+        assert!(limiter.check(&addr([10, 0, 0, 2])).is_some());
+        assert!(limiter.check(&addr([10, 0, 0, 4])).is_none());
+        assert!(tracked([10, 0, 0, 2]));
+        assert!(!tracked([10, 0, 0, 3]));
+    }
+
+    #[test]
+    fn test_accept_limiter_ipv6_prefix() {
+        let limiter = AcceptLimiter::new().per_ip(Some(RateLimit::new(1, 1)));
+        let addr = |ip: &str| Address::One(Socket::tcp((ip.parse::<IpAddr>().unwrap(), 50000)));
+
+        // 同一个/64网段内更换地址不能绕过限制
+        assert!(limiter.check(&addr("2001:db8::1")).is_none());
+        assert_eq!(
+            limiter.check(&addr("2001:db8::ffff:2")),
+            Some(Limited::Ip("2001:db8::ffff:2".parse().unwrap()))
+        );
+        assert!(limiter.check(&addr("2001:db8:0:1::1")).is_none());
+
+        // ipv4映射的ipv6地址与ipv4地址是同一个来源
+        assert!(limiter.check(&addr("10.0.0.1")).is_none());
+        assert!(limiter.check(&addr("::ffff:10.0.0.1")).is_some());
+    }
+}
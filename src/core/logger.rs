@@ -0,0 +1,453 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{
+    kv::{self, Key, Value, Visitor},
+    LevelFilter,
+};
+
+use super::json::json_string;
+use crate::metrics::metrics;
+
+/// 等待写入日志文件的最大行数, 超过时丢弃新的日志, 不阻塞转发
+const LOG_BUFFER_LINES: usize = 8192;
+
+/// fuso模块当前的日志级别, 可以通过管理服务在运行时修改
+static LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+/// 其他模块由 `RUST_LOG` 决定的最高级别, 修改级别时不低于该级别
+static OTHERS: AtomicUsize = AtomicUsize::new(LevelFilter::Off as usize);
+
+/// 日志输出格式, `json` 每行输出一个json对象, 便于日志系统收集
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// 日志文件的轮转方式, 按时间轮转时以UTC的整点或零点为界
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+    /// 文件达到该字节数时轮转
+    Size(u64),
+}
+
+/// 写入日志文件的配置
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub rotation: LogRotation,
+    /// 保留的已轮转文件数量, 依次命名为 `<path>.1`, `<path>.2`...
+    pub keep: usize,
+    /// 同时输出到stderr
+    pub tee: bool,
+}
+
+/// 交给后台线程写入文件, 队列已满时丢弃并计数
+struct FileTarget {
+    sender: SyncSender<Vec<u8>>,
+    tee: bool,
+}
+
+/// 只在后台线程中写入, 每次写入一条完整的日志, 轮转发生在两条日志之间
+struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    keep: usize,
+    file: BufWriter<File>,
+    written: u64,
+    period: u64,
+}
+
+struct TextFields<'a>(&'a mut String);
+
+struct JsonFields<'a>(&'a mut String);
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            format => Err(format!(
+                "unknown log format `{}`, expect text or json",
+                format
+            )),
+        }
+    }
+}
+
+impl FromStr for LogRotation {
+    type Err = String;
+
+    fn from_str(rotation: &str) -> Result<Self, Self::Err> {
+        let lower = rotation.to_ascii_lowercase();
+
+        let (size, unit) = match lower.as_str() {
+            "never" => return Ok(Self::Never),
+            "hourly" => return Ok(Self::Hourly),
+            "daily" => return Ok(Self::Daily),
+            size => match size.find(|c: char| !c.is_ascii_digit()) {
+                None => (size, 1),
+                Some(n) => (
+                    &size[..n],
+                    match &size[n..] {
+                        "k" | "kb" => 1 << 10,
+                        "m" | "mb" => 1 << 20,
+                        "g" | "gb" => 1 << 30,
+                        _ => 0,
+                    },
+                ),
+            },
+        };
+
+        match size.parse::<u64>() {
+            Ok(size) if size > 0 && unit > 0 => Ok(Self::Size(size * unit)),
+            _ => Err(format!(
+                "unknown log rotation `{}`, expect never, hourly, daily or a size like 100MB",
+                rotation
+            )),
+        }
+    }
+}
+
+impl LogRotation {
+    fn period(&self, now: SystemTime) -> u64 {
+        let secs = now
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        match self {
+            Self::Hourly => secs / 3600,
+            Self::Daily => secs / 86400,
+            _ => 0,
+        }
+    }
+}
+
+impl Write for FileTarget {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.tee {
+            let _ = std::io::stderr().write_all(buf);
+        }
+
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(buf.to_vec()) {
+            metrics().on_log_dropped();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", n));
+    PathBuf::from(rotated)
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: LogRotation, keep: usize) -> std::io::Result<Self> {
+        let file = open_append(&path)?;
+        let modified = file
+            .metadata()?
+            .modified()
+            .unwrap_or_else(|_| SystemTime::now());
+
+        Ok(Self {
+            written: file.metadata()?.len(),
+            period: rotation.period(modified),
+            file: BufWriter::new(file),
+            path,
+            rotation,
+            keep,
+        })
+    }
+
+    fn should_rotate(&self, len: usize) -> bool {
+        match self.rotation {
+            LogRotation::Never => false,
+            LogRotation::Size(max) => self.written > 0 && self.written + len as u64 > max,
+            rotation => rotation.period(SystemTime::now()) != self.period,
+        }
+    }
+
+    /// 依次后移已轮转的文件, 新文件打开成功后才替换, 失败时继续写入原文件
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+
+        for n in (1..self.keep).rev() {
+            let from = rotated_path(&self.path, n);
+            if from.exists() {
+                std::fs::rename(from, rotated_path(&self.path, n + 1))?;
+            }
+        }
+
+        match self.keep {
+            0 => std::fs::remove_file(&self.path)?,
+            _ => std::fs::rename(&self.path, rotated_path(&self.path, 1))?,
+        }
+
+        self.file = BufWriter::new(open_append(&self.path)?);
+        self.written = 0;
+        self.period = self.rotation.period(SystemTime::now());
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) {
+        if self.should_rotate(line.len()) {
+            if let Err(e) = self.rotate() {
+                eprintln!("failed to rotate log file {}: {}", self.path.display(), e);
+                self.period = self.rotation.period(SystemTime::now());
+            }
+        }
+
+        match self.file.write_all(line) {
+            Ok(()) => self.written += line.len() as u64,
+            Err(e) => eprintln!("failed to write log file {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// 写完队列中所有的日志后再刷新到磁盘
+    fn run(mut self, receiver: Receiver<Vec<u8>>) {
+        while let Ok(line) = receiver.recv() {
+            self.write_line(&line);
+
+            while let Ok(line) = receiver.try_recv() {
+                self.write_line(&line);
+            }
+
+            let _ = self.file.flush();
+        }
+    }
+}
+
+/// 内部的env_logger允许fuso的所有级别, 由 `LEVEL` 过滤
+struct Reloadable(env_logger::Logger);
+
+fn level_from_usize(level: usize) -> LevelFilter {
+    match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// fuso模块当前的日志级别
+pub fn level() -> LevelFilter {
+    level_from_usize(LEVEL.load(Ordering::Relaxed))
+}
+
+/// 在运行时修改fuso模块的日志级别, 立即生效
+pub fn set_level(level: LevelFilter) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level.max(level_from_usize(OTHERS.load(Ordering::Relaxed))));
+}
+
+impl log::Log for Reloadable {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        (!metadata.target().starts_with("fuso") || metadata.level() <= level())
+            && self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+fn install(mut builder: env_logger::Builder, level: LevelFilter) {
+    OTHERS.store(
+        env_logger::Builder::from_default_env().build().filter() as usize,
+        Ordering::Relaxed,
+    );
+
+    if log::set_boxed_logger(Box::new(Reloadable(builder.build()))).is_ok() {
+        set_level(level);
+    }
+}
+
+fn builder(format: LogFormat, with_target: bool) -> env_logger::Builder {
+    let mut builder = env_logger::builder();
+
+    builder.filter_module("fuso", LevelFilter::Trace);
+
+    match format {
+        LogFormat::Text => builder.format(move |buf, record| {
+            let mut fields = String::new();
+            let _ = record.key_values().visit(&mut TextFields(&mut fields));
+
+            let timestamp = buf.timestamp_millis();
+            let level = buf.default_styled_level(record.level());
+
+            if with_target {
+                writeln!(
+                    buf,
+                    "[{} {:<5} {}] {}{}",
+                    timestamp,
+                    level,
+                    record.target(),
+                    record.args(),
+                    fields
+                )
+            } else {
+                writeln!(
+                    buf,
+                    "[{} {:<5}] {}{}",
+                    timestamp,
+                    level,
+                    record.args(),
+                    fields
+                )
+            }
+        }),
+        LogFormat::Json => builder.format(|buf, record| {
+            let mut line = String::from("{");
+
+            let _ = write!(
+                line,
+                "\"timestamp\":\"{}\",\"level\":\"{}\",\"target\":",
+                buf.timestamp_millis(),
+                record.level()
+            );
+
+            json_string(&mut line, record.target());
+            let _ = record.key_values().visit(&mut JsonFields(&mut line));
+
+            line.push_str(",\"message\":");
+            json_string(&mut line, &record.args().to_string());
+            line.push('}');
+
+            writeln!(buf, "{}", line)
+        }),
+    };
+
+    builder
+}
+
+/// 初始化日志, `with_target` 为false时文本格式不输出模块名,
+/// 之后可以通过 `set_level` 修改级别
+pub fn init(level: LevelFilter, format: LogFormat, with_target: bool) {
+    install(builder(format, with_target), level);
+}
+
+/// 初始化日志并写入文件, 由后台线程写入和轮转, 磁盘较慢时丢弃日志而不是阻塞
+pub fn init_with_file(
+    level: LevelFilter,
+    format: LogFormat,
+    with_target: bool,
+    log_file: LogFile,
+) -> crate::Result<()> {
+    let file = RotatingFile::open(log_file.path, log_file.rotation, log_file.keep)?;
+    let (sender, receiver) = mpsc::sync_channel(LOG_BUFFER_LINES);
+
+    std::thread::Builder::new()
+        .name(String::from("fuso-log"))
+        .spawn(move || file.run(receiver))?;
+
+    // env_logger 0.9 只有 `is_test` 时才会写入 `Target::Pipe`, 每条日志写入一次
+    let mut builder = builder(format, with_target);
+
+    builder
+        .is_test(true)
+        .write_style(env_logger::WriteStyle::Never)
+        .target(env_logger::Target::Pipe(Box::new(FileTarget {
+            sender,
+            tee: log_file.tee,
+        })));
+
+    install(builder, level);
+
+    Ok(())
+}
+
+impl<'kvs> Visitor<'kvs> for TextFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(self.0, " {}={}", key, value);
+        Ok(())
+    }
+}
+
+impl<'kvs> Visitor<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push(',');
+        json_string(self.0, key.as_str());
+        self.0.push(':');
+
+        match value.to_u64() {
+            Some(n) => {
+                let _ = write!(self.0, "{}", n);
+            }
+            None => json_string(self.0, &value.to_string()),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rotated_path, LogRotation, RotatingFile};
+
+    #[test]
+    fn test_rotate_log_file() {
+        assert_eq!("100MB".parse(), Ok(LogRotation::Size(100 << 20)));
+        assert_eq!("512".parse(), Ok(LogRotation::Size(512)));
+        assert_eq!("Daily".parse(), Ok(LogRotation::Daily));
+        assert!("0k".parse::<LogRotation>().is_err());
+        assert!("weekly".parse::<LogRotation>().is_err());
+
+        let dir = std::env::temp_dir().join(format!("fuso-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("fuso.log");
+        let (sender, receiver) = std::sync::mpsc::sync_channel(16);
+
+        let file = RotatingFile::open(path.clone(), LogRotation::Size(10), 2).unwrap();
+        for line in ["line 1\n", "line 2\n", "line 3\n", "line 4\n"] {
+            sender.send(line.as_bytes().to_vec()).unwrap();
+        }
+        drop(sender);
+        file.run(receiver);
+
+        let read = |path| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "line 4\n");
+        assert_eq!(read(rotated_path(&path, 1)), "line 3\n");
+        assert_eq!(read(rotated_path(&path, 2)), "line 2\n");
+        assert!(!rotated_path(&path, 3).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,335 @@
+use std::{
+    fmt::Write,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    ext::{AsyncReadExt, AsyncWriteExt},
+    resource::{Headroom, SAMPLE_INTERVAL},
+    time, Accepter, AccepterExt, AsyncRead, AsyncWrite, Provider, Socket,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+static METRICS: Metrics = Metrics::new();
+
+/// 全局的运行指标, 以prometheus文本格式输出
+pub struct Metrics {
+    connections: AtomicU64,
+    handshake_failures: AtomicU64,
+    penetrate_active: AtomicI64,
+    penetrate_total: AtomicU64,
+    convs_active: AtomicI64,
+    routes: AtomicU64,
+    forwarded_bytes: AtomicU64,
+    log_dropped: AtomicU64,
+    sniff_websocket: AtomicU64,
+    sniff_websocket_failures: AtomicU64,
+    sniff_fallback: AtomicU64,
+    rate_limited: AtomicU64,
+    decrypt_failures: AtomicU64,
+    resource_rejected: AtomicU64,
+    headroom: Mutex<Option<(Instant, Headroom)>>,
+}
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            handshake_failures: AtomicU64::new(0),
+            penetrate_active: AtomicI64::new(0),
+            penetrate_total: AtomicU64::new(0),
+            convs_active: AtomicI64::new(0),
+            routes: AtomicU64::new(0),
+            forwarded_bytes: AtomicU64::new(0),
+            log_dropped: AtomicU64::new(0),
+            sniff_websocket: AtomicU64::new(0),
+            sniff_websocket_failures: AtomicU64::new(0),
+            sniff_fallback: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            decrypt_failures: AtomicU64::new(0),
+            resource_rejected: AtomicU64::new(0),
+            headroom: Mutex::new(None),
+        }
+    }
+
+    pub fn on_connect(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_penetrate_start(&self) {
+        self.penetrate_total.fetch_add(1, Ordering::Relaxed);
+        self.penetrate_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_penetrate_stop(&self) {
+        self.penetrate_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn on_conv_open(&self) {
+        self.convs_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_conv_close(&self) {
+        self.convs_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn on_route(&self) {
+        self.routes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn on_forward(&self, bytes: usize) {
+        self.forwarded_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_log_dropped(&self) {
+        self.log_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 首个请求被识别为websocket
+    pub fn on_sniff_websocket(&self) {
+        self.sniff_websocket.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 识别为websocket后升级失败, 可能是误判
+    pub fn on_sniff_websocket_failed(&self) {
+        self.sniff_websocket_failures
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 不是websocket, 交还数据给后续的握手
+    pub fn on_sniff_fallback(&self) {
+        self.sniff_fallback.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 超过接受速率被直接关闭的连接
+    pub fn on_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// aead帧认证失败, 密钥不一致或数据被篡改
+    pub fn on_decrypt_failed(&self) {
+        self.decrypt_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 系统资源低于下限被拒绝的连接
+    pub fn on_resource_rejected(&self) {
+        self.resource_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `ResourceGuard` 每次采样后更新, 输出时不必再读取 `/proc`
+    pub fn on_headroom(&self, headroom: Headroom) {
+        if let Ok(mut sampled) = self.headroom.lock() {
+            *sampled = Some((Instant::now(), headroom));
+        }
+    }
+
+    /// 最近一秒内没有采样时(没有启用 `ResourceGuard` 或没有新的连接)才重新采样
+    fn headroom(&self) -> Headroom {
+        let mut sampled = match self.headroom.lock() {
+            Ok(sampled) => sampled,
+            Err(_) => return Headroom::default(),
+        };
+
+        match *sampled {
+            Some((at, headroom)) if at.elapsed() < SAMPLE_INTERVAL => headroom,
+            _ => {
+                let headroom = Headroom::sample();
+                *sampled = Some((Instant::now(), headroom));
+                headroom
+            }
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+
+        let metrics: [(&str, &str, &str, i64); 14] = [
+            (
+                "fuso_connections_total",
+                "counter",
+                "Accepted connections",
+                self.connections.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_handshake_failures_total",
+                "counter",
+                "Failed handshakes",
+                self.handshake_failures.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_penetrate_active",
+                "gauge",
+                "Active port mappings",
+                self.penetrate_active.load(Ordering::Relaxed),
+            ),
+            (
+                "fuso_penetrate_total",
+                "counter",
+                "Port mappings created",
+                self.penetrate_total.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_convs_active",
+                "gauge",
+                "Active visitor connections on the server",
+                self.convs_active.load(Ordering::Relaxed),
+            ),
+            (
+                "fuso_routes_total",
+                "counter",
+                "Visitor connections routed to clients",
+                self.routes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_forwarded_bytes_total",
+                "counter",
+                "Bytes forwarded",
+                self.forwarded_bytes.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_log_dropped_total",
+                "counter",
+                "Log lines dropped because the log file could not keep up",
+                self.log_dropped.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_sniff_websocket_total",
+                "counter",
+                "Connections detected as websocket",
+                self.sniff_websocket.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_sniff_websocket_failures_total",
+                "counter",
+                "Connections detected as websocket that failed to upgrade",
+                self.sniff_websocket_failures.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_sniff_fallback_total",
+                "counter",
+                "Connections not detected as websocket",
+                self.sniff_fallback.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_rate_limited_total",
+                "counter",
+                "Connections dropped because the accept rate was exceeded",
+                self.rate_limited.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_decrypt_failures_total",
+                "counter",
+                "Encrypted frames that failed authentication, caused by a wrong key or tampering",
+                self.decrypt_failures.load(Ordering::Relaxed) as i64,
+            ),
+            (
+                "fuso_resource_rejected_total",
+                "counter",
+                "Connections dropped because the system was low on memory or file descriptors",
+                self.resource_rejected.load(Ordering::Relaxed) as i64,
+            ),
+        ];
+
+        // 无法获取的资源不输出
+        let headroom = self.headroom();
+        let headroom = [
+            (
+                "fuso_available_memory_bytes",
+                "gauge",
+                "Memory available to new allocations",
+                headroom.available_memory,
+            ),
+            (
+                "fuso_free_fds",
+                "gauge",
+                "File descriptors that can still be opened before RLIMIT_NOFILE",
+                headroom.free_fds,
+            ),
+        ];
+
+        let headroom = headroom
+            .into_iter()
+            .filter_map(|(name, kind, help, value)| Some((name, kind, help, value? as i64)));
+
+        for (name, kind, help, value) in metrics.into_iter().chain(headroom) {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+
+        text
+    }
+}
+
+/// 启动一个简单的http服务, 任何请求都返回当前的指标
+pub async fn serve<P, A, S>(provider: P, bind: Socket) -> crate::Result<()>
+where
+    P: Provider<Socket, Output = BoxedFuture<A>>,
+    A: Accepter<Stream = S> + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut accepter = provider.call(bind).await?;
+
+    log::info!("metrics listens on {}", accepter.local_addr()?);
+
+    loop {
+        let stream = accepter.accept().await?;
+
+        let response = time::wait_for(Duration::from_secs(5), async move {
+            let mut stream = stream;
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await?;
+
+            let body = metrics().render();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+
+            stream.write_all(response.as_bytes()).await?;
+            stream.close().await
+        });
+
+        if let Err(e) = response.await {
+            log::debug!("failed to respond metrics {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+
+    #[test]
+    fn test_metrics_render() {
+        let metrics = Metrics::new();
+
+        metrics.on_connect();
+        metrics.on_penetrate_start();
+        metrics.on_forward(1500);
+        metrics.on_sniff_fallback();
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE fuso_penetrate_active gauge\nfuso_penetrate_active 1\n"));
+        assert!(text.contains("fuso_forwarded_bytes_total 1500\n"));
+        assert!(text.contains("fuso_connections_total 1\n"));
+        assert!(text.contains("fuso_sniff_fallback_total 1\n"));
+        assert!(text.contains("fuso_sniff_websocket_total 0\n"));
+    }
+}
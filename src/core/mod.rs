@@ -9,6 +9,12 @@ pub use processor::*;
 pub use observer::*;
 pub mod compress;
 
+pub mod acl;
+pub mod admin;
+#[cfg(feature = "fuso-clap")]
+pub mod cli;
+pub mod limit;
+
 mod accepter;
 pub use accepter::*;
 
@@ -22,8 +28,15 @@ pub use socket::*;
 pub mod encryption;
 pub mod generator;
 pub mod guard;
+pub mod health;
+mod json;
+#[cfg(feature = "fuso-log")]
+pub mod logger;
+pub mod metrics;
 pub mod mixing;
 pub mod protocol;
+pub mod resource;
+pub mod systemd;
 
 use std::marker::PhantomData;
 use std::sync::Arc;
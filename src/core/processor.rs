@@ -5,11 +5,17 @@ use crate::{ClientProvider, DecorateProvider, Provider, Socket};
 type BoxedFuture<O> = Pin<Box<dyn std::future::Future<Output = crate::Result<O>> + Send + 'static>>;
 
 pub struct Processor<P, S, O> {
+    cid: Arc<str>,
     provider: Arc<P>,
     observer: Option<Arc<O>>,
     decorator: Option<DecorateProvider<S>>,
 }
 
+/// 生成关联id, 同一个控制连接产生的日志都带有该id
+pub fn correlation_id() -> Arc<str> {
+    Arc::from(format!("{:08x}", rand::random::<u32>()))
+}
+
 impl<P, S, O> Processor<P, S, O> {
     pub fn new(
         provider: Arc<P>,
@@ -17,12 +23,23 @@ impl<P, S, O> Processor<P, S, O> {
         decorator: Option<DecorateProvider<S>>,
     ) -> Self {
         Self {
+            cid: correlation_id(),
             provider,
             observer,
             decorator,
         }
     }
 
+    /// 使用接受连接时生成的关联id
+    pub fn with_cid(mut self, cid: Arc<str>) -> Self {
+        self.cid = cid;
+        self
+    }
+
+    pub fn cid(&self) -> &Arc<str> {
+        &self.cid
+    }
+
     pub fn observer(&self) -> &Option<Arc<O>> {
         &self.observer
     }
@@ -55,6 +72,7 @@ where
 impl<P, S, O> Clone for Processor<P, S, O> {
     fn clone(&self) -> Self {
         Self {
+            cid: self.cid.clone(),
             provider: self.provider.clone(),
             observer: self.observer.clone(),
             decorator: self.decorator.clone(),
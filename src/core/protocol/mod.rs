@@ -22,6 +22,9 @@ use crate::{r#async::ReadBuf, AsyncRead, AsyncWrite, PacketErr, Result};
 /// o => 0x6f
 pub const MAGIC: [u8; 4] = [0x66, 0x75, 0x73, 0x6f];
 
+/// `recv_packet` 接收数据包允许的最大长度
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
 pub fn head_size() -> usize {
     8
 }
@@ -61,6 +64,7 @@ where
     buf: Vec<u8>,
     state: State,
     offset: usize,
+    max: usize,
     #[pin]
     reader: &'a mut R,
 }
@@ -78,6 +82,14 @@ where
 
 pub trait AsyncRecvPacket: AsyncRead {
     fn recv_packet<'a>(&'a mut self) -> RecvPacket<'a, Self>
+    where
+        Self: Unpin + Sized,
+    {
+        self.recv_packet_limit(DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// 数据长度超过 `max` 时在分配内存前返回 `InvalidData`
+    fn recv_packet_limit<'a>(&'a mut self, max: usize) -> RecvPacket<'a, Self>
     where
         Self: Unpin + Sized,
     {
@@ -85,6 +97,7 @@ pub trait AsyncRecvPacket: AsyncRead {
             buf: Vec::new(),
             state: State::None,
             offset: 0,
+            max,
             reader: self,
         }
     }
@@ -179,6 +192,15 @@ where
 
                     let len = head.data_len;
 
+                    if len as usize > *this.max {
+                        log::debug!("received oversized packet, data size {}bytes", len);
+                        break Poll::Ready(Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("packet size {} exceeds the limit {}", len, this.max),
+                        )
+                        .into()));
+                    }
+
                     log::trace!("received legal packet, data size {}bytes", len);
 
                     if len == 0 {
@@ -225,3 +247,45 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{make_packet, AsyncRecvPacket, MAGIC};
+    use crate::{ext::AsyncWriteExt, Kind};
+
+    #[test]
+    fn test_max_frame_size() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (mut w, mut r) = tokio::io::duplex(1024);
+
+                w.write_all(&make_packet(b"fuso".to_vec()).encode())
+                    .await
+                    .unwrap();
+                assert_eq!(r.recv_packet().await.unwrap().payload, b"fuso");
+
+                w.write_all(&make_packet(b"fuso".to_vec()).encode())
+                    .await
+                    .unwrap();
+                assert_eq!(r.recv_packet_limit(4).await.unwrap().payload, b"fuso");
+
+                // 数据长度超过本次的限制
+                w.write_all(&make_packet(vec![0u8; 5]).encode())
+                    .await
+                    .unwrap();
+                assert!(r.recv_packet_limit(4).await.is_err());
+
+                let (mut w, mut r) = tokio::io::duplex(1024);
+
+                let mut head = MAGIC.to_vec();
+                head.extend_from_slice(&u32::MAX.to_le_bytes());
+                w.write_all(&head).await.unwrap();
+
+                match r.recv_packet().await.unwrap_err().kind() {
+                    Kind::IO(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+                    kind => panic!("unexpected error {:?}", kind),
+                }
+            });
+    }
+}
@@ -1,9 +1,12 @@
-use std::fmt::Display;
+use std::{fmt::Display, time::Duration};
 
 use bytes::{BufMut, BytesMut};
 use serde::{Deserialize, Serialize};
 
-use crate::{Addr, Address, Socket};
+use crate::{
+    compress::Compression, encryption::Cipher, Addr, Address, BuildErr, CompressErr, EncryptionErr,
+    Error, Kind, Socket, SocksErr,
+};
 
 use super::make_packet;
 
@@ -26,7 +29,41 @@ pub enum Connect {
 pub enum Bind {
     Setup(Socket, Socket),
     Success(Address, Address),
+    /// 旧版本服务端绑定失败时发送, 现在以 `Poto::Error` 回复
     Failed(String),
+    /// 携带客户端名称, 服务端据此复用断线前的监听
+    Named(String, Socket, Socket),
+    /// 带有存活时间的映射, 到期后无论是否空闲都会被关闭, 监听不保留
+    Expiring(Option<String>, Socket, Socket, Duration),
+}
+
+/// 错误码, 以u16传输, 对端据此区分错误而不需要匹配错误信息,
+/// 无法识别的错误码视为 `Generic`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    /// 未分类的错误
+    Generic,
+    /// 不允许绑定请求的端口
+    BindDenied,
+    /// 端口已被占用
+    PortInUse,
+    /// token校验失败
+    AuthFailed,
+    /// 超过连接数限制
+    QuotaExceeded,
+    /// conv不存在或已经关闭
+    NoSuchConv,
+    /// 无法解析的消息
+    InvalidPacket,
+    /// 没有共同支持的加密或压缩方式
+    NegotiationFailed,
+    /// 转发目标无法连接或没有应答
+    Unreachable,
+    /// 映射的存活时间已到
+    Expired,
+    /// 映射已经排空, 客户端不再重连
+    Drained,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -39,11 +76,138 @@ pub enum Auth {
 pub enum Poto {
     Ping,
     Close,
+    /// 旧版本客户端的映射失败消息, 服务端仍然接受, 现在使用 `MapFailed`
     MapError(u32, String),
     Bind(Bind),
     Map(u32, Socket),
     Connect(Connect, Auth),
     Forward(Addr),
+    Hello(Vec<Cipher>),
+    /// 错误码与可选的详细信息
+    Error(ErrorCode, Option<String>),
+    Pong,
+    /// conv, rx_bytes, tx_bytes
+    Stats(u32, u64, u64),
+    /// 强制关闭指定conv的连接
+    Reset(u32),
+    /// 协商转发数据的压缩方式, 客户端按优先级发送, 服务端回复选中的一种
+    Compress(Vec<Compression>),
+    /// 服务端配置了token时, 客户端需在 `Bind` 之前发送
+    Token(String),
+    /// 客户端请求将该连接用于多路复用, 服务端支持时原样回复
+    Mux,
+    /// 客户端请求停止接受新的访问, 正在转发的连接不受影响, 服务端原样回复,
+    /// 等待中的访问处理完后以 `ErrorCode::Drained` 结束映射
+    Drain,
+    /// 客户端无法完成映射(连接转发目标失败等), conv, 错误码与可选的详细信息
+    MapFailed(u32, ErrorCode, Option<String>),
+}
+
+impl ErrorCode {
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCode::Generic => 0,
+            ErrorCode::BindDenied => 1,
+            ErrorCode::PortInUse => 2,
+            ErrorCode::AuthFailed => 3,
+            ErrorCode::QuotaExceeded => 4,
+            ErrorCode::NoSuchConv => 5,
+            ErrorCode::InvalidPacket => 6,
+            ErrorCode::NegotiationFailed => 7,
+            ErrorCode::Unreachable => 8,
+            ErrorCode::Expired => 9,
+            ErrorCode::Drained => 10,
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::BindDenied,
+            2 => ErrorCode::PortInUse,
+            3 => ErrorCode::AuthFailed,
+            4 => ErrorCode::QuotaExceeded,
+            5 => ErrorCode::NoSuchConv,
+            6 => ErrorCode::InvalidPacket,
+            7 => ErrorCode::NegotiationFailed,
+            8 => ErrorCode::Unreachable,
+            9 => ErrorCode::Expired,
+            10 => ErrorCode::Drained,
+            _ => ErrorCode::Generic,
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+/// 按错误类型选择错误码, 无法归类的为 `Generic`
+impl From<&Error> for ErrorCode {
+    fn from(e: &Error) -> Self {
+        use std::io::ErrorKind;
+
+        match e.kind() {
+            Kind::Refused(code, _) => *code,
+            Kind::IO(e) | Kind::Build(BuildErr::Bind(_, e)) => match e.kind() {
+                ErrorKind::AddrInUse => ErrorCode::PortInUse,
+                ErrorKind::PermissionDenied | ErrorKind::AddrNotAvailable => ErrorCode::BindDenied,
+                ErrorKind::ConnectionRefused | ErrorKind::TimedOut => ErrorCode::Unreachable,
+                _ => ErrorCode::Generic,
+            },
+            Kind::Socks(SocksErr::Reply(0x03..=0x06)) => ErrorCode::Unreachable,
+            Kind::Deserialize(_) | Kind::Packet(_) => ErrorCode::InvalidPacket,
+            Kind::Encryption(EncryptionErr::Negotiate(_))
+            | Kind::Compress(CompressErr::Negotiate(_)) => ErrorCode::NegotiationFailed,
+            _ => ErrorCode::Generic,
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ErrorCode::Generic => "error",
+            ErrorCode::BindDenied => "bind denied",
+            ErrorCode::PortInUse => "port in use",
+            ErrorCode::AuthFailed => "authentication failed",
+            ErrorCode::QuotaExceeded => "quota exceeded",
+            ErrorCode::NoSuchConv => "no such conv",
+            ErrorCode::InvalidPacket => "invalid packet",
+            ErrorCode::NegotiationFailed => "negotiation failed",
+            ErrorCode::Unreachable => "unreachable",
+            ErrorCode::Expired => "expired",
+            ErrorCode::Drained => "drained",
+        };
+
+        write!(f, "{}({})", description, self.code())
+    }
+}
+
+impl Poto {
+    /// 将本地的错误转换为发送给对端的错误消息
+    pub fn from_error(e: &Error) -> Self {
+        match e.kind() {
+            Kind::Refused(code, detail) => Poto::Error(*code, detail.clone()),
+            _ => Poto::Error(ErrorCode::from(e), Some(e.to_string())),
+        }
+    }
+
+    /// 映射失败时发送给服务端, 无法归类的错误使用 `fallback`
+    pub fn map_failed(id: u32, e: &Error, fallback: ErrorCode) -> Self {
+        let code = match ErrorCode::from(e) {
+            ErrorCode::Generic => fallback,
+            code => code,
+        };
+
+        match e.kind() {
+            Kind::Refused(_, detail) => Poto::MapFailed(id, code, detail.clone()),
+            _ => Poto::MapFailed(id, code, Some(e.to_string())),
+        }
+    }
 }
 
 impl Packet {
@@ -99,3 +263,73 @@ impl Display for Poto {
         write!(f, "{:#?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorCode, Poto, TryToPoto};
+    use crate::{protocol::make_packet, Error, Kind};
+
+    #[test]
+    fn test_error_code() {
+        let poto = Poto::Error(ErrorCode::NoSuchConv, Some(String::from("conv 7")));
+        let payload = bincode::serialize(&poto).unwrap();
+        assert_eq!(make_packet(payload.clone()).try_poto().unwrap(), poto);
+
+        // 变体序号(u32)之后为错误码(u16)
+        assert_eq!(&payload[4..6], &5u16.to_le_bytes());
+
+        let mut payload = payload;
+        payload[4..6].copy_from_slice(&999u16.to_le_bytes());
+        assert_eq!(
+            make_packet(payload).try_poto().unwrap(),
+            Poto::Error(ErrorCode::Generic, Some(String::from("conv 7")))
+        );
+
+        let in_use: Error = std::io::Error::from(std::io::ErrorKind::AddrInUse).into();
+        assert_eq!(ErrorCode::from(&in_use), ErrorCode::PortInUse);
+        assert_eq!(
+            Poto::from_error(&in_use),
+            Poto::Error(ErrorCode::PortInUse, Some(in_use.to_string()))
+        );
+
+        let denied: Error = Kind::Refused(ErrorCode::AuthFailed, None).into();
+        assert_eq!(
+            Poto::from_error(&denied),
+            Poto::Error(ErrorCode::AuthFailed, None)
+        );
+        assert_eq!(denied.to_string(), "authentication failed(3)");
+
+        let expired: Error = Kind::Refused(ErrorCode::Expired, None).into();
+        assert_eq!(ErrorCode::from(&expired), ErrorCode::from(9u16));
+
+        let drained: Error = Kind::Refused(ErrorCode::Drained, None).into();
+        assert_eq!(ErrorCode::from(&drained), ErrorCode::from(10u16));
+        assert_eq!(drained.to_string(), "drained(10)");
+    }
+
+    #[test]
+    fn test_map_failed() {
+        let refused: Error = std::io::Error::from(std::io::ErrorKind::ConnectionRefused).into();
+        assert_eq!(
+            Poto::map_failed(7, &refused, ErrorCode::Generic),
+            Poto::MapFailed(7, ErrorCode::Unreachable, Some(refused.to_string()))
+        );
+
+        // 无法归类的连接失败视为目标不可达
+        let unknown: Error = Kind::Message(String::from("no address")).into();
+        assert_eq!(
+            Poto::map_failed(7, &unknown, ErrorCode::Unreachable),
+            Poto::MapFailed(7, ErrorCode::Unreachable, Some(String::from("no address")))
+        );
+
+        let denied: Error = Kind::Refused(ErrorCode::BindDenied, None).into();
+        assert_eq!(
+            Poto::map_failed(7, &denied, ErrorCode::Unreachable),
+            Poto::MapFailed(7, ErrorCode::BindDenied, None)
+        );
+
+        let poto = Poto::MapFailed(7, ErrorCode::Unreachable, None);
+        let payload = bincode::serialize(&poto).unwrap();
+        assert_eq!(make_packet(payload).try_poto().unwrap(), poto);
+    }
+}
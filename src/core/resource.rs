@@ -0,0 +1,267 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::metrics::metrics;
+
+/// 两次采样的最小间隔, 大量连接时不必每个连接都读取 `/proc`
+pub(crate) const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 采样时剩余的系统资源, 无法获取的为None
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Headroom {
+    /// 可用内存(字节), 即 `/proc/meminfo` 中的 `MemAvailable`
+    pub available_memory: Option<u64>,
+    /// 距离 `RLIMIT_NOFILE` 还可以打开的文件描述符数量
+    pub free_fds: Option<u64>,
+}
+
+/// 资源低于下限的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LowResource {
+    Memory(u64),
+    Fds(u64),
+}
+
+/// 接受新连接前检查剩余的系统资源, 低于下限时直接关闭新连接, 而不是等到内存或描述符耗尽后被结束.
+/// 资源每秒最多采样一次, 在单独的线程中读取 `/proc`, 不阻塞接受连接的任务,
+/// 同一个实例可以在多个监听之间共享采样结果. 只支持linux, 其他平台不检查
+#[derive(Debug, Clone, Default)]
+pub struct ResourceGuard {
+    min_available_memory: Option<u64>,
+    min_free_fds: Option<u64>,
+    sampled: Arc<Mutex<Option<(Instant, Headroom)>>>,
+    sampling: Arc<AtomicBool>,
+}
+
+impl Headroom {
+    /// 读取当前剩余的系统资源
+    pub fn sample() -> Self {
+        Self {
+            available_memory: available_memory(),
+            free_fds: free_fds(),
+        }
+    }
+}
+
+impl ResourceGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 可用内存(字节)的下限, None 表示不检查
+    pub fn min_available_memory(mut self, bytes: Option<u64>) -> Self {
+        self.min_available_memory = bytes.filter(|bytes| *bytes > 0);
+        self
+    }
+
+    /// 剩余文件描述符数量的下限, None 表示不检查
+    pub fn min_free_fds(mut self, fds: Option<u64>) -> Self {
+        self.min_free_fds = fds.filter(|fds| *fds > 0);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min_available_memory.is_none() && self.min_free_fds.is_none()
+    }
+
+    /// 资源低于下限时返回原因, 无法获取的资源不限制. 使用最近一次的采样,
+    /// 第一次采样完成之前不限制
+    pub fn check(&self) -> Option<LowResource> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let headroom = self.headroom();
+
+        match (headroom.available_memory, self.min_available_memory) {
+            (Some(available), Some(min)) if available < min => {
+                return Some(LowResource::Memory(available))
+            }
+            _ => {}
+        }
+
+        match (headroom.free_fds, self.min_free_fds) {
+            (Some(free), Some(min)) if free < min => Some(LowResource::Fds(free)),
+            _ => None,
+        }
+    }
+
+    /// 采样过期时在后台重新采样, 本次仍然使用上一次的结果
+    fn headroom(&self) -> Headroom {
+        let sampled = match self.sampled.lock() {
+            Ok(sampled) => *sampled,
+            Err(_) => return Headroom::default(),
+        };
+
+        match sampled {
+            Some((at, headroom)) if at.elapsed() < SAMPLE_INTERVAL => headroom,
+            stale => {
+                self.resample();
+                stale.map(|(_, headroom)| headroom).unwrap_or_default()
+            }
+        }
+    }
+
+    fn resample(&self) {
+        // 同一时间只有一个采样线程
+        if self.sampling.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let sampled = self.sampled.clone();
+        let sampling = self.sampling.clone();
+
+        let spawned = std::thread::Builder::new()
+            .name(String::from("fuso-resource"))
+            .spawn(move || {
+                let headroom = Headroom::sample();
+
+                metrics().on_headroom(headroom);
+
+                if let Ok(mut sampled) = sampled.lock() {
+                    *sampled = Some((Instant::now(), headroom));
+                }
+
+                sampling.store(false, Ordering::Release);
+            });
+
+        if let Err(e) = spawned {
+            log::warn!("failed to sample resources {}", e);
+            self.sampling.store(false, Ordering::Release);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+
+    meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))
+        .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+        .map(|kb| kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn free_fds() -> Option<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return None;
+    }
+
+    if limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+
+    // 读取目录本身占用一个描述符, 不影响判断, 描述符已经用完时无法打开目录
+    let opened = match std::fs::read_dir("/proc/self/fd") {
+        Ok(fds) => fds.count() as _,
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE)) => return Some(0),
+        Err(_) => return None,
+    };
+
+    Some(limit.rlim_cur.saturating_sub(opened) as _)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn free_fds() -> Option<u64> {
+    None
+}
+
+impl std::fmt::Display for LowResource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LowResource::Memory(available) => {
+                write!(f, "low on memory, {} bytes available", available)
+            }
+            LowResource::Fds(free) => write!(f, "low on file descriptors, {} free", free),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Headroom, LowResource, ResourceGuard, SAMPLE_INTERVAL};
+
+    fn sampled(guard: &ResourceGuard, available_memory: Option<u64>, free_fds: Option<u64>) {
+        let headroom = Headroom {
+            available_memory,
+            free_fds,
+        };
+
+        *guard.sampled.lock().unwrap() = Some((Instant::now(), headroom));
+    }
+
+    #[test]
+    fn test_resource_guard() {
+        let guard = ResourceGuard::new()
+            .min_available_memory(Some(64 * 1024 * 1024))
+            .min_free_fds(Some(128));
+
+        sampled(&guard, Some(1024 * 1024 * 1024), Some(1000));
+        assert!(guard.check().is_none());
+
+        sampled(&guard, Some(1024), Some(1000));
+        assert_eq!(guard.check(), Some(LowResource::Memory(1024)));
+
+        sampled(&guard, Some(1024 * 1024 * 1024), Some(10));
+        assert_eq!(guard.check(), Some(LowResource::Fds(10)));
+
+        // 无法获取的资源不限制
+        sampled(&guard, None, None);
+        assert!(guard.check().is_none());
+
+        assert!(ResourceGuard::new().is_empty());
+        assert!(ResourceGuard::new().min_free_fds(Some(0)).is_empty());
+
+        #[cfg(target_os = "linux")]
+        {
+            let headroom = Headroom::sample();
+            assert!(headroom.available_memory.is_some_and(|bytes| bytes > 0));
+        }
+    }
+
+    #[test]
+    fn test_resource_guard_resample() {
+        let guard = ResourceGuard::new().min_free_fds(Some(128));
+
+        // 过期的采样仍然生效, 同时在后台重新采样
+        let stale = Instant::now() - SAMPLE_INTERVAL * 2;
+        let headroom = Headroom {
+            available_memory: None,
+            free_fds: Some(10),
+        };
+
+        *guard.sampled.lock().unwrap() = Some((stale, headroom));
+        assert_eq!(guard.check(), Some(LowResource::Fds(10)));
+
+        let started = Instant::now();
+
+        while guard
+            .sampled
+            .lock()
+            .unwrap()
+            .is_some_and(|(at, _)| at == stale)
+        {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
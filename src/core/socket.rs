@@ -1,6 +1,6 @@
 use std::{
     fmt::{Debug, Display},
-    net::{IpAddr, SocketAddr},
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
     ops::{Add, Deref, DerefMut},
     str::FromStr,
 };
@@ -38,6 +38,8 @@ pub enum Address {
 pub enum InnerAddr {
     Socket(SocketAddr),
     Domain(String, u16),
+    /// unix域套接字路径, 只能作为转发目标
+    Unix(String),
 }
 
 #[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -51,6 +53,8 @@ pub enum SocketKind {
     Quic,
     /// udp forward
     Ufd,
+    /// socks5 bind, 在客户端监听并接受一个连接
+    Bind,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
@@ -65,6 +69,7 @@ impl_socket!(kcp, is_kcp, Kcp);
 impl_socket!(tcp, is_tcp, Tcp);
 impl_socket!(quic, is_quic, Quic);
 impl_socket!(ufd, is_ufd, Ufd);
+impl_socket!(bind, is_bind, Bind);
 
 impl From<SocketAddr> for Addr {
     fn from(addr: SocketAddr) -> Self {
@@ -85,8 +90,11 @@ impl From<([u8; 16], u16)> for Addr {
 }
 
 impl From<(String, u16)> for Addr {
-    fn from(addr: (String, u16)) -> Self {
-        Self(InnerAddr::Domain(addr.0, addr.1))
+    fn from((host, port): (String, u16)) -> Self {
+        match parse_ip_host(&host, port) {
+            Some(addr) => Self(InnerAddr::Socket(addr)),
+            None => Self(InnerAddr::Domain(host, port)),
+        }
     }
 }
 
@@ -127,23 +135,60 @@ impl FromStr for Addr {
         match s.parse::<SocketAddr>() {
             Ok(socket) => Ok(socket.into()),
             Err(e) => {
-                let index = s
-                    .find(":")
+                let (host, port) = s
+                    .rsplit_once(":")
                     .ok_or_else(|| Error::from(InvalidAddr::Socket(e)))?;
 
-                let (host, port) = s.split_at(index + 1);
                 let port = port
                     .parse::<u16>()
                     .map_err(|_| Error::from(InvalidAddr::Domain(format!("{}", s))))?;
 
                 log::debug!("{}:{}", host, port);
 
-                Ok((host.replace(":", ""), port).into())
+                Ok((host.to_owned(), port).into())
             }
         }
     }
 }
 
+/// 支持 `::1`, `[::1]`, `fe80::1%2`, `[fe80::1%eth0]` 等形式的ip地址
+fn parse_ip_host(host: &str, port: u16) -> Option<SocketAddr> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+
+    match host.split_once('%') {
+        None => host
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, port)),
+        Some((ip, scope)) => {
+            let ip = ip.parse::<Ipv6Addr>().ok()?;
+            let scope_id = scope
+                .parse::<u32>()
+                .ok()
+                .or_else(|| interface_index(scope))?;
+
+            Some(SocketAddrV6::new(ip, port, 0, scope_id).into())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_: &str) -> Option<u32> {
+    None
+}
+
 impl Debug for Addr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self)
@@ -161,6 +206,7 @@ impl Display for Addr {
                 }
             }
             InnerAddr::Domain(domain, port) => format!("{}:{}", domain, port),
+            InnerAddr::Unix(path) => format!("unix:{}", path),
         };
         write!(f, "{}", fmt)
     }
@@ -193,6 +239,7 @@ impl Display for SocketKind {
             SocketKind::Tcp => "TCP",
             SocketKind::Quic => "QUIC",
             SocketKind::Ufd => "UFD",
+            SocketKind::Bind => "BIND",
         };
 
         write!(f, "{}", fmt)
@@ -208,6 +255,7 @@ impl Display for SocketKind {
             SocketKind::Tcp => "T",
             SocketKind::Quic => "Q",
             SocketKind::Ufd => "F",
+            SocketKind::Bind => "B",
         };
 
         write!(f, "{}", fmt)
@@ -253,52 +301,70 @@ impl Display for Socket {
 }
 
 impl Addr {
+    pub fn unix<P: Into<String>>(path: P) -> Self {
+        Self(InnerAddr::Unix(path.into()))
+    }
+
     pub fn as_string(&self) -> String {
         match &self.0 {
             InnerAddr::Socket(addr) => format!("{}", addr),
             InnerAddr::Domain(host, port) => format!("{}:{}", host, port),
+            InnerAddr::Unix(path) => path.clone(),
         }
     }
 
     pub fn is_ip(&self) -> bool {
         match &self.0 {
             InnerAddr::Socket(_) => true,
-            InnerAddr::Domain(_, _) => false,
+            InnerAddr::Domain(_, _) | InnerAddr::Unix(_) => false,
         }
     }
 
     pub fn is_domain(&self) -> bool {
         match self.0 {
-            InnerAddr::Socket(_) => false,
+            InnerAddr::Socket(_) | InnerAddr::Unix(_) => false,
             InnerAddr::Domain(_, _) => true,
         }
     }
 
+    pub fn is_unix(&self) -> bool {
+        matches!(self.0, InnerAddr::Unix(_))
+    }
+
     pub fn ip(&self) -> Option<IpAddr> {
         match &self.0 {
             InnerAddr::Socket(addr) => Some(addr.ip()),
-            InnerAddr::Domain(_, _) => None,
+            InnerAddr::Domain(_, _) | InnerAddr::Unix(_) => None,
         }
     }
 
     pub fn domain(&self) -> Option<&str> {
         match &self.0 {
-            InnerAddr::Socket(_) => None,
+            InnerAddr::Socket(_) | InnerAddr::Unix(_) => None,
             InnerAddr::Domain(domain, _) => Some(domain),
         }
     }
 
+    pub fn unix_path(&self) -> Option<&str> {
+        match &self.0 {
+            InnerAddr::Unix(path) => Some(path),
+            _ => None,
+        }
+    }
+
     pub fn is_ip_unspecified(&self) -> bool {
         match self.0 {
-            InnerAddr::Domain(_, _) => false,
+            InnerAddr::Domain(_, _) | InnerAddr::Unix(_) => false,
             InnerAddr::Socket(socket) => socket.ip().is_unspecified(),
         }
     }
 
+    /// unix域套接字没有端口, 返回0
     pub fn port(&self) -> u16 {
         match &self.0 {
             InnerAddr::Socket(addr) => addr.port(),
             InnerAddr::Domain(_, port) => *port,
+            InnerAddr::Unix(_) => 0,
         }
     }
 
@@ -326,12 +392,13 @@ impl Addr {
             InnerAddr::Domain(_, old_port) => {
                 *old_port = new_port;
             }
+            InnerAddr::Unix(_) => {}
         }
     }
 
     pub fn is_default(&self) -> bool {
         match &self.0 {
-            InnerAddr::Domain(_, _) => false,
+            InnerAddr::Domain(_, _) | InnerAddr::Unix(_) => false,
             InnerAddr::Socket(addr) => addr.port() == 0 && addr.ip().is_unspecified(),
         }
     }
@@ -475,7 +542,7 @@ impl Address {
                 let addrs = addrs.into_iter().filter(|addr| match socket.kind() {
                     SocketKind::Kcp => addr.is_kcp(),
                     SocketKind::Udp => addr.is_udp(),
-                    SocketKind::Tcp => addr.is_tcp(),
+                    SocketKind::Tcp | SocketKind::Bind => addr.is_tcp(),
                     SocketKind::Quic => addr.is_quic(),
                     SocketKind::Ufd => {
                         addr.is_kcp()
@@ -517,3 +584,54 @@ impl Add for Address {
         Self::Many(address)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+
+    use super::Addr;
+
+    #[test]
+    fn test_ipv6_addr() {
+        let v6 = |ip: &str, port, scope_id| {
+            SocketAddr::from(SocketAddrV6::new(
+                ip.parse::<Ipv6Addr>().unwrap(),
+                port,
+                0,
+                scope_id,
+            ))
+        };
+
+        let addr = Addr::from((String::from("::1"), 6722));
+        assert_eq!(addr, Addr::from(v6("::1", 6722, 0)));
+        assert_eq!(addr.as_string(), "[::1]:6722");
+
+        let addr = Addr::from((String::from("[::]"), 6722));
+        assert!(addr.is_ip_unspecified());
+
+        let addr = Addr::from((String::from("fe80::1%3"), 80));
+        assert_eq!(addr, Addr::from(v6("fe80::1", 80, 3)));
+
+        let addr = "[fe80::1%3]:80".parse::<Addr>().unwrap();
+        assert_eq!(addr, Addr::from(v6("fe80::1", 80, 3)));
+
+        let addr = "example.com:80".parse::<Addr>().unwrap();
+        assert_eq!(addr.domain(), Some("example.com"));
+        assert_eq!(addr.port(), 80);
+    }
+
+    #[test]
+    fn test_unix_addr() {
+        let mut addr = Addr::unix("/run/fuso.sock");
+        addr.set_port(80);
+
+        assert!(addr.is_unix() && !addr.is_domain() && !addr.is_ip());
+        assert_eq!(addr.unix_path(), Some("/run/fuso.sock"));
+        assert_eq!(addr.as_string(), "/run/fuso.sock");
+        assert_eq!(addr.to_string(), "unix:/run/fuso.sock");
+        assert_eq!(addr.port(), 0);
+
+        let bytes = bincode::serialize(&addr).unwrap();
+        assert_eq!(bincode::deserialize::<Addr>(&bytes).unwrap(), addr);
+    }
+}
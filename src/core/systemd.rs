@@ -0,0 +1,163 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Mutex,
+};
+
+/// systemd传递的第一个文件描述符, 之后依次递增
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// 还没有被服务使用的监听
+static INHERITED: Mutex<Vec<TcpListener>> = Mutex::new(Vec::new());
+
+/// 取得systemd socket activation传递的tcp监听, 不是由systemd启动或没有传递时返回空, 只应调用一次.
+/// 运行时的其他线程可能同时读取环境变量, 不清除 `LISTEN_PID` 与 `LISTEN_FDS`,
+/// 子进程的pid与 `LISTEN_PID` 不同, 不会再次使用
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid = std::env::var("LISTEN_PID").ok();
+    let fds = std::env::var("LISTEN_FDS").ok();
+
+    // 传递给其他进程的描述符不属于当前进程
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Vec::new();
+    }
+
+    let fds = fds.and_then(|fds| fds.parse::<i32>().ok()).unwrap_or(0);
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+        .filter(|fd| {
+            let stream = is_stream_socket(*fd);
+
+            if !stream {
+                log::warn!("fd {} passed by systemd is not a tcp socket, ignore", fd);
+            }
+
+            stream
+        })
+        .filter_map(|fd| {
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+            match listener.set_nonblocking(true) {
+                Ok(()) => Some(listener),
+                Err(e) => {
+                    log::warn!("failed to use fd {} passed by systemd {}", fd, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+#[cfg(unix)]
+fn is_stream_socket(fd: i32) -> bool {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    ret == 0 && kind == libc::SOCK_STREAM
+}
+
+/// 交给服务使用, 监听相同地址时使用继承的监听而不是重新绑定
+pub fn adopt(listeners: Vec<TcpListener>) {
+    if let Ok(mut inherited) = INHERITED.lock() {
+        inherited.extend(listeners);
+    }
+}
+
+/// 取出监听地址为 `addr` 的继承的监听
+pub fn take(addr: SocketAddr) -> Option<TcpListener> {
+    let mut inherited = INHERITED.lock().ok()?;
+
+    let index = inherited
+        .iter()
+        .position(|listener| listener.local_addr().ok() == Some(addr))?;
+
+    Some(inherited.remove(index))
+}
+
+/// 向systemd报告状态, 如 `READY=1`, 不是由systemd以 `Type=notify` 启动时忽略
+#[cfg(unix)]
+pub fn notify(state: &str) -> std::io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send(&path, state),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+
+    // `@` 开头的为抽象命名空间
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{net::TcpListener, os::unix::net::UnixDatagram};
+
+    #[test]
+    fn test_adopt_listener() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        super::adopt(vec![listener]);
+
+        assert!(super::take("127.0.0.1:1".parse().unwrap()).is_none());
+        assert_eq!(super::take(addr).unwrap().local_addr().unwrap(), addr);
+        assert!(super::take(addr).is_none());
+    }
+
+    #[test]
+    fn test_notify() {
+        let path = std::env::temp_dir().join(format!("fuso-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        super::send(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buf = [0u8; 16];
+        let n = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
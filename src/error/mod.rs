@@ -1,6 +1,6 @@
 use std::fmt::Display;
 
-use crate::{kcp, Socket, SocketKind};
+use crate::{kcp, protocol::ErrorCode, Socket, SocketKind};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -37,9 +37,11 @@ pub enum SocksErr {
     InvalidAddress,
     BindNotSupport,
     Socks5Frg,
+    Denied,
     Head { ver: u8, nmethod: u8 },
     Method(u8),
     BadLength { expect: usize, current: usize },
+    Reply(u8),
 }
 
 #[derive(Debug)]
@@ -53,6 +55,8 @@ pub enum Lz4Err {
 #[derive(Debug)]
 pub enum CompressErr {
     Lz4(Lz4Err),
+    Unknown(String),
+    Negotiate(String),
 }
 
 #[derive(Debug)]
@@ -76,6 +80,14 @@ pub enum EncryptionErr {
     Rsa(rsa::errors::Error),
     RsaPkcs7(rsa::pkcs1::Error),
     RsaSpki(rsa::pkcs8::spki::Error),
+    UnknownCipher(String),
+    Negotiate(String),
+}
+
+#[derive(Debug)]
+pub enum WebSocketErr {
+    Handshake(String),
+    Frame(&'static str),
 }
 
 #[derive(Debug)]
@@ -83,6 +95,15 @@ pub enum MixErr {
     Unsupported(Socket),
 }
 
+/// 启动服务时的错误, 携带出错的地址或选项
+#[derive(Debug)]
+pub enum BuildErr {
+    /// 监听失败的地址与系统返回的错误
+    Bind(Socket, std::io::Error),
+    /// 无效的选项名与原因
+    InvalidOption(&'static str, String),
+}
+
 #[derive(Debug)]
 pub enum Kind {
     Channel,
@@ -115,6 +136,11 @@ pub enum Kind {
     Improper(Socket),
     Text(String),
     MaxRetries(usize),
+    WebSocket(WebSocketErr),
+    Tls(String),
+    /// 请求被拒绝, 携带发送给对端或从对端收到的错误码
+    Refused(ErrorCode, Option<String>),
+    Build(BuildErr),
 }
 
 impl Display for SyncErr {
@@ -167,6 +193,7 @@ impl Display for SocksErr {
                 SocksErr::InvalidAddress => format!("invalid address"),
                 SocksErr::BindNotSupport => format!("bind not support"),
                 SocksErr::Socks5Frg => format!("fragment not support"),
+                SocksErr::Denied => String::from("target not allowed"),
                 SocksErr::Head { ver, nmethod } => {
                     format!("invalid socks5 head ver={}, nmethod={}", ver, nmethod)
                 }
@@ -174,6 +201,20 @@ impl Display for SocksErr {
                 SocksErr::BadLength { expect, current } => {
                     format!("bad socks5 packet expect {} , current={}", expect, current)
                 }
+                SocksErr::Reply(rep) => format!(
+                    "upstream socks5 replied {}",
+                    match rep {
+                        0x01 => "general failure",
+                        0x02 => "connection not allowed by ruleset",
+                        0x03 => "network unreachable",
+                        0x04 => "host unreachable",
+                        0x05 => "connection refused",
+                        0x06 => "ttl expired",
+                        0x07 => "command not supported",
+                        0x08 => "address type not supported",
+                        _ => "an unknown error",
+                    }
+                ),
             }
         })
     }
@@ -245,6 +286,26 @@ impl Display for AesErr {
     }
 }
 
+impl Display for WebSocketErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketErr::Handshake(e) => write!(f, "websocket handshake failed: {}", e),
+            WebSocketErr::Frame(e) => write!(f, "bad websocket frame: {}", e),
+        }
+    }
+}
+
+impl Display for BuildErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildErr::Bind(socket, e) => write!(f, "failed to bind {}: {}", socket, e),
+            BuildErr::InvalidOption(option, reason) => {
+                write!(f, "invalid option `{}`: {}", option, reason)
+            }
+        }
+    }
+}
+
 impl Display for EncryptionErr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", {
@@ -253,6 +314,8 @@ impl Display for EncryptionErr {
                 EncryptionErr::Rsa(e) => format!("{}", e),
                 EncryptionErr::RsaPkcs7(e) => format!("{}", e),
                 EncryptionErr::RsaSpki(e) => format!("{}", e),
+                EncryptionErr::UnknownCipher(e) => format!("unknown cipher {}", e),
+                EncryptionErr::Negotiate(e) => format!("cipher negotiation failed: {}", e),
             }
         })
     }
@@ -290,6 +353,11 @@ impl Display for Error {
             Kind::Improper(e) => format!("no suitable ones {}", e),
             Kind::Text(txt) => format!("{}", txt),
             Kind::MaxRetries(retry) => format!("exceeded maximum number of attempts {}", retry),
+            Kind::WebSocket(e) => format!("{}", e),
+            Kind::Tls(e) => format!("tls: {}", e),
+            Kind::Refused(code, None) => format!("{}", code),
+            Kind::Refused(code, Some(detail)) => format!("{}: {}", code, detail),
+            Kind::Build(e) => format!("{}", e),
         };
         write!(f, "{}", fmt)
     }
@@ -321,6 +389,24 @@ impl From<rsa::errors::Error> for Error {
     }
 }
 
+impl From<CompressErr> for Error {
+    fn from(e: CompressErr) -> Self {
+        Kind::Compress(e).into()
+    }
+}
+
+impl From<EncryptionErr> for Error {
+    fn from(e: EncryptionErr) -> Self {
+        Kind::Encryption(e).into()
+    }
+}
+
+impl From<BuildErr> for Error {
+    fn from(e: BuildErr) -> Self {
+        Kind::Build(e).into()
+    }
+}
+
 impl From<SyncErr> for Error {
     fn from(e: SyncErr) -> Self {
         Self {
@@ -329,6 +415,12 @@ impl From<SyncErr> for Error {
     }
 }
 
+impl From<WebSocketErr> for Error {
+    fn from(e: WebSocketErr) -> Self {
+        Kind::WebSocket(e).into()
+    }
+}
+
 impl From<SocksErr> for Error {
     fn from(e: SocksErr) -> Self {
         Self {
@@ -393,12 +485,25 @@ impl From<std::cell::BorrowMutError> for Error {
     }
 }
 
+impl From<std::cell::BorrowError> for Error {
+    fn from(_: std::cell::BorrowError) -> Self {
+        SyncErr::Mutex.into()
+    }
+}
+
 impl From<bincode::Error> for Error {
     fn from(e: bincode::Error) -> Self {
         Kind::Deserialize(e.to_string()).into()
     }
 }
 
+#[cfg(feature = "fuso-toml")]
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Kind::Deserialize(e.to_string()).into()
+    }
+}
+
 #[cfg(feature = "fuso-rt-tokio")]
 impl From<tokio::time::error::Elapsed> for Error {
     fn from(e: tokio::time::error::Elapsed) -> Self {
@@ -464,4 +569,21 @@ impl Error {
             _ => false,
         }
     }
+
+    pub fn is_addr_in_use(&self) -> bool {
+        match &self.kind {
+            Kind::IO(e) | Kind::Build(BuildErr::Bind(_, e)) => {
+                e.kind() == std::io::ErrorKind::AddrInUse
+            }
+            _ => false,
+        }
+    }
+
+    /// 监听失败时带上监听的地址, 其他错误不变
+    pub fn with_bind_addr(self, socket: &Socket) -> Self {
+        match self.kind {
+            Kind::IO(e) => BuildErr::Bind(socket.clone(), e).into(),
+            kind => kind.into(),
+        }
+    }
 }
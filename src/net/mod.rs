@@ -9,9 +9,21 @@ pub mod quic;
 #[cfg(feature = "fuso-socks5")]
 pub mod socks;
 
+#[cfg(feature = "fuso-websocket")]
+pub mod ws;
+
+#[cfg(feature = "fuso-tls")]
+pub mod tls;
+
 pub mod tun;
 
+pub mod resolver;
+
+pub mod proxy_protocol;
+
 #[cfg(feature = "fuso-proxy")]
 pub mod proxy;
 
 pub mod penetrate;
+
+pub mod mux;
@@ -0,0 +1,658 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use crate::{error::Kind, Address, AsyncRead, AsyncWrite, NetSocket, ReadBuf, Stream};
+
+// frame: | cmd: u8 | conv: u32 | len: u16 | data: [u8; len] |
+
+const CMD_OPEN: u8 = 0x00;
+const CMD_DATA: u8 = 0x01;
+const CMD_FIN: u8 = 0x02;
+/// 接收方已经读取的字节数, data为u32
+const CMD_WINDOW: u8 = 0x03;
+const CMD_RESET: u8 = 0x04;
+
+const HEAD_LEN: usize = 7;
+const MAX_DATA_LEN: usize = 16 * 1024;
+
+/// 每个逻辑连接未被读取的数据上限, 读取超过一半后通知对端
+const WINDOW: u32 = 256 * 1024;
+
+/// 单次写入底层连接的数据上限
+const MAX_WRITE_LEN: usize = 64 * 1024;
+
+struct Conv {
+    recv: VecDeque<u8>,
+    recv_fin: bool,
+    send_fin: bool,
+    reset: bool,
+    send_window: u32,
+    consumed: u32,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+struct Inner {
+    convs: HashMap<u32, Conv>,
+    outgoing: VecDeque<Vec<u8>>,
+    driver: Option<Waker>,
+    closed: bool,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    peer_addr: Address,
+    local_addr: Address,
+}
+
+/// 在一个连接上承载多个逻辑连接, 每个逻辑连接由conv区分, 并有独立的流量控制,
+/// 底层连接的读写由 `MuxDriver` 完成, 需要一直轮询
+#[derive(Clone)]
+pub struct Mux {
+    shared: Arc<Shared>,
+    accepter: async_channel::Receiver<MuxStream>,
+}
+
+pub struct MuxStream {
+    conv: u32,
+    shared: Arc<Shared>,
+}
+
+pub struct MuxDriver<S> {
+    stream: S,
+    shared: Arc<Shared>,
+    accepter: async_channel::Sender<MuxStream>,
+    rbuf: Vec<u8>,
+    rlen: usize,
+    wbuf: Vec<u8>,
+    wpos: usize,
+    flushing: bool,
+}
+
+fn frame(cmd: u8, conv: u32, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEAD_LEN + data.len());
+    frame.push(cmd);
+    frame.extend_from_slice(&conv.to_be_bytes());
+    frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+impl Conv {
+    fn new() -> Self {
+        Self {
+            recv: VecDeque::new(),
+            recv_fin: false,
+            send_fin: false,
+            reset: false,
+            send_window: WINDOW,
+            consumed: 0,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+    }
+}
+
+impl Inner {
+    fn send(&mut self, frame: Vec<u8>) {
+        self.outgoing.push_back(frame);
+        wake(&mut self.driver);
+    }
+
+    fn close(&mut self) {
+        self.closed = true;
+        self.outgoing.clear();
+        self.convs.values_mut().for_each(Conv::wake);
+    }
+}
+
+impl Mux {
+    pub fn new<S>(stream: S) -> crate::Result<(Self, MuxDriver<S>)>
+    where
+        S: Stream,
+    {
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(Inner {
+                convs: HashMap::new(),
+                outgoing: VecDeque::new(),
+                driver: None,
+                closed: false,
+            }),
+            peer_addr: stream.peer_addr()?,
+            local_addr: stream.local_addr()?,
+        });
+
+        let (accept_tx, accept_ax) = async_channel::unbounded();
+
+        let mux = Self {
+            shared: shared.clone(),
+            accepter: accept_ax,
+        };
+
+        let driver = MuxDriver {
+            stream,
+            shared,
+            accepter: accept_tx,
+            rbuf: vec![0; HEAD_LEN + MAX_DATA_LEN],
+            rlen: 0,
+            wbuf: Vec::new(),
+            wpos: 0,
+            flushing: false,
+        };
+
+        Ok((mux, driver))
+    }
+
+    /// 打开一个逻辑连接, conv由调用方保证唯一
+    pub fn open(&self, conv: u32) -> crate::Result<MuxStream> {
+        let mut inner = self.shared.inner.lock()?;
+
+        if inner.closed {
+            return Err(Kind::Channel.into());
+        }
+
+        if inner.convs.contains_key(&conv) {
+            return Err(Kind::Unexpected(format!("mux conv {} already exists", conv)).into());
+        }
+
+        inner.convs.insert(conv, Conv::new());
+        inner.send(frame(CMD_OPEN, conv, &[]));
+
+        Ok(MuxStream {
+            conv,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// 等待对端打开的逻辑连接
+    pub async fn accept(&self) -> crate::Result<MuxStream> {
+        Ok(self.accepter.recv().await?)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.inner.lock().map_or(true, |inner| inner.closed)
+    }
+}
+
+impl MuxStream {
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+}
+
+impl<S> MuxDriver<S>
+where
+    S: Stream,
+{
+    fn on_frame(&mut self, cmd: u8, conv: u32, data: &[u8]) -> crate::Result<()> {
+        let mut inner = self.shared.inner.lock()?;
+
+        if cmd == CMD_OPEN {
+            if inner.convs.contains_key(&conv) {
+                log::warn!(conv = conv; "mux conv already exists, ignore");
+                return Ok(());
+            }
+
+            inner.convs.insert(conv, Conv::new());
+
+            drop(inner);
+
+            let stream = MuxStream {
+                conv,
+                shared: self.shared.clone(),
+            };
+
+            // 接收方已经关闭时由 `MuxStream` 的drop通知对端
+            let _ = self.accepter.try_send(stream);
+
+            return Ok(());
+        }
+
+        let target = match inner.convs.get_mut(&conv) {
+            Some(target) => target,
+            None => {
+                // 本端已经关闭的逻辑连接, 只回复数据帧避免双方互相发送
+                if cmd == CMD_DATA {
+                    inner.send(frame(CMD_RESET, conv, &[]));
+                }
+
+                return Ok(());
+            }
+        };
+
+        match cmd {
+            // 已经重置的逻辑连接不再接收数据
+            CMD_DATA if target.reset => {}
+            // 对端发送的数据超过了接收窗口, 重置该逻辑连接而不是继续缓存
+            CMD_DATA
+                if target.recv.len() + target.consumed as usize + data.len() > WINDOW as usize =>
+            {
+                log::warn!(conv = conv; "mux peer exceeded the receive window, reset");
+                target.reset = true;
+                target.wake();
+                inner.send(frame(CMD_RESET, conv, &[]));
+            }
+            CMD_DATA => {
+                target.recv.extend(data);
+                wake(&mut target.read_waker);
+            }
+            CMD_FIN => {
+                target.recv_fin = true;
+                wake(&mut target.read_waker);
+            }
+            CMD_WINDOW if data.len() == 4 => {
+                let n = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                target.send_window = target.send_window.saturating_add(n);
+                wake(&mut target.write_waker);
+            }
+            CMD_RESET => {
+                target.reset = true;
+                target.wake();
+            }
+            cmd => {
+                return Err(Kind::Unexpected(format!("invalid mux frame cmd={}", cmd)).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 读取并处理完整的帧, 返回读取的字节数, 为0时对端已经关闭
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<crate::Result<usize>> {
+        let mut buf = ReadBuf::new(&mut self.rbuf[self.rlen..]);
+
+        let n = match Pin::new(&mut self.stream).poll_read(cx, &mut buf)? {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(0) => return Poll::Ready(Ok(0)),
+            Poll::Ready(n) => n,
+        };
+
+        self.rlen += n;
+
+        let mut offset = 0;
+
+        while self.rlen - offset >= HEAD_LEN {
+            let head = &self.rbuf[offset..offset + HEAD_LEN];
+            let cmd = head[0];
+            let conv = u32::from_be_bytes([head[1], head[2], head[3], head[4]]);
+            let len = u16::from_be_bytes([head[5], head[6]]) as usize;
+
+            if len > MAX_DATA_LEN {
+                return Poll::Ready(Err(Kind::Unexpected(format!(
+                    "mux frame too large len={}",
+                    len
+                ))
+                .into()));
+            }
+
+            if self.rlen - offset < HEAD_LEN + len {
+                break;
+            }
+
+            let data = self.rbuf[offset + HEAD_LEN..offset + HEAD_LEN + len].to_vec();
+
+            self.on_frame(cmd, conv, &data)?;
+
+            offset += HEAD_LEN + len;
+        }
+
+        self.rbuf.copy_within(offset..self.rlen, 0);
+        self.rlen -= offset;
+
+        Poll::Ready(Ok(n))
+    }
+
+    /// 写入待发送的帧, 没有可发送的数据或底层连接无法写入时返回Pending
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        if self.wpos == self.wbuf.len() {
+            self.wbuf.clear();
+            self.wpos = 0;
+
+            let mut inner = self.shared.inner.lock()?;
+
+            while self.wbuf.len() < MAX_WRITE_LEN {
+                match inner.outgoing.pop_front() {
+                    Some(frame) => self.wbuf.extend(frame),
+                    None => break,
+                }
+            }
+        }
+
+        if self.wbuf.is_empty() {
+            if self.flushing && Pin::new(&mut self.stream).poll_flush(cx)?.is_ready() {
+                self.flushing = false;
+            }
+
+            return Poll::Pending;
+        }
+
+        match Pin::new(&mut self.stream).poll_write(cx, &self.wbuf[self.wpos..])? {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(0) => Poll::Ready(Err(Kind::Channel.into())),
+            Poll::Ready(n) => {
+                self.wpos += n;
+                self.flushing = true;
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+impl<S> Future for MuxDriver<S>
+where
+    S: Stream,
+{
+    type Output = crate::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+
+        this.shared.inner.lock()?.driver = Some(cx.waker().clone());
+
+        loop {
+            let received = match this.poll_recv(cx)? {
+                Poll::Ready(0) => return Poll::Ready(Ok(())),
+                Poll::Ready(_) => true,
+                Poll::Pending => false,
+            };
+
+            let sent = this.poll_send(cx)?.is_ready();
+
+            if !received && !sent {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl<S> Drop for MuxDriver<S> {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.shared.inner.lock() {
+            inner.close();
+        }
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        let mut inner = self.shared.inner.lock()?;
+        let closed = inner.closed;
+
+        let target = match inner.convs.get_mut(&self.conv) {
+            Some(target) => target,
+            None => return Poll::Ready(Err(Kind::Channel.into())),
+        };
+
+        if !target.recv.is_empty() {
+            let unfilled = buf.initialize_unfilled();
+            let n = unfilled.len().min(target.recv.len());
+
+            for (dst, src) in unfilled.iter_mut().zip(target.recv.drain(..n)) {
+                *dst = src;
+            }
+
+            buf.advance(n);
+
+            target.consumed += n as u32;
+
+            if target.consumed >= WINDOW / 2 && !target.recv_fin {
+                let consumed = std::mem::replace(&mut target.consumed, 0);
+                inner.send(frame(CMD_WINDOW, self.conv, &consumed.to_be_bytes()));
+            }
+
+            return Poll::Ready(Ok(n));
+        }
+
+        if target.reset {
+            Poll::Ready(Err(std::io::Error::from(
+                std::io::ErrorKind::ConnectionReset,
+            )
+            .into()))
+        } else if target.recv_fin {
+            Poll::Ready(Ok(0))
+        } else if closed {
+            Poll::Ready(Err(std::io::Error::from(
+                std::io::ErrorKind::ConnectionAborted,
+            )
+            .into()))
+        } else {
+            target.read_waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        let mut inner = self.shared.inner.lock()?;
+
+        if inner.closed {
+            return Poll::Ready(Err(std::io::Error::from(
+                std::io::ErrorKind::ConnectionAborted,
+            )
+            .into()));
+        }
+
+        let target = match inner.convs.get_mut(&self.conv) {
+            Some(target) => target,
+            None => return Poll::Ready(Err(Kind::Channel.into())),
+        };
+
+        if target.reset {
+            return Poll::Ready(Err(std::io::Error::from(
+                std::io::ErrorKind::ConnectionReset,
+            )
+            .into()));
+        }
+
+        if target.send_fin {
+            return Poll::Ready(Err(
+                std::io::Error::from(std::io::ErrorKind::BrokenPipe).into()
+            ));
+        }
+
+        if target.send_window == 0 {
+            target.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(MAX_DATA_LEN).min(target.send_window as usize);
+
+        target.send_window -= n as u32;
+
+        inner.send(frame(CMD_DATA, self.conv, &buf[..n]));
+
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        let mut inner = self.shared.inner.lock()?;
+
+        let sent = match inner.convs.get_mut(&self.conv) {
+            Some(target) => std::mem::replace(&mut target.send_fin, true),
+            None => true,
+        };
+
+        if !sent && !inner.closed {
+            inner.send(frame(CMD_FIN, self.conv, &[]));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl NetSocket for MuxStream {
+    fn peer_addr(&self) -> crate::Result<Address> {
+        Ok(self.shared.peer_addr.clone())
+    }
+
+    fn local_addr(&self) -> crate::Result<Address> {
+        Ok(self.shared.local_addr.clone())
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        let mut inner = match self.shared.inner.lock() {
+            Ok(inner) => inner,
+            Err(_) => return,
+        };
+
+        if let Some(target) = inner.convs.remove(&self.conv) {
+            // 双方都已经关闭写入时不需要通知对端
+            let finished = target.send_fin && target.recv_fin;
+
+            if !(inner.closed || target.reset || finished) {
+                log::debug!(conv = self.conv; "reset mux conv");
+                inner.send(frame(CMD_RESET, self.conv, &[]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame, Mux, MuxStream, CMD_DATA, CMD_OPEN, CMD_RESET, HEAD_LEN, MAX_DATA_LEN};
+    use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+    async fn read_to_end(stream: &mut MuxStream) -> crate::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buf = [0u8; 4096];
+
+        loop {
+            match stream.read(&mut buf).await? {
+                0 => break Ok(data),
+                n => data.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mux_streams() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let (s1, s2) = tokio::join!(tokio::net::TcpStream::connect(addr), async {
+                    listener.accept().await.map(|(s, _)| s)
+                });
+
+                let (client, driver) = Mux::new(s1.unwrap()).unwrap();
+                tokio::spawn(driver);
+                let (server, driver) = Mux::new(s2.unwrap()).unwrap();
+                tokio::spawn(driver);
+
+                // 超过窗口大小的数据需要对端读取后才能继续发送
+                let data = (0..super::WINDOW as usize * 3)
+                    .map(|i| i as u8)
+                    .collect::<Vec<u8>>();
+
+                let mut c1 = client.open(1).unwrap();
+                let mut c2 = client.open(2).unwrap();
+                assert!(client.open(2).is_err());
+
+                let writer = data.clone();
+                let sender = tokio::spawn(async move {
+                    c1.write_all(&writer).await.unwrap();
+                    c1.close().await.unwrap();
+                    c1
+                });
+
+                let mut s1 = server.accept().await.unwrap();
+                let mut s2 = server.accept().await.unwrap();
+                assert_eq!((s1.conv(), s2.conv()), (1, 2));
+
+                s2.write_all(b"fuso").await.unwrap();
+                let mut buf = [0u8; 4];
+                c2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"fuso");
+
+                assert_eq!(read_to_end(&mut s1).await.unwrap(), data);
+
+                let mut c1 = sender.await.unwrap();
+                s1.write_all(b"done").await.unwrap();
+                s1.close().await.unwrap();
+                assert_eq!(read_to_end(&mut c1).await.unwrap(), b"done");
+
+                // 一端drop后对端读写都返回错误
+                drop(s2);
+                assert!(c2.read(&mut buf).await.is_err());
+                assert!(c2.write_all(b"fuso").await.is_err());
+            });
+    }
+
+    #[test]
+    fn test_mux_recv_window() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let (s1, s2) = tokio::join!(tokio::net::TcpStream::connect(addr), async {
+                    listener.accept().await.map(|(s, _)| s)
+                });
+
+                let mut peer = s1.unwrap();
+                let (server, driver) = Mux::new(s2.unwrap()).unwrap();
+                tokio::spawn(driver);
+
+                // 对端不等待窗口更新, 发送超过窗口大小的数据
+                peer.write_all(&frame(CMD_OPEN, 1, &[])).await.unwrap();
+
+                let data = [0u8; MAX_DATA_LEN];
+                for _ in 0..super::WINDOW as usize / MAX_DATA_LEN + 1 {
+                    peer.write_all(&frame(CMD_DATA, 1, &data)).await.unwrap();
+                }
+
+                let mut head = [0u8; HEAD_LEN];
+                peer.read_exact(&mut head).await.unwrap();
+                assert_eq!(head[0], CMD_RESET);
+                assert_eq!(u32::from_be_bytes([head[1], head[2], head[3], head[4]]), 1);
+
+                // 窗口内的数据仍然可以读取, 之后返回错误
+                let mut s1 = server.accept().await.unwrap();
+                let mut received = 0;
+                let mut buf = [0u8; 4096];
+                let err = loop {
+                    match s1.read(&mut buf).await {
+                        Ok(n) => received += n,
+                        Err(e) => break e,
+                    }
+                };
+
+                assert_eq!(received, super::WINDOW as usize);
+                assert!(err.to_string().contains("reset"));
+            });
+    }
+}
@@ -1,14 +1,18 @@
 use std::{pin::Pin, task::Poll};
 
-use crate::{Accepter, Address, NetSocket, Stream};
+use crate::{Accepter, Address, NetSocket, Socket, Stream};
 
 pub enum Pen<S> {
-    Visit(S),
+    /// 访问同一个客户端的其他服务时带有该服务的转发目标
+    Visit(S, Option<Socket>),
     Client(S),
 }
 
+/// 访问者的监听关闭后仍然接受客户端建立的映射连接
 pub struct PenetrateAccepter<CA, SA> {
-    visit: SA,
+    visit: Option<SA>,
+    /// 其他服务的监听与转发目标, 与访问者的监听一起关闭
+    services: Vec<(SA, Socket)>,
     client: CA,
 }
 
@@ -18,7 +22,35 @@ where
     SA: Accepter<Stream = S> + Unpin + 'static,
 {
     pub fn new(visit: SA, client: CA) -> Self {
-        Self { visit, client }
+        Self {
+            visit: Some(visit),
+            services: Vec::new(),
+            client,
+        }
+    }
+
+    /// 其他服务的监听与访问者的连接转发目标
+    pub fn with_services(mut self, services: Vec<(SA, Socket)>) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// 访问者的监听已经关闭时返回None, 其他服务的监听随之关闭
+    pub fn into_inner(self) -> Option<(SA, CA)> {
+        Some((self.visit?, self.client))
+    }
+
+    /// 关闭访问者与其他服务的监听, 不再接受新的访问
+    pub fn close_visit(&mut self) {
+        drop(self.visit.take());
+        self.services.clear();
+    }
+
+    pub fn visit_port(&self) -> Option<u16> {
+        match self.visit.as_ref()?.local_addr().ok()? {
+            Address::One(addr) => Some(addr.port()),
+            Address::Many(addrs) => addrs.first().map(|addr| addr.port()),
+        }
     }
 }
 
@@ -28,11 +60,17 @@ where
     SA: NetSocket,
 {
     fn local_addr(&self) -> crate::Result<crate::Address> {
-        Ok(self.visit.local_addr()? + self.client.local_addr()?)
+        match self.visit.as_ref() {
+            Some(visit) => Ok(visit.local_addr()? + self.client.local_addr()?),
+            None => self.client.local_addr(),
+        }
     }
 
     fn peer_addr(&self) -> crate::Result<Address> {
-        Ok(self.visit.peer_addr()? + self.client.peer_addr()?)
+        match self.visit.as_ref() {
+            Some(visit) => Ok(visit.peer_addr()? + self.client.peer_addr()?),
+            None => self.client.peer_addr(),
+        }
     }
 }
 
@@ -51,9 +89,19 @@ where
         let mut poll_next = true;
 
         while poll_next {
-            match Pin::new(&mut self.visit).poll_accept(cx)? {
-                std::task::Poll::Ready(visit) => return Poll::Ready(Ok(Pen::Visit(visit))),
-                std::task::Poll::Pending => {}
+            if let Some(visit) = self.visit.as_mut() {
+                match Pin::new(visit).poll_accept(cx)? {
+                    std::task::Poll::Ready(visit) => {
+                        return Poll::Ready(Ok(Pen::Visit(visit, None)))
+                    }
+                    std::task::Poll::Pending => {}
+                }
+            }
+
+            for (service, target) in self.services.iter_mut() {
+                if let Poll::Ready(visit) = Pin::new(service).poll_accept(cx)? {
+                    return Poll::Ready(Ok(Pen::Visit(visit, Some(target.clone()))));
+                }
             }
 
             match Pin::new(&mut self.client).poll_accept(cx)? {
@@ -67,3 +115,44 @@ where
         return Poll::Pending;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Pen, PenetrateAccepter};
+    use crate::{AccepterExt, MemoryNetwork, Provider, Socket};
+
+    #[test]
+    fn test_services_close_with_visit() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let network = MemoryNetwork::default();
+                let bind = |port: u16| network.call(Socket::tcp(([127, 0, 0, 1], port)));
+
+                let visit = bind(8080).await.unwrap();
+                let client = bind(8081).await.unwrap();
+                let service = bind(8082).await.unwrap();
+
+                let target = Socket::tcp(([127, 0, 0, 1], 22));
+                let mut accepter = PenetrateAccepter::new(visit, client)
+                    .with_services(vec![(service, target.clone())]);
+
+                let service_socket = Socket::tcp(([127, 0, 0, 1], 8082));
+                let _visitor = network
+                    .connector()
+                    .call(service_socket.clone())
+                    .await
+                    .unwrap();
+
+                match accepter.accept().await.unwrap() {
+                    Pen::Visit(_, Some(dst)) => assert_eq!(dst, target),
+                    _ => panic!("expect a service visitor"),
+                }
+
+                accepter.close_visit();
+
+                assert!(accepter.visit_port().is_none());
+                assert!(network.connector().call(service_socket).await.is_err());
+            });
+    }
+}
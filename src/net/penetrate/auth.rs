@@ -0,0 +1,109 @@
+use std::{pin::Pin, sync::Arc};
+
+use crate::Address;
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+/// 认证结果, 允许时可以附带客户端的身份, 用于日志
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Allow(Option<String>),
+    Deny,
+}
+
+/// 控制通道的认证, 在客户端请求映射之前调用, 可以实现为查询http接口或数据库,
+/// `token` 为客户端提供的凭据, 客户端未提供时为None
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, peer_addr: Address, token: Option<String>) -> BoxedFuture<Verdict>;
+}
+
+/// 不校验, 允许所有客户端
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoAuth;
+
+/// 校验客户端提供的token与设置的一致
+#[derive(Debug, Clone)]
+pub struct TokenAuth(Arc<str>);
+
+/// 比较token, 耗时只与期望值的长度有关, 不会因为前缀相同而提前返回
+fn token_eq(token: &[u8], expect: &[u8]) -> bool {
+    let diff = expect
+        .iter()
+        .enumerate()
+        .fold(token.len() ^ expect.len(), |diff, (i, b)| {
+            diff | (b ^ token.get(i).copied().unwrap_or(0)) as usize
+        });
+
+    diff == 0
+}
+
+impl TokenAuth {
+    pub fn new<T: Into<String>>(token: T) -> Self {
+        Self(Arc::from(token.into()))
+    }
+}
+
+impl Authenticator for NoAuth {
+    fn authenticate(&self, _: Address, _: Option<String>) -> BoxedFuture<Verdict> {
+        Box::pin(async move { Ok(Verdict::Allow(None)) })
+    }
+}
+
+impl Authenticator for TokenAuth {
+    fn authenticate(&self, _: Address, token: Option<String>) -> BoxedFuture<Verdict> {
+        let authorized = token.is_some_and(|token| token_eq(token.as_bytes(), self.0.as_bytes()));
+
+        Box::pin(async move {
+            Ok(match authorized {
+                true => Verdict::Allow(None),
+                false => Verdict::Deny,
+            })
+        })
+    }
+}
+
+impl<T> Authenticator for Arc<T>
+where
+    T: Authenticator + ?Sized,
+{
+    fn authenticate(&self, peer_addr: Address, token: Option<String>) -> BoxedFuture<Verdict> {
+        (**self).authenticate(peer_addr, token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Authenticator, NoAuth, TokenAuth, Verdict};
+    use crate::{Address, Socket};
+
+    #[test]
+    fn test_token_eq() {
+        assert!(super::token_eq(b"secret", b"secret"));
+        assert!(!super::token_eq(b"secreT", b"secret"));
+        assert!(!super::token_eq(b"secret!", b"secret"));
+        assert!(!super::token_eq(b"secre", b"secret"));
+        assert!(!super::token_eq(b"", b"secret"));
+        assert!(super::token_eq(b"", b""));
+    }
+
+    #[test]
+    fn test_builtin_authenticators() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let peer = || Address::One(Socket::tcp(6722));
+                let token = |token: &str| Some(String::from(token));
+
+                let auth = TokenAuth::new("secret");
+                let verdict = auth.authenticate(peer(), token("secret")).await;
+                assert_eq!(verdict.unwrap(), Verdict::Allow(None));
+                let verdict = auth.authenticate(peer(), token("secreT")).await;
+                assert_eq!(verdict.unwrap(), Verdict::Deny);
+                let verdict = auth.authenticate(peer(), None).await;
+                assert_eq!(verdict.unwrap(), Verdict::Deny);
+
+                let verdict = NoAuth.authenticate(peer(), None).await;
+                assert_eq!(verdict.unwrap(), Verdict::Allow(None));
+            });
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fmt::Display,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{client::Route, Provider, Socket};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+/// 多个转发目标之间分配连接的方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BalanceStrategy {
+    /// 依次使用每个目标
+    #[default]
+    RoundRobin,
+    /// 使用当前连接数最少的目标, 连接数相同时依次使用
+    LeastConnections,
+}
+
+struct Target {
+    socket: Socket,
+    active: Arc<AtomicUsize>,
+}
+
+/// 在多个转发目标之间分配连接, 连接失败时换下一个目标, 全部失败才放弃
+pub struct Balancer {
+    targets: Vec<Target>,
+    next: AtomicUsize,
+    strategy: BalanceStrategy,
+}
+
+/// 占用目标的一个连接数, 转发结束后释放
+pub struct Assigned(Arc<AtomicUsize>);
+
+impl FromStr for BalanceStrategy {
+    type Err = crate::Error;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        Ok(match strategy {
+            "round-robin" => Self::RoundRobin,
+            "least-conn" => Self::LeastConnections,
+            strategy => return Err(format!("unknown balance strategy `{}`", strategy).into()),
+        })
+    }
+}
+
+impl Display for BalanceStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", {
+            match self {
+                BalanceStrategy::RoundRobin => "round-robin",
+                BalanceStrategy::LeastConnections => "least-conn",
+            }
+        })
+    }
+}
+
+impl Balancer {
+    pub fn new(targets: Vec<Socket>, strategy: BalanceStrategy) -> Self {
+        Self {
+            targets: targets
+                .into_iter()
+                .map(|socket| Target {
+                    socket,
+                    active: Default::default(),
+                })
+                .collect(),
+            next: Default::default(),
+            strategy,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// 本次连接依次尝试的目标, 第一个为按策略选中的目标
+    fn candidates(&self) -> Vec<usize> {
+        let len = self.targets.len();
+
+        if len == 0 {
+            return Vec::new();
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % len;
+        let mut candidates = (0..len).map(|i| (start + i) % len).collect::<Vec<_>>();
+
+        // 稳定排序, 连接数相同的目标保持轮询顺序
+        if self.strategy == BalanceStrategy::LeastConnections {
+            candidates.sort_by_key(|i| self.targets[*i].active.load(Ordering::Acquire));
+        }
+
+        candidates
+    }
+
+    /// 按策略连接转发目标, 所有目标沿用 `socket` 的类型
+    pub async fn connect<C, S>(
+        self: Arc<Self>,
+        connector: Arc<C>,
+        socket: Socket,
+    ) -> crate::Result<(Route<S>, Assigned)>
+    where
+        C: Provider<Socket, Output = BoxedFuture<Route<S>>>,
+    {
+        let mut last_error = None;
+
+        for i in self.candidates() {
+            let target = &self.targets[i];
+            let target_socket = target
+                .socket
+                .clone()
+                .if_stream_mixed(socket.is_mixed())
+                .with_kind(socket.kind());
+
+            match connector.call(target_socket).await {
+                Ok(route) => {
+                    log::debug!("forward to {}", target.socket);
+                    target.active.fetch_add(1, Ordering::AcqRel);
+                    return Ok((route, Assigned(target.active.clone())));
+                }
+                Err(e) => {
+                    log::warn!(
+                        "failed to connect to {}, try the next target err={}",
+                        target.socket,
+                        e
+                    );
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| "no forward target".to_string().into()))
+    }
+}
+
+impl Drop for Assigned {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{atomic::Ordering, Arc};
+
+    use super::{BalanceStrategy, Balancer};
+    use crate::{client::Route, Provider, Socket};
+
+    struct Refuse(u16);
+
+    impl Provider<Socket> for Refuse {
+        type Output = super::BoxedFuture<Route<u16>>;
+
+        fn call(&self, socket: Socket) -> Self::Output {
+            let refused = self.0;
+            Box::pin(async move {
+                match socket.port() {
+                    port if port == refused => Err("refused".to_string().into()),
+                    port => Ok(Route::Forward(port)),
+                }
+            })
+        }
+    }
+
+    fn forwarded(route: Route<u16>) -> u16 {
+        match route {
+            Route::Forward(port) => port,
+            Route::Provider(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_balancer() {
+        let targets = || vec![Socket::tcp(8001), Socket::tcp(8002), Socket::tcp(8003)];
+
+        let balancer = Balancer::new(targets(), BalanceStrategy::RoundRobin);
+        assert_eq!(balancer.candidates(), vec![0, 1, 2]);
+        assert_eq!(balancer.candidates(), vec![1, 2, 0]);
+
+        let balancer = Balancer::new(targets(), BalanceStrategy::LeastConnections);
+        balancer.targets[0].active.store(2, Ordering::Relaxed);
+        balancer.targets[1].active.store(1, Ordering::Relaxed);
+        assert_eq!(balancer.candidates(), vec![2, 1, 0]);
+
+        assert_eq!(
+            "least-conn".parse::<BalanceStrategy>().unwrap(),
+            BalanceStrategy::LeastConnections
+        );
+        assert!("random".parse::<BalanceStrategy>().is_err());
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let balancer = Arc::new(Balancer::new(targets(), BalanceStrategy::RoundRobin));
+                let connector = Arc::new(Refuse(8001));
+
+                let (route, active) = balancer
+                    .clone()
+                    .connect(connector.clone(), Socket::default())
+                    .await
+                    .unwrap();
+                assert_eq!(forwarded(route), 8002);
+                assert_eq!(balancer.targets[1].active.load(Ordering::Relaxed), 1);
+
+                drop(active);
+                assert_eq!(balancer.targets[1].active.load(Ordering::Relaxed), 0);
+
+                let balancer = Arc::new(Balancer::new(
+                    vec![Socket::tcp(8001)],
+                    BalanceStrategy::RoundRobin,
+                ));
+                assert!(balancer
+                    .connect(connector, Socket::default())
+                    .await
+                    .is_err());
+            });
+    }
+}
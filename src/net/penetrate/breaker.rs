@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{client::Route, Provider, Socket, SocketKind};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+/// 最多记录的目标数, socks与http代理的访问者可以连接任意目标, 超过后移除最久没有失败的
+const MAX_CIRCUITS: usize = 1024;
+
+struct Circuit {
+    /// 连续失败的次数, 连接成功后清零
+    failures: usize,
+    /// 最近一次失败的时间
+    failed_at: Instant,
+    /// 在此之前直接拒绝连接
+    open_until: Option<Instant>,
+}
+
+impl Circuit {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            failed_at: now,
+            open_until: None,
+        }
+    }
+
+    /// 未熔断时最近一次失败超过 `cooldown`, 或熔断结束后超过 `cooldown` 都没有探测,
+    /// 不再需要记录
+    fn is_expired(&self, now: Instant, cooldown: Duration) -> bool {
+        match self.open_until {
+            Some(until) => now >= until + cooldown,
+            None => now >= self.failed_at + cooldown,
+        }
+    }
+}
+
+/// 连接转发目标的熔断器, 同一个目标连续失败 `threshold` 次后,
+/// 在 `cooldown` 内直接返回错误, 不再等待连接超时.
+/// 冷却期过后放行一个连接探测, 成功后恢复, 失败则重新冷却, 只作用于tcp目标
+pub struct CircuitBreaker<C> {
+    connector: C,
+    threshold: usize,
+    cooldown: Duration,
+    circuits: Arc<Mutex<HashMap<String, Circuit>>>,
+}
+
+impl<C> CircuitBreaker<C> {
+    /// 默认不启用, 需要设置 `threshold`
+    pub fn new(connector: C) -> Self {
+        Self {
+            connector,
+            threshold: 0,
+            cooldown: Duration::from_secs(10),
+            circuits: Default::default(),
+        }
+    }
+
+    /// 触发熔断的连续失败次数, 为0时不启用
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// 熔断后拒绝连接的时间, 之后放行一个连接探测目标是否恢复
+    pub fn cooldown(mut self, cooldown: Duration) -> Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// 熔断中返回false, 冷却期已过时放行本次连接作为探测,
+    /// 探测期间其他连接仍然被拒绝
+    fn allow(&self, target: &str) -> bool {
+        let mut circuits = match self.circuits.lock() {
+            Ok(circuits) => circuits,
+            Err(_) => return true,
+        };
+
+        let now = Instant::now();
+
+        let circuit = match circuits.get_mut(target) {
+            Some(circuit) if circuit.is_expired(now, self.cooldown) => {
+                circuits.remove(target);
+                return true;
+            }
+            Some(circuit) => circuit,
+            None => return true,
+        };
+
+        match circuit.open_until {
+            Some(until) if now < until => false,
+            Some(_) => {
+                log::debug!("probe {} after {:?}", target, self.cooldown);
+                circuit.open_until = Some(now + self.cooldown);
+                true
+            }
+            None => true,
+        }
+    }
+}
+
+fn record(
+    circuits: &Mutex<HashMap<String, Circuit>>,
+    target: String,
+    ok: bool,
+    threshold: usize,
+    cooldown: Duration,
+) {
+    let mut circuits = match circuits.lock() {
+        Ok(circuits) => circuits,
+        Err(_) => return,
+    };
+
+    if ok {
+        if let Some(circuit) = circuits.remove(&target) {
+            if circuit.open_until.is_some() {
+                log::info!("{} recovered, close the circuit", target);
+            }
+        }
+        return;
+    }
+
+    let now = Instant::now();
+
+    if circuits.len() >= MAX_CIRCUITS && !circuits.contains_key(&target) {
+        circuits.retain(|_, circuit| !circuit.is_expired(now, cooldown));
+
+        let oldest = circuits
+            .iter()
+            .min_by_key(|(_, circuit)| circuit.failed_at)
+            .map(|(target, _)| target.clone())
+            .filter(|_| circuits.len() >= MAX_CIRCUITS);
+
+        if let Some(oldest) = oldest {
+            circuits.remove(&oldest);
+        }
+    }
+
+    let circuit = circuits
+        .entry(target.clone())
+        .or_insert_with(|| Circuit::new(now));
+
+    // 很久之前的失败不再计入连续失败
+    if circuit.is_expired(now, cooldown) {
+        *circuit = Circuit::new(now);
+    }
+
+    circuit.failures += 1;
+    circuit.failed_at = now;
+
+    if circuit.failures >= threshold {
+        log::warn!(
+            "failed to connect to {} {} times in a row, refuse for {:?}",
+            target,
+            circuit.failures,
+            cooldown
+        );
+        circuit.open_until = Some(now + cooldown);
+    }
+}
+
+impl<C, S> Provider<Socket> for CircuitBreaker<C>
+where
+    C: Provider<Socket, Output = BoxedFuture<Route<S>>>,
+    S: Send + 'static,
+{
+    type Output = BoxedFuture<Route<S>>;
+
+    fn call(&self, socket: Socket) -> Self::Output {
+        if self.threshold == 0 || socket.kind() != SocketKind::Tcp {
+            return self.connector.call(socket);
+        }
+
+        let target = socket.as_string();
+
+        if !self.allow(&target) {
+            log::debug!("circuit of {} is open, refuse", target);
+
+            let err = std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("{} is unavailable, circuit open", target),
+            );
+
+            return Box::pin(async move { Err(err.into()) });
+        }
+
+        let connect = self.connector.call(socket);
+        let circuits = self.circuits.clone();
+        let (threshold, cooldown) = (self.threshold, self.cooldown);
+
+        Box::pin(async move {
+            let result = connect.await;
+            record(&circuits, target, result.is_ok(), threshold, cooldown);
+
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::CircuitBreaker;
+    use crate::{client::Route, Provider, Socket};
+
+    #[derive(Default, Clone)]
+    struct Backend {
+        up: Arc<AtomicBool>,
+        dials: Arc<AtomicUsize>,
+    }
+
+    impl Provider<Socket> for Backend {
+        type Output = super::BoxedFuture<Route<u16>>;
+
+        fn call(&self, socket: Socket) -> Self::Output {
+            self.dials.fetch_add(1, Ordering::SeqCst);
+            let up = self.up.load(Ordering::SeqCst);
+            Box::pin(async move {
+                match up {
+                    true => Ok(Route::Forward(socket.port())),
+                    false => Err("refused".to_string().into()),
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let backend = Backend::default();
+                let breaker = CircuitBreaker::new(backend.clone())
+                    .threshold(2)
+                    .cooldown(Duration::from_millis(50));
+
+                let target = Socket::tcp(8080);
+
+                assert!(breaker.call(target.clone()).await.is_err());
+                assert!(breaker.call(target.clone()).await.is_err());
+                assert_eq!(backend.dials.load(Ordering::SeqCst), 2);
+
+                // 熔断中不再连接目标, 其他目标不受影响
+                assert!(breaker.call(target.clone()).await.is_err());
+                assert_eq!(backend.dials.load(Ordering::SeqCst), 2);
+                assert!(breaker.call(Socket::tcp(8081)).await.is_err());
+                assert_eq!(backend.dials.load(Ordering::SeqCst), 3);
+
+                // 冷却后探测失败, 重新冷却
+                tokio::time::sleep(Duration::from_millis(60)).await;
+                assert!(breaker.call(target.clone()).await.is_err());
+                assert!(breaker.call(target.clone()).await.is_err());
+                assert_eq!(backend.dials.load(Ordering::SeqCst), 4);
+
+                // 目标恢复后探测成功, 不再熔断
+                backend.up.store(true, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(60)).await;
+                assert!(breaker.call(target.clone()).await.is_ok());
+                assert!(breaker.call(target.clone()).await.is_ok());
+                assert_eq!(backend.dials.load(Ordering::SeqCst), 6);
+            });
+    }
+
+    #[test]
+    fn test_circuit_expiry() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let backend = Backend::default();
+                let breaker = CircuitBreaker::new(backend.clone())
+                    .threshold(2)
+                    .cooldown(Duration::from_millis(50));
+
+                // 两次失败间隔超过冷却时间, 不算连续失败
+                let target = Socket::tcp(8080);
+                assert!(breaker.call(target.clone()).await.is_err());
+                tokio::time::sleep(Duration::from_millis(60)).await;
+                assert!(breaker.call(target.clone()).await.is_err());
+                assert!(breaker.call(target.clone()).await.is_err());
+                assert_eq!(backend.dials.load(Ordering::SeqCst), 3);
+
+                // 不可达的目标再多也只记录有限个
+                for port in 0..super::MAX_CIRCUITS as u16 + 100 {
+                    let _ = breaker.call(Socket::tcp(10000 + port)).await;
+                }
+                assert_eq!(breaker.circuits.lock().unwrap().len(), super::MAX_CIRCUITS);
+
+                // 过期后不再记录
+                tokio::time::sleep(Duration::from_millis(110)).await;
+                assert!(breaker.call(Socket::tcp(9090)).await.is_err());
+                assert_eq!(breaker.circuits.lock().unwrap().len(), 1);
+            });
+    }
+}
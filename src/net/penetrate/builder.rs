@@ -1,27 +1,65 @@
-use std::{pin::Pin, sync::Arc, time::Duration};
+use std::{net::IpAddr, ops::RangeInclusive, pin::Pin, sync::Arc, time::Duration};
 
 use crate::{
+    acl::Egress,
+    admin::Admin,
     client::{Client, ClientBuilder, Route},
     guard::Fallback,
+    io::ForwardStrategy,
+    protocol::DEFAULT_MAX_FRAME_SIZE,
+    resource::ResourceGuard,
     server::{Server, ServerBuilder},
-    Accepter, Executor, Fuso, Provider, Socket, Stream, WrappedProvider, Platform,
+    Accepter, Addr, Executor, Fuso, FusoStream, Platform, Provider, Socket, Stream,
+    WrappedProvider,
 };
 
 use super::{
     client::PenetrateClientProvider,
-    server::{Config, Peer, PenetrateProvider},
-    PenetrateObserver,
+    server::{Config, Connections, Peer, PenetrateProvider},
+    session::Sessions,
+    Authenticator, BalanceStrategy, Balancer, Connected, Disconnected, Event, Events, Hooks,
+    NoAuth, PenetrateObserver, TenantKey, TokenAuth,
 };
 
 type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
 
+/// 以太网的MTU, 更大的数据包在链路上需要分片
+const DEFAULT_UDP_MAX_DATAGRAM: usize = 1500;
+
 pub struct PenetrateServerBuilder<E, P, S, O> {
     is_mixed: bool,
     max_wait_time: Duration,
     heartbeat_timeout: Duration,
+    keepalive_timeout: Duration,
     read_timeout: Option<Duration>,
     write_timeout: Option<Duration>,
     fallback_strict_mode: bool,
+    socks5_credentials: Vec<(String, String)>,
+    allowed_bind_ports: Option<RangeInclusive<u16>>,
+    allowed_bind_hosts: Vec<IpAddr>,
+    allow_random_bind_port: bool,
+    egress: Egress,
+    udp_public_ip: Option<IpAddr>,
+    udp_max_datagram: usize,
+    max_udp_associations: Option<usize>,
+    max_frame_size: usize,
+    session_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    forward_strategy: ForwardStrategy,
+    stats_interval: Option<Duration>,
+    max_connections: Option<usize>,
+    max_convs: Option<usize>,
+    resource_guard: Option<ResourceGuard>,
+    proxy_protocol: bool,
+    events: Events,
+    hooks: Hooks,
+    admin: Option<Admin>,
+    trace_timing: bool,
+    bind_retries: usize,
+    authenticator: Arc<dyn Authenticator>,
+    tenant_keys: Vec<(String, TenantKey)>,
+    default_tenant_key: Option<TenantKey>,
     server_builder: ServerBuilder<E, P, S, O>,
 }
 
@@ -36,6 +74,10 @@ pub struct PenetrateClientBuilder<E, CF, S> {
     maximum_wait: Option<Duration>,
     /// 重连延时
     reconnect_delay: Option<Duration>,
+    /// 连续重连失败时延时加倍的上限
+    reconnect_max_delay: Option<Duration>,
+    /// 重连延时随机减少的最大比例
+    reconnect_jitter: f64,
     /// 重连尝试次数，如果为None那么永不停止
     maximum_retries: Option<usize>,
     /// 心跳延时
@@ -50,6 +92,32 @@ pub struct PenetrateClientBuilder<E, CF, S> {
     socks_password: Option<String>,
     /// 是否启用socks5 udp转发
     enable_socks5_udp: bool,
+    /// 连接服务端时提供的token
+    token: Option<String>,
+    /// 映射的存活时间, 到期后服务端关闭映射, 客户端不再重连
+    ttl: Option<Duration>,
+    /// 额外的转发目标, 与下游地址一起分配连接
+    forward_targets: Vec<Socket>,
+    /// 多个转发目标之间分配连接的方式
+    balance_strategy: BalanceStrategy,
+    /// 按tls SNI选择的转发目标
+    sni_routes: Vec<(String, Addr)>,
+    /// 按http请求的 `Host` 选择的转发目标
+    host_routes: Vec<(String, Addr)>,
+    /// `Host` 没有对应的目标时回复404
+    host_not_found: bool,
+    /// 在访问者的第一个http请求中添加 `X-Forwarded-For`
+    forwarded_for: bool,
+    /// 同一个控制连接上的其他服务
+    services: Vec<(String, u16, Addr)>,
+    /// 映射的连接通过同一个连接多路复用
+    mux: bool,
+    /// 映射的连接使用的密钥, 与服务端为该客户端名设置的一致
+    tenant_key: Option<TenantKey>,
+    /// 映射的连接与转发目标之间的复制方式
+    forward_strategy: ForwardStrategy,
+    /// 从服务端接收的数据包的最大长度
+    max_frame_size: usize,
     /// builder ...
     client_builder: ClientBuilder<E, CF, S>,
 }
@@ -62,7 +130,34 @@ impl<E, P, S, O> ServerBuilder<E, P, S, O> {
             read_timeout: None,
             max_wait_time: Duration::from_secs(10),
             heartbeat_timeout: Duration::from_secs(60),
+            keepalive_timeout: Duration::from_secs(90),
             fallback_strict_mode: true,
+            socks5_credentials: Vec::new(),
+            allowed_bind_ports: None,
+            allowed_bind_hosts: Vec::new(),
+            allow_random_bind_port: true,
+            egress: Egress::default(),
+            udp_public_ip: None,
+            udp_max_datagram: DEFAULT_UDP_MAX_DATAGRAM,
+            max_udp_associations: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            session_timeout: Duration::from_secs(30),
+            idle_timeout: None,
+            max_lifetime: None,
+            forward_strategy: ForwardStrategy::default(),
+            stats_interval: None,
+            max_connections: None,
+            max_convs: None,
+            resource_guard: None,
+            proxy_protocol: false,
+            events: Events::default(),
+            hooks: Hooks::default(),
+            admin: None,
+            trace_timing: false,
+            bind_retries: 0,
+            authenticator: Arc::new(NoAuth),
+            tenant_keys: Vec::new(),
+            default_tenant_key: None,
             server_builder: self,
         }
     }
@@ -72,7 +167,7 @@ impl<E, P, A, S, O> PenetrateServerBuilder<E, P, S, O>
 where
     E: Executor + 'static,
     A: Accepter<Stream = S> + Unpin + Send + 'static,
-    S: Stream + Send + Sync + 'static,
+    S: Stream + From<FusoStream> + Send + Sync + 'static,
     P: Provider<Socket, Output = BoxedFuture<A>> + Send + Sync + 'static,
     O: PenetrateObserver + Send + Sync + 'static,
 {
@@ -81,6 +176,8 @@ where
         self
     }
 
+    /// 通知客户端建立映射时控制连接的写入超时, 超时后关闭该客户端,
+    /// 不超过 `max_wait_time` 的一半, None 时使用该值
     pub fn write_timeout(mut self, time: Option<Duration>) -> Self {
         self.write_timeout = time;
         self
@@ -96,6 +193,203 @@ where
         self
     }
 
+    /// 超过该时间没有收到客户端的任何消息, 将断开客户端并关闭映射
+    pub fn keepalive_timeout(mut self, time: Duration) -> Self {
+        self.keepalive_timeout = time;
+        self
+    }
+
+    /// socks5用户名密码, 设置后所有socks5连接都需要认证
+    pub fn socks5_credentials(mut self, credentials: Vec<(String, String)>) -> Self {
+        self.socks5_credentials = credentials;
+        self
+    }
+
+    /// 限制客户端可以监听的访问端口, None 表示不限制
+    pub fn allowed_bind_ports(mut self, ports: Option<RangeInclusive<u16>>) -> Self {
+        self.allowed_bind_ports = ports;
+        self
+    }
+
+    /// 限制客户端可以监听的地址, 未指定的地址也需要列出才能使用, 为空时允许本机的任意地址
+    pub fn allowed_bind_hosts(mut self, hosts: Vec<IpAddr>) -> Self {
+        self.allowed_bind_hosts = hosts;
+        self
+    }
+
+    /// 访问者通过socks, http代理等可以到达的目标, 为空时不限制, 客户端配置的转发目标不受限制
+    pub fn egress(mut self, egress: Egress) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    /// socks5 udp中继应答给访问者的地址, 服务端在NAT之后时设置为公网地址,
+    /// 端口仍为中继实际监听的端口, 默认应答同一协议族的未指定地址(0.0.0.0或::)
+    pub fn udp_public_ip(mut self, ip: Option<IpAddr>) -> Self {
+        self.udp_public_ip = ip;
+        self
+    }
+
+    /// 访问者发往socks5 udp中继的数据包携带的数据的最大长度(不包括socks5头部),
+    /// 超过后记录警告并丢弃, 不截断也不分片, 默认1500
+    pub fn udp_max_datagram(mut self, size: usize) -> Self {
+        self.udp_max_datagram = size;
+        self
+    }
+
+    /// 每个客户端同时存在的socks5 udp中继数, 超过后拒绝新的 `UDP ASSOCIATE`, None 表示不限制
+    pub fn max_udp_associations(mut self, max: Option<usize>) -> Self {
+        self.max_udp_associations = max;
+        self
+    }
+
+    /// 从客户端接收的数据包的最大长度, 超过时在分配内存前断开该客户端, 默认16MiB
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// 是否允许客户端不指定端口, 由系统随机分配, 随机端口不受 `allowed_bind_ports` 限制
+    pub fn allow_random_bind_port(mut self, allow: bool) -> Self {
+        self.allow_random_bind_port = allow;
+        self
+    }
+
+    /// 客户端断开后保留监听的时间, 期间同名客户端重连将继续使用原来的端口
+    pub fn session_timeout(mut self, time: Duration) -> Self {
+        self.session_timeout = time;
+        self
+    }
+
+    /// 转发的连接超过该时间两个方向都没有数据时关闭, None 表示不限制
+    pub fn idle_timeout(mut self, time: Option<Duration>) -> Self {
+        self.idle_timeout = time;
+        self
+    }
+
+    /// 转发的连接从建立起超过该时间后强制关闭, 即使仍有数据, None 表示不限制
+    pub fn max_lifetime(mut self, time: Option<Duration>) -> Self {
+        self.max_lifetime = time;
+        self
+    }
+
+    /// 访问者与客户端之间转发时的缓冲区大小与是否使用 `splice`
+    pub fn forward_strategy(mut self, strategy: ForwardStrategy) -> Self {
+        self.forward_strategy = strategy;
+        self
+    }
+
+    /// 定时向客户端推送每个转发连接的流量统计, None 表示不推送
+    pub fn stats_interval(mut self, interval: Option<Duration>) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// 每个客户端同时存在的最大连接数, 超过后拒绝新的访问, None 表示不限制
+    pub fn max_connections(mut self, max: Option<usize>) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// 整个服务端同时存在的最大连接数, 所有客户端共享, None 表示不限制
+    pub fn max_convs(mut self, max: Option<usize>) -> Self {
+        self.max_convs = max;
+        self
+    }
+
+    /// 可用内存或文件描述符低于下限时拒绝新的访问, 并通知客户端.
+    /// 与监听使用同一个实例时共享采样结果
+    pub fn resource_guard(mut self, guard: ResourceGuard) -> Self {
+        self.resource_guard = Some(guard).filter(|guard| !guard.is_empty());
+        self
+    }
+
+    /// 直接转发时在数据前发送PROXY protocol v2头, 让后端获取访问者的真实地址
+    pub fn proxy_protocol(mut self, enable: bool) -> Self {
+        self.proxy_protocol = enable;
+        self
+    }
+
+    /// 访问端口被占用时的重试次数, 每次重试的间隔加倍, 其他错误不重试
+    pub fn bind_retries(mut self, retries: usize) -> Self {
+        self.bind_retries = retries;
+        self
+    }
+
+    /// 客户端需要提供的token, None 表示不校验, 会替换之前设置的认证方式
+    pub fn token(mut self, token: Option<String>) -> Self {
+        self.authenticator = match token {
+            None => Arc::new(NoAuth),
+            Some(token) => Arc::new(TokenAuth::new(token)),
+        };
+        self
+    }
+
+    /// 自定义客户端的认证方式, 会替换之前设置的token
+    pub fn authenticator<T>(mut self, authenticator: T) -> Self
+    where
+        T: Authenticator + 'static,
+    {
+        self.authenticator = Arc::new(authenticator);
+        self
+    }
+
+    /// 按客户端名设置映射连接的密钥, 客户端需要使用相同的密钥
+    pub fn tenant_keys(mut self, keys: Vec<(String, TenantKey)>) -> Self {
+        self.tenant_keys = keys;
+        self
+    }
+
+    /// 客户端名没有对应的密钥时使用, None 表示拒绝未知的客户端
+    pub fn default_tenant_key(mut self, key: Option<TenantKey>) -> Self {
+        self.default_tenant_key = key;
+        self
+    }
+
+    /// 接收映射的生命周期事件, 通道已满或接收端被丢弃时事件会被丢弃
+    pub fn events(mut self, sender: async_channel::Sender<Event>) -> Self {
+        self.events = Events::new(sender);
+        self
+    }
+
+    /// 每个转发开始前执行, 执行完成后才开始转发, 返回的错误只记录日志
+    pub fn on_connect<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(Connected) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = crate::Result<()>> + Send + 'static,
+    {
+        self.hooks = self.hooks.on_connect(hook);
+        self
+    }
+
+    /// 每个转发结束后执行, 可以取得访问者地址与转发的字节数, 返回的错误只记录日志
+    pub fn on_close<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(Disconnected) -> Fut + Send + Sync + 'static,
+        Fut: std::future::Future<Output = crate::Result<()>> + Send + 'static,
+    {
+        self.hooks = self.hooks.on_close(hook);
+        self
+    }
+
+    /// `on_connect` 与 `on_close` 的最长等待时间, 超时后记录日志并继续转发, 默认5秒
+    pub fn hook_timeout(mut self, timeout: Duration) -> Self {
+        self.hooks = self.hooks.timeout(timeout);
+        self
+    }
+
+    /// 记录正在转发的连接, 与管理服务使用同一个实例时可以查询与关闭, None 表示不记录
+    pub fn admin(mut self, admin: Option<Admin>) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    /// 记录认证, 监听以及每个映射各阶段的耗时, 以debug级别输出并产生 `Event::Timing`
+    pub fn trace_timing(mut self, enable: bool) -> Self {
+        self.trace_timing = enable;
+        self
+    }
+
     pub fn enable_fallback_strict_mode(mut self) -> Self {
         self.fallback_strict_mode = true;
         self
@@ -121,6 +415,7 @@ where
                 is_mixed: self.is_mixed,
                 maximum_wait: self.max_wait_time,
                 heartbeat_delay: self.heartbeat_timeout,
+                keepalive_timeout: self.keepalive_timeout,
                 read_timeout: self.read_timeout,
                 write_timeout: self.write_timeout,
                 fallback_strict_mode: self.fallback_strict_mode,
@@ -128,9 +423,43 @@ where
                 enable_socks_udp: false,
                 socks5_password: None,
                 socks5_username: None,
+                socks5_credentials: self.socks5_credentials,
+                allowed_bind_ports: self.allowed_bind_ports,
+                allowed_bind_hosts: self.allowed_bind_hosts,
+                allow_random_bind_port: self.allow_random_bind_port,
+                egress: self.egress,
+                udp_public_ip: self.udp_public_ip,
+                udp_max_datagram: self.udp_max_datagram,
+                max_udp_associations: self.max_udp_associations,
+                max_frame_size: self.max_frame_size,
+                udp_associations: Connections::default(),
+                session_timeout: self.session_timeout,
+                idle_timeout: self.idle_timeout,
+                max_lifetime: self.max_lifetime,
+                forward_strategy: self.forward_strategy,
+                stats_interval: self.stats_interval,
+                max_connections: self.max_connections,
+                server_connections: Connections::server(self.max_convs),
+                resource_guard: self.resource_guard,
+                proxy_protocol: self.proxy_protocol,
+                events: self.events,
+                hooks: self.hooks,
+                admin: self.admin,
+                trace_timing: self.trace_timing,
+                bind_retries: self.bind_retries,
+                ttl: None,
+                sni_routes: Vec::new(),
+                host_routes: Vec::new(),
+                host_not_found: false,
+                forwarded_for: false,
+                tenant_keys: self.tenant_keys,
+                default_tenant_key: self.default_tenant_key,
+                tenant_key: None,
                 platform: Default::default()
             },
             mock: Arc::new(WrappedProvider::wrap(mock)),
+            sessions: Sessions::new(self.session_timeout),
+            authenticator: self.authenticator,
         })
     }
 }
@@ -149,12 +478,27 @@ impl<E, CF, S> ClientBuilder<E, CF, S> {
             maximum_wait: None,
             maximum_retries: None,
             reconnect_delay: None,
+            reconnect_max_delay: None,
+            reconnect_jitter: 0.0,
             heartbeat_delay: None,
             enable_kcp: false,
             enable_socks5: false,
             socks_username: None,
             socks_password: None,
             enable_socks5_udp: false,
+            token: None,
+            ttl: None,
+            forward_targets: Vec::new(),
+            balance_strategy: BalanceStrategy::default(),
+            sni_routes: Vec::new(),
+            host_routes: Vec::new(),
+            host_not_found: false,
+            forwarded_for: false,
+            services: Vec::new(),
+            mux: false,
+            tenant_key: None,
+            forward_strategy: ForwardStrategy::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
         }
     }
 }
@@ -163,13 +507,23 @@ impl<E, CF, S> PenetrateClientBuilder<E, CF, S>
 where
     E: Executor + 'static,
     CF: Provider<Socket, Output = BoxedFuture<S>> + Send + Sync + 'static,
-    S: Stream + Send + 'static,
+    S: Stream + From<FusoStream> + Send + 'static,
 {
     pub fn reconnect_delay(mut self, delay: Duration) -> Self {
         self.reconnect_delay = Some(delay.min(Duration::from_secs(2)));
         self
     }
 
+    pub fn reconnect_max_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_max_delay = Some(delay);
+        self
+    }
+
+    pub fn reconnect_jitter(mut self, jitter: f64) -> Self {
+        self.reconnect_jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
     pub fn set_name(mut self, name: String) -> Self {
         self.name = name;
         self
@@ -200,6 +554,85 @@ where
         self
     }
 
+    pub fn set_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    pub fn set_ttl(mut self, ttl: Option<Duration>) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// 额外的转发目标, 与 `using_penetrate` 指定的下游地址一起按 `balance_strategy` 分配连接,
+    /// 连接失败时依次尝试其他目标
+    pub fn add_forward_targets(mut self, targets: Vec<Socket>) -> Self {
+        self.forward_targets.extend(targets);
+        self
+    }
+
+    pub fn balance_strategy(mut self, strategy: BalanceStrategy) -> Self {
+        self.balance_strategy = strategy;
+        self
+    }
+
+    /// 访问者的tls SNI与转发目标, 服务端需启用 `using_sni`, 没有匹配时转发到下游地址
+    pub fn add_sni_routes(mut self, routes: Vec<(String, Addr)>) -> Self {
+        self.sni_routes.extend(routes);
+        self
+    }
+
+    /// 访问者的http `Host` 与转发目标, 服务端需启用 `using_http_host`
+    pub fn add_host_routes(mut self, routes: Vec<(String, Addr)>) -> Self {
+        self.host_routes.extend(routes);
+        self
+    }
+
+    /// 在同一个控制连接上注册其他服务, 服务端为每个服务监听各自的端口,
+    /// 访问者的连接转发到对应的目标, 与控制连接一起关闭
+    pub fn add_services(mut self, services: Vec<(String, u16, Addr)>) -> Self {
+        self.services.extend(services);
+        self
+    }
+
+    /// `Host` 没有对应的目标时回复404, 默认转发到下游地址
+    pub fn host_not_found(mut self, not_found: bool) -> Self {
+        self.host_not_found = not_found;
+        self
+    }
+
+    /// 在访问者的第一个http请求中添加访问者的地址, 已有 `X-Forwarded-For` 时追加到末尾,
+    /// 只用于转发到http服务的映射
+    pub fn forwarded_for(mut self, forwarded_for: bool) -> Self {
+        self.forwarded_for = forwarded_for;
+        self
+    }
+
+    /// 映射的连接通过同一个连接多路复用, 减少连接的建立,
+    /// 服务端不支持时为每个映射创建连接
+    pub fn using_mux(mut self, mux: bool) -> Self {
+        self.mux = mux;
+        self
+    }
+
+    /// 映射的连接使用该密钥加密, 需要与服务端为该客户端名设置的密钥一致
+    pub fn tenant_key(mut self, key: Option<TenantKey>) -> Self {
+        self.tenant_key = key;
+        self
+    }
+
+    /// 映射的连接与转发目标之间的缓冲区大小与是否使用 `splice`
+    pub fn forward_strategy(mut self, strategy: ForwardStrategy) -> Self {
+        self.forward_strategy = strategy;
+        self
+    }
+
+    /// 从服务端接收的数据包的最大长度, 超过时断开与服务端的连接, 默认16MiB
+    pub fn max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
     pub fn maximum_retries(mut self, maximum_retries: Option<usize>) -> Self {
         self.maximum_retries = maximum_retries;
         self
@@ -223,9 +656,20 @@ where
     where
         C: Provider<Socket, Output = BoxedFuture<Route<S>>> + Unpin + Send + Sync + 'static,
     {
+        let balancer = match self.forward_targets.is_empty() {
+            true => None,
+            false => {
+                let mut targets = vec![self.downstream.clone()];
+                targets.extend(self.forward_targets);
+                Some(Arc::new(Balancer::new(targets, self.balance_strategy)))
+            }
+        };
+
         ClientBuilder {
             executor: self.client_builder.executor,
             retry_delay: self.reconnect_delay,
+            retry_max_delay: self.reconnect_max_delay,
+            retry_jitter: self.reconnect_jitter,
             maximum_retries: self.maximum_retries,
             handshake: self.client_builder.handshake,
             client_provider: self.client_builder.client_provider,
@@ -235,6 +679,13 @@ where
             PenetrateClientProvider {
                 forward: (self.upstream, self.downstream),
                 connector_provider: Arc::new(connector),
+                token: self.token,
+                ttl: self.ttl,
+                balancer,
+                mux: self.mux,
+                tenant_key: self.tenant_key,
+                forward_strategy: self.forward_strategy,
+                max_frame_size: self.max_frame_size,
                 config: super::client::Config {
                     name: self.name,
                     maximum_wait: self.maximum_wait.unwrap_or(Duration::from_secs(10)),
@@ -245,7 +696,12 @@ where
                     socks_password: self.socks_password,
                     enable_socks5_udp: self.enable_socks5_udp,
                     version: String::from(env!("CARGO_PKG_VERSION")),
-                    platform: Platform::default()
+                    platform: Platform::default(),
+                    sni_routes: self.sni_routes,
+                    host_routes: self.host_routes,
+                    host_not_found: self.host_not_found,
+                    forwarded_for: self.forwarded_for,
+                    services: self.services,
                 },
             },
         )
@@ -5,19 +5,27 @@ use std::{future::Future, task::Poll};
 
 use serde::{Deserialize, Serialize};
 
-use crate::io::{ReadHalf, WriteHalf};
+use crate::io::{ForwardStrategy, ReadHalf, WriteHalf};
+use crate::mux::Mux;
 use crate::protocol::IntoPacket;
 use crate::{
     client::Route,
     generator::Generator,
-    protocol::{AsyncRecvPacket, AsyncSendPacket, Bind, Poto, ToBytes, TryToPoto},
-    Kind, Socket, Stream, {ClientProvider, Provider},
+    protocol::{AsyncRecvPacket, AsyncSendPacket, Bind, ErrorCode, Poto, ToBytes, TryToPoto},
+    FusoStream, Kind, Socket, Stream, ToBoxStream, {ClientProvider, Provider},
 };
 
-use crate::{io, join, time, Address, Processor, Platform};
+use crate::{encryption, io, join, time, Addr, Address, Platform, Processor};
+
+use super::{Assigned, Balancer, TenantKey};
 
 type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
 
+/// 多路复用建立失败或断开后重新建立的初始间隔, 每次失败后翻倍
+const MUX_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// 重新建立多路复用的最大间隔
+const MUX_RETRY_MAX_DELAY: Duration = Duration::from_secs(300);
+
 macro_rules! async_connect {
     ($writer: expr, $connector: expr, $id: expr, $socket: expr) => {{
         let socket = $socket.clone();
@@ -27,7 +35,7 @@ macro_rules! async_connect {
             match $connector.call(socket).await {
                 Ok(ok) => Ok(ok),
                 Err(err) => {
-                    let poto = Poto::MapError($id, err.to_string()).bytes();
+                    let poto = Poto::map_failed($id, &err, ErrorCode::Unreachable).bytes();
                     return match writer.send_packet(&poto).await {
                         Ok(_) => Err(err),
                         Err(err) => Err(err),
@@ -50,7 +58,6 @@ macro_rules! default_socket {
     }};
 }
 
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     /// 服务名
@@ -70,19 +77,93 @@ pub struct Config {
     /// 是否启用socks5 udp转发
     pub(super) enable_socks5_udp: bool,
     pub(super) version: String,
-    pub(super) platform: Platform
+    pub(super) platform: Platform,
+    /// 按tls SNI选择的转发目标, 由服务端读取ClientHello后选择
+    pub(super) sni_routes: Vec<(String, Addr)>,
+    /// 按http请求的 `Host` 选择的转发目标
+    pub(super) host_routes: Vec<(String, Addr)>,
+    /// `Host` 没有对应的目标时由服务端回复404
+    pub(super) host_not_found: bool,
+    /// 由服务端在访问者的第一个http请求中添加 `X-Forwarded-For`
+    pub(super) forwarded_for: bool,
+    /// 同一个控制连接上的其他服务: 名称, 服务端的访问端口与转发目标
+    pub(super) services: Vec<(String, u16, Addr)>,
+}
+
+/// `platform` 之后的字段, 旧版本的客户端不会发送, 新增字段只能加在末尾
+type Appended = (
+    Vec<(String, Addr)>,
+    Vec<(String, Addr)>,
+    bool,
+    bool,
+    Vec<(String, u16, Addr)>,
+);
+
+impl Config {
+    /// 旧版本的客户端发送的配置缺少末尾新增的字段, 补上这些字段默认值的编码后再解码
+    pub(super) fn decode(payload: &[u8]) -> bincode::Result<Self> {
+        match bincode::deserialize(payload) {
+            Err(e) if is_eof(&e) => {
+                let appended = bincode::serialize(&Appended::default())?;
+                bincode::deserialize(&[payload, &appended].concat()).map_err(|_| e)
+            }
+            config => config,
+        }
+    }
+}
+
+fn is_eof(e: &bincode::Error) -> bool {
+    matches!(&**e, bincode::ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
 }
 
 pub struct PenetrateClientProvider<C> {
     pub config: Config,
     pub forward: (Socket, Socket),
     pub connector_provider: Arc<C>,
+    /// 不随配置发送给服务端, 在 `Bind` 之前单独发送
+    pub token: Option<String>,
+    /// 映射的存活时间, 随 `Bind` 发送
+    pub ttl: Option<Duration>,
+    /// 默认转发目标有多个时使用
+    pub balancer: Option<Arc<Balancer>>,
+    /// 映射的连接通过同一个连接多路复用, 服务端不支持时使用单独的连接
+    pub mux: bool,
+    /// 与服务端为该客户端名设置的密钥一致, 用于加密映射的连接
+    pub tenant_key: Option<TenantKey>,
+    /// 映射的连接与转发目标之间的复制方式
+    pub forward_strategy: ForwardStrategy,
+    /// 从服务端接收的数据包的最大长度
+    pub max_frame_size: usize,
+}
+
+/// 服务端指定的unix域套接字目标必须是客户端自己配置的目标,
+/// 否则服务端可以借此连接客户端主机上任意的unix域套接字
+fn check_unix_target(config: &Config, local: &Socket, target: &Socket) -> crate::Result<()> {
+    if !target.is_unix() {
+        return Ok(());
+    }
+
+    let configured = std::iter::once(local.addr())
+        .chain(config.sni_routes.iter().map(|(_, addr)| addr))
+        .chain(config.host_routes.iter().map(|(_, addr)| addr))
+        .chain(config.services.iter().map(|(_, _, addr)| addr))
+        .any(|addr| addr == target.addr());
+
+    if configured {
+        Ok(())
+    } else {
+        log::warn!("refusing unconfigured unix socket {}", target);
+        let reason = format!("{} is not a configured target", target);
+        Err(Kind::Refused(ErrorCode::Unreachable, Some(reason)).into())
+    }
 }
 
 enum State {
     Leave(Socket),
     Ready(BoxedFuture<()>),
     Map(u32, Socket),
+    Mux(Mux, BoxedFuture<()>),
+    Demux,
     Error(crate::Error),
 }
 
@@ -94,37 +175,72 @@ pub struct PenetrateClient<P, C, S> {
     futures: Vec<BoxedFuture<State>>,
     processor: Processor<ClientProvider<P>, S, ()>,
     connector_provider: Arc<C>,
+    balancer: Option<Arc<Balancer>>,
+    mux: Option<Mux>,
+    /// 下次重新建立多路复用前等待的时间, 未启用多路复用时为None
+    mux_retry: Option<Duration>,
+    tenant_key: Option<TenantKey>,
+    forward_strategy: ForwardStrategy,
+    max_frame_size: usize,
 }
 
+/// 转发到默认目标且有多个目标时由 `Balancer` 选择目标, 否则直接连接
+struct TargetConnector<C>(Arc<C>, Option<Arc<Balancer>>);
+
 impl<P, C, S> Provider<(S, Processor<ClientProvider<P>, S, ()>)> for PenetrateClientProvider<C>
 where
     P: Provider<Socket, Output = BoxedFuture<S>> + Send + Sync + 'static,
     C: Provider<Socket, Output = BoxedFuture<Route<S>>> + Send + Sync + 'static,
-    S: Stream + Send + 'static,
+    S: Stream + From<FusoStream> + Send + 'static,
 {
     type Output = BoxedFuture<PenetrateClient<P, C, S>>;
 
     fn call(&self, (stream, processor): (S, Processor<ClientProvider<P>, S, ()>)) -> Self::Output {
         let socket = self.forward.clone();
         let config = self.config.clone();
+        let token = self.token.clone();
+        let ttl = self.ttl;
+        let balancer = self.balancer.clone();
+        let mux = self.mux;
+        let tenant_key = self.tenant_key.clone();
+        let forward_strategy = self.forward_strategy;
+        let max_frame_size = self.max_frame_size;
 
         let connector_provider = self.connector_provider.clone();
 
         Box::pin(async move {
             let mut stream = stream;
             let (visit_addr, route_addr) = socket;
-            let bind = Poto::Bind(Bind::Setup(
-                Socket::tcp(0).if_stream_mixed(config.enable_kcp || config.enable_socks5_udp),
-                visit_addr.clone(),
-            ))
+            let client_addr =
+                Socket::tcp(0).if_stream_mixed(config.enable_kcp || config.enable_socks5_udp);
+
+            // 匿名客户端不保留映射, 每次连接都重新监听
+            let name = Some(config.name.clone()).filter(|name| name != "anonymous");
+
+            let bind = match (name, ttl) {
+                (name, Some(ttl)) => {
+                    Poto::Bind(Bind::Expiring(name, client_addr, visit_addr.clone(), ttl))
+                }
+                (None, None) => Poto::Bind(Bind::Setup(client_addr, visit_addr.clone())),
+                (Some(name), None) => {
+                    Poto::Bind(Bind::Named(name, client_addr, visit_addr.clone()))
+                }
+            }
             .bytes();
 
+            if let Some(token) = token {
+                if let Err(e) = stream.send_packet(&Poto::Token(token).bytes()).await {
+                    log::error!("failed to send token to server err={}", e);
+                    return Err(e);
+                }
+            }
+
             if let Err(e) = stream.send_packet(&bind).await {
                 log::error!("failed to send listen message to server err={}", e);
                 return Err(e);
             }
 
-            let message = match stream.recv_packet().await {
+            let message = match stream.recv_packet_limit(max_frame_size).await {
                 Ok(packet) => packet.try_poto(),
                 Err(e) => {
                     log::error!("the listen message was sent successfully, but the server seems to have an error err={}", e);
@@ -150,7 +266,7 @@ where
                         return Err(e);
                     };
 
-                    let configured = stream.recv_packet().await;
+                    let configured = stream.recv_packet_limit(max_frame_size).await;
 
                     if let Err(e) = configured.as_ref() {
                         log::warn!("server error {}", e);
@@ -183,14 +299,26 @@ where
                         copy_cfg,
                         processor,
                         connector_provider,
-                    ))
+                        mux,
+                        max_frame_size,
+                    )
+                    .with_balancer(balancer)
+                    .with_tenant_key(tenant_key)
+                    .with_forward_strategy(forward_strategy))
                 }
+                // 旧版本服务端, 没有错误码
                 Poto::Bind(Bind::Failed(fail)) => {
+                    let err: crate::Error = Kind::Refused(ErrorCode::Generic, Some(fail)).into();
                     log::error!(
                         "an error occurred while creating the listener on the server {}",
-                        fail,
+                        err
                     );
-                    Err(Kind::Message(fail).into())
+                    Err(err)
+                }
+                Poto::Error(code, detail) => {
+                    let err: crate::Error = Kind::Refused(code, detail).into();
+                    log::error!("the server refused the connection {}", err);
+                    Err(err)
                 }
                 message => {
                     log::error!(
@@ -208,7 +336,7 @@ impl<P, C, S> PenetrateClient<P, C, S>
 where
     P: Provider<Socket, Output = BoxedFuture<S>> + Send + Sync + 'static,
     C: Provider<Socket, Output = BoxedFuture<Route<S>>> + Send + Sync + 'static,
-    S: Stream + Send + 'static,
+    S: Stream + From<FusoStream> + Send + 'static,
 {
     pub fn new(
         socket: (Address, Socket),
@@ -216,26 +344,134 @@ where
         config: Config,
         processor: Processor<ClientProvider<P>, S, ()>,
         connector_provider: Arc<C>,
+        mux: bool,
+        max_frame_size: usize,
     ) -> Self {
         let (reader, writer) = io::split(conn);
 
-        let fut1 = Box::pin(Self::register_server_handle(reader.clone()));
+        let fut1 = Box::pin(Self::register_server_handle(
+            reader.clone(),
+            writer.clone(),
+            max_frame_size,
+        ));
         let fut2 = Box::pin(Self::guard_server_heartbeat(
             writer.clone(),
             config.maximum_wait,
         ));
 
+        let mut futures: Vec<BoxedFuture<State>> = vec![fut1, fut2];
+
+        if mux {
+            match socket.0.select(&Socket::tcp(0)) {
+                Ok(server_socket) => futures.push(Box::pin(Self::establish_mux(
+                    processor.clone(),
+                    server_socket,
+                    config.maximum_wait,
+                    Duration::ZERO,
+                    max_frame_size,
+                ))),
+                Err(e) => log::warn!("unable to establish mux, {}", e),
+            }
+        }
+
         Self {
             forward: socket,
             processor,
             config,
             connector_provider,
+            balancer: None,
             reader: reader.clone(),
             writer: writer.clone(),
-            futures: vec![fut1, fut2],
+            futures,
+            mux: None,
+            mux_retry: if mux { Some(MUX_RETRY_DELAY) } else { None },
+            tenant_key: None,
+            forward_strategy: ForwardStrategy::default(),
+            max_frame_size,
         }
     }
 
+    /// 默认转发目标有多个时由它选择目标
+    pub fn with_balancer(mut self, balancer: Option<Arc<Balancer>>) -> Self {
+        self.balancer = balancer;
+        self
+    }
+
+    /// 映射的连接在转发之前使用该密钥加密
+    pub fn with_tenant_key(mut self, tenant_key: Option<TenantKey>) -> Self {
+        self.tenant_key = tenant_key;
+        self
+    }
+
+    /// 映射的连接与转发目标之间的复制方式
+    pub fn with_forward_strategy(mut self, strategy: ForwardStrategy) -> Self {
+        self.forward_strategy = strategy;
+        self
+    }
+
+    /// 多路复用不可用时等待一段时间后重新建立, 期间为每个映射创建单独的连接
+    fn retry_mux(&mut self) -> Option<BoxedFuture<State>> {
+        let delay = self.mux_retry?;
+
+        self.mux_retry = Some((delay * 2).min(MUX_RETRY_MAX_DELAY));
+
+        match self.forward.0.select(&Socket::tcp(0)) {
+            Ok(server_socket) => Some(Box::pin(Self::establish_mux(
+                self.processor.clone(),
+                server_socket,
+                self.config.maximum_wait,
+                delay,
+                self.max_frame_size,
+            ))),
+            Err(e) => {
+                log::warn!("unable to establish mux, {}", e);
+                None
+            }
+        }
+    }
+
+    /// 服务端不支持时不会回复, 超时后继续为每个映射创建连接
+    async fn establish_mux(
+        processor: Processor<ClientProvider<P>, S, ()>,
+        server_socket: Socket,
+        timeout: Duration,
+        delay: Duration,
+        max_frame_size: usize,
+    ) -> crate::Result<State> {
+        if !delay.is_zero() {
+            time::sleep(delay).await;
+        }
+
+        let connector = processor.clone();
+
+        let establish = async move {
+            let conn = connector.call(server_socket).await?;
+            let mut conn = processor.decorate(conn).await?;
+
+            conn.send_packet(&Poto::Mux.bytes()).await?;
+
+            match conn.recv_packet_limit(max_frame_size).await?.try_poto()? {
+                Poto::Mux => Mux::new(conn),
+                poto => Err(Kind::Unexpected(format!("{}", poto)).into()),
+            }
+        };
+
+        let (mux, driver) = match time::wait_for(timeout, establish).await {
+            Ok(Ok(mux)) => mux,
+            Ok(Err(e)) | Err(e) => {
+                log::warn!(
+                    "the server does not support mux, use separate connections {}",
+                    e
+                );
+                return Ok(State::Demux);
+            }
+        };
+
+        log::info!("mux established");
+
+        Ok(State::Mux(mux, Box::pin(driver)))
+    }
+
     async fn guard_server_heartbeat(
         mut writer: WriteHalf<S>,
         timeout: Duration,
@@ -252,24 +488,57 @@ where
         }
     }
 
-    async fn register_server_handle(mut reader: ReadHalf<S>) -> crate::Result<State> {
+    async fn register_server_handle(
+        mut reader: ReadHalf<S>,
+        mut writer: WriteHalf<S>,
+        max_frame_size: usize,
+    ) -> crate::Result<State> {
         loop {
-            let message = match reader.recv_packet().await {
-                Ok(packet) => packet.try_poto(),
+            let packet = match reader.recv_packet_limit(max_frame_size).await {
+                Ok(packet) => packet,
                 Err(e) => return Ok(State::Error(e)),
             };
 
-            if let Err(e) = message.as_ref() {
-                log::warn!("server error {}", e);
-                return Ok(State::Error(unsafe { message.unwrap_err_unchecked() }));
-            }
-
-            let message = unsafe { message.unwrap_unchecked() };
+            // 包长度完整时只丢弃无法解析的消息, 不回复以免双方互相发送错误
+            let message = match packet.try_poto() {
+                Ok(message) => message,
+                Err(e) => {
+                    log::warn!("the server sent an invalid packet {}", e);
+                    continue;
+                }
+            };
 
             match message {
                 Poto::Map(id, socket) => {
                     break Ok(State::Map(id, socket));
                 }
+                Poto::Ping => {
+                    if let Err(e) = writer.send_packet(&Poto::Pong.bytes()).await {
+                        log::error!("failed to reply heartbeat to server err={}", e);
+                        return Ok(State::Error(e));
+                    }
+                }
+                Poto::Stats(id, rx_bytes, tx_bytes) => {
+                    log::debug!(
+                        conv = id, rx_bytes = rx_bytes, tx_bytes = tx_bytes;
+                        "mapping traffic statistics"
+                    );
+                }
+                Poto::Reset(id) => {
+                    log::info!(conv = id; "the mapping was reset");
+                }
+                Poto::Drain => {
+                    log::info!("the server stopped accepting visitors, draining the mapping");
+                }
+                Poto::Error(code @ (ErrorCode::Expired | ErrorCode::Drained), detail) => {
+                    let err: crate::Error = Kind::Refused(code, detail).into();
+                    log::info!("{}", err);
+                    return Ok(State::Error(err));
+                }
+                Poto::Error(code, detail) => {
+                    let err: crate::Error = Kind::Refused(code, detail).into();
+                    log::warn!("server error {}", err);
+                }
                 message => {
                     log::trace!("received server message {:?}", message);
                 }
@@ -282,18 +551,32 @@ where
         id: u32,
         server_socket: Socket,
         target_socket: Socket,
+        balancer: Option<Arc<Balancer>>,
     ) -> BoxedFuture<State> {
         let s1_connector = self.processor.clone();
-        let s2_connector = self.connector_provider.clone();
+        let s2_connector = TargetConnector(self.connector_provider.clone(), balancer);
         let maximum_wait = self.config.maximum_wait.clone();
 
         let server_fut = async_connect!(self.writer, s1_connector, id, server_socket);
         let client_fut = async_connect!(self.writer, s2_connector, id, target_socket);
         let server_writer = self.writer.clone();
         let processor = self.processor.clone();
+        let mux = self.mux.clone().filter(|mux| !mux.is_closed());
+        let tenant_key = self.tenant_key.clone();
+        let strategy = self.forward_strategy;
 
         let future = async move {
             let mut server_writer = server_writer;
+
+            // 多路复用时连接转发目标后直接打开逻辑连接, conv即为映射的id
+            if let Some(mux) = mux {
+                let (s2, assigned) = time::wait_for(maximum_wait, client_fut).await??;
+                let s1 = S::from(mux.open(id)?.into_boxed_stream());
+                let s1 = Self::tenant(s1, tenant_key);
+
+                return Ok(State::Ready(Self::forward(id, s1, s2, assigned, strategy)));
+            }
+
             let result =
                 time::wait_for(maximum_wait, join::join_output(server_fut, client_fut)).await;
 
@@ -309,31 +592,84 @@ where
             let poto = Poto::Map(id, target_socket).bytes();
 
             if let Err(e) = s1.send_packet(&poto).await {
-                let message = Poto::MapError(id, e.to_string()).bytes();
+                let message = Poto::map_failed(id, &e, ErrorCode::Generic).bytes();
                 if let Err(e) = server_writer.send_packet(&message).await {
                     Ok(State::Error(e))
                 } else {
                     Err(e)
                 }
             } else {
-                Ok(State::Ready({
-                    match s2 {
-                        Route::Forward(s2) => Box::pin(io::forward(s1, s2)),
-                        Route::Provider(s2) => s2.call(s1),
-                    }
-                }))
+                let (s2, assigned) = s2;
+                let s1 = Self::tenant(s1, tenant_key);
+                Ok(State::Ready(Self::forward(id, s1, s2, assigned, strategy)))
             }
         };
 
         Box::pin(future)
     }
+
+    /// 设置了密钥时, 映射的连接在转发之前使用该密钥加密
+    fn tenant(s1: S, tenant_key: Option<TenantKey>) -> S {
+        match tenant_key {
+            Some(key) => S::from(key.wrap(s1)),
+            None => s1,
+        }
+    }
+
+    fn forward(
+        id: u32,
+        s1: S,
+        s2: Route<S>,
+        assigned: Option<Assigned>,
+        strategy: ForwardStrategy,
+    ) -> BoxedFuture<()> {
+        let forward = match s2 {
+            Route::Forward(s2) => Box::pin(io::forward_with_strategy(
+                s1,
+                s2,
+                None,
+                Default::default(),
+                strategy,
+            )),
+            Route::Provider(s2) => s2.call(s1),
+        };
+
+        Box::pin(async move {
+            let _assigned = assigned;
+            let result = forward.await;
+            let closed = io::Closed::of(&result);
+            match result.as_ref() {
+                Ok(()) => log::debug!(conv = id; "forward finished ({})", closed),
+                Err(e) if encryption::is_auth_failure(e) => {
+                    log::warn!(conv = id; "forward finished ({}) {}", closed, e)
+                }
+                Err(e) => log::debug!(conv = id; "forward finished ({}) {}", closed, e),
+            }
+            result
+        })
+    }
+}
+
+impl<C> TargetConnector<C> {
+    async fn call<S>(self, socket: Socket) -> crate::Result<(Route<S>, Option<Assigned>)>
+    where
+        C: Provider<Socket, Output = BoxedFuture<Route<S>>>,
+    {
+        match self.1 {
+            None => Ok((self.0.call(socket).await?, None)),
+            Some(balancer) => {
+                let (route, assigned) = balancer.connect(self.0, socket).await?;
+                Ok((route, Some(assigned)))
+            }
+        }
+    }
 }
 
 impl<CF, C, S> Generator for PenetrateClient<CF, C, S>
 where
     CF: Provider<Socket, Output = BoxedFuture<S>> + Send + Sync + 'static,
     C: Provider<Socket, Output = BoxedFuture<Route<S>>> + Send + Sync + 'static,
-    S: Stream + Send + 'static,
+    S: Stream + From<FusoStream> + Send + 'static,
 {
     type Output = Option<BoxedFuture<()>>;
     fn poll_generate(
@@ -356,16 +692,19 @@ where
                     log::debug!("{}", target_socket);
 
                     let (server, local) = self.forward.clone();
-                    let target_socket = target_socket.default_or(local);
+                    let balancer = self.balancer.clone().filter(|_| target_socket.is_default());
+                    let target_socket = target_socket.default_or(local.clone());
                     let server_writer = self.writer.clone();
 
-                    let future = match server.select(&target_socket) {
+                    let future = match check_unix_target(&self.config, &local, &target_socket)
+                        .and_then(|()| server.select(&target_socket))
+                    {
                         Ok(server_socket) => {
-                            self.start_async_forward(id, server_socket, target_socket)
+                            self.start_async_forward(id, server_socket, target_socket, balancer)
                         }
                         Err(e) => Box::pin(async move {
                             let mut server_writer = server_writer;
-                            let poto = Poto::MapError(id, e.to_string()).bytes();
+                            let poto = Poto::map_failed(id, &e, ErrorCode::Generic).bytes();
                             match server_writer.send_packet(&poto).await {
                                 Ok(()) => Ok(State::Leave(target_socket)),
                                 Err(e) => Ok(State::Error(e)),
@@ -373,7 +712,11 @@ where
                         }),
                     };
 
-                    let fut2 = Box::pin(Self::register_server_handle(self.reader.clone()));
+                    let fut2 = Box::pin(Self::register_server_handle(
+                        self.reader.clone(),
+                        self.writer.clone(),
+                        self.max_frame_size,
+                    ));
 
                     futures.push(future);
                     futures.push(fut2);
@@ -382,6 +725,27 @@ where
                     self.futures.extend(futures);
                     return Poll::Ready(Ok(Some(fut)));
                 }
+                Poll::Ready(Ok(State::Mux(mux, driver))) => {
+                    self.mux = Some(mux);
+                    self.mux_retry = self.mux_retry.map(|_| MUX_RETRY_DELAY);
+
+                    futures.push(Box::pin(async move {
+                        if let Err(e) = driver.await {
+                            log::warn!("mux error {}", e);
+                        }
+
+                        Ok(State::Demux)
+                    }));
+                }
+                Poll::Ready(Ok(State::Demux)) => {
+                    if self.mux.take().is_some() {
+                        log::warn!("mux closed, use separate connections");
+                    }
+
+                    if let Some(future) = self.retry_mux() {
+                        futures.push(future);
+                    }
+                }
                 Poll::Ready(Err(e)) => {
                     log::trace!("{:?}", e);
                 }
@@ -393,3 +757,88 @@ where
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{check_unix_target, Config};
+    use crate::{Addr, Platform, Socket};
+
+    #[test]
+    fn test_decode_old_config() {
+        let config = Config {
+            name: String::from("anonymous"),
+            maximum_wait: Duration::from_secs(10),
+            heartbeat_delay: Duration::from_secs(30),
+            enable_kcp: false,
+            enable_socks5: true,
+            socks_username: None,
+            socks_password: None,
+            enable_socks5_udp: false,
+            version: String::from("1.0.0"),
+            platform: Platform::default(),
+            sni_routes: Vec::new(),
+            host_routes: Vec::new(),
+            host_not_found: false,
+            forwarded_for: false,
+            services: Vec::new(),
+        };
+
+        let payload = bincode::serialize(&config).unwrap();
+        let appended = bincode::serialize(&super::Appended::default()).unwrap();
+
+        // 旧版本的客户端发送的配置在 `platform` 之后结束
+        let old = &payload[..payload.len() - appended.len()];
+        let decoded = Config::decode(old).unwrap();
+
+        assert_eq!(decoded.name, config.name);
+        assert!(decoded.enable_socks5);
+        assert_eq!(decoded.version, config.version);
+        assert!(decoded.services.is_empty());
+
+        let mut config = config;
+        config.services = vec![(String::from("ssh"), 2222, Addr::from(([127, 0, 0, 1], 22)))];
+
+        let payload = bincode::serialize(&config).unwrap();
+        assert_eq!(Config::decode(&payload).unwrap().services, config.services);
+
+        // 缺少的不只是新增的字段时仍然失败
+        assert!(Config::decode(&payload[..8]).is_err());
+    }
+
+    #[test]
+    fn test_unix_target() {
+        let config = Config {
+            name: String::from("anonymous"),
+            maximum_wait: Duration::from_secs(10),
+            heartbeat_delay: Duration::from_secs(30),
+            enable_kcp: false,
+            enable_socks5: false,
+            socks_username: None,
+            socks_password: None,
+            enable_socks5_udp: false,
+            version: String::new(),
+            platform: Platform::default(),
+            sni_routes: vec![(String::from("a.example.com"), Addr::unix("/run/a.sock"))],
+            host_routes: Vec::new(),
+            host_not_found: false,
+            forwarded_for: false,
+            services: vec![(String::from("b"), 8080, Addr::unix("/run/b.sock"))],
+        };
+
+        let local = Socket::tcp(Addr::unix("/run/local.sock"));
+
+        for path in ["/run/local.sock", "/run/a.sock", "/run/b.sock"] {
+            let target = Socket::tcp(Addr::unix(path));
+            assert!(check_unix_target(&config, &local, &target).is_ok());
+        }
+
+        // 服务端不能指定客户端没有配置的unix域套接字
+        let target = Socket::tcp(Addr::unix("/var/run/docker.sock"));
+        assert!(check_unix_target(&config, &local, &target).is_err());
+
+        let target = Socket::tcp(([127, 0, 0, 1], 22));
+        assert!(check_unix_target(&config, &local, &target).is_ok());
+    }
+}
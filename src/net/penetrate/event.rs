@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use crate::{io::Closed, Address};
+
+/// 映射的生命周期事件, `name` 为客户端名称, 未命名的客户端为 `anonymous`
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// 客户端完成配置, 开始监听访问端口
+    Open {
+        name: String,
+        peer_addr: Address,
+        visit_addr: Address,
+    },
+    /// 收到访问者连接, 分配conv并通知客户端建立映射
+    Map {
+        conv: u32,
+        name: String,
+        peer_addr: Address,
+    },
+    /// 客户端完成映射, 开始转发数据
+    Forward {
+        conv: u32,
+        name: String,
+        peer_addr: Address,
+    },
+    /// 转发结束, `closed` 区分正常关闭, 重置, 超时与其他错误
+    Finish {
+        conv: u32,
+        name: String,
+        closed: Closed,
+        rx_bytes: u64,
+        tx_bytes: u64,
+    },
+    /// 客户端断开, 映射结束
+    Close { name: String, peer_addr: Address },
+    /// 开启 `trace_timing` 时在转发结束后产生, 记录映射各阶段的耗时
+    Timing {
+        conv: u32,
+        name: String,
+        peer_addr: Address,
+        /// 访问者连接到识别出转发目标, 包含socks等协议的握手与认证
+        handshake: Duration,
+        /// 通知客户端到客户端建立映射, 包含客户端连接目标的时间
+        map: Duration,
+        /// 映射完成到转发任务开始执行
+        spawn: Duration,
+        /// 开始转发到收到目标的首个字节, 目标没有返回数据时为None
+        first_byte: Option<Duration>,
+    },
+}
+
+/// 事件发送端, 通道已满或接收端被丢弃时直接丢弃事件, 不阻塞转发
+#[derive(Debug, Clone, Default)]
+pub struct Events(Option<async_channel::Sender<Event>>);
+
+impl Events {
+    pub fn new(sender: async_channel::Sender<Event>) -> Self {
+        Self(Some(sender))
+    }
+
+    pub fn emit(&self, event: Event) {
+        if let Some(sender) = self.0.as_ref() {
+            if let Err(e) = sender.try_send(event) {
+                log::trace!("drop event {:?}", e.into_inner());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, Events};
+    use crate::{Address, Socket};
+
+    #[test]
+    fn test_emit_without_receiver() {
+        let (sender, receiver) = async_channel::bounded(1);
+        let events = Events::new(sender);
+
+        let close = || Event::Close {
+            name: String::from("anonymous"),
+            peer_addr: Address::One(Socket::tcp(6722)),
+        };
+
+        events.emit(close());
+        events.emit(close());
+        assert_eq!(receiver.len(), 1);
+
+        drop(receiver);
+        events.emit(close());
+
+        Events::default().emit(close());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{pin::Pin, sync::Arc};
+
+use crate::{
+    ext::{AsyncReadExt, AsyncWriteExt},
+    DecorateProvider, FusoStream, Kind, Provider,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+type Handshake = dyn Provider<FusoStream, Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>>
+    + Send
+    + Sync
+    + 'static;
+
+/// 握手之前交换的固定数据, 用于伪装成其他协议(如ssh的问候)或作为简单的端口敲门
+#[derive(Debug, Default, Clone)]
+pub struct Banner {
+    /// 服务端连接建立后立即发送, 客户端读取后校验
+    greeting: Option<Vec<u8>>,
+    /// 客户端连接建立后立即发送, 服务端读取后校验, 不一致时断开
+    knock: Option<Vec<u8>>,
+}
+
+/// 在握手之前先交换 `Banner`, 两者都为空时直接握手, 不读写任何数据
+pub enum PenetrateBannerHandshake {
+    Server(Banner, Arc<Handshake>),
+    Client(Banner, Arc<Handshake>),
+}
+
+impl Banner {
+    pub fn greeting(mut self, greeting: Option<Vec<u8>>) -> Self {
+        self.greeting = greeting.filter(|greeting| !greeting.is_empty());
+        self
+    }
+
+    pub fn knock(mut self, knock: Option<Vec<u8>>) -> Self {
+        self.knock = knock.filter(|knock| !knock.is_empty());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.greeting.is_none() && self.knock.is_none()
+    }
+}
+
+impl PenetrateBannerHandshake {
+    pub fn server<H>(banner: Banner, handshake: H) -> Self
+    where
+        H: Provider<
+                FusoStream,
+                Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        Self::Server(banner, Arc::new(handshake))
+    }
+
+    pub fn client<H>(banner: Banner, handshake: H) -> Self
+    where
+        H: Provider<
+                FusoStream,
+                Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        Self::Client(banner, Arc::new(handshake))
+    }
+}
+
+async fn expect(stream: &mut FusoStream, expected: &[u8], what: &str) -> crate::Result<()> {
+    let mut buf = vec![0u8; expected.len()];
+
+    stream.read_exact(&mut buf).await?;
+
+    if buf != expected {
+        log::debug!("unexpected {} `{}`", what, buf.escape_ascii());
+        return Err(Kind::Unexpected(format!("unexpected {}", what)).into());
+    }
+
+    Ok(())
+}
+
+impl Provider<FusoStream> for PenetrateBannerHandshake {
+    type Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>;
+
+    fn call(&self, stream: FusoStream) -> Self::Output {
+        let (banner, handshake, server) = match self {
+            PenetrateBannerHandshake::Server(banner, handshake) => (banner, handshake, true),
+            PenetrateBannerHandshake::Client(banner, handshake) => (banner, handshake, false),
+        };
+
+        let banner = banner.clone();
+        let handshake = handshake.clone();
+
+        Box::pin(async move {
+            let mut stream = stream;
+
+            // 各自先发送再读取, 双方不需要等待对方
+            if server {
+                if let Some(greeting) = banner.greeting.as_ref() {
+                    stream.write_all(greeting).await?;
+                }
+
+                if let Some(knock) = banner.knock.as_ref() {
+                    expect(&mut stream, knock, "knock").await?;
+                }
+            } else {
+                if let Some(knock) = banner.knock.as_ref() {
+                    stream.write_all(knock).await?;
+                }
+
+                if let Some(greeting) = banner.greeting.as_ref() {
+                    expect(&mut stream, greeting, "greeting").await?;
+                }
+            }
+
+            handshake.call(stream).await
+        })
+    }
+}
+
+/// 解析命令行中的banner, 支持 `\r`, `\n`, `\t`, `\0`, `\\` 与 `\xHH` 转义
+pub fn parse_banner(banner: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid escape in banner `{}`", banner);
+
+    let mut bytes = Vec::with_capacity(banner.len());
+    let mut chars = banner.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        let byte = match chars.next().ok_or_else(invalid)? {
+            'r' => b'\r',
+            'n' => b'\n',
+            't' => b'\t',
+            '0' => b'\0',
+            '\\' => b'\\',
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 {
+                    return Err(invalid());
+                }
+                u8::from_str_radix(&hex, 16).map_err(|_| invalid())?
+            }
+            _ => return Err(invalid()),
+        };
+
+        bytes.push(byte);
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::{
+        ext::{AsyncReadExt, AsyncWriteExt},
+        MemoryStream, Provider, ToBoxStream,
+    };
+
+    use super::super::tests::Plain;
+    use super::{parse_banner, Banner, PenetrateBannerHandshake};
+
+    #[test]
+    fn test_parse_banner() {
+        assert_eq!(
+            parse_banner("SSH-2.0-OpenSSH_8.9\\r\\n").unwrap(),
+            b"SSH-2.0-OpenSSH_8.9\r\n"
+        );
+        assert_eq!(parse_banner("\\x16\\x03\\\\").unwrap(), b"\x16\x03\\");
+        assert!(parse_banner("\\x1").is_err());
+        assert!(parse_banner("\\q").is_err());
+    }
+
+    #[test]
+    fn test_banner_handshake() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let client_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+                let server_addr = SocketAddr::from(([127, 0, 0, 1], 6722));
+
+                let banner = Banner::default()
+                    .greeting(Some(b"SSH-2.0-OpenSSH_8.9\r\n".to_vec()))
+                    .knock(Some(b"knock".to_vec()));
+
+                let (c, s) = MemoryStream::pair(client_addr, server_addr);
+                let (server, client) = tokio::join!(
+                    PenetrateBannerHandshake::server(banner.clone(), Plain)
+                        .call(s.into_boxed_stream()),
+                    PenetrateBannerHandshake::client(banner.clone(), Plain)
+                        .call(c.into_boxed_stream())
+                );
+
+                let (mut server, mut client) = (server.unwrap().0, client.unwrap().0);
+
+                client.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+
+                // 没有发送敲门数据的客户端被断开
+                let (mut c, s) = MemoryStream::pair(client_addr, server_addr);
+                c.write_all(b"\x05\x01\x00\x05\x00").await.unwrap();
+
+                let server = PenetrateBannerHandshake::server(banner, Plain)
+                    .call(s.into_boxed_stream())
+                    .await;
+
+                assert!(server.is_err());
+            });
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{
+    ext::AsyncWriteExt,
+    io::{ReadHalf, WriteHalf},
+    time, AsyncRead, AsyncWrite, DecorateProvider, FusoStream, NetSocket, Provider, ReadBuf,
+    ToBoxStream,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+type Handshake = dyn Provider<FusoStream, Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>>
+    + Send
+    + Sync
+    + 'static;
+
+/// 回复伪装内容的最长等待时间, 外层的握手超时需要多留出这段时间
+pub const DECOY_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 仿照nginx的404页面, 可以作为 `decoy` 使用
+pub const DECOY_NOT_FOUND: &[u8] = b"HTTP/1.1 404 Not Found\r\n\
+Server: nginx\r\n\
+Content-Type: text/html\r\n\
+Content-Length: 146\r\n\
+Connection: close\r\n\
+\r\n\
+<html>\r\n\
+<head><title>404 Not Found</title></head>\r\n\
+<body>\r\n\
+<center><h1>404 Not Found</h1></center>\r\n\
+<hr><center>nginx</center>\r\n\
+</body>\r\n\
+</html>\r\n";
+
+/// 不是fuso客户端的连接(端口扫描, 浏览器等)握手失败或超时时回复伪装内容后关闭,
+/// 让端口看起来像普通的web服务. 只在握手还没有回复过任何数据时回复,
+/// 与是否使用websocket无关
+pub struct PenetrateDecoyHandshake {
+    decoy: Arc<[u8]>,
+    timeout: Option<Duration>,
+    handshake: Arc<Handshake>,
+}
+
+/// 握手时保留写入的一半, 握手失败后仍然可以回复伪装内容
+struct Decoyed {
+    reader: ReadHalf<FusoStream>,
+    writer: WriteHalf<FusoStream>,
+    written: Arc<AtomicBool>,
+}
+
+impl PenetrateDecoyHandshake {
+    pub fn server<H>(decoy: Vec<u8>, handshake: H) -> Self
+    where
+        H: Provider<
+                FusoStream,
+                Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            decoy: Arc::from(decoy),
+            timeout: None,
+            handshake: Arc::new(handshake),
+        }
+    }
+
+    /// 握手的最长时间, 超时后同样回复伪装内容. 大多数探测(ssh问候, tls的ClientHello等)
+    /// 会让握手一直等待数据, 只靠外层的超时取消时来不及回复
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+}
+
+impl Provider<FusoStream> for PenetrateDecoyHandshake {
+    type Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>;
+
+    fn call(&self, stream: FusoStream) -> Self::Output {
+        let decoy = self.decoy.clone();
+        let timeout = self.timeout;
+        let handshake = self.handshake.clone();
+
+        Box::pin(async move {
+            let (reader, writer) = crate::io::split(stream);
+            let mut retained = writer.clone();
+            let written = Arc::new(AtomicBool::new(false));
+
+            let decoyed = Decoyed {
+                reader,
+                writer,
+                written: written.clone(),
+            };
+
+            let handshake = handshake.call(decoyed.into_boxed_stream());
+
+            let result = match timeout {
+                None => handshake.await,
+                Some(timeout) => time::wait_for(timeout, handshake)
+                    .await
+                    .and_then(|result| result),
+            };
+
+            let err = match result {
+                Ok(output) => return Ok(output),
+                Err(e) => e,
+            };
+
+            if !written.load(Ordering::Acquire) {
+                log::debug!("handshake failed, reply decoy {}", err);
+                let _ = time::wait_for(DECOY_WRITE_TIMEOUT, async move {
+                    retained.write_all(&decoy).await?;
+                    retained.close().await
+                })
+                .await;
+            }
+
+            Err(err)
+        })
+    }
+}
+
+impl NetSocket for Decoyed {
+    fn peer_addr(&self) -> crate::Result<crate::Address> {
+        self.reader.peer_addr()
+    }
+
+    fn local_addr(&self) -> crate::Result<crate::Address> {
+        self.reader.local_addr()
+    }
+}
+
+impl AsyncRead for Decoyed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Decoyed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        self.written.store(true, Ordering::Release);
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.writer).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use crate::{
+        ext::{AsyncReadExt, AsyncWriteExt},
+        DecorateProvider, FusoStream, MemoryStream, Provider, ToBoxStream,
+    };
+
+    use super::super::tests::Plain;
+    use super::{BoxedFuture, PenetrateDecoyHandshake, DECOY_NOT_FOUND};
+
+    /// 读取到的不是fuso的握手, 没有回复任何数据就失败
+    struct Reject;
+
+    impl Provider<FusoStream> for Reject {
+        type Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>;
+
+        fn call(&self, mut stream: FusoStream) -> Self::Output {
+            Box::pin(async move {
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await?;
+                Err(crate::Kind::Unexpected(String::from("not fuso")).into())
+            })
+        }
+    }
+
+    /// 像rsa握手一样等待读取更多的数据
+    struct Stall;
+
+    impl Provider<FusoStream> for Stall {
+        type Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>;
+
+        fn call(&self, mut stream: FusoStream) -> Self::Output {
+            Box::pin(async move {
+                let mut buf = [0u8; 1024];
+                stream.read_exact(&mut buf).await?;
+                Ok((stream, None))
+            })
+        }
+    }
+
+    #[test]
+    fn test_decoy() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let client_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+                let server_addr = SocketAddr::from(([127, 0, 0, 1], 6722));
+
+                let server = PenetrateDecoyHandshake::server(DECOY_NOT_FOUND.to_vec(), Reject);
+
+                let (mut c, s) = MemoryStream::pair(client_addr, server_addr);
+                c.write_all(b"HEAD / HTTP/1.0\r\n\r\n").await.unwrap();
+
+                assert!(server.call(s.into_boxed_stream()).await.is_err());
+
+                let mut buf = vec![0u8; DECOY_NOT_FOUND.len()];
+                c.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, DECOY_NOT_FOUND);
+
+                // 握手成功后正常读写
+                let (mut c, s) = MemoryStream::pair(client_addr, server_addr);
+                c.write_all(b"\x05\x01\x00\x05").await.unwrap();
+
+                let server = PenetrateDecoyHandshake::server(DECOY_NOT_FOUND.to_vec(), Plain);
+                let (mut s, _) = server.call(s.into_boxed_stream()).await.unwrap();
+                s.write_all(b"fuso").await.unwrap();
+
+                let mut buf = [0u8; 4];
+                c.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"fuso");
+            });
+    }
+
+    #[test]
+    fn test_decoy_timeout() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let client_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+                let server_addr = SocketAddr::from(([127, 0, 0, 1], 6722));
+
+                let server = PenetrateDecoyHandshake::server(DECOY_NOT_FOUND.to_vec(), Stall)
+                    .timeout(Some(Duration::from_millis(100)));
+
+                // 发送问候后等待回复的探测同样收到伪装内容
+                let (mut c, s) = MemoryStream::pair(client_addr, server_addr);
+                c.write_all(b"SSH-2.0-OpenSSH_8.9\r\n").await.unwrap();
+
+                let handshake = server.call(s.into_boxed_stream());
+                let handshake = tokio::time::timeout(Duration::from_secs(5), handshake);
+                assert!(handshake.await.unwrap().is_err());
+
+                let mut buf = vec![0u8; DECOY_NOT_FOUND.len()];
+                c.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, DECOY_NOT_FOUND);
+            });
+    }
+}
@@ -1,29 +1,62 @@
+#[cfg(feature = "fuso-websocket")]
+mod websocket;
+#[cfg(feature = "fuso-websocket")]
+pub use websocket::*;
+#[cfg(feature = "fuso-tls")]
+mod tls;
+#[cfg(feature = "fuso-tls")]
+pub use tls::*;
+mod banner;
+pub use banner::*;
+mod decoy;
+pub use decoy::*;
+
 use std::pin::Pin;
 
 use rsa::pkcs8::{DecodePublicKey, EncodePublicKey};
 
 use crate::{
-    compress::Lz4Compress,
-    encryption::{AESEncryptor, RSAEncryptor},
+    compress::{Compression, Lz4BlockCompress, Lz4Compress},
+    encryption::{AESEncryptor, Cipher, RSAEncryptor},
     ext::{AsyncReadExt, AsyncWriteExt},
-    DecorateProvider, FusoStream, Provider, Stream, ToBoxStream,
+    protocol::{
+        make_packet, AsyncRecvPacket, AsyncSendPacket, ErrorCode, Packet, Poto, ToBytes, TryToPoto,
+    },
+    AsyncRead, AsyncWrite, CompressErr, DecorateProvider, EncryptionErr, FusoStream, Kind,
+    Provider, Stream, ToBoxStream,
 };
 
+#[cfg(feature = "fuso-crypt-aead")]
+use crate::encryption::{AESGcmEncryptor, ChaCha20Encryptor};
+
 type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
 
+/// 握手扩展的版本, 附加在协商数据加密方式的 `Hello` 之后, 旧版本解析时忽略多余的字节.
+/// 双方都携带时才交换控制连接的密钥并协商加密方式, 否则控制连接与旧版本一样使用rsa
+const HANDSHAKE_VERSION: u8 = 1;
+
+/// 服务端与客户端各自携带支持的数据加密方式, 压缩方式与控制连接的加密方式,
+/// 握手时分别协商出双方都支持的一种. rsa只用于交换密钥,
+/// 控制连接与数据连接使用不同的密钥. 控制连接的加密方式为空或对端不支持时,
+/// 控制连接继续使用rsa. 控制连接传输token, 不允许使用 `none`
 pub enum PenetrateRsaAndAesHandshake {
-    Server,
-    Client,
+    Server(Vec<Cipher>, Vec<Compression>, Vec<Cipher>),
+    Client(Vec<Cipher>, Vec<Compression>, Vec<Cipher>),
 }
 
 pub struct PenetrateAesAndLz4Decorator {
     iv: [u8; 16],
     key: [u8; 16],
+    cipher: Cipher,
+    compression: Compression,
 }
 
 impl PenetrateRsaAndAesHandshake {
     pub fn server_handshake<S>(
         client: S,
+        ciphers: Vec<Cipher>,
+        compressions: Vec<Compression>,
+        control_ciphers: Vec<Cipher>,
     ) -> BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>
     where
         S: Stream + Unpin + Send + 'static,
@@ -60,20 +93,62 @@ impl PenetrateRsaAndAesHandshake {
 
             fuso_stream.read_exact(&mut key).await?;
 
-            log::trace!("iv: {:?}, key: {:?}", iv, key);
+            let control_ciphers = Self::control_ciphers(control_ciphers);
+
+            let (cipher, extended) =
+                Self::server_negotiate(&mut fuso_stream, &ciphers, !control_ciphers.is_empty())
+                    .await?;
+            let compression =
+                Self::server_negotiate_compression(&mut fuso_stream, &compressions).await?;
+
+            log::trace!(
+                "iv: {:?}, key: {:?}, cipher: {}, compression: {}",
+                iv,
+                key,
+                cipher,
+                compression
+            );
+
+            let decorator = DecorateProvider::wrap(PenetrateAesAndLz4Decorator {
+                iv,
+                key,
+                cipher,
+                compression,
+            });
+
+            if !extended {
+                log::debug!("the client does not negotiate a control cipher, keep rsa");
+                return Ok((fuso_stream.into_boxed_stream(), Some(decorator)));
+            }
+
+            let mut control_iv = [0u8; 16];
+            let mut control_key = [0u8; 16];
+
+            fuso_stream.read_exact(&mut control_iv).await?;
+            fuso_stream.read_exact(&mut control_key).await?;
+
+            let (control_cipher, _) =
+                Self::server_negotiate(&mut fuso_stream, &control_ciphers, false).await?;
+
+            log::trace!("control cipher: {}", control_cipher);
 
             Ok((
-                fuso_stream.into_boxed_stream(),
-                Some(DecorateProvider::wrap(PenetrateAesAndLz4Decorator {
-                    iv,
-                    key,
-                })),
+                encrypt(
+                    fuso_stream.into_inner(),
+                    control_cipher,
+                    control_iv,
+                    control_key,
+                ),
+                Some(decorator),
             ))
         })
     }
 
     pub fn client_handshake<S>(
         stream: S,
+        ciphers: Vec<Cipher>,
+        compressions: Vec<Compression>,
+        control_ciphers: Vec<Cipher>,
     ) -> BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>
     where
         S: Stream + Unpin + Send + 'static,
@@ -113,20 +188,237 @@ impl PenetrateRsaAndAesHandshake {
             iv.fill_with(rand::random);
             key.fill_with(rand::random);
 
-            log::trace!("iv: {:?}, key: {:?}", iv, key);
-
             fuso_stream.write_all(&iv).await?;
             fuso_stream.write_all(&key).await?;
 
+            let control_ciphers = Self::control_ciphers(control_ciphers);
+
+            let (cipher, extended) =
+                Self::client_negotiate(&mut fuso_stream, ciphers, !control_ciphers.is_empty())
+                    .await?;
+            let compression =
+                Self::client_negotiate_compression(&mut fuso_stream, compressions).await?;
+
+            log::trace!(
+                "iv: {:?}, key: {:?}, cipher: {}, compression: {}",
+                iv,
+                key,
+                cipher,
+                compression
+            );
+
+            let decorator = DecorateProvider::wrap(PenetrateAesAndLz4Decorator {
+                iv,
+                key,
+                cipher,
+                compression,
+            });
+
+            if !extended {
+                if !control_ciphers.is_empty() {
+                    log::warn!("the server does not negotiate a control cipher, keep rsa");
+                }
+
+                return Ok((fuso_stream.into_boxed_stream(), Some(decorator)));
+            }
+
+            let mut control_iv = [0u8; 16];
+            let mut control_key = [0u8; 16];
+
+            control_iv.fill_with(rand::random);
+            control_key.fill_with(rand::random);
+
+            fuso_stream.write_all(&control_iv).await?;
+            fuso_stream.write_all(&control_key).await?;
+
+            let (control_cipher, _) =
+                Self::client_negotiate(&mut fuso_stream, control_ciphers, false).await?;
+
+            log::trace!("control cipher: {}", control_cipher);
+
             Ok((
-                fuso_stream.into_boxed_stream(),
-                Some(DecorateProvider::wrap(PenetrateAesAndLz4Decorator {
-                    iv,
-                    key,
-                })),
+                encrypt(
+                    fuso_stream.into_inner(),
+                    control_cipher,
+                    control_iv,
+                    control_key,
+                ),
+                Some(decorator),
             ))
         })
     }
+
+    /// 去掉控制连接的 `none`, 控制连接传输token, 最少也要使用rsa
+    fn control_ciphers(mut ciphers: Vec<Cipher>) -> Vec<Cipher> {
+        if ciphers.contains(&Cipher::None) {
+            log::warn!("the control channel carries the token, ignore cipher none");
+            ciphers.retain(|cipher| *cipher != Cipher::None);
+        }
+
+        ciphers
+    }
+
+    /// 编码 `Hello`, `extend` 时在之后附加握手扩展的版本
+    fn hello(ciphers: Vec<Cipher>, extend: bool) -> crate::Result<Vec<u8>> {
+        let mut data = bincode::serialize(&Poto::Hello(ciphers))?;
+
+        if extend {
+            data.push(HANDSHAKE_VERSION);
+        }
+
+        Ok(make_packet(data).encode())
+    }
+
+    /// 对端是否在 `poto` 之后附加了握手扩展的版本, 旧版本没有
+    fn is_extended(packet: &Packet, poto: &Poto) -> crate::Result<bool> {
+        let len = bincode::serialized_size(poto)? as usize;
+        Ok(packet
+            .payload
+            .get(len)
+            .is_some_and(|version| *version >= HANDSHAKE_VERSION))
+    }
+
+    /// `extend` 时支持握手扩展, 返回协商的加密方式与双方是否都支持握手扩展
+    async fn server_negotiate<S>(
+        stream: &mut S,
+        ciphers: &[Cipher],
+        extend: bool,
+    ) -> crate::Result<(Cipher, bool)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let packet = stream.recv_packet().await?;
+        let poto = packet.clone().try_poto()?;
+        let extended = extend && Self::is_extended(&packet, &poto)?;
+
+        let client_ciphers = match poto {
+            Poto::Hello(client_ciphers) => client_ciphers,
+            poto => return Err(Kind::Unexpected(format!("{}", poto)).into()),
+        };
+
+        log::debug!(
+            "client ciphers {:?}, server ciphers {:?}",
+            client_ciphers,
+            ciphers
+        );
+
+        // 以客户端的顺序为优先
+        match client_ciphers
+            .iter()
+            .find(|cipher| ciphers.contains(cipher))
+        {
+            Some(cipher) => {
+                stream
+                    .send_packet(&Self::hello(vec![*cipher], extended)?)
+                    .await?;
+                Ok((*cipher, extended))
+            }
+            None => {
+                let reason = format!("no common cipher, server supports {}", Self::join(ciphers));
+                let message = Poto::Error(ErrorCode::NegotiationFailed, Some(reason.clone()));
+                stream.send_packet(&message.bytes()).await?;
+                Err(EncryptionErr::Negotiate(reason).into())
+            }
+        }
+    }
+
+    /// `extend` 时支持握手扩展, 返回协商的加密方式与双方是否都支持握手扩展
+    async fn client_negotiate<S>(
+        stream: &mut S,
+        ciphers: Vec<Cipher>,
+        extend: bool,
+    ) -> crate::Result<(Cipher, bool)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        stream
+            .send_packet(&Self::hello(ciphers.clone(), extend)?)
+            .await?;
+
+        let packet = stream.recv_packet().await?;
+        let poto = packet.clone().try_poto()?;
+        let extended = extend && Self::is_extended(&packet, &poto)?;
+
+        match poto {
+            Poto::Hello(selected) if selected.len() == 1 && ciphers.contains(&selected[0]) => {
+                Ok((selected[0], extended))
+            }
+            Poto::Error(code, detail) => {
+                let reason = detail.unwrap_or_else(|| code.to_string());
+                log::error!("cipher negotiation failed: {}", reason);
+                Err(EncryptionErr::Negotiate(reason).into())
+            }
+            poto => Err(Kind::Unexpected(format!("{}", poto)).into()),
+        }
+    }
+
+    async fn server_negotiate_compression<S>(
+        stream: &mut S,
+        compressions: &[Compression],
+    ) -> crate::Result<Compression>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let client_compressions = match stream.recv_packet().await?.try_poto()? {
+            Poto::Compress(client_compressions) => client_compressions,
+            poto => return Err(Kind::Unexpected(format!("{}", poto)).into()),
+        };
+
+        match client_compressions
+            .iter()
+            .find(|compression| compressions.contains(compression))
+        {
+            Some(compression) => {
+                stream
+                    .send_packet(&Poto::Compress(vec![*compression]).bytes())
+                    .await?;
+                Ok(*compression)
+            }
+            None => {
+                let reason = format!(
+                    "no common compression, server supports {}",
+                    Self::join(compressions)
+                );
+                let message = Poto::Error(ErrorCode::NegotiationFailed, Some(reason.clone()));
+                stream.send_packet(&message.bytes()).await?;
+                Err(CompressErr::Negotiate(reason).into())
+            }
+        }
+    }
+
+    async fn client_negotiate_compression<S>(
+        stream: &mut S,
+        compressions: Vec<Compression>,
+    ) -> crate::Result<Compression>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        stream
+            .send_packet(&Poto::Compress(compressions.clone()).bytes())
+            .await?;
+
+        match stream.recv_packet().await?.try_poto()? {
+            Poto::Compress(selected)
+                if selected.len() == 1 && compressions.contains(&selected[0]) =>
+            {
+                Ok(selected[0])
+            }
+            Poto::Error(code, detail) => {
+                let reason = detail.unwrap_or_else(|| code.to_string());
+                log::error!("compression negotiation failed: {}", reason);
+                Err(CompressErr::Negotiate(reason).into())
+            }
+            poto => Err(Kind::Unexpected(format!("{}", poto)).into()),
+        }
+    }
+
+    fn join<T: ToString>(items: &[T]) -> String {
+        items
+            .iter()
+            .map(|item| item.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
 }
 
 impl<S> Provider<S> for PenetrateRsaAndAesHandshake
@@ -137,8 +429,22 @@ where
 
     fn call(&self, client: S) -> Self::Output {
         match self {
-            PenetrateRsaAndAesHandshake::Server => Self::server_handshake(client),
-            PenetrateRsaAndAesHandshake::Client => Self::client_handshake(client),
+            PenetrateRsaAndAesHandshake::Server(ciphers, compressions, control_ciphers) => {
+                Self::server_handshake(
+                    client,
+                    ciphers.clone(),
+                    compressions.clone(),
+                    control_ciphers.clone(),
+                )
+            }
+            PenetrateRsaAndAesHandshake::Client(ciphers, compressions, control_ciphers) => {
+                Self::client_handshake(
+                    client,
+                    ciphers.clone(),
+                    compressions.clone(),
+                    control_ciphers.clone(),
+                )
+            }
         }
     }
 }
@@ -151,10 +457,212 @@ where
     fn call(&self, stream: S) -> Self::Output {
         let iv = self.iv.clone();
         let key = self.key.clone();
+        let cipher = self.cipher;
+        let compression = self.compression;
         Box::pin(async move {
-            let lz4 = Lz4Compress::new(stream);
-            let aes = AESEncryptor::new(lz4, iv, key);
-            Ok(aes.into_boxed_stream())
+            let stream = encrypt(Lz4Compress::new(stream), cipher, iv, key);
+
+            // 先压缩再加密, 加密后的数据无法压缩
+            match compression {
+                Compression::None => Ok(stream),
+                Compression::Lz4 => Ok(Lz4BlockCompress::new(stream).into_boxed_stream()),
+            }
         })
     }
 }
+
+fn encrypt<S>(stream: S, cipher: Cipher, iv: [u8; 16], key: [u8; 16]) -> FusoStream
+where
+    S: Stream + Unpin + Send + 'static,
+{
+    #[cfg(feature = "fuso-crypt-aead")]
+    let peer = stream.peer_addr().ok();
+
+    match cipher {
+        Cipher::None => stream.into_boxed_stream(),
+        Cipher::Aes => AESEncryptor::new(stream, iv, key).into_boxed_stream(),
+        #[cfg(feature = "fuso-crypt-aead")]
+        Cipher::AesGcm => AESGcmEncryptor::new(stream, aead_key(&iv, &key))
+            .peer(peer)
+            .into_boxed_stream(),
+        #[cfg(feature = "fuso-crypt-aead")]
+        Cipher::ChaCha20 => ChaCha20Encryptor::new(stream, aead_key(&iv, &key))
+            .peer(peer)
+            .into_boxed_stream(),
+    }
+}
+
+#[cfg(feature = "fuso-crypt-aead")]
+fn aead_key(iv: &[u8; 16], key: &[u8; 16]) -> [u8; 32] {
+    let mut aead_key = [0u8; 32];
+    aead_key[..16].copy_from_slice(iv);
+    aead_key[16..].copy_from_slice(key);
+    aead_key
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compress::Compression,
+        encryption::Cipher,
+        ext::{AsyncReadExt, AsyncWriteExt},
+        protocol::{Poto, TryToPoto},
+        DecorateProvider, FusoStream, MemoryStream, Provider,
+    };
+
+    use super::{BoxedFuture, PenetrateRsaAndAesHandshake};
+
+    /// 直接返回原连接, 只测试外层的握手
+    pub(super) struct Plain;
+
+    impl Provider<FusoStream> for Plain {
+        type Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>;
+
+        fn call(&self, stream: FusoStream) -> Self::Output {
+            Box::pin(async move { Ok((stream, None)) })
+        }
+    }
+
+    fn negotiate(server: Vec<Cipher>, client: Vec<Cipher>) -> crate::Result<Cipher> {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (mut s, mut c) = tokio::io::duplex(1024);
+                let (server, client) = tokio::join!(
+                    PenetrateRsaAndAesHandshake::server_negotiate(&mut s, &server, false),
+                    PenetrateRsaAndAesHandshake::client_negotiate(&mut c, client, false)
+                );
+                assert_eq!(server.is_ok(), client.is_ok());
+                client.map(|(cipher, _)| cipher)
+            })
+    }
+
+    #[test]
+    fn test_handshake_version() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let negotiate = |server: bool, client: bool| async move {
+                    let (mut s, mut c) = tokio::io::duplex(1024);
+                    let (server, client) = tokio::join!(
+                        PenetrateRsaAndAesHandshake::server_negotiate(
+                            &mut s,
+                            &[Cipher::Aes],
+                            server
+                        ),
+                        PenetrateRsaAndAesHandshake::client_negotiate(
+                            &mut c,
+                            vec![Cipher::Aes],
+                            client
+                        )
+                    );
+                    (server.unwrap().1, client.unwrap().1)
+                };
+
+                assert_eq!(negotiate(true, true).await, (true, true));
+                assert_eq!(negotiate(true, false).await, (false, false));
+                assert_eq!(negotiate(false, true).await, (false, false));
+            });
+
+        // 旧版本解析 `Hello` 时忽略之后的版本
+        let hello = PenetrateRsaAndAesHandshake::hello(vec![Cipher::Aes], true).unwrap();
+        let packet = crate::protocol::make_packet(hello[8..].to_vec());
+        assert_eq!(packet.try_poto().unwrap(), Poto::Hello(vec![Cipher::Aes]));
+    }
+
+    #[test]
+    fn test_cipher_negotiate() {
+        let server = vec![Cipher::Aes, Cipher::AesGcm, Cipher::ChaCha20];
+        let cipher = negotiate(server.clone(), vec![Cipher::ChaCha20, Cipher::Aes]).unwrap();
+        assert_eq!(cipher, Cipher::ChaCha20);
+        assert!(negotiate(server, vec![Cipher::None]).is_err());
+        assert_eq!(
+            negotiate(vec![Cipher::None], vec![Cipher::None]).unwrap(),
+            Cipher::None
+        );
+    }
+
+    fn negotiate_compression(
+        server: Vec<Compression>,
+        client: Vec<Compression>,
+    ) -> crate::Result<Compression> {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (mut s, mut c) = tokio::io::duplex(1024);
+                let (server, client) = tokio::join!(
+                    PenetrateRsaAndAesHandshake::server_negotiate_compression(&mut s, &server),
+                    PenetrateRsaAndAesHandshake::client_negotiate_compression(&mut c, client)
+                );
+                assert_eq!(server.is_ok(), client.is_ok());
+                client
+            })
+    }
+
+    #[test]
+    fn test_compression_negotiate() {
+        let server = vec![Compression::Lz4, Compression::None];
+        let compression =
+            negotiate_compression(server.clone(), vec![Compression::Lz4, Compression::None]);
+        assert_eq!(compression.unwrap(), Compression::Lz4);
+
+        let compression = negotiate_compression(vec![Compression::None], vec![Compression::Lz4]);
+        assert!(compression.is_err());
+
+        let compression = negotiate_compression(server, vec![Compression::None]);
+        assert_eq!(compression.unwrap(), Compression::None);
+    }
+
+    fn handshake(
+        server: Vec<Cipher>,
+        client: Vec<Cipher>,
+    ) -> crate::Result<(FusoStream, FusoStream)> {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (s, c) = MemoryStream::pair(
+                    ([127, 0, 0, 1], 6000).into(),
+                    ([127, 0, 0, 1], 6001).into(),
+                );
+                let (server, client) = tokio::join!(
+                    PenetrateRsaAndAesHandshake::server_handshake(
+                        s,
+                        vec![Cipher::None],
+                        vec![Compression::None],
+                        server
+                    ),
+                    PenetrateRsaAndAesHandshake::client_handshake(
+                        c,
+                        vec![Cipher::None],
+                        vec![Compression::None],
+                        client
+                    )
+                );
+                let (mut server, mut client) = (server?.0, client?.0);
+
+                client.write_all(b"token").await?;
+                server.write_all(b"addr").await?;
+
+                let mut buf = [0u8; 5];
+                server.read_exact(&mut buf).await?;
+                assert_eq!(&buf, b"token");
+                client.read_exact(&mut buf[..4]).await?;
+                assert_eq!(&buf[..4], b"addr");
+
+                Ok((server, client))
+            })
+    }
+
+    #[test]
+    fn test_control_cipher() {
+        // 数据连接不加密时控制连接仍然使用协商的加密方式
+        assert!(handshake(vec![Cipher::AesGcm], vec![Cipher::AesGcm]).is_ok());
+        assert!(handshake(vec![Cipher::Aes, Cipher::ChaCha20], vec![Cipher::ChaCha20]).is_ok());
+        assert!(handshake(vec![Cipher::AesGcm], vec![Cipher::ChaCha20]).is_err());
+
+        // 一方不协商控制连接的加密方式时与旧版本一样使用rsa, `none` 被忽略
+        assert!(handshake(vec![Cipher::AesGcm], vec![]).is_ok());
+        assert!(handshake(vec![], vec![Cipher::AesGcm]).is_ok());
+        assert!(handshake(vec![Cipher::AesGcm], vec![Cipher::None]).is_ok());
+    }
+}
@@ -0,0 +1,159 @@
+use std::{pin::Pin, sync::Arc};
+
+use tokio_rustls::rustls::{ClientConfig, ServerConfig, ServerName};
+
+use crate::{
+    ext::AsyncReadExt, guard::Fallback, tls, DecorateProvider, FusoStream, Provider, ProviderChain,
+    ToBoxStream,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+type Handshake = dyn Provider<FusoStream, Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>>
+    + Send
+    + Sync
+    + 'static;
+
+/// 在握手之前先建立tls连接, 之后的连接同样使用tls
+pub enum PenetrateTlsHandshake {
+    /// 只接受tls连接, 非tls的客户端将被拒绝
+    Server(Arc<ServerConfig>, Arc<Handshake>),
+    /// 以tls的方式连接服务端, 参数为用于校验证书的域名
+    Client(Arc<ClientConfig>, ServerName, Arc<Handshake>),
+}
+
+#[derive(Clone)]
+pub enum TlsDecorator {
+    Server(Arc<ServerConfig>),
+    Client(Arc<ClientConfig>, ServerName),
+}
+
+impl PenetrateTlsHandshake {
+    pub fn server<H>(config: Arc<ServerConfig>, handshake: H) -> Self
+    where
+        H: Provider<
+                FusoStream,
+                Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        Self::Server(config, Arc::new(handshake))
+    }
+
+    pub fn client<H>(config: Arc<ClientConfig>, server_name: ServerName, handshake: H) -> Self
+    where
+        H: Provider<
+                FusoStream,
+                Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        Self::Client(config, server_name, Arc::new(handshake))
+    }
+}
+
+fn decorate_with(
+    tls: TlsDecorator,
+    decorator: Option<DecorateProvider<FusoStream>>,
+) -> DecorateProvider<FusoStream> {
+    match decorator {
+        None => DecorateProvider::wrap(tls),
+        Some(decorator) => DecorateProvider::wrap(ProviderChain::chain(tls, decorator)),
+    }
+}
+
+async fn accept(config: Arc<ServerConfig>, stream: FusoStream) -> crate::Result<FusoStream> {
+    let mut stream = Fallback::new(stream, false);
+    let mut head = [0u8; 1];
+
+    stream.mark().await?;
+    stream.read_exact(&mut head).await?;
+    stream.backward().await?;
+
+    Ok(tls::accept(config, head[0], stream)
+        .await?
+        .into_boxed_stream())
+}
+
+impl Provider<FusoStream> for PenetrateTlsHandshake {
+    type Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>;
+
+    fn call(&self, stream: FusoStream) -> Self::Output {
+        let (tls, handshake) = match self {
+            PenetrateTlsHandshake::Server(config, handshake) => {
+                (TlsDecorator::Server(config.clone()), handshake.clone())
+            }
+            PenetrateTlsHandshake::Client(config, server_name, handshake) => (
+                TlsDecorator::Client(config.clone(), server_name.clone()),
+                handshake.clone(),
+            ),
+        };
+
+        Box::pin(async move {
+            let stream = tls.call(stream).await?;
+            let (stream, decorator) = handshake.call(stream).await?;
+            Ok((stream, Some(decorate_with(tls, decorator))))
+        })
+    }
+}
+
+impl Provider<FusoStream> for TlsDecorator {
+    type Output = BoxedFuture<FusoStream>;
+
+    fn call(&self, stream: FusoStream) -> Self::Output {
+        let tls = self.clone();
+        Box::pin(async move {
+            match tls {
+                TlsDecorator::Server(config) => accept(config, stream).await,
+                TlsDecorator::Client(config, server_name) => {
+                    Ok(tls::connect(config, server_name, stream)
+                        .await?
+                        .into_boxed_stream())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio_rustls::rustls::{server::ResolvesServerCertUsingSni, ServerConfig};
+
+    use crate::{ext::AsyncWriteExt, Kind, MemoryStream, Provider, ToBoxStream};
+
+    use super::TlsDecorator;
+
+    #[test]
+    fn test_reject_plain_client() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                // 非tls的客户端在读取证书之前就被拒绝, 不需要真实的证书
+                let config = ServerConfig::builder()
+                    .with_safe_defaults()
+                    .with_no_client_auth()
+                    .with_cert_resolver(Arc::new(ResolvesServerCertUsingSni::new()));
+
+                let (mut client, server) = MemoryStream::pair(
+                    "127.0.0.1:50000".parse().unwrap(),
+                    "127.0.0.1:6722".parse().unwrap(),
+                );
+
+                // rsa握手的开头是公钥的长度
+                client.write_all(&[0x00, 0x00, 0x01, 0x26]).await.unwrap();
+
+                let accepted = TlsDecorator::Server(Arc::new(config))
+                    .call(server.into_boxed_stream())
+                    .await;
+
+                match accepted {
+                    Err(e) => assert!(matches!(e.kind(), Kind::Tls(_))),
+                    Ok(_) => panic!("a plain client passed the tls handshake"),
+                }
+            });
+    }
+}
@@ -0,0 +1,253 @@
+use std::{pin::Pin, sync::Arc};
+
+use crate::{
+    ext::AsyncReadExt, guard::Fallback, metrics::metrics, ws, DecorateProvider, FusoStream,
+    Provider, ProviderChain, ToBoxStream,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+type Handshake = dyn Provider<FusoStream, Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>>
+    + Send
+    + Sync
+    + 'static;
+
+/// 在握手之前先完成websocket升级, 之后的连接同样使用websocket
+pub enum PenetrateWebSocketHandshake {
+    /// 自动识别websocket请求, 普通连接不受影响, 参数为允许的请求头长度与握手失败时回复的伪装内容
+    Server(usize, Option<Arc<[u8]>>, Arc<Handshake>),
+    /// 以websocket的方式连接服务端, 参数为 Host 与请求路径
+    Client(String, String, Arc<Handshake>),
+}
+
+#[derive(Clone)]
+pub enum WebSocketDecorator {
+    Server(usize),
+    Client(String, String),
+}
+
+impl PenetrateWebSocketHandshake {
+    pub fn server<H>(handshake: H) -> Self
+    where
+        H: Provider<
+                FusoStream,
+                Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        Self::Server(ws::MAX_HEAD_LEN, None, Arc::new(handshake))
+    }
+
+    pub fn client<H>(host: String, path: String, handshake: H) -> Self
+    where
+        H: Provider<
+                FusoStream,
+                Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>,
+            > + Send
+            + Sync
+            + 'static,
+    {
+        Self::Client(host, path, Arc::new(handshake))
+    }
+
+    /// 设置服务端允许的websocket请求头长度, 请求携带大量头部(如cookie)时需要调大
+    pub fn max_head_len(self, max_head_len: usize) -> Self {
+        match self {
+            Self::Server(_, decoy, handshake) => Self::Server(max_head_len, decoy, handshake),
+            client => client,
+        }
+    }
+
+    /// 不是websocket的GET请求回复 `decoy` 而不是400, 如 `DECOY_NOT_FOUND`,
+    /// 其他连接握手失败时的回复由 `PenetrateDecoyHandshake` 处理
+    pub fn decoy(self, decoy: Option<Vec<u8>>) -> Self {
+        match self {
+            Self::Server(max_head_len, _, handshake) => {
+                let decoy = decoy.filter(|decoy| !decoy.is_empty()).map(Arc::from);
+                Self::Server(max_head_len, decoy, handshake)
+            }
+            client => client,
+        }
+    }
+}
+
+fn decorate_with(
+    websocket: WebSocketDecorator,
+    decorator: Option<DecorateProvider<FusoStream>>,
+) -> DecorateProvider<FusoStream> {
+    match decorator {
+        None => DecorateProvider::wrap(websocket),
+        Some(decorator) => DecorateProvider::wrap(ProviderChain::chain(websocket, decorator)),
+    }
+}
+
+impl Provider<FusoStream> for PenetrateWebSocketHandshake {
+    type Output = BoxedFuture<(FusoStream, Option<DecorateProvider<FusoStream>>)>;
+
+    fn call(&self, stream: FusoStream) -> Self::Output {
+        match self {
+            PenetrateWebSocketHandshake::Server(max_head_len, decoy, handshake) => {
+                let max_head_len = *max_head_len;
+                let decoy = decoy.clone();
+                let handshake = handshake.clone();
+                Box::pin(async move {
+                    let mut stream = Fallback::new(stream, false);
+                    let mut method = [0u8; 4];
+
+                    stream.mark().await?;
+                    stream.read_exact(&mut method).await?;
+                    stream.backward().await?;
+
+                    if &method != b"GET " {
+                        log::debug!("sniffed `{}`, not websocket", method.escape_ascii());
+                        metrics().on_sniff_fallback();
+                        return handshake.call(stream.into_boxed_stream()).await;
+                    }
+
+                    log::debug!("sniffed `{}`, upgrade to websocket", method.escape_ascii());
+                    metrics().on_sniff_websocket();
+
+                    let reject = decoy.as_deref().unwrap_or(ws::BAD_REQUEST);
+                    let upgrade = ws::server_upgrade_with_reject(stream, max_head_len, reject);
+
+                    let stream = match upgrade.await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::debug!("websocket upgrade failed, maybe not a websocket client");
+                            metrics().on_sniff_websocket_failed();
+                            return Err(e);
+                        }
+                    };
+                    let (stream, decorator) = handshake.call(stream.into_boxed_stream()).await?;
+
+                    Ok((
+                        stream,
+                        Some(decorate_with(
+                            WebSocketDecorator::Server(max_head_len),
+                            decorator,
+                        )),
+                    ))
+                })
+            }
+            PenetrateWebSocketHandshake::Client(host, path, handshake) => {
+                let handshake = handshake.clone();
+                let websocket = WebSocketDecorator::Client(host.clone(), path.clone());
+                Box::pin(async move {
+                    let stream = websocket.call(stream).await?;
+                    let (stream, decorator) = handshake.call(stream).await?;
+                    Ok((stream, Some(decorate_with(websocket, decorator))))
+                })
+            }
+        }
+    }
+}
+
+impl Provider<FusoStream> for WebSocketDecorator {
+    type Output = BoxedFuture<FusoStream>;
+
+    fn call(&self, stream: FusoStream) -> Self::Output {
+        let websocket = self.clone();
+        Box::pin(async move {
+            match websocket {
+                WebSocketDecorator::Server(max_head_len) => {
+                    Ok(ws::server_upgrade_with_limit(stream, max_head_len)
+                        .await?
+                        .into_boxed_stream())
+                }
+                WebSocketDecorator::Client(host, path) => {
+                    Ok(ws::client_upgrade(stream, &host, &path)
+                        .await?
+                        .into_boxed_stream())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::{
+        ext::{AsyncReadExt, AsyncWriteExt},
+        MemoryStream, Provider, ToBoxStream,
+    };
+
+    use super::super::tests::Plain;
+    use super::super::DECOY_NOT_FOUND;
+    use super::PenetrateWebSocketHandshake;
+
+    #[test]
+    fn test_sniff_websocket() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let client_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+                let server_addr = SocketAddr::from(([127, 0, 0, 1], 6722));
+
+                // 普通连接读取的数据原样交给后面的握手
+                let (mut c, s) = MemoryStream::pair(client_addr, server_addr);
+                c.write_all(b"\x05\x01\x00\x05").await.unwrap();
+
+                let (mut s, decorator) = PenetrateWebSocketHandshake::server(Plain)
+                    .call(s.into_boxed_stream())
+                    .await
+                    .unwrap();
+
+                assert!(decorator.is_none());
+
+                let mut buf = [0u8; 4];
+                s.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"\x05\x01\x00\x05");
+
+                let (c, s) = MemoryStream::pair(client_addr, server_addr);
+                let client = PenetrateWebSocketHandshake::client(
+                    String::from("fuso"),
+                    String::from("/"),
+                    Plain,
+                );
+                let server = PenetrateWebSocketHandshake::server(Plain);
+
+                let (c, s) = tokio::join!(
+                    client.call(c.into_boxed_stream()),
+                    server.call(s.into_boxed_stream())
+                );
+
+                let ((mut c, client_decorator), (mut s, server_decorator)) =
+                    (c.unwrap(), s.unwrap());
+
+                assert!(client_decorator.is_some());
+                assert!(server_decorator.is_some());
+
+                c.write_all(b"hello websocket").await.unwrap();
+
+                let mut buf = [0u8; 15];
+                s.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello websocket");
+            });
+    }
+
+    #[test]
+    fn test_decoy() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let client_addr = SocketAddr::from(([127, 0, 0, 1], 50000));
+                let server_addr = SocketAddr::from(([127, 0, 0, 1], 6722));
+
+                let server = PenetrateWebSocketHandshake::server(Plain)
+                    .decoy(Some(DECOY_NOT_FOUND.to_vec()));
+
+                // 不是websocket的GET请求回复伪装内容而不是400
+                let (mut c, s) = MemoryStream::pair(client_addr, server_addr);
+                c.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+
+                assert!(server.call(s.into_boxed_stream()).await.is_err());
+
+                let mut buf = vec![0u8; DECOY_NOT_FOUND.len()];
+                c.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, DECOY_NOT_FOUND);
+            });
+    }
+}
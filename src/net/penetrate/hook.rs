@@ -0,0 +1,215 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use crate::{io::Closed, time, Address};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+type Hook<T> = Arc<dyn Fn(T) -> BoxedFuture<()> + Send + Sync + 'static>;
+
+/// 回调的默认超时时间
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 转发开始时传给 `on_connect`, `peer_addr` 为访问者的地址
+#[derive(Debug, Clone)]
+pub struct Connected {
+    pub conv: u32,
+    pub name: String,
+    pub peer_addr: Address,
+}
+
+/// 转发结束时传给 `on_close`, 字节数为整个转发的累计值,
+/// socks5 udp中继与bind等由服务端处理的转发不统计字节数, 为0
+#[derive(Debug, Clone)]
+pub struct Disconnected {
+    pub conv: u32,
+    pub name: String,
+    pub peer_addr: Address,
+    pub closed: Closed,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// 在每个转发的开始与结束时等待执行的回调, 用于审计等需要可靠记录的场景.
+/// 与 `Events` 不同, 回调在转发任务中执行, 不会被丢弃; 回调返回的错误与超时只记录日志, 不影响转发
+#[derive(Clone)]
+pub struct Hooks {
+    on_connect: Option<Hook<Connected>>,
+    on_close: Option<Hook<Disconnected>>,
+    timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            on_connect: None,
+            on_close: None,
+            timeout: DEFAULT_HOOK_TIMEOUT,
+        }
+    }
+}
+
+impl Hooks {
+    /// 转发开始前执行, 执行完成后才开始转发
+    pub fn on_connect<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(Connected) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = crate::Result<()>> + Send + 'static,
+    {
+        self.on_connect = Some(Arc::new(move |connected| Box::pin(hook(connected))));
+        self
+    }
+
+    /// 转发结束且两端的连接关闭后执行
+    pub fn on_close<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(Disconnected) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = crate::Result<()>> + Send + 'static,
+    {
+        self.on_close = Some(Arc::new(move |disconnected| Box::pin(hook(disconnected))));
+        self
+    }
+
+    /// 每次回调的最长等待时间, 超时后不再等待, 转发照常开始或结束, 默认5秒
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.on_connect.is_none() && self.on_close.is_none()
+    }
+
+    pub(crate) async fn connect(&self, connected: Connected) {
+        if let Some(hook) = self.on_connect.as_ref() {
+            let conv = connected.conv;
+            match time::wait_for(self.timeout, hook(connected)).await {
+                Err(_) => {
+                    log::warn!(conv = conv; "on_connect hook timed out after {:?}", self.timeout)
+                }
+                Ok(Err(e)) => log::warn!(conv = conv; "on_connect hook failed {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+
+    pub(crate) async fn close(&self, disconnected: Disconnected) {
+        if let Some(hook) = self.on_close.as_ref() {
+            let conv = disconnected.conv;
+            match time::wait_for(self.timeout, hook(disconnected)).await {
+                Err(_) => {
+                    log::warn!(conv = conv; "on_close hook timed out after {:?}", self.timeout)
+                }
+                Ok(Err(e)) => log::warn!(conv = conv; "on_close hook failed {}", e),
+                Ok(Ok(())) => {}
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_connect", &self.on_connect.is_some())
+            .field("on_close", &self.on_close.is_some())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::{Connected, Disconnected, Hooks};
+    use crate::{io::Closed, Address, Kind, Socket};
+
+    #[test]
+    fn test_hooks() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let records = Arc::new(Mutex::new(Vec::new()));
+
+                let hooks = Hooks::default()
+                    .on_connect({
+                        let records = records.clone();
+                        move |connected: Connected| {
+                            let records = records.clone();
+                            async move {
+                                let record = format!("connect {}", connected.conv);
+                                records.lock().unwrap().push(record);
+                                Err(Kind::Unexpected("database is down".into()).into())
+                            }
+                        }
+                    })
+                    .on_close({
+                        let records = records.clone();
+                        move |disconnected: Disconnected| {
+                            let records = records.clone();
+                            async move {
+                                records.lock().unwrap().push(format!(
+                                    "close {} {}",
+                                    disconnected.conv, disconnected.rx_bytes
+                                ));
+                                Ok(())
+                            }
+                        }
+                    });
+
+                let peer_addr = Address::One(Socket::tcp(([127, 0, 0, 1], 50000)));
+
+                // 回调失败不会中断调用方
+                hooks
+                    .connect(Connected {
+                        conv: 1,
+                        name: String::from("anonymous"),
+                        peer_addr: peer_addr.clone(),
+                    })
+                    .await;
+
+                hooks
+                    .close(Disconnected {
+                        conv: 1,
+                        name: String::from("anonymous"),
+                        peer_addr,
+                        closed: Closed::Clean,
+                        rx_bytes: 5,
+                        tx_bytes: 2,
+                    })
+                    .await;
+
+                assert_eq!(*records.lock().unwrap(), ["connect 1", "close 1 5"]);
+                assert!(Hooks::default().is_empty());
+            });
+    }
+
+    #[test]
+    fn test_hooks_timeout() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let hooks = Hooks::default()
+                    .timeout(Duration::from_millis(100))
+                    .on_connect(|_: Connected| async move {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                        Ok(())
+                    });
+
+                let started = Instant::now();
+
+                // 卡住的回调不会一直阻塞转发
+                hooks
+                    .connect(Connected {
+                        conv: 1,
+                        name: String::from("anonymous"),
+                        peer_addr: Address::One(Socket::tcp(([127, 0, 0, 1], 50000))),
+                    })
+                    .await;
+
+                assert!(started.elapsed() < Duration::from_secs(5));
+            });
+    }
+}
@@ -0,0 +1,264 @@
+use std::{net::IpAddr, pin::Pin, sync::Arc};
+
+use crate::{
+    ext::{AsyncReadExt, AsyncWriteExt},
+    guard::Fallback,
+    penetrate::{
+        server::{Peer, Visitor},
+        Selector,
+    },
+    AsyncRead, Provider, Socket, Stream,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+/// `CONNECT` 由http代理处理
+const METHODS: [&[u8]; 8] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"HEAD ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"TRACE ",
+];
+
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+const NOT_FOUND: &[u8] =
+    b"HTTP/1.1 404 Not Found\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// 按http请求的 `Host` 选择转发目标, 请求行和请求头会原样转发,
+/// 不是http请求时交给下一个处理, 没有对应的目标时回复404或交给下一个处理
+pub struct HttpHostMock;
+
+/// 逐字节读取请求头, 不是http请求时尽早返回None, 避免等待其他协议的数据
+pub(crate) async fn read_request_head<S>(stream: &mut Fallback<S>) -> crate::Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LEN {
+            return Ok(None);
+        }
+
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+
+        let matched = METHODS.iter().any(|method| {
+            let n = head.len().min(method.len());
+            head[..n] == method[..n]
+        });
+
+        if !matched {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(head))
+}
+
+/// 取出 `Host` 并去掉端口, 统一为小写
+fn parse_host(head: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(head).ok()?;
+
+    let host = head
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, host)| host.trim())?;
+
+    let host = match host.strip_prefix('[') {
+        Some(ipv6) => ipv6.split_once(']').map(|(ip, _)| ip)?,
+        None => host.split(':').next()?,
+    };
+
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
+/// 在请求头中添加访问者的地址, 已有 `X-Forwarded-For` 时追加到最后一个的末尾
+pub(crate) fn append_forwarded_for(head: &[u8], ip: IpAddr) -> Vec<u8> {
+    // 去掉结尾的空行后每一行都以 `\r\n` 结束
+    let head = &head[..head.len() - 2];
+
+    let mut lines = Vec::new();
+    let mut start = 0;
+
+    while let Some(n) = head[start..].windows(2).position(|w| w == b"\r\n") {
+        lines.push(&head[start..start + n]);
+        start += n + 2;
+    }
+
+    let value = |line: &[u8]| {
+        let n = line.iter().position(|b| *b == b':')?;
+        line[..n]
+            .eq_ignore_ascii_case(b"x-forwarded-for")
+            .then(|| line[n + 1..].to_vec())
+    };
+
+    // 第一行是请求行
+    let last = lines
+        .iter()
+        .rposition(|line| value(line).is_some())
+        .filter(|n| *n > 0);
+
+    let mut rewritten = Vec::with_capacity(head.len() + 64);
+
+    for (n, line) in lines.iter().enumerate() {
+        rewritten.extend_from_slice(line);
+
+        if Some(n) == last {
+            match value(line).unwrap_or_default().trim_ascii().is_empty() {
+                true => rewritten.extend_from_slice(format!(" {}", ip).as_bytes()),
+                false => rewritten.extend_from_slice(format!(", {}", ip).as_bytes()),
+            }
+        }
+
+        rewritten.extend_from_slice(b"\r\n");
+    }
+
+    if last.is_none() {
+        rewritten.extend_from_slice(format!("X-Forwarded-For: {}\r\n", ip).as_bytes());
+    }
+
+    rewritten.extend_from_slice(b"\r\n");
+    rewritten
+}
+
+impl<S> Provider<(Fallback<S>, Arc<super::super::server::Config>)> for HttpHostMock
+where
+    S: Stream + Send + Sync + 'static,
+{
+    type Output = BoxedFuture<Selector<S>>;
+
+    fn call(
+        &self,
+        (stream, config): (Fallback<S>, Arc<super::super::server::Config>),
+    ) -> Self::Output {
+        Box::pin(async move {
+            let mut stream = stream;
+
+            if config.host_routes.is_empty() {
+                return Ok(Selector::Unselected(stream));
+            }
+
+            let host = match read_request_head(&mut stream).await? {
+                Some(head) => parse_host(&head),
+                None => return Ok(Selector::Unselected(stream)),
+            };
+
+            let route = host.as_ref().and_then(|host| {
+                config
+                    .host_routes
+                    .iter()
+                    .find(|(name, _)| name.eq_ignore_ascii_case(host))
+            });
+
+            match route {
+                Some((name, addr)) => {
+                    log::debug!("route host {} to {}", name, addr);
+
+                    // 交还读取的请求头, 转发的请求与访问者发送的一致
+                    stream.backward().await?;
+
+                    Ok(Selector::Checked(Peer::Route(
+                        Visitor::Route(stream),
+                        Socket::tcp(addr.clone()),
+                    )))
+                }
+                None if config.host_not_found => {
+                    log::debug!("no route for host {:?}, reply 404", host);
+                    stream.consume_back_data();
+                    stream.write_all(NOT_FOUND).await?;
+                    Ok(Selector::Checked(Peer::Finished(stream)))
+                }
+                None => {
+                    log::debug!("no route for host {:?}", host);
+                    Ok(Selector::Unselected(stream))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ext::{AsyncReadExt, AsyncWriteExt},
+        guard::Fallback,
+    };
+
+    #[test]
+    fn test_parse_host() {
+        let host = |head: &str| super::parse_host(head.as_bytes());
+
+        assert_eq!(
+            host("GET / HTTP/1.1\r\nHost: Example.com:8080\r\n\r\n").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(
+            host("GET / HTTP/1.1\r\nhost: [::1]:80\r\n\r\n").as_deref(),
+            Some("::1")
+        );
+        assert!(host("GET / HTTP/1.1\r\nAccept: */*\r\n\r\n").is_none());
+        assert!(host("GET / HTTP/1.1\r\nHost: \r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn test_append_forwarded_for() {
+        let ip = "10.0.0.1".parse().unwrap();
+        let append = |head: &str| {
+            String::from_utf8(super::append_forwarded_for(head.as_bytes(), ip)).unwrap()
+        };
+
+        assert_eq!(
+            append("GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"),
+            "GET / HTTP/1.1\r\nHost: example.com\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n"
+        );
+        assert_eq!(
+            append("GET / HTTP/1.1\r\nx-forwarded-for: 1.1.1.1\r\nHost: a\r\n\r\n"),
+            "GET / HTTP/1.1\r\nx-forwarded-for: 1.1.1.1, 10.0.0.1\r\nHost: a\r\n\r\n"
+        );
+        assert_eq!(
+            append("GET / HTTP/1.1\r\nX-Forwarded-For: 1.1.1.1\r\nX-Forwarded-For: 2.2.2.2\r\n\r\n"),
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 1.1.1.1\r\nX-Forwarded-For: 2.2.2.2, 10.0.0.1\r\n\r\n"
+        );
+        assert_eq!(
+            append("GET / HTTP/1.1\r\nX-Forwarded-For:\r\n\r\n"),
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_replay_request_head() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let request = b"POST /api HTTP/1.1\r\nHost: fuso.example.com\r\nContent-Length: 4\r\n\r\nfuso";
+
+                let (mut w, r) = tokio::io::duplex(1024);
+                let mut r = Fallback::new(r, false);
+
+                w.write_all(request).await.unwrap();
+
+                r.mark().await.unwrap();
+                let head = super::read_request_head(&mut r).await.unwrap().unwrap();
+                assert_eq!(super::parse_host(&head).as_deref(), Some("fuso.example.com"));
+                r.backward().await.unwrap();
+
+                let mut forwarded = vec![0u8; request.len()];
+                r.read_exact(&mut forwarded).await.unwrap();
+                assert_eq!(forwarded, request);
+
+                let (mut w, r) = tokio::io::duplex(1024);
+                let mut r = Fallback::new(r, false);
+                w.write_all(b"\x16\x03\x01").await.unwrap();
+                assert!(super::read_request_head(&mut r).await.unwrap().is_none());
+            });
+    }
+}
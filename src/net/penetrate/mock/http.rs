@@ -0,0 +1,191 @@
+use std::{pin::Pin, sync::Arc};
+
+use crate::{
+    ext::{AsyncReadExt, AsyncWriteExt},
+    guard::Fallback,
+    penetrate::{
+        server::{Peer, Visitor},
+        Selector,
+    },
+    Addr, Provider, Socket, Stream,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+const METHOD: &[u8] = b"CONNECT ";
+const MAX_HEAD_LEN: usize = 8 * 1024;
+
+const ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const FORBIDDEN: &[u8] =
+    b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+const AUTH_REQUIRED: &[u8] = b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"fuso\"\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// http代理, 只处理 `CONNECT host:port HTTP/1.1`, 与socks5共用开关和认证信息
+pub struct HttpConnectMock;
+
+/// 逐字节读取请求头, 不是 `CONNECT` 请求时尽早返回None, 避免等待其他协议的数据
+async fn read_connect_head<S>(stream: &mut Fallback<S>) -> crate::Result<Option<String>>
+where
+    S: Stream + Send,
+{
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_LEN {
+            return Ok(None);
+        }
+
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+
+        let n = head.len().min(METHOD.len());
+
+        if !head[..n].eq_ignore_ascii_case(&METHOD[..n]) {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(String::from_utf8_lossy(&head).into_owned()))
+}
+
+fn parse_target(request_line: &str) -> Option<Addr> {
+    let mut request_line = request_line.split_whitespace().skip(1);
+    let target = request_line.next()?;
+
+    if !request_line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let (host, port) = target.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?;
+
+    if host.is_empty() {
+        return None;
+    }
+
+    Some(Addr::from((host.to_string(), port)))
+}
+
+fn authorized(headers: &[&str], credentials: &[(String, String)]) -> bool {
+    if credentials.is_empty() {
+        return true;
+    }
+
+    let token = headers
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|(_, value)| {
+            let (scheme, token) = value.trim().split_once(' ')?;
+            scheme.eq_ignore_ascii_case("basic").then(|| token.trim())
+        })
+        .and_then(|token| base64::decode(token).ok())
+        .and_then(|token| String::from_utf8(token).ok());
+
+    match token.as_ref().and_then(|token| token.split_once(':')) {
+        None => false,
+        Some((username, password)) => credentials
+            .iter()
+            .any(|(user, pass)| user == username && pass == password),
+    }
+}
+
+impl<S> Provider<(Fallback<S>, Arc<super::super::server::Config>)> for HttpConnectMock
+where
+    S: Stream + Send + Sync + 'static,
+{
+    type Output = BoxedFuture<Selector<S>>;
+
+    fn call(
+        &self,
+        (stream, config): (Fallback<S>, Arc<super::super::server::Config>),
+    ) -> Self::Output {
+        Box::pin(async move {
+            let mut stream = stream;
+
+            if !config.enable_socks {
+                log::debug!("skip http connect mock");
+                return Ok(Selector::Unselected(stream));
+            }
+
+            let head = match read_connect_head(&mut stream).await? {
+                Some(head) => head,
+                None => return Ok(Selector::Unselected(stream)),
+            };
+
+            stream.consume_back_data();
+
+            let mut lines = head.split("\r\n");
+            let request_line = lines.next().unwrap_or_default();
+            let headers = lines.filter(|line| !line.is_empty()).collect::<Vec<_>>();
+
+            let mut credentials = config.socks5_credentials.clone();
+
+            match (&config.socks5_password, &config.socks5_username) {
+                (Some(pwd), Some(username)) => credentials.push((username.clone(), pwd.clone())),
+                (Some(pwd), None) => credentials.push((config.whoami.clone(), pwd.clone())),
+                _ => {}
+            }
+
+            if !authorized(&headers, &credentials) {
+                log::debug!("http connect authentication failed");
+                stream.write_all(AUTH_REQUIRED).await?;
+                return Ok(Selector::Checked(Peer::Finished(stream)));
+            }
+
+            let addr = match parse_target(request_line) {
+                Some(addr) => addr,
+                None => {
+                    log::debug!("bad http connect request `{}`", request_line);
+                    stream.write_all(BAD_REQUEST).await?;
+                    return Ok(Selector::Checked(Peer::Finished(stream)));
+                }
+            };
+
+            if !config.egress.is_allowed(&addr) {
+                log::warn!("http connect target {} is not allowed", addr);
+                stream.write_all(FORBIDDEN).await?;
+                return Ok(Selector::Checked(Peer::Finished(stream)));
+            }
+
+            log::debug!("http connect to {}", addr);
+
+            stream.write_all(ESTABLISHED).await?;
+
+            Ok(Selector::Checked(Peer::Route(
+                Visitor::Route(stream),
+                Socket::tcp(addr),
+            )))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_connect() {
+        let addr = super::parse_target("CONNECT example.com:443 HTTP/1.1").unwrap();
+        assert_eq!(addr.to_string(), "example.com:443");
+
+        let addr = super::parse_target("CONNECT [::1]:8080 HTTP/1.1").unwrap();
+        assert_eq!(addr.port(), 8080);
+        assert!(!addr.is_domain());
+
+        assert!(super::parse_target("CONNECT example.com HTTP/1.1").is_none());
+        assert!(super::parse_target("CONNECT :443 HTTP/1.1").is_none());
+        assert!(super::parse_target("CONNECT example.com:443").is_none());
+
+        let credentials = vec![(String::from("fuso"), String::from("secret"))];
+        // fuso:secret
+        let headers = [
+            "Host: example.com:443",
+            "Proxy-Authorization: Basic ZnVzbzpzZWNyZXQ=",
+        ];
+        assert!(super::authorized(&headers, &credentials));
+        assert!(!super::authorized(&headers[..1], &credentials));
+        assert!(super::authorized(&headers[..1], &[]));
+    }
+}
@@ -2,11 +2,20 @@ mod direct;
 
 mod socks;
 
+#[cfg(feature = "fuso-http-proxy")]
+mod http;
+
+mod sni;
+
+mod host;
+
 use std::{pin::Pin, sync::Arc};
 
 use self::socks::PenetrateSocksBuilder;
 
-pub use socks::SocksUdpForwardMock;
+pub use socks::{SocksBindMock, SocksUdpForwardMock};
+
+pub(crate) use host::{append_forwarded_for, read_request_head};
 
 use super::{server::Peer, PenetrateSelectorBuilder};
 use crate::{guard::Fallback, Accepter, Executor, Provider, Socket, Stream, WrappedProvider};
@@ -28,6 +37,27 @@ where
         self
     }
 
+    /// 支持http代理的 `CONNECT` 请求, 客户端启用socks5时生效
+    #[cfg(feature = "fuso-http-proxy")]
+    pub fn using_http_connect(mut self) -> Self {
+        self.adapters
+            .insert(0, WrappedProvider::wrap(http::HttpConnectMock));
+        self
+    }
+
+    /// 按tls SNI转发到客户端配置的目标, 客户端没有配置时不读取数据
+    pub fn using_sni(mut self) -> Self {
+        self.adapters.insert(0, WrappedProvider::wrap(sni::SniMock));
+        self
+    }
+
+    /// 按http请求的 `Host` 转发到客户端配置的目标, 客户端没有配置时不读取数据
+    pub fn using_http_host(mut self) -> Self {
+        self.adapters
+            .insert(0, WrappedProvider::wrap(host::HttpHostMock));
+        self
+    }
+
     pub fn using_socks(self) -> PenetrateSocksBuilder<E, P, S, O> {
         PenetrateSocksBuilder {
             adapter_builder: self,
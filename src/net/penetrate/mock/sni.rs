@@ -0,0 +1,280 @@
+use std::{pin::Pin, sync::Arc};
+
+use crate::{
+    ext::AsyncReadExt,
+    guard::Fallback,
+    penetrate::{
+        server::{Peer, Visitor},
+        Selector,
+    },
+    AsyncRead, Provider, Socket, Stream,
+};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+// record: | type: u8 | version: u16 | len: u16 | fragment: [u8; len] |
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+/// ClientHello可能跨越多个记录, 超过该长度时不再读取
+const MAX_HELLO_LEN: usize = 64 * 1024;
+
+/// 按tls ClientHello中的SNI选择转发目标, 只读取不解密,
+/// 读取的数据会原样转发, 不是tls或SNI没有对应的目标时交给下一个处理
+pub struct SniMock;
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| u32::from_be_bytes([0, b[0], b[1], b[2]]) as usize)
+    }
+}
+
+/// 读取完整的ClientHello握手消息, 第一个字节不是握手记录时尽早返回None
+async fn read_client_hello<S>(stream: &mut Fallback<S>) -> crate::Result<Option<Vec<u8>>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 5];
+    let mut hello = Vec::new();
+
+    loop {
+        stream.read_exact(&mut head[..1]).await?;
+
+        if head[0] != CONTENT_TYPE_HANDSHAKE {
+            return Ok(None);
+        }
+
+        stream.read_exact(&mut head[1..]).await?;
+
+        let len = u16::from_be_bytes([head[3], head[4]]) as usize;
+
+        if head[1] != 0x03 || len == 0 || hello.len() + len > MAX_HELLO_LEN {
+            return Ok(None);
+        }
+
+        let offset = hello.len();
+        hello.resize(offset + len, 0);
+        stream.read_exact(&mut hello[offset..]).await?;
+
+        if let Some(hello_len) = Reader(&hello[1..]).u24() {
+            if hello.len() >= 4 + hello_len {
+                return Ok(Some(hello));
+            }
+        }
+    }
+}
+
+/// 从ClientHello中取出SNI, 统一为小写
+fn parse_sni(hello: &[u8]) -> Option<String> {
+    let mut hello = Reader(hello);
+
+    if hello.u8()? != HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+
+    let len = hello.u24()?;
+    let mut body = Reader(hello.take(len)?);
+
+    // version, random
+    body.take(2 + 32)?;
+
+    // session id, cipher suites, compression methods
+    let len = body.u8()? as usize;
+    body.take(len)?;
+    let len = body.u16()? as usize;
+    body.take(len)?;
+    let len = body.u8()? as usize;
+    body.take(len)?;
+
+    let len = body.u16()? as usize;
+    let mut extensions = Reader(body.take(len)?);
+
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let data = extensions.take(len)?;
+
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut data = Reader(data);
+        let len = data.u16()? as usize;
+        let mut names = Reader(data.take(len)?);
+
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+
+            if name_type == NAME_TYPE_HOST_NAME {
+                return std::str::from_utf8(name)
+                    .ok()
+                    .map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+impl<S> Provider<(Fallback<S>, Arc<super::super::server::Config>)> for SniMock
+where
+    S: Stream + Send + Sync + 'static,
+{
+    type Output = BoxedFuture<Selector<S>>;
+
+    fn call(
+        &self,
+        (stream, config): (Fallback<S>, Arc<super::super::server::Config>),
+    ) -> Self::Output {
+        Box::pin(async move {
+            let mut stream = stream;
+
+            if config.sni_routes.is_empty() {
+                return Ok(Selector::Unselected(stream));
+            }
+
+            let sni = match read_client_hello(&mut stream).await? {
+                Some(hello) => parse_sni(&hello),
+                None => None,
+            };
+
+            let route = sni.as_ref().and_then(|sni| {
+                config
+                    .sni_routes
+                    .iter()
+                    .find(|(host, _)| host.eq_ignore_ascii_case(sni))
+            });
+
+            match route {
+                None => {
+                    log::debug!("no route for sni {:?}", sni);
+                    Ok(Selector::Unselected(stream))
+                }
+                Some((host, addr)) => {
+                    log::debug!("route sni {} to {}", host, addr);
+
+                    // 交还读取的ClientHello, 转发的数据与访问者发送的一致
+                    stream.backward().await?;
+
+                    Ok(Selector::Checked(Peer::Route(
+                        Visitor::Route(stream),
+                        Socket::tcp(addr.clone()),
+                    )))
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ext::{AsyncReadExt, AsyncWriteExt},
+        guard::Fallback,
+    };
+
+    /// 只带SNI扩展的ClientHello记录
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let mut server_name = vec![0x00];
+        server_name.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+        server_name.extend_from_slice(sni.as_bytes());
+
+        let mut extension = (server_name.len() as u16).to_be_bytes().to_vec();
+        extension.extend_from_slice(&server_name);
+
+        let mut extensions = vec![0x00, 0x00];
+        extensions.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&extension);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut hello = vec![0x01];
+        hello.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        hello.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+        record.extend_from_slice(&hello);
+        record
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        let record = client_hello("Example.COM");
+        assert_eq!(
+            super::parse_sni(&record[5..]).as_deref(),
+            Some("example.com")
+        );
+        assert!(super::parse_sni(&record[5..record.len() - 1]).is_none());
+        assert!(super::parse_sni(b"GET / HTTP/1.1\r\n").is_none());
+    }
+
+    #[test]
+    fn test_peek_client_hello() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let mut data = client_hello("fuso.example.com");
+                data.extend_from_slice(b"application data");
+
+                let (mut w, r) = tokio::io::duplex(64);
+                let mut r = Fallback::new(r, false);
+
+                let writer = data.clone();
+                tokio::spawn(async move {
+                    // 分多次发送, 模拟ClientHello被拆分
+                    for chunk in writer.chunks(7) {
+                        w.write_all(chunk).await.unwrap();
+                    }
+                });
+
+                // 前一个处理读取过的数据也需要按原顺序交还
+                r.mark().await.unwrap();
+                let mut byte = [0u8; 1];
+                r.read_exact(&mut byte).await.unwrap();
+                r.backward().await.unwrap();
+
+                r.mark().await.unwrap();
+                let hello = super::read_client_hello(&mut r).await.unwrap().unwrap();
+                assert_eq!(
+                    super::parse_sni(&hello).as_deref(),
+                    Some("fuso.example.com")
+                );
+                r.backward().await.unwrap();
+
+                let mut forwarded = vec![0u8; data.len()];
+                r.read_exact(&mut forwarded).await.unwrap();
+                assert_eq!(forwarded, data);
+            });
+    }
+}
@@ -1,27 +1,49 @@
-use std::{net::SocketAddr, pin::Pin, sync::Arc};
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
 
 use crate::{
+    acl::Egress,
     ext::AsyncReadExt,
     guard::Fallback,
     io,
     penetrate::{
-        server::{Peer, Visitor},
+        server::{Connections, Peer, Permit, Visitor},
         PenetrateSelectorBuilder, Selector,
     },
     protocol::{make_packet, AsyncRecvPacket, AsyncSendPacket, Poto, ToBytes, TryToPoto},
+    proxy_protocol,
     select::Select,
     socks::{self, S5Authenticate, Socks},
-    Addr, Kind, Provider, Socket, SocketKind, Stream, UdpReceiverExt, UdpSocket, WrappedProvider,
+    time, Accepter, AccepterExt, Addr, Kind, NetSocket, Provider, Socket, SocketKind, Stream,
+    UdpReceiverExt, UdpSocket, WrappedProvider,
 };
 
 type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
 
+/// bind后等待连接的最长时间
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// 接收缓冲区能容纳任意udp数据包, 超长的数据包不会被截断
+const UDP_MAX_PAYLOAD: usize = 65535;
+
 macro_rules! get_auth {
     ($config: expr) => {{
+        let mut credentials = $config.socks5_credentials.clone();
+
         match (&$config.socks5_password, &$config.socks5_username) {
-            (Some(pwd), Some(username)) => S5Authenticate::standard(username, pwd),
-            (Some(pwd), None) => S5Authenticate::standard(&$config.whoami, pwd),
-            _ => S5Authenticate::default(),
+            (Some(pwd), Some(username)) => credentials.push((username.clone(), pwd.clone())),
+            (Some(pwd), None) => credentials.push(($config.whoami.clone(), pwd.clone())),
+            _ => {}
+        }
+
+        if credentials.is_empty() {
+            S5Authenticate::default()
+        } else {
+            S5Authenticate::with_credentials(credentials)
         }
     }};
 }
@@ -32,15 +54,30 @@ pub struct PenetrateSocksBuilder<E, P, S, O> {
 
 pub struct SimpleSocksMock;
 
+/// `udp_provider` 在给定的地址上监听udp中继
 pub struct SocksMock<U> {
-    pub(crate) udp_provider: Arc<WrappedProvider<(), (SocketAddr, U)>>,
+    pub(crate) udp_provider: Arc<WrappedProvider<SocketAddr, (SocketAddr, U)>>,
 }
 
 pub struct SocksUdpForwardMock<U>(pub(crate) WrappedProvider<Addr, (SocketAddr, U)>);
 
-pub struct SocksUdpForward<S, U> {
+/// 客户端处理socks5 bind, 监听后把监听地址与连接进来的地址依次发送给服务端
+pub struct SocksBindMock<A>(pub(crate) WrappedProvider<Socket, A>);
+
+/// 服务端处理socks5 bind, 把客户端发来的两次地址转换为socks5应答
+pub struct SocksBind<S> {
     stream: std::sync::Mutex<Option<S>>,
-    udp_provider: Arc<WrappedProvider<(), (SocketAddr, U)>>,
+}
+
+/// 占用当前客户端的一个udp中继数, 中继结束时归还
+pub struct SocksUdpForward<S, U> {
+    stream: std::sync::Mutex<Option<(S, Permit)>>,
+    associations: Connections,
+    egress: Egress,
+    public_ip: Option<IpAddr>,
+    max_datagram: usize,
+    max_frame_size: usize,
+    udp_provider: Arc<WrappedProvider<SocketAddr, (SocketAddr, U)>>,
 }
 
 impl<E, P, S, O> PenetrateSocksBuilder<E, P, S, O>
@@ -60,7 +97,7 @@ where
         udp_forward: UF,
     ) -> PenetrateSelectorBuilder<E, P, S, O>
     where
-        UF: Provider<(), Output = BoxedFuture<(SocketAddr, U)>> + Send + Sync + 'static,
+        UF: Provider<SocketAddr, Output = BoxedFuture<(SocketAddr, U)>> + Send + Sync + 'static,
         U: UdpSocket + Unpin + Send + Sync + 'static,
     {
         let udp_forward = WrappedProvider::wrap(udp_forward);
@@ -95,7 +132,9 @@ where
 
             let mut socks_auth = get_auth!(config);
 
-            let socket = match stream.socks5_handshake(&mut socks_auth).await {
+            let handshake = stream.socks5_handshake(&mut socks_auth);
+
+            let socket = match handshake.egress(&config.egress).await {
                 Err(e) if !e.is_socks_error() => return Err(e),
                 Err(_) => return Ok(Selector::Unselected(stream)),
                 Ok(socket) => socket,
@@ -108,6 +147,12 @@ where
                     Visitor::Route(stream),
                     socket,
                 ))),
+                SocketKind::Bind => Ok(Selector::Checked(Peer::Route(
+                    Visitor::Provider(WrappedProvider::wrap(SocksBind {
+                        stream: std::sync::Mutex::new(Some(stream.into_inner())),
+                    })),
+                    socket,
+                ))),
                 SocketKind::Udp => Ok({
                     socks::finish_udp_forward(&mut stream).await?;
                     Selector::Checked(Peer::Finished(stream))
@@ -140,7 +185,9 @@ where
 
             let mut socks_auth = get_auth!(config);
 
-            let socket = match stream.socks5_handshake(&mut socks_auth).await {
+            let handshake = stream.socks5_handshake(&mut socks_auth);
+
+            let socket = match handshake.egress(&config.egress).await {
                 Err(e) if !e.is_socks_error() => return Err(e),
                 Err(_) => return Ok(Selector::Unselected(stream)),
                 Ok(socket) => socket,
@@ -153,21 +200,53 @@ where
                     Visitor::Route(stream),
                     socket,
                 ))),
+                SocketKind::Bind => Ok(Selector::Checked(Peer::Route(
+                    Visitor::Provider(WrappedProvider::wrap(SocksBind {
+                        stream: std::sync::Mutex::new(Some(stream.into_inner())),
+                    })),
+                    socket,
+                ))),
                 SocketKind::Udp => Ok({
                     if !config.enable_socks_udp {
                         log::debug!("skip udp forwarding");
                         socks::finish_udp_forward(&mut stream).await?;
                         Selector::Checked(Peer::Finished(stream))
                     } else {
-                        let stream = stream.into_inner();
-                        let udp_forward = SocksUdpForward {
-                            udp_provider,
-                            stream: std::sync::Mutex::new(Some(stream)),
-                        };
-                        Selector::Checked(Peer::Route(
-                            Visitor::Provider(WrappedProvider::wrap(udp_forward)),
-                            Socket::ufd(socket.into_addr()),
-                        ))
+                        let associations = config.udp_associations.clone();
+
+                        match associations.acquire() {
+                            None => {
+                                log::warn!(
+                                    "too many udp associations ({}), refuse {}",
+                                    associations.active(),
+                                    stream.peer_addr()?
+                                );
+                                socks::finish_udp_forward(&mut stream).await?;
+                                Selector::Checked(Peer::Finished(stream))
+                            }
+                            Some(permit) => {
+                                log::debug!(
+                                    "udp association from {}, {} active",
+                                    stream.peer_addr()?,
+                                    associations.active()
+                                );
+
+                                let stream = stream.into_inner();
+                                let udp_forward = SocksUdpForward {
+                                    udp_provider,
+                                    associations,
+                                    egress: config.egress.clone(),
+                                    public_ip: config.udp_public_ip,
+                                    max_datagram: config.udp_max_datagram,
+                                    max_frame_size: config.max_frame_size,
+                                    stream: std::sync::Mutex::new(Some((stream, permit))),
+                                };
+                                Selector::Checked(Peer::Route(
+                                    Visitor::Provider(WrappedProvider::wrap(udp_forward)),
+                                    Socket::ufd(socket.into_addr()),
+                                ))
+                            }
+                        }
                     }
                 }),
                 _ => unsafe { std::hint::unreachable_unchecked() },
@@ -185,7 +264,7 @@ where
 
     fn call(&self, s2: Fallback<S>) -> Self::Output {
         let s2 = s2.into_inner();
-        let s1 = match self.stream.lock() {
+        let (s1, permit) = match self.stream.lock() {
             Err(_) => return Box::pin(async move { Err(Kind::Once.into()) }),
             Ok(mut lock) => match lock.take() {
                 None => return Box::pin(async move { Err(Kind::Once.into()) }),
@@ -193,18 +272,29 @@ where
             },
         };
 
+        let associations = self.associations.clone();
         let provider = self.udp_provider.clone();
+        let egress = self.egress.clone();
+        let public_ip = self.public_ip;
+        let max_datagram = self.max_datagram;
+        let max_frame_size = self.max_frame_size;
 
         let fut = async move {
             let mut s1 = s1;
             let peer_addr = s2.peer_addr()?;
             let (mut reader, mut writer) = io::split(s2);
 
-            let (addr, mut udp) = provider.call(()).await?;
+            // 中继与访问者的tcp连接使用相同的协议族, 应答地址为该连接的本地地址
+            let local = udp_relay_addr(proxy_protocol::socket_addr(&s1.local_addr()?));
+            let bind = SocketAddr::new(unspecified(&local), 0);
+
+            let (addr, mut udp) = provider.call(bind).await?;
 
             log::debug!("udp forwarding service listening on {}", addr);
 
-            socks::send_udp_forward_message(&mut s1, addr).await?;
+            let reply = udp_reply_addr(&local, public_ip, addr.port());
+
+            socks::send_udp_forward_message(&mut s1, reply).await?;
 
             let fut1 = {
                 let mut writer = writer.clone();
@@ -232,26 +322,43 @@ where
             };
 
             let fut2 = async move {
-                let mut buf = Vec::with_capacity(1500);
-
-                unsafe {
-                    buf.set_len(1500);
-                }
+                let mut buf = vec![0u8; UDP_MAX_PAYLOAD];
 
                 loop {
-                    let (n, addr) = udp.recv_from(&mut buf).await?;
-                    let origin = socks::parse_and_forward_data(&mut writer, &buf[..n]).await?;
+                    let (n, addr) = recv_datagram(&udp, &mut buf, max_datagram).await?;
+                    let forward = socks::parse_and_forward_data(&mut writer, &buf[..n], &egress);
+
+                    let origin = match forward.await? {
+                        Some(origin) => origin,
+                        None => {
+                            log::warn!("udp from {} to a target that is not allowed", peer_addr);
+                            continue;
+                        }
+                    };
+
                     log::info!("connect from {} to {}", peer_addr, origin);
 
-                    let packet = reader.recv_packet().await?;
+                    // 客户端无法解析或连接目标时返回错误, 只丢弃这个数据包
+                    match reader.recv_packet_limit(max_frame_size).await?.try_poto()? {
+                        Poto::Forward(_) => {}
+                        Poto::Error(code, detail) => {
+                            let reason = detail.unwrap_or_else(|| code.to_string());
+                            log::warn!("failed to forward udp to {}, {}", origin, reason);
+                            continue;
+                        }
+                        message => return Err(Kind::Unexpected(format!("{}", message)).into()),
+                    }
+
+                    let packet = reader.recv_packet_limit(max_frame_size).await?;
 
-                    socks::send_packed_udp_forward_message(
+                    // 应答超过udp的长度上限等原因发送失败时已经记录警告, 只丢弃这个应答
+                    let _ = socks::send_packed_udp_forward_message(
                         &mut udp,
                         &addr,
                         origin,
                         &packet.payload,
                     )
-                    .await?;
+                    .await;
                 }
             };
 
@@ -259,7 +366,14 @@ where
         };
 
         Box::pin(async move {
-            match fut.await {
+            let result = fut.await;
+
+            // 出错时同样归还
+            drop(permit);
+
+            log::debug!("udp association closed, {} active", associations.active());
+
+            match result {
                 Ok(()) => {
                     log::debug!("forward packet success");
                     Ok(())
@@ -273,6 +387,65 @@ where
     }
 }
 
+/// ipv4映射的ipv6地址按ipv4处理, 只支持ipv4的客户端也能使用应答的地址
+fn udp_relay_addr(local: Option<SocketAddr>) -> SocketAddr {
+    match local {
+        Some(SocketAddr::V6(v6)) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::from((v4, v6.port())),
+            None => SocketAddr::V6(v6),
+        },
+        Some(local) => local,
+        None => SocketAddr::from(([0, 0, 0, 0], 0)),
+    }
+}
+
+/// 默认应答同一协议族的未指定地址, 由访问者使用连接服务端的地址发送数据包,
+/// 服务端在NAT之后且访问者不支持时才需要指定公网地址
+fn udp_reply_addr(local: &SocketAddr, public_ip: Option<IpAddr>, port: u16) -> SocketAddr {
+    SocketAddr::new(public_ip.unwrap_or_else(|| unspecified(local)), port)
+}
+
+/// socks5 udp头部的长度, 地址类型未知时返回0, 由 `parse_and_forward_data` 拒绝
+fn udp_header_len(data: &[u8]) -> usize {
+    match (data.get(3), data.get(4)) {
+        (Some(0x01), _) => 10,
+        (Some(0x03), Some(len)) => 7 + *len as usize,
+        (Some(0x04), _) => 22,
+        _ => 0,
+    }
+}
+
+fn unspecified(addr: &SocketAddr) -> std::net::IpAddr {
+    match addr {
+        SocketAddr::V4(_) => [0u8; 4].into(),
+        SocketAddr::V6(_) => [0u8; 16].into(),
+    }
+}
+
+/// 接收一个数据(不包括socks5头部)不超过 `max` 的数据包, 超长的数据包记录警告后丢弃,
+/// 不截断后转发. socks5的udp中继不支持分片, 也没有针对单个数据包的错误应答,
+/// tcp连接上已经应答过 `UDP ASSOCIATE`, 再次应答会被访问者当作关联失败
+async fn recv_datagram<U>(udp: &U, buf: &mut [u8], max: usize) -> crate::Result<(usize, SocketAddr)>
+where
+    U: UdpSocket + Unpin,
+{
+    loop {
+        let (n, addr) = udp.recv_from(buf).await?;
+        let payload = n.saturating_sub(udp_header_len(&buf[..n]));
+
+        if payload <= max {
+            return Ok((n, addr));
+        }
+
+        log::warn!(
+            "udp datagram from {} carries {}bytes, exceeds the maximum {}bytes, drop it",
+            addr,
+            payload,
+            max
+        );
+    }
+}
+
 impl<S, U> Provider<S> for SocksUdpForwardMock<U>
 where
     S: Stream + Send + 'static,
@@ -283,11 +456,7 @@ where
     fn call(&self, mut stream: S) -> Self::Output {
         let provider = self.0.clone();
         Box::pin(async move {
-            let mut buf = Vec::with_capacity(1500);
-
-            unsafe {
-                buf.set_len(1500);
-            }
+            let mut buf = vec![0u8; UDP_MAX_PAYLOAD];
 
             loop {
                 let message = stream.recv_packet().await?.try_poto()?;
@@ -304,11 +473,17 @@ where
                     }
                 };
 
-                let (_, udp) = provider.call(addr).await?;
-
                 let data = stream.recv_packet().await?;
 
-                let _ = udp.send(&data.payload).await?;
+                let udp = match provider.call(addr.clone()).await {
+                    Ok((_, udp)) => udp,
+                    Err(e) => {
+                        log::warn!("failed to connect to udp {} err={}", addr, e);
+                        let message = Poto::from_error(&e).bytes();
+                        stream.send_packet(&message).await?;
+                        continue;
+                    }
+                };
 
                 log::info!(
                     "connect from {} to {} forward {}bytes",
@@ -317,8 +492,17 @@ where
                     data.payload.len()
                 );
 
+                // 数据包超过目标链路的长度上限时发送失败, 返回错误而不是结束转发
+                if let Err(e) = udp.send(&data.payload).await {
+                    log::warn!("failed to send udp to {} err={}", addr, e);
+                    stream.send_packet(&Poto::from_error(&e).bytes()).await?;
+                    continue;
+                }
+
                 let n = udp.recv(&mut buf).await?;
 
+                stream.send_packet(&Poto::Forward(addr).bytes()).await?;
+
                 let packet = make_packet(buf[..n].to_vec()).encode();
 
                 stream.send_packet(&packet).await?;
@@ -328,3 +512,239 @@ where
         })
     }
 }
+
+impl<S> Provider<Fallback<S>> for SocksBind<S>
+where
+    S: Stream + Send + 'static,
+{
+    type Output = BoxedFuture<()>;
+
+    fn call(&self, s2: Fallback<S>) -> Self::Output {
+        let mut s2 = s2.into_inner();
+        let s1 = match self.stream.lock() {
+            Err(_) => return Box::pin(async move { Err(Kind::Once.into()) }),
+            Ok(mut lock) => match lock.take() {
+                None => return Box::pin(async move { Err(Kind::Once.into()) }),
+                Some(s) => s,
+            },
+        };
+
+        Box::pin(async move {
+            let mut s1 = s1;
+
+            // 第一次为监听地址, 第二次为连接进来的地址
+            for _ in 0..2 {
+                match s2.recv_packet().await?.try_poto()? {
+                    Poto::Forward(addr) => {
+                        log::debug!("socks bind reply {}", addr);
+                        socks::send_bind_message(&mut s1, addr).await?;
+                    }
+                    Poto::Error(code, detail) => {
+                        let reason = detail.unwrap_or_else(|| code.to_string());
+                        log::warn!("socks bind failed, {}", reason);
+                        socks::finish_bind(&mut s1).await?;
+                        return Ok(());
+                    }
+                    message => return Err(Kind::Unexpected(format!("{}", message)).into()),
+                }
+            }
+
+            io::forward(s1, s2).await
+        })
+    }
+}
+
+impl<S, A> Provider<S> for SocksBindMock<A>
+where
+    S: Stream + Send + 'static,
+    A: Accepter + Unpin + Send + 'static,
+    A::Stream: Stream + Send + 'static,
+{
+    type Output = BoxedFuture<()>;
+
+    fn call(&self, mut stream: S) -> Self::Output {
+        let provider = self.0.clone();
+        Box::pin(async move {
+            // 以连接服务端时使用的本地地址作为应答地址, 内网中的其他主机可以连接
+            let local = match proxy_protocol::socket_addr(&stream.local_addr()?) {
+                Some(local) => local,
+                None => SocketAddr::from(([0, 0, 0, 0], 0)),
+            };
+
+            let bind = SocketAddr::new(unspecified(&local), 0);
+
+            let mut accepter = match provider.call(Socket::tcp(bind)).await {
+                Ok(accepter) => accepter,
+                Err(e) => {
+                    log::warn!("failed to bind {} err={}", bind, e);
+                    stream.send_packet(&Poto::from_error(&e).bytes()).await?;
+                    return Ok(());
+                }
+            };
+
+            let port = match proxy_protocol::socket_addr(&accepter.local_addr()?) {
+                Some(addr) => addr.port(),
+                None => return Err(Kind::Unexpected(format!("{}", accepter.local_addr()?)).into()),
+            };
+
+            let addr = Addr::from((local.ip(), port));
+
+            log::debug!("socks bind listening on {}", addr);
+
+            stream.send_packet(&Poto::Forward(addr).bytes()).await?;
+
+            let incoming =
+                time::wait_for(BIND_ACCEPT_TIMEOUT, async move { accepter.accept().await }).await;
+
+            let incoming = match incoming.and_then(|incoming| incoming) {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    log::warn!("socks bind accept failed err={}", e);
+                    stream.send_packet(&Poto::from_error(&e).bytes()).await?;
+                    return Ok(());
+                }
+            };
+
+            let peer_addr = match proxy_protocol::socket_addr(&incoming.peer_addr()?) {
+                Some(peer_addr) => Addr::from(peer_addr),
+                None => return Err(Kind::Unexpected(format!("{}", incoming.peer_addr()?)).into()),
+            };
+
+            log::info!("socks bind accepted {}", peer_addr);
+
+            stream
+                .send_packet(&Poto::Forward(peer_addr).bytes())
+                .await?;
+
+            io::forward(stream, incoming).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::SocksUdpForward;
+    use crate::{
+        guard::Fallback,
+        penetrate::server::Connections,
+        protocol::{AsyncRecvPacket, Poto, TryToPoto, DEFAULT_MAX_FRAME_SIZE},
+        Addr, Provider, UdpForwardProvider, WrappedProvider,
+    };
+
+    #[test]
+    fn test_udp_relay_addr() {
+        let relay = |addr: &str| super::udp_relay_addr(Some(addr.parse().unwrap()));
+
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+
+        assert_eq!(relay("[2001:db8::1]:6722"), addr("[2001:db8::1]:6722"));
+        assert_eq!(relay("[::ffff:10.0.0.1]:6722"), addr("10.0.0.1:6722"));
+        assert_eq!(relay("10.0.0.1:6722"), addr("10.0.0.1:6722"));
+        assert_eq!(super::udp_relay_addr(None), addr("0.0.0.0:0"));
+
+        let local = relay("[2001:db8::1]:6722");
+        assert!(super::unspecified(&local).is_ipv6());
+
+        let reply = |local: &str, public_ip: Option<&str>| {
+            let public_ip = public_ip.map(|ip| ip.parse().unwrap());
+            super::udp_reply_addr(&relay(local), public_ip, 1080)
+        };
+
+        assert_eq!(reply("10.0.0.1:6722", None), addr("0.0.0.0:1080"));
+        assert_eq!(reply("[::ffff:10.0.0.1]:6722", None), addr("0.0.0.0:1080"));
+        assert_eq!(reply("[2001:db8::1]:6722", None), addr("[::]:1080"));
+        assert_eq!(
+            reply("10.0.0.1:6722", Some("192.0.2.1")),
+            addr("192.0.2.1:1080")
+        );
+    }
+
+    #[test]
+    fn test_udp_oversized_datagram() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let visitor = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                visitor.connect(relay.local_addr().unwrap()).await.unwrap();
+
+                // ipv4下udp能承载的最大数据, 超过上限被丢弃而不是截断
+                visitor.send(&datagram(0xff, 65497)).await.unwrap();
+                // 上限只计算数据, 不包括socks5头部
+                visitor.send(&datagram(0x01, 1500)).await.unwrap();
+
+                let mut buf = vec![0u8; super::UDP_MAX_PAYLOAD];
+                let (n, addr) = super::recv_datagram(&relay, &mut buf, 1500).await.unwrap();
+
+                assert_eq!(addr, visitor.local_addr().unwrap());
+                assert_eq!(n, 1510);
+                assert!(buf[10..n].iter().all(|b| *b == 0x01));
+            });
+    }
+
+    #[test]
+    fn test_udp_forward_oversized_datagram() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let mut control = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let (s1, _) = listener.accept().await.unwrap();
+                let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let (s2, _) = listener.accept().await.unwrap();
+
+                let associations = Connections::new(None);
+                let permit = associations.acquire().unwrap();
+
+                let forward = SocksUdpForward {
+                    stream: std::sync::Mutex::new(Some((s1, permit))),
+                    associations,
+                    egress: Default::default(),
+                    public_ip: None,
+                    max_datagram: 1500,
+                    max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+                    udp_provider: Arc::new(WrappedProvider::wrap(UdpForwardProvider::default())),
+                };
+
+                tokio::spawn(forward.call(Fallback::new(s2, false)));
+
+                let mut reply = [0u8; 10];
+                control.read_exact(&mut reply).await.unwrap();
+                assert_eq!(&reply[..4], &[0x05, 0x00, 0x00, 0x01]);
+
+                let port = u16::from_be_bytes([reply[8], reply[9]]);
+                let visitor = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                visitor.connect(("127.0.0.1", port)).await.unwrap();
+
+                // 接近64KB, ipv4下udp能承载的最大数据包
+                visitor.send(&datagram(0xff, 65497)).await.unwrap();
+                visitor.send(&datagram(0xff, 1501)).await.unwrap();
+                visitor.send(&datagram(0x01, 1500)).await.unwrap();
+
+                // 超长的数据包没有发给客户端, 第一个转发的就是未超过上限的数据包
+                let message = client.recv_packet().await.unwrap().try_poto().unwrap();
+                assert!(matches!(message, Poto::Forward(addr) if addr == Addr::from(target())));
+
+                let packet = client.recv_packet().await.unwrap();
+                assert_eq!(packet.payload.len(), 1500);
+                assert!(packet.payload.iter().all(|b| *b == 0x01));
+            });
+    }
+
+    fn target() -> SocketAddr {
+        "127.0.0.1:9".parse().unwrap()
+    }
+
+    /// 发往 `target` 的socks5 udp数据包, ipv4地址的头部为10字节
+    fn datagram(byte: u8, len: usize) -> Vec<u8> {
+        let mut buf = vec![0x00, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x00, 0x09];
+        buf.resize(10 + len, byte);
+        buf
+    }
+}
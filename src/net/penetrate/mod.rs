@@ -4,9 +4,22 @@ mod builder;
 mod handshake;
 mod observer;
 mod bridge;
+mod session;
+mod event;
+mod hook;
+mod balance;
+mod breaker;
+mod auth;
+mod tenant;
 
 pub use handshake::*;
 pub use observer::*;
+pub use event::*;
+pub use hook::*;
+pub use balance::*;
+pub use breaker::*;
+pub use auth::*;
+pub use tenant::*;
 
 mod mock;
 
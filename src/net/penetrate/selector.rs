@@ -1,8 +1,8 @@
 use std::{pin::Pin, sync::Arc};
 
 use crate::{
-    guard::Fallback, server::Server, Accepter, Executor, Fuso, Provider, WrappedProvider, Socket,
-    Stream,
+    guard::Fallback, server::Server, Accepter, Executor, Fuso, FusoStream, Provider, Socket,
+    Stream, WrappedProvider,
 };
 
 use super::{
@@ -37,7 +37,7 @@ impl<E, A, P, S, O> PenetrateSelectorBuilder<E, P, S, O>
 where
     E: Executor + 'static,
     A: Accepter<Stream = S> + Unpin + Send + 'static,
-    S: Stream + Send + Sync + 'static,
+    S: Stream + From<FusoStream> + Send + Sync + 'static,
     P: Provider<Socket, Output = BoxedFuture<A>> + Send + Sync + 'static,
     O: PenetrateObserver + Send + Sync + 'static
 {
@@ -1,4 +1,18 @@
-use std::{collections::HashMap, fmt::Display, pin::Pin, sync::Arc, task::Poll, time::Duration};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::IpAddr,
+    ops::RangeInclusive,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use crate::io::{ForwardStrategy, Traffic};
 
 use crate::penetrate::accepter::PenetrateAccepter;
 use crate::penetrate::client;
@@ -13,23 +27,46 @@ use crate::{
     generator::Generator,
     guard::Fallback,
     io,
-    protocol::{AsyncRecvPacket, AsyncSendPacket, Bind, Poto, ToBytes, TryToPoto},
-    ready, Accepter, Provider, Socket, Stream, WrappedProvider,
+    mux::Mux,
+    protocol::{AsyncRecvPacket, AsyncSendPacket, Bind, ErrorCode, Poto, ToBytes, TryToPoto},
+    ready,
+    resource::ResourceGuard,
+    select::Select,
+    Accepter, AsyncWrite, FusoStream, Provider, Socket, Stream, ToBoxStream, WrappedProvider,
 };
 
 use super::accepter::Pen;
-use super::mock::Mock;
-use super::PenetrateObserver;
-use crate::{join, time, Address, Error, Kind, NetSocket, Processor, Platform};
+use super::mock::{self, Mock};
+use super::session::{Lease, Sessions};
+use super::{
+    Authenticator, Connected, Disconnected, Event, Events, Hooks, PenetrateObserver, TenantKey,
+    Verdict,
+};
+use crate::{
+    acl::Egress,
+    admin::{self, Admin},
+    encryption, join,
+    metrics::metrics,
+    proxy_protocol, time, Addr, Address, Error, Kind, NetSocket, Platform, Processor,
+};
 
 type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
 
+type Traffics = Arc<std::sync::Mutex<HashMap<u32, Arc<Traffic>>>>;
+
+const MAX_BIND_BACKOFF: Duration = Duration::from_secs(5);
+
+/// token校验失败后延迟断开, 减缓暴力猜测
+const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+type LeasedPenetrate<P, T, A, O> = Penetrate<P, T, Lease<PenetrateAccepter<A, A>>, O>;
+
 macro_rules! throw_client_error {
-    ($result: expr) => {
+    ($cid: expr, $conv: expr, $result: expr) => {
         match $result {
             Ok(t) => t,
             Err(e) => {
-                log::warn!("client error {}", e);
+                log::warn!(cid = $cid, conv = $conv; "client error {}", e);
                 return Ok(State::Error(e));
             }
         }
@@ -37,32 +74,32 @@ macro_rules! throw_client_error {
 }
 
 macro_rules! read_client_config {
-    ($client: expr) => {{
-        let config = $client.recv_packet().await;
+    ($cid: expr, $client: expr, $max: expr) => {{
+        let config = $client.recv_packet_limit($max).await;
 
         if let Err(e) = config.as_ref() {
-            log::warn!("client error {}", e);
+            log::warn!(cid = $cid; "client error {}", e);
             return Err(unsafe { config.unwrap_err_unchecked() });
         }
 
         let config = unsafe { config.unwrap_unchecked() };
 
-        let config = bincode::deserialize::<client::Config>(&config.payload);
+        let config = client::Config::decode(&config.payload);
 
         if let Err(e) = config.as_ref() {
-            log::warn!("configuration error {}", e);
+            log::warn!(cid = $cid; "configuration error {}", e);
             let err_msg = e.to_string().into_packet();
             let err_msg = err_msg.encode();
             if let Err(e) = $client.send_packet(&err_msg).await {
-                log::warn!("client error {}", e);
+                log::warn!(cid = $cid; "client error {}", e);
             }
             return Err(e.to_string().into());
         } else {
-            log::debug!("recv client config ");
+            log::debug!(cid = $cid; "recv client config ");
             let ok_msg = "YES".into_packet();
             let ok_msg = ok_msg.encode();
             if let Err(e) = $client.send_packet(&ok_msg).await {
-                log::warn!("client error {}", e);
+                log::warn!(cid = $cid; "client error {}", e);
                 return Err(e);
             }
         }
@@ -71,26 +108,47 @@ macro_rules! read_client_config {
     }};
 }
 
+/// 第一个字段为conv
 pub enum Outcome<T> {
-    Route(T, T),
-    Future(BoxedFuture<()>),
+    Route(u32, T, T, Arc<Traffic>, Permit, Option<Timing>),
+    Future(u32, BoxedFuture<()>),
+    /// 客户端请求排空映射, 只产生一次
+    Drain,
 }
 
 pub enum State<T> {
     Stop,
     Close(T),
     Finish,
-    Route(T, T),
-    Provider(BoxedFuture<()>),
+    Route(u32, T, T, Arc<Traffic>, Permit, Option<Timing>),
+    Provider(u32, BoxedFuture<()>),
+    /// 管理服务要求客户端连接指定地址
+    Push(FusoStream, Socket),
     Error(crate::Error),
 }
 
+/// 映射建立过程中各阶段的耗时, 转发结束后输出
+pub struct Timing {
+    name: String,
+    peer_addr: Address,
+    handshake: Duration,
+    map: Duration,
+    /// 映射完成的时间, 用于计算转发任务开始执行前的等待
+    mapped: Instant,
+}
+
 pub enum Visitor<T> {
     Route(T),
     Provider(WrappedProvider<T, ()>),
 }
 
-pub struct PenetrateGenerator<P, T, A, O>(Penetrate<P, T, PenetrateAccepter<A, A>, O>);
+pub struct PenetrateGenerator<P, T, A, O>
+where
+    A: Send + 'static,
+{
+    penetrate: Option<LeasedPenetrate<P, T, A, O>>,
+    linger: Option<(BoxedFuture<()>, Error)>,
+}
 
 pub enum Peer<T> {
     Route(Visitor<T>, Socket),
@@ -98,10 +156,31 @@ pub enum Peer<T> {
     Unknown(T),
 }
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct MQueue<T> {
     identify: Arc<Mutex<u32>>,
     wait_list: Arc<async_mutex::Mutex<HashMap<u32, T>>>,
+    /// 客户端请求排空后不再接受新的访问
+    draining: Arc<AtomicBool>,
+    /// 等待中的访问被移除时通知
+    removed: (async_channel::Sender<()>, async_channel::Receiver<()>),
+}
+
+/// 同时存在的连接数, 包括等待映射和正在转发的连接
+#[derive(Debug, Default, Clone)]
+pub struct Connections {
+    active: Arc<AtomicUsize>,
+    limit: Option<usize>,
+    /// 整个服务端的连接数, 变化时同步到指标
+    metered: bool,
+}
+
+/// 占用一个连接数, 释放时归还
+pub struct Permit {
+    active: Arc<AtomicUsize>,
+    metered: bool,
+    /// 同时占用的其他连接数, 一起释放
+    joined: Option<Box<Permit>>,
 }
 
 #[derive(Debug, Clone)]
@@ -110,6 +189,7 @@ pub struct Config {
     pub(super) is_mixed: bool,
     pub(super) maximum_wait: Duration,
     pub(super) heartbeat_delay: Duration,
+    pub(super) keepalive_timeout: Duration,
     pub(super) read_timeout: Option<Duration>,
     pub(super) write_timeout: Option<Duration>,
     pub(super) fallback_strict_mode: bool,
@@ -117,12 +197,67 @@ pub struct Config {
     pub(super) enable_socks_udp: bool,
     pub(super) socks5_password: Option<String>,
     pub(super) socks5_username: Option<String>,
+    pub(super) socks5_credentials: Vec<(String, String)>,
+    pub(super) allowed_bind_ports: Option<RangeInclusive<u16>>,
+    /// 允许客户端监听的地址, 为空时允许本机的任意地址
+    pub(super) allowed_bind_hosts: Vec<IpAddr>,
+    pub(super) allow_random_bind_port: bool,
+    /// 访问者可以到达的目标
+    pub(super) egress: Egress,
+    /// socks5 udp中继应答的地址, 服务端在NAT之后时使用
+    pub(super) udp_public_ip: Option<IpAddr>,
+    /// socks5 udp中继接收的数据包携带的数据的最大长度, 不包括socks5头部
+    pub(super) udp_max_datagram: usize,
+    /// 从客户端接收的数据包的最大长度
+    pub(super) max_frame_size: usize,
+    pub(super) max_udp_associations: Option<usize>,
+    /// 当前客户端的socks5 udp中继数, 每个客户端单独计数
+    pub(super) udp_associations: Connections,
+    pub(super) session_timeout: Duration,
+    pub(super) idle_timeout: Option<Duration>,
+    /// 转发的最长存活时间, 与是否空闲无关
+    pub(super) max_lifetime: Option<Duration>,
+    /// 访问者与客户端之间转发时的复制方式
+    pub(super) forward_strategy: ForwardStrategy,
+    pub(super) stats_interval: Option<Duration>,
+    pub(super) max_connections: Option<usize>,
+    /// 所有客户端共享的连接数
+    pub(super) server_connections: Connections,
+    /// 可用内存或文件描述符低于下限时拒绝新的访问
+    pub(super) resource_guard: Option<ResourceGuard>,
+    pub(super) proxy_protocol: bool,
+    pub(super) events: Events,
+    /// 每个转发开始与结束时执行的回调
+    pub(super) hooks: Hooks,
+    /// 记录正在转发的连接, 供管理服务查询与关闭
+    pub(super) admin: Option<Admin>,
+    /// 记录各阶段的耗时
+    pub(super) trace_timing: bool,
+    pub(super) bind_retries: usize,
+    /// 客户端在 `Bind` 中指定的存活时间
+    pub(super) ttl: Option<Duration>,
+    /// 客户端配置的SNI与转发目标
+    pub(super) sni_routes: Vec<(String, Addr)>,
+    /// 客户端配置的http `Host` 与转发目标
+    pub(super) host_routes: Vec<(String, Addr)>,
+    /// `Host` 没有对应的目标时回复404, 否则交给下一个处理
+    pub(super) host_not_found: bool,
+    /// 在访问者的第一个http请求中添加 `X-Forwarded-For`
+    pub(super) forwarded_for: bool,
+    /// 按客户端名区分的密钥
+    pub(super) tenant_keys: Vec<(String, TenantKey)>,
+    /// 客户端名没有对应的密钥时使用, 未设置时拒绝该客户端
+    pub(super) default_tenant_key: Option<TenantKey>,
+    /// 当前客户端使用的密钥, 在 `Bind` 之后确定
+    pub(super) tenant_key: Option<TenantKey>,
     pub(super) platform: Platform
 }
 
 pub struct PenetrateProvider<T> {
     pub(crate) mock: Arc<Mock<T>>,
     pub(crate) config: Config,
+    pub(crate) sessions: Sessions,
+    pub(crate) authenticator: Arc<dyn Authenticator>,
 }
 
 pub struct Penetrate<P, S, A, O> {
@@ -134,7 +269,24 @@ pub struct Penetrate<P, S, A, O> {
     processor: Processor<P, S, O>,
     futures: Vec<BoxedFuture<State<S>>>,
     mqueue: MQueue<async_channel::Sender<S>>,
+    traffics: Traffics,
+    connections: Connections,
     client_addr: Address,
+    /// 已经产生过 `Outcome::Drain`
+    drained: bool,
+    /// 管理服务交给客户端转发的连接
+    pushed: async_channel::Receiver<(FusoStream, Socket)>,
+}
+
+impl<T> Default for MQueue<T> {
+    fn default() -> Self {
+        Self {
+            identify: Default::default(),
+            wait_list: Default::default(),
+            draining: Default::default(),
+            removed: async_channel::bounded(1),
+        }
+    }
 }
 
 impl<T> MQueue<T> {
@@ -152,13 +304,113 @@ impl<T> MQueue<T> {
             }
         }
 
-        wait_list.insert(*ident, item);
+        let id = *ident;
+
+        // 不立即复用刚分配的id, 保证统计数据对应的conv在连接存活期间唯一
+        *ident = id.wrapping_add(1);
+        wait_list.insert(id, item);
 
-        *ident
+        id
     }
 
     pub async fn remove(&self, id: u32) -> Option<T> {
-        self.wait_list.lock().await.remove(&id)
+        let item = self.wait_list.lock().await.remove(&id);
+
+        if item.is_some() {
+            let _ = self.removed.0.try_send(());
+        }
+
+        item
+    }
+
+    /// 等待中的访问全部被映射或移除
+    pub async fn wait_empty(&self) {
+        while !self.wait_list.lock().await.is_empty() {
+            let _ = self.removed.1.recv().await;
+        }
+    }
+
+    /// 第一次请求排空时返回true
+    pub fn drain(&self) -> bool {
+        !self.draining.swap(true, Ordering::AcqRel)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+}
+
+impl<T> MQueue<async_channel::Sender<T>> {
+    /// 将客户端建立的映射交给等待中的访问者,
+    /// conv不存在或访问者已经关闭时原样返回映射连接
+    pub async fn route(&self, id: u32, item: T) -> Result<(), T> {
+        match self.remove(id).await {
+            None => Err(item),
+            Some(tx) => tx.send(item).await.map_err(|e| e.into_inner()),
+        }
+    }
+}
+
+impl Connections {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            active: Default::default(),
+            limit,
+            metered: false,
+        }
+    }
+
+    /// 整个服务端共享的连接数, 当前的数量会输出到指标
+    pub fn server(limit: Option<usize>) -> Self {
+        Self {
+            metered: true,
+            ..Self::new(limit)
+        }
+    }
+
+    /// 超过限制时返回None
+    pub fn acquire(&self) -> Option<Permit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                match self.limit {
+                    Some(limit) if active >= limit => None,
+                    _ => Some(active + 1),
+                }
+            })
+            .ok()
+            .map(|_| {
+                if self.metered {
+                    metrics().on_conv_open();
+                }
+
+                Permit {
+                    active: self.active.clone(),
+                    metered: self.metered,
+                    joined: None,
+                }
+            })
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+}
+
+impl Permit {
+    /// 同时持有 `other`, 释放时一起归还
+    pub fn join(mut self, other: Permit) -> Self {
+        self.joined = Some(Box::new(other));
+        self
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+
+        if self.metered {
+            metrics().on_conv_close();
+        }
     }
 }
 
@@ -173,12 +425,113 @@ impl Config {
         self.maximum_wait = config.maximum_wait;
         self.is_mixed = config.enable_kcp;
         self.platform = config.platform;
+        self.sni_routes = config.sni_routes;
+        self.host_routes = config.host_routes;
+        self.host_not_found = config.host_not_found;
+        self.forwarded_for = config.forwarded_for;
+    }
+
+    fn check_bind_port(&self, port: u16) -> crate::Result<()> {
+        if port == 0 {
+            if self.allow_random_bind_port {
+                return Ok(());
+            }
+
+            return Err(Kind::Refused(
+                ErrorCode::BindDenied,
+                Some(String::from("binding a random port is not allowed")),
+            )
+            .into());
+        }
+
+        match &self.allowed_bind_ports {
+            Some(ports) if !ports.contains(&port) => Err(Kind::Refused(
+                ErrorCode::BindDenied,
+                Some(format!(
+                    "port {} is not allowed, allowed ports are {}-{}",
+                    port,
+                    ports.start(),
+                    ports.end()
+                )),
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 客户端指定的监听地址必须是ip, 并且在允许的范围内, 限制了地址时未指定的地址也需要在允许的范围内.
+/// 不是本机的地址由绑定失败时的错误返回(`BindDenied`), 不在这里提前检查
+fn check_bind_host(allowed_hosts: &[IpAddr], socket: &Socket) -> crate::Result<()> {
+    let denied = |message: String| Err(Kind::Refused(ErrorCode::BindDenied, Some(message)).into());
+
+    let ip = match socket.ip() {
+        Some(ip) => ip,
+        None => return denied(format!("{} is not an ip address", socket.as_string())),
+    };
+
+    let allowed = allowed_hosts.is_empty()
+        || allowed_hosts
+            .iter()
+            .any(|host| *host == ip || (host.is_unspecified() && ip.is_unspecified()));
+
+    if !allowed {
+        return denied(format!("host {} is not allowed", ip));
+    }
+
+    Ok(())
+}
+
+/// 客户端连接使用的监听总是由服务端在任意地址上随机选择端口, 只保留客户端要求的连接类型,
+/// 否则客户端可以通过该监听绕过 `allowed_bind_ports` 与 `allowed_bind_hosts`.
+/// 返回客户端是否指定了其他地址
+fn server_chosen(socket: &mut Socket) -> bool {
+    if socket.is_ip_unspecified() && socket.port() == 0 {
+        return false;
+    }
+
+    socket.set_ip(std::net::Ipv4Addr::UNSPECIFIED);
+    socket.set_port(0);
+
+    true
+}
+
+/// 端口被占用时(如上一个实例的连接处于TIME_WAIT)按指数退避重试, 其他错误立即返回
+async fn bind_with_retry<P, A, S, O>(
+    processor: Processor<P, S, O>,
+    socket: Socket,
+    retries: usize,
+) -> crate::Result<A>
+where
+    P: Provider<Socket, Output = BoxedFuture<A>>,
+{
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 0;
+
+    loop {
+        match processor.bind(socket.clone()).await {
+            Err(e) if e.is_addr_in_use() && attempt < retries => {
+                attempt += 1;
+
+                log::warn!(
+                    "{} is in use, retry after {:?} ({}/{})",
+                    socket,
+                    delay,
+                    attempt,
+                    retries
+                );
+
+                time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BIND_BACKOFF);
+            }
+            result => break result.map_err(|e| e.with_bind_addr(&socket)),
+        }
     }
 }
 
 impl<P, T, A, O> Penetrate<P, T, A, O>
 where
-    T: Stream + Sync + Send + 'static,
+    T: Stream + From<FusoStream> + Sync + Send + 'static,
     A: Accepter<Stream = Pen<T>> + Unpin + Send + 'static,
     O: PenetrateObserver + Sync + Send + 'static,
     P: Sync + Send + 'static,
@@ -194,75 +547,289 @@ where
         let client_addr = unsafe { client.peer_addr().unwrap_unchecked() };
         let (reader, writer) = crate::io::split(client);
 
-        let mqueue = MQueue {
-            identify: Default::default(),
-            wait_list: Default::default(),
-        };
+        let mut config = config;
+
+        config.udp_associations = Connections::new(config.max_udp_associations);
+
+        let config = Arc::new(config);
+
+        metrics().on_penetrate_start();
+
+        let mqueue = MQueue::default();
+
+        let traffics = Traffics::default();
+
+        let recv_fut = Self::poll_handle_recv(
+            processor.cid().clone(),
+            mqueue.clone(),
+            traffics.clone(),
+            reader.clone(),
+            writer.clone(),
+            client_addr.clone(),
+            config.clone(),
+        );
+        // 保证在超时前至少能收到客户端的几次回应
+        let heartbeat_delay = config.heartbeat_delay.min(config.keepalive_timeout / 3);
+        let write_fut =
+            Self::poll_heartbeat_future(processor.cid().clone(), writer.clone(), heartbeat_delay);
+
+        let mut futures: Vec<BoxedFuture<State<T>>> = vec![Box::pin(recv_fut), Box::pin(write_fut)];
+
+        if let Some(interval) = config.stats_interval {
+            futures.push(Box::pin(Self::poll_stats_future(
+                processor.cid().clone(),
+                writer.clone(),
+                traffics.clone(),
+                interval,
+            )));
+        }
+
+        let (push, pushed) = async_channel::unbounded();
+        let (drain, drain_requested) = async_channel::bounded(1);
+
+        if let Some(admin) = &config.admin {
+            admin.register_client(admin::Client {
+                cid: processor.cid().clone(),
+                name: config.whoami.clone(),
+                push,
+                drain,
+            });
+        }
 
-        let recv_fut = Self::poll_handle_recv(mqueue.clone(), reader.clone());
-        let write_fut = Self::poll_heartbeat_future(writer.clone(), config.heartbeat_delay);
+        futures.push(Box::pin(Self::poll_push_future(pushed.clone())));
+        futures.push(Box::pin(Self::poll_drain_request_future(
+            processor.cid().clone(),
+            mqueue.clone(),
+            writer.clone(),
+            drain_requested,
+        )));
+
+        if let Some(ttl) = config.ttl {
+            futures.push(Box::pin(Self::poll_deadline_future(
+                processor.cid().clone(),
+                mqueue.clone(),
+                writer.clone(),
+                traffics.clone(),
+                ttl,
+            )));
+        }
 
         Self {
             writer,
-            config: Arc::new(config),
+            connections: Connections::new(config.max_connections),
+            config,
             mock: converter,
             accepter,
             mqueue,
+            traffics,
             client_addr,
             processor,
             address,
-            futures: vec![Box::pin(recv_fut), Box::pin(write_fut)],
+            futures,
+            drained: false,
+            pushed,
+        }
+    }
+
+    async fn poll_push_future(
+        pushed: async_channel::Receiver<(FusoStream, Socket)>,
+    ) -> crate::Result<State<T>> {
+        match pushed.recv().await {
+            Ok((stream, dst)) => Ok(State::Push(stream, dst)),
+            Err(_) => Ok(State::Finish),
         }
     }
 
     async fn poll_handle_recv(
+        cid: Arc<str>,
         mqueue: MQueue<async_channel::Sender<T>>,
-        mut stream: ReadHalf<T>,
+        traffics: Traffics,
+        stream: ReadHalf<T>,
+        mut writer: WriteHalf<T>,
+        client_addr: Address,
+        config: Arc<Config>,
     ) -> crate::Result<State<T>> {
+        let keepalive_timeout = config.keepalive_timeout;
+        let max_frame_size = config.max_frame_size;
+
         loop {
-            let packet = stream.recv_packet().await;
+            let mut reader = stream.clone();
+            let packet = match time::wait_for(keepalive_timeout, async move {
+                reader.recv_packet_limit(max_frame_size).await
+            })
+            .await
+            {
+                Ok(packet) => packet,
+                Err(e) => {
+                    log::warn!(
+                        cid = &*cid;
+                        "no heartbeat from client {} within {:?}, drop the mapping",
+                        client_addr,
+                        keepalive_timeout
+                    );
+                    return Ok(State::Error(e));
+                }
+            };
 
             if packet.is_err() {
                 let err = unsafe { packet.unwrap_err_unchecked() };
-                log::warn!("client error {}", err);
+                log::warn!(cid = &*cid; "client error {}", err);
                 return Ok(State::Error(err));
             }
 
-            let packet = unsafe { packet.unwrap_unchecked() }.try_poto();
+            // 包长度完整时只丢弃无法解析的消息, 连接继续使用
+            let message = match unsafe { packet.unwrap_unchecked() }.try_poto() {
+                Ok(message) => message,
+                Err(e) => {
+                    log::warn!(cid = &*cid; "The client sent an invalid packet {}", e);
 
-            if packet.is_err() {
-                log::warn!("The client sent an invalid packet");
-                return Ok(State::Error(unsafe { packet.unwrap_err_unchecked() }));
-            }
+                    let reply = Poto::from_error(&e).bytes();
 
-            let message = unsafe { packet.unwrap_unchecked() };
+                    if let Err(e) = writer.send_packet(&reply).await {
+                        return Ok(State::Error(e));
+                    }
+
+                    continue;
+                }
+            };
 
             match message {
                 Poto::Ping => {
-                    log::trace!("client ping received");
+                    log::trace!(cid = &*cid; "client ping received");
+                }
+                Poto::Pong => {
+                    log::trace!(cid = &*cid; "client pong received");
                 }
+                Poto::MapFailed(id, code, detail) => {
+                    let err: crate::Error = Kind::Refused(code, detail).into();
+                    log::warn!(cid = &*cid, conv = id; "client mapping failed {}", err);
+                    mqueue.remove(id).await.map(|r| r.close());
+                }
+                // 旧版本客户端
                 Poto::MapError(id, err) => {
-                    log::warn!("client mapping failed, msg = {}", err);
+                    log::warn!(cid = &*cid, conv = id; "client mapping failed, msg = {}", err);
                     mqueue.remove(id).await.map(|r| r.close());
                 }
+                Poto::Reset(id) => {
+                    let reply = match Self::reset_conv(&mqueue, &traffics, id).await? {
+                        true => {
+                            log::info!(cid = &*cid, conv = id; "connection reset by client");
+                            Poto::Reset(id)
+                        }
+                        false => {
+                            log::debug!(
+                                cid = &*cid, conv = id;
+                                "reset a connection that does not exist"
+                            );
+                            Poto::Error(
+                                ErrorCode::NoSuchConv,
+                                Some(format!("conv {} does not exist", id)),
+                            )
+                        }
+                    };
+
+                    if let Err(e) = writer.send_packet(&reply.bytes()).await {
+                        log::warn!(cid = &*cid, conv = id; "failed to reply reset to client");
+                        return Ok(State::Error(e));
+                    }
+                }
+                Poto::Drain => {
+                    if mqueue.drain() {
+                        log::info!(cid = &*cid; "the client requested to drain the mapping");
+                    }
+
+                    if let Err(e) = writer.send_packet(&Poto::Drain.bytes()).await {
+                        log::warn!(cid = &*cid; "failed to reply drain to client");
+                        return Ok(State::Error(e));
+                    }
+                }
                 message => {
-                    log::warn!("ignore client message {:?}", message);
+                    log::warn!(cid = &*cid; "ignore client message {:?}", message);
                 }
             }
         }
     }
 
+    /// 丢弃等待中的映射并关闭正在转发的连接, conv不存在时返回false
+    async fn reset_conv(
+        mqueue: &MQueue<async_channel::Sender<T>>,
+        traffics: &Traffics,
+        id: u32,
+    ) -> crate::Result<bool> {
+        let waiting = mqueue.remove(id).await.map(|r| r.close()).is_some();
+
+        let routing = match traffics.lock()?.get(&id) {
+            Some(traffic) if !traffic.is_finished() && !traffic.is_reset() => {
+                traffic.reset();
+                true
+            }
+            _ => false,
+        };
+
+        Ok(waiting || routing)
+    }
+
+    /// 管理服务要求排空映射时通知客户端, 之后与客户端请求排空的处理相同
+    async fn poll_drain_request_future(
+        cid: Arc<str>,
+        mqueue: MQueue<async_channel::Sender<T>>,
+        mut stream: WriteHalf<T>,
+        requested: async_channel::Receiver<()>,
+    ) -> crate::Result<State<T>> {
+        // 没有启动管理服务时不会被记录, 立即结束
+        if requested.recv().await.is_err() {
+            return Ok(State::Finish);
+        }
+
+        if mqueue.drain() {
+            log::info!(cid = &*cid; "the admin requested to drain the mapping");
+
+            if let Err(e) = stream.send_packet(&Poto::Drain.bytes()).await {
+                log::warn!(cid = &*cid; "failed to notify the client of draining");
+                return Ok(State::Error(e));
+            }
+        }
+
+        Ok(State::Finish)
+    }
+
+    /// 等待中的访问处理完后通知客户端并结束映射, 最多等待 `timeout`,
+    /// 已经开始转发的连接不受影响
+    async fn poll_drained_future(
+        cid: Arc<str>,
+        mqueue: MQueue<async_channel::Sender<T>>,
+        mut stream: WriteHalf<T>,
+        timeout: Duration,
+    ) -> crate::Result<State<T>> {
+        let pending = async move { mqueue.wait_empty().await };
+
+        if time::wait_for(timeout, pending).await.is_err() {
+            log::warn!(cid = &*cid; "pending visitors were not mapped within {:?}", timeout);
+        }
+
+        log::info!(cid = &*cid; "the mapping was drained");
+
+        let err: Error = Kind::Refused(ErrorCode::Drained, None).into();
+
+        if let Err(e) = stream.send_packet(&Poto::from_error(&err).bytes()).await {
+            log::warn!(cid = &*cid; "failed to notify the client of draining err={}", e);
+        }
+
+        Ok(State::Error(err))
+    }
+
     async fn poll_heartbeat_future(
+        cid: Arc<str>,
         mut stream: WriteHalf<T>,
         timeout: Duration,
     ) -> crate::Result<State<T>> {
         let ping = Poto::Ping.bytes();
 
         loop {
-            log::trace!("send heartbeat packet to client");
+            log::trace!(cid = &*cid; "send heartbeat packet to client");
 
             if let Err(e) = stream.send_packet(&ping).await {
-                log::warn!("failed to send heartbeat packet to client");
+                log::warn!(cid = &*cid; "failed to send heartbeat packet to client");
                 break Ok(State::Error(e));
             }
 
@@ -270,22 +837,300 @@ where
         }
     }
 
+    async fn poll_stats_future(
+        cid: Arc<str>,
+        mut stream: WriteHalf<T>,
+        traffics: Traffics,
+        interval: Duration,
+    ) -> crate::Result<State<T>> {
+        loop {
+            time::sleep(interval).await;
+
+            for poto in collect_stats(&traffics)? {
+                log::trace!(cid = &*cid; "send traffic statistics {:?}", poto);
+
+                if let Err(e) = stream.send_packet(&poto.bytes()).await {
+                    log::warn!(cid = &*cid; "failed to send traffic statistics to client");
+                    return Ok(State::Error(e));
+                }
+            }
+        }
+    }
+
+    /// 到期后关闭所有连接并通知客户端, 不受空闲超时影响
+    async fn poll_deadline_future(
+        cid: Arc<str>,
+        mqueue: MQueue<async_channel::Sender<T>>,
+        mut stream: WriteHalf<T>,
+        traffics: Traffics,
+        ttl: Duration,
+    ) -> crate::Result<State<T>> {
+        time::sleep(ttl).await;
+
+        log::info!(cid = &*cid; "the mapping expired after {:?}", ttl);
+
+        for (_, waiting) in mqueue.wait_list.lock().await.drain() {
+            waiting.close();
+        }
+
+        for traffic in traffics.lock()?.values() {
+            if !traffic.is_finished() {
+                traffic.reset();
+            }
+        }
+
+        let err: Error = Kind::Refused(
+            ErrorCode::Expired,
+            Some(format!("the mapping expired after {:?}", ttl)),
+        )
+        .into();
+
+        if let Err(e) = stream.send_packet(&Poto::from_error(&err).bytes()).await {
+            log::warn!(cid = &*cid; "failed to notify the client of expiration err={}", e);
+        }
+
+        Ok(State::Error(err))
+    }
+
+    /// 与访问者的流程相同, 但目标由管理服务指定, 不经过mock识别,
+    /// 客户端映射完成后与管理连接转发
+    fn async_push_handle(
+        self: &mut Pin<&mut Self>,
+        stream: FusoStream,
+        dst: Socket,
+    ) -> BoxedFuture<State<T>> {
+        let writer = self.writer.clone();
+        let timeout = self.config.maximum_wait;
+        let send_timeout = self.config.control_write_timeout();
+        let mqueue = self.mqueue.clone();
+        let processor = self.processor.clone();
+        let traffics = self.traffics.clone();
+        let push_stats = self.config.stats_interval.is_some();
+        let events = self.config.events.clone();
+        let name = self.config.whoami.clone();
+        let connections = self.connections.clone();
+        let server_connections = self.config.server_connections.clone();
+        let resource_guard = self.config.resource_guard.clone();
+        let admin = self.config.admin.clone();
+        let cid = self.processor.cid().clone();
+        let tenant_key = self.config.tenant_key.clone();
+
+        let fut = async move {
+            let src = T::from(stream);
+
+            if mqueue.is_draining() {
+                log::debug!(cid = &*cid; "draining, refuse to connect {}", dst);
+                return Ok(State::Close(src));
+            }
+
+            if let Some(low) = resource_guard.as_ref().and_then(|guard| guard.check()) {
+                log::warn!(cid = &*cid; "{}, refuse to connect {}", low, dst);
+                metrics().on_resource_rejected();
+                return Ok(State::Close(src));
+            }
+
+            let permit = connections.acquire().and_then(|permit| {
+                server_connections
+                    .acquire()
+                    .map(|server_permit| permit.join(server_permit))
+            });
+
+            let permit = match permit {
+                Some(permit) => permit,
+                None => {
+                    log::warn!(cid = &*cid; "too many connections, refuse to connect {}", dst);
+                    return Ok(State::Close(src));
+                }
+            };
+
+            let visit_addr = src.peer_addr()?;
+            let (accept_tx, accept_ax) = async_channel::bounded(1);
+            let id = mqueue.push(accept_tx).await;
+
+            let route = Poto::Map(id, dst).bytes();
+
+            throw_client_error!(
+                &*cid,
+                id,
+                notify_map(&mqueue, id, writer.clone(), route, send_timeout).await
+            );
+
+            log::trace!(
+                cid = &*cid, conv = id, peer_addr = visit_addr.to_string();
+                "client notified by admin, waiting for mapping"
+            );
+
+            events.emit(Event::Map {
+                conv: id,
+                name: name.clone(),
+                peer_addr: visit_addr.clone(),
+            });
+
+            let mut dst = accept_ax.recv().await?;
+
+            if let Some(key) = tenant_key.as_ref() {
+                dst = T::from(key.wrap(dst));
+            }
+
+            processor
+                .observer()
+                .on_pen_route(&writer.peer_addr()?, &visit_addr, &dst.peer_addr()?);
+
+            metrics().on_route();
+
+            events.emit(Event::Forward {
+                conv: id,
+                name: name.clone(),
+                peer_addr: visit_addr.clone(),
+            });
+
+            let traffic = Arc::new(Traffic::default());
+
+            if let Some(admin) = admin.as_ref() {
+                admin.register(admin::Tunnel {
+                    cid: cid.clone(),
+                    conv: id,
+                    name,
+                    bind_addr: src.local_addr()?,
+                    client_addr: writer.peer_addr()?,
+                    visitor_addr: visit_addr,
+                    traffic: traffic.clone(),
+                    started: std::time::Instant::now(),
+                });
+            }
+
+            {
+                let mut traffics = traffics.lock()?;
+
+                if !push_stats {
+                    traffics.retain(|_, traffic| !traffic.is_finished());
+                }
+
+                traffics.insert(id, traffic.clone());
+            }
+
+            Ok(State::Route(id, src, dst, traffic, permit, None))
+        };
+
+        let wait_fut = crate::time::wait_for(timeout, fut);
+
+        Box::pin(async move { wait_fut.await? })
+    }
+
     fn async_penetrate_handle(self: &mut Pin<&mut Self>, pen: Pen<T>) -> BoxedFuture<State<T>> {
         let mut writer = self.writer.clone();
         let mock = self.mock.clone();
         let timeout = self.config.maximum_wait;
+        let send_timeout = self.config.control_write_timeout();
         let mqueue = self.mqueue.clone();
         let fallback_strict_mode = self.config.fallback_strict_mode;
         let processor = self.processor.clone();
         let config = self.config.clone();
+        let traffics = self.traffics.clone();
+        let push_stats = self.config.stats_interval.is_some();
+        let proxy_protocol = self.config.proxy_protocol;
+        let events = self.config.events.clone();
+        let hooks = self.config.hooks.clone();
+        let name = self.config.whoami.clone();
+        let connections = self.connections.clone();
+        let server_connections = self.config.server_connections.clone();
+        let resource_guard = self.config.resource_guard.clone();
+        let admin = self.config.admin.clone();
+        let cid = self.processor.cid().clone();
+        let tenant_key = self.config.tenant_key.clone();
+        let trace_timing = self.config.trace_timing;
+        let forwarded_for = self.config.forwarded_for;
 
         let fut = async move {
             match pen {
-                Pen::Visit(visitor) => {
+                Pen::Visit(visitor, _) if mqueue.is_draining() => {
+                    log::debug!(cid = &*cid; "draining, refuse {}", visitor.peer_addr()?);
+                    Ok(State::Close(visitor))
+                }
+                Pen::Visit(visitor, service) => {
+                    let accepted = Instant::now();
+
+                    if let Some(low) = resource_guard.as_ref().and_then(|guard| guard.check()) {
+                        log::warn!(
+                            cid = &*cid, peer_addr = writer.peer_addr()?.to_string();
+                            "{}, refuse {}",
+                            low,
+                            visitor.peer_addr()?
+                        );
+
+                        metrics().on_resource_rejected();
+
+                        let message = Poto::Error(ErrorCode::QuotaExceeded, Some(low.to_string()));
+
+                        if let Err(e) = writer.send_packet(&message.bytes()).await {
+                            return Ok(State::Error(e));
+                        }
+
+                        return Ok(State::Close(visitor));
+                    }
+
+                    let permit = match connections.acquire() {
+                        Some(permit) => permit,
+                        None => {
+                            log::warn!(
+                                cid = &*cid, peer_addr = writer.peer_addr()?.to_string();
+                                "too many connections ({}), refuse {}",
+                                connections.active(),
+                                visitor.peer_addr()?
+                            );
+
+                            let message = Poto::Error(
+                                ErrorCode::QuotaExceeded,
+                                Some(format!("too many connections ({})", connections.active())),
+                            );
+
+                            if let Err(e) = writer.send_packet(&message.bytes()).await {
+                                return Ok(State::Error(e));
+                            }
+
+                            return Ok(State::Close(visitor));
+                        }
+                    };
+
+                    let permit = match server_connections.acquire() {
+                        Some(server_permit) => permit.join(server_permit),
+                        None => {
+                            log::warn!(
+                                cid = &*cid, peer_addr = writer.peer_addr()?.to_string();
+                                "too many connections on the server ({}), refuse {}",
+                                server_connections.active(),
+                                visitor.peer_addr()?
+                            );
+
+                            let message = Poto::Error(
+                                ErrorCode::QuotaExceeded,
+                                Some(format!(
+                                    "too many connections on the server ({})",
+                                    server_connections.active()
+                                )),
+                            );
+
+                            if let Err(e) = writer.send_packet(&message.bytes()).await {
+                                return Ok(State::Error(e));
+                            }
+
+                            return Ok(State::Close(visitor));
+                        }
+                    };
+
                     let mut fallback = Fallback::new(visitor, fallback_strict_mode);
                     let visit_addr = fallback.peer_addr()?;
+                    let visit_local_addr = fallback.local_addr()?;
                     let _ = fallback.mark().await?;
-                    let peer = mock.call((fallback, config)).await?;
+                    let is_service = service.is_some();
+
+                    // 其他服务的转发目标已经确定, 不经过mock识别
+                    let peer = match service {
+                        Some(dst) => Peer::Route(Visitor::Route(fallback), dst),
+                        None => mock.call((fallback, config)).await?,
+                    };
+
+                    let handshake = accepted.elapsed();
                     let (accept_tx, accept_ax) = async_channel::bounded(1);
                     let id = mqueue.push(accept_tx).await;
 
@@ -295,24 +1140,93 @@ where
                         Peer::Route(visitor, dst) => (visitor, dst),
                     };
 
+                    // 只有直接转发或转发到其他服务时才能确定后端就是本地服务
+                    let proxy_protocol = proxy_protocol && (is_service || dst.is_default());
+
                     let route = Poto::Map(id, dst).bytes();
+                    let notified = Instant::now();
 
-                    throw_client_error!(writer.send_packet(&route).await);
+                    throw_client_error!(
+                        &*cid,
+                        id,
+                        notify_map(&mqueue, id, writer.clone(), route, send_timeout).await
+                    );
 
-                    log::trace!("client notified, waiting for mapping");
+                    log::trace!(
+                        cid = &*cid, conv = id, peer_addr = visit_addr.to_string();
+                        "client notified, waiting for mapping"
+                    );
+
+                    events.emit(Event::Map {
+                        conv: id,
+                        name: name.clone(),
+                        peer_addr: visit_addr.clone(),
+                    });
 
                     match visitor {
                         Visitor::Route(src) => {
                             let mut src = src;
                             let mut dst = accept_ax.recv().await?;
+                            let map = notified.elapsed();
+
+                            if let Some(key) = tenant_key.as_ref() {
+                                dst = T::from(key.wrap(dst));
+                            }
 
                             src.backward().await?;
 
+                            // 读取第一个请求头后回退, 与之前读取的数据一起转发
+                            let forwarded = match proxy_protocol::socket_addr(&visit_addr) {
+                                Some(addr) if forwarded_for => {
+                                    src.mark().await?;
+                                    let head = mock::read_request_head(&mut src).await?;
+                                    src.backward().await?;
+                                    head.map(|head| {
+                                        (head.len(), mock::append_forwarded_for(&head, addr.ip()))
+                                    })
+                                }
+                                _ => None,
+                            };
+
+                            if proxy_protocol {
+                                let addrs = (
+                                    proxy_protocol::socket_addr(&visit_addr),
+                                    proxy_protocol::socket_addr(&visit_local_addr),
+                                );
+
+                                if let (Some(peer), Some(local)) = addrs {
+                                    let header = proxy_protocol::encode_v2(peer, local);
+
+                                    log::trace!(cid = &*cid, conv = id; "send proxy protocol header");
+
+                                    if let Err(e) = dst.write_all(&header).await {
+                                        log::warn!(
+                                            cid = &*cid, conv = id;
+                                            "mapping failed, the client has closed the connection"
+                                        );
+                                        return Err(e);
+                                    }
+                                }
+                            }
+
                             if let Some(data) = src.back_data() {
-                                log::debug!("copy data to peer {}bytes", data.len());
+                                let data = match forwarded {
+                                    Some((len, head)) => {
+                                        log::trace!(cid = &*cid, conv = id; "add x-forwarded-for");
+                                        [&head, &data[len..]].concat()
+                                    }
+                                    None => data,
+                                };
+
+                                log::debug!(
+                                    cid = &*cid, conv = id;
+                                    "copy data to peer {}bytes",
+                                    data.len()
+                                );
 
                                 if let Err(e) = dst.write_all(&data).await {
                                     log::warn!(
+                                        cid = &*cid, conv = id;
                                         "mapping failed, the client has closed the connection"
                                     );
                                     return Err(e.into());
@@ -325,11 +1239,65 @@ where
                                 &dst.peer_addr()?,
                             );
 
-                            Ok::<_, crate::Error>(State::Route(src.into_inner(), dst))
+                            metrics().on_route();
+
+                            events.emit(Event::Forward {
+                                conv: id,
+                                name: name.clone(),
+                                peer_addr: visit_addr.clone(),
+                            });
+
+                            let traffic = Arc::new(Traffic::default());
+
+                            let timing = trace_timing.then(|| Timing {
+                                name: name.clone(),
+                                peer_addr: visit_addr.clone(),
+                                handshake,
+                                map,
+                                mapped: Instant::now(),
+                            });
+
+                            if let Some(admin) = admin.as_ref() {
+                                admin.register(admin::Tunnel {
+                                    cid: cid.clone(),
+                                    conv: id,
+                                    name,
+                                    bind_addr: visit_local_addr,
+                                    client_addr: writer.peer_addr()?,
+                                    visitor_addr: visit_addr,
+                                    traffic: traffic.clone(),
+                                    started: std::time::Instant::now(),
+                                });
+                            }
+
+                            {
+                                let mut traffics = traffics.lock()?;
+
+                                // 不推送统计时没有其他地方清理已结束的连接
+                                if !push_stats {
+                                    traffics.retain(|_, traffic| !traffic.is_finished());
+                                }
+
+                                traffics.insert(id, traffic.clone());
+                            }
+
+                            Ok::<_, crate::Error>(State::Route(
+                                id,
+                                src.into_inner(),
+                                dst,
+                                traffic,
+                                permit,
+                                timing,
+                            ))
                         }
                         Visitor::Provider(provider) => {
-                            let fallback =
-                                Fallback::new(accept_ax.recv().await?, fallback_strict_mode);
+                            let mut dst = accept_ax.recv().await?;
+
+                            if let Some(key) = tenant_key.as_ref() {
+                                dst = T::from(key.wrap(dst));
+                            }
+
+                            let fallback = Fallback::new(dst, fallback_strict_mode);
 
                             processor.observer().on_pen_route(
                                 &writer.peer_addr()?,
@@ -337,27 +1305,115 @@ where
                                 &fallback.peer_addr()?,
                             );
 
+                            metrics().on_route();
+
+                            events.emit(Event::Forward {
+                                conv: id,
+                                name: name.clone(),
+                                peer_addr: visit_addr.clone(),
+                            });
+
                             let dst = provider.call(fallback);
 
-                            Ok(State::Provider(dst))
+                            Ok(State::Provider(
+                                id,
+                                Box::pin(async move {
+                                    let _permit = permit;
+
+                                    hooks
+                                        .connect(Connected {
+                                            conv: id,
+                                            name: name.clone(),
+                                            peer_addr: visit_addr.clone(),
+                                        })
+                                        .await;
+
+                                    let result = dst.await;
+
+                                    // 由服务端处理的转发没有流量统计
+                                    hooks
+                                        .close(Disconnected {
+                                            conv: id,
+                                            name,
+                                            peer_addr: visit_addr,
+                                            closed: io::Closed::of(&result),
+                                            rx_bytes: 0,
+                                            tx_bytes: 0,
+                                        })
+                                        .await;
+
+                                    result
+                                }),
+                            ))
                         }
                     }
                 }
                 Pen::Client(client) => {
                     let mut client = processor.decorate(client).await?;
 
-                    let poto = client.recv_packet().await?.try_poto()?;
+                    let poto = client
+                        .recv_packet_limit(config.max_frame_size)
+                        .await?
+                        .try_poto()?;
 
                     match poto {
                         Poto::Map(id, _) => {
-                            if let Some(tx) = mqueue.remove(id).await {
-                                if let Err(_) = tx.send(client).await {
-                                    log::warn!("the client established a mapping request, but the peer was closed");
+                            log::trace!(cid = &*cid, conv = id; "client mapping established");
+
+                            if let Err(mut client) = mqueue.route(id, client).await {
+                                log::debug!(
+                                    cid = &*cid, conv = id;
+                                    "the client established a mapping, but the conv no longer exists"
+                                );
+
+                                let _ = client.close().await;
+
+                                let message = Poto::Error(
+                                    ErrorCode::NoSuchConv,
+                                    Some(format!("conv {} does not exist", id)),
+                                );
+
+                                if let Err(e) = writer.send_packet(&message.bytes()).await {
+                                    log::warn!(cid = &*cid, conv = id; "failed to send message to client");
+                                    return Ok(State::Error(e));
                                 }
                             }
                         }
+                        Poto::Mux => {
+                            client.send_packet(&Poto::Mux.bytes()).await?;
+
+                            let (mux, driver) = Mux::new(client)?;
+
+                            log::debug!(cid = &*cid; "client mux established");
+
+                            // 逻辑连接的conv即为映射的id
+                            let accept = async move {
+                                loop {
+                                    let stream = mux.accept().await?;
+                                    let id = stream.conv();
+
+                                    log::trace!(cid = &*cid, conv = id; "client mux stream established");
+
+                                    if mqueue
+                                        .route(id, T::from(stream.into_boxed_stream()))
+                                        .await
+                                        .is_err()
+                                    {
+                                        log::debug!(
+                                            cid = &*cid, conv = id;
+                                            "the client opened a mux stream, but the conv no longer exists"
+                                        );
+                                    }
+                                }
+                            };
+
+                            return Ok(State::Provider(
+                                0,
+                                Box::pin(Select::select(driver, accept)),
+                            ));
+                        }
                         poto => {
-                            log::warn!("bad message {}", poto)
+                            log::warn!(cid = &*cid; "bad message {}", poto)
                         }
                     }
 
@@ -377,6 +1433,17 @@ where
     }
 }
 
+impl<P, S, A, O> Drop for Penetrate<P, S, A, O> {
+    fn drop(&mut self) {
+        metrics().on_penetrate_stop();
+
+        self.config.events.emit(Event::Close {
+            name: self.config.whoami.clone(),
+            peer_addr: self.client_addr.clone(),
+        });
+    }
+}
+
 impl<P, T, A, O> NetSocket for Penetrate<P, T, A, O>
 where
     T: Stream,
@@ -393,7 +1460,7 @@ where
 
 impl<P, T, A, O> Accepter for Penetrate<P, T, A, O>
 where
-    T: Stream + Send + Sync + 'static,
+    T: Stream + From<FusoStream> + Send + Sync + 'static,
     A: Accepter<Stream = Pen<T>> + Unpin + Send + 'static,
     O: PenetrateObserver + Sync + Send + 'static,
     P: Send + Sync + 'static,
@@ -424,21 +1491,30 @@ where
                     Poll::Pending => {
                         self.futures.push(future);
                     }
-                    Poll::Ready(Ok(State::Route(s1, s2))) => {
+                    Poll::Ready(Ok(State::Route(id, s1, s2, traffic, permit, timing))) => {
                         self.futures.extend(futures);
 
-                        return Poll::Ready(Ok::<_, crate::Error>(Outcome::Route(s1, s2)));
+                        return Poll::Ready(Ok::<_, crate::Error>(Outcome::Route(
+                            id, s1, s2, traffic, permit, timing,
+                        )));
                     }
-                    Poll::Ready(Ok(State::Provider(fut))) => {
+                    Poll::Ready(Ok(State::Provider(id, fut))) => {
                         self.futures.extend(futures);
-                        return Poll::Ready(Ok::<_, crate::Error>(Outcome::Future(fut)));
+                        return Poll::Ready(Ok::<_, crate::Error>(Outcome::Future(id, fut)));
                     }
                     Poll::Ready(Ok(State::Stop)) => {
-                        log::warn!("client aborted {}", self.client_addr);
+                        log::warn!(
+                            cid = &**self.processor.cid(), peer_addr = self.client_addr.to_string();
+                            "client aborted"
+                        );
                         return Poll::Ready(Err(crate::error::Kind::Channel.into()));
                     }
                     Poll::Ready(Ok(State::Error(e))) => {
-                        log::warn!("client error {}, err: {}", self.client_addr, e);
+                        log::warn!(
+                            cid = &**self.processor.cid(), peer_addr = self.client_addr.to_string();
+                            "client error {}",
+                            e
+                        );
                         self.processor.observer().on_pen_error(&self.address, &e);
                         return Poll::Ready(Err(e));
                     }
@@ -448,12 +1524,21 @@ where
                             Ok(State::Finish)
                         }));
                     }
+                    Poll::Ready(Ok(State::Push(stream, dst))) => {
+                        futures.push(self.async_push_handle(stream, dst));
+                        futures.push(Box::pin(Self::poll_push_future(self.pushed.clone())));
+                    }
                     _ => {}
                 }
             }
         }
 
-        log::debug!("{} futures remaining", self.futures.len());
+        log::debug!(cid = &**self.processor.cid(); "{} futures remaining", self.futures.len());
+
+        if !self.drained && self.mqueue.is_draining() {
+            self.drained = true;
+            return Poll::Ready(Ok(Outcome::Drain));
+        }
 
         Poll::Pending
     }
@@ -462,7 +1547,7 @@ where
 impl<P, A, S, O> Provider<(S, Processor<P, S, O>)> for PenetrateProvider<S>
 where
     A: Accepter<Stream = S> + Send + Unpin + 'static,
-    S: Stream + Sync + Send + 'static,
+    S: Stream + From<FusoStream> + Sync + Send + 'static,
     P: Provider<Socket, Output = BoxedFuture<A>> + Send + Sync + 'static,
     O: PenetrateObserver + Send + Sync + 'static,
 {
@@ -471,17 +1556,69 @@ where
     fn call(&self, (mut client, processor): (S, Processor<P, S, O>)) -> Self::Output {
         let peer_provider = self.mock.clone();
         let mut config = self.config.clone();
+        let sessions = self.sessions.clone();
+        let cid = processor.cid().clone();
+        let authenticator = self.authenticator.clone();
         Box::pin(async move {
-            let poto = client.recv_packet().await?.try_poto()?;
-            let penetrate = match poto {
-                Poto::Bind(Bind::Setup(client_addr, visit_addr)) => {
-                    log::debug!("try to bind the server to {}", visit_addr);
-                    let visit_fut = processor.bind(visit_addr);
-                    let client_fut = processor.bind(client_addr);
-                    join::join_output(client_fut, visit_fut).await
+            let started = Instant::now();
+            let mut poto = client
+                .recv_packet_limit(config.max_frame_size)
+                .await?
+                .try_poto()?;
+
+            let token = match &poto {
+                Poto::Token(token) => Some(token.clone()),
+                _ => None,
+            };
+
+            let verdict = authenticator
+                .authenticate(client.peer_addr()?, token)
+                .await?;
+
+            let auth = started.elapsed();
+
+            if let Verdict::Allow(Some(identity)) = &verdict {
+                log::info!(cid = &*cid; "client authenticated as {}", identity);
+            }
+
+            if verdict == Verdict::Deny {
+                log::warn!(
+                    cid = &*cid, peer_addr = client.peer_addr()?.to_string();
+                    "client authentication failed"
+                );
+
+                time::sleep(AUTH_FAILURE_DELAY).await;
+
+                let err: Error = Kind::Refused(ErrorCode::AuthFailed, None).into();
+
+                let _ = client.send_packet(&Poto::from_error(&err).bytes()).await;
+
+                processor
+                    .observer()
+                    .on_pen_error(&client.peer_addr()?, &err);
+
+                return Err(err);
+            }
+
+            // 认证方式不需要token时忽略客户端发送的token
+            if let Poto::Token(_) = poto {
+                poto = client
+                    .recv_packet_limit(config.max_frame_size)
+                    .await?
+                    .try_poto()?;
+            }
+
+            let (name, mut client_addr, mut visit_addr) = match poto {
+                Poto::Bind(Bind::Setup(client_addr, visit_addr)) => (None, client_addr, visit_addr),
+                Poto::Bind(Bind::Named(name, client_addr, visit_addr)) => {
+                    (Some(name), client_addr, visit_addr)
+                }
+                Poto::Bind(Bind::Expiring(name, client_addr, visit_addr, ttl)) => {
+                    config.ttl = Some(ttl);
+                    (name, client_addr, visit_addr)
                 }
                 message => {
-                    log::debug!("received an invalid message {}", message);
+                    log::debug!(cid = &*cid; "received an invalid message {}", message);
 
                     let err: Error = Kind::Unexpected(format!("{}", message)).into();
 
@@ -493,34 +1630,175 @@ where
                 }
             };
 
+            let tenant_key = super::lookup_tenant_key(
+                &config.tenant_keys,
+                config.default_tenant_key.as_ref(),
+                name.as_deref(),
+            );
+
+            match tenant_key {
+                Ok(key) => config.tenant_key = key.cloned(),
+                Err(()) => {
+                    log::warn!(
+                        cid = &*cid, peer_addr = client.peer_addr()?.to_string();
+                        "no tenant key for client {:?}",
+                        name
+                    );
+
+                    let err: Error =
+                        Kind::Refused(ErrorCode::AuthFailed, Some(String::from("unknown tenant")))
+                            .into();
+
+                    let _ = client.send_packet(&Poto::from_error(&err).bytes()).await;
+
+                    processor
+                        .observer()
+                        .on_pen_error(&client.peer_addr()?, &err);
+
+                    return Err(err);
+                }
+            }
+
+            if server_chosen(&mut client_addr) {
+                log::warn!(
+                    cid = &*cid, peer_addr = client.peer_addr()?.to_string();
+                    "ignore the listen address requested by the client for its connections"
+                );
+            }
+
+            // 服务端监听在ipv6上时, 未指定的ipv4地址改为监听所有ipv6地址
+            if let Address::One(local) = client.local_addr()? {
+                if local.ip().is_some_and(|ip| ip.is_ipv6()) {
+                    for addr in [&mut client_addr, &mut visit_addr] {
+                        if addr.is_ip_unspecified() {
+                            addr.set_ip(std::net::Ipv6Addr::UNSPECIFIED);
+                        }
+                    }
+                }
+            }
+
+            let checked = config
+                .check_bind_port(visit_addr.port())
+                .and_then(|_| check_bind_host(&config.allowed_bind_hosts, &visit_addr));
+
+            let binding = Instant::now();
+            let service_addr = visit_addr.clone();
+
+            let penetrate = match checked {
+                Err(e) => Err(e),
+                Ok(()) => match name
+                    .map(|name| sessions.claim::<PenetrateAccepter<A, A>>(&name))
+                    .transpose()
+                {
+                    Err(e) => Err(e),
+                    Ok(Some((session, Some(parked))))
+                        if parked.visit_port().is_some_and(|port| {
+                            visit_addr.port() == 0 || port == visit_addr.port()
+                        }) =>
+                    {
+                        log::info!(cid = &*cid; "reuse the listener of {}", session.name());
+                        parked
+                            .into_inner()
+                            .map(|parked| (Some(session), parked))
+                            .ok_or_else(|| Kind::Once.into())
+                    }
+                    Ok(claimed) => {
+                        // 端口发生变化时丢弃保留的监听, 重新监听
+                        let session = claimed.map(|(session, _)| session);
+                        log::debug!(cid = &*cid; "try to bind the server to {}", visit_addr);
+                        let retries = config.bind_retries;
+                        let visit_fut = bind_with_retry(processor.clone(), visit_addr, retries);
+                        let client_fut = bind_with_retry(processor.clone(), client_addr, retries);
+                        join::join_output(client_fut, visit_fut)
+                            .await
+                            .map(|(aclient, avisit)| (session, (avisit, aclient)))
+                    }
+                },
+            };
+
             match penetrate {
                 Err(e) => {
-                    let message = Poto::Bind(Bind::Failed(e.to_string())).bytes();
+                    let message = Poto::from_error(&e).bytes();
 
-                    log::warn!("failed to create listener err={}", e);
+                    log::warn!(cid = &*cid; "failed to create listener err={}", e);
 
                     if let Err(e) = client.send_packet(&message).await {
-                        log::warn!("failed to send failure message to client err={}", e);
+                        log::warn!(cid = &*cid; "failed to send failure message to client err={}", e);
                         processor.observer().on_pen_error(&client.peer_addr()?, &e);
                     }
 
                     Err(e)
                 }
-                Ok((aclient, avisit)) => {
+                Ok((session, (avisit, aclient))) => {
+                    if config.trace_timing {
+                        log::debug!(
+                            cid = &*cid;
+                            "timing: auth {:?}, bind {:?}",
+                            auth,
+                            binding.elapsed()
+                        );
+                    }
+
                     let visit_addr = avisit.local_addr()?;
                     let client_addr = aclient.local_addr()?;
 
                     let poto = Poto::Bind(Bind::Success(client_addr, visit_addr));
 
                     client.send_packet(&poto.bytes()).await.map_err(|e| {
-                        log::warn!("failed to send message to client err={}", e);
+                        log::warn!(cid = &*cid; "failed to send message to client err={}", e);
                         e
                     })?;
 
-                    let client_config = read_client_config!(client);
+                    let mut client_config =
+                        read_client_config!(&*cid, client, config.max_frame_size);
+                    let client_services = std::mem::take(&mut client_config.services);
 
                     config.update(client_config);
 
+                    // 其他服务与访问者监听在同一个地址上, 任意一个失败时整个连接失败
+                    let mut services = Vec::with_capacity(client_services.len());
+
+                    for (service, port, target) in client_services {
+                        let mut bind_addr = service_addr.clone();
+                        bind_addr.set_port(port);
+
+                        let bound = match config
+                            .check_bind_port(port)
+                            .and_then(|_| check_bind_host(&config.allowed_bind_hosts, &bind_addr))
+                        {
+                            Err(e) => Err(e),
+                            Ok(()) => {
+                                bind_with_retry(processor.clone(), bind_addr, config.bind_retries)
+                                    .await
+                            }
+                        };
+
+                        match bound {
+                            Ok(accepter) => {
+                                log::info!(
+                                    cid = &*cid;
+                                    "please visit {} for service {}",
+                                    accepter.local_addr()?,
+                                    service
+                                );
+
+                                services.push((accepter, Socket::tcp(target)));
+                            }
+                            Err(e) => {
+                                log::warn!(
+                                    cid = &*cid;
+                                    "failed to create listener for service {} err={}",
+                                    service,
+                                    e
+                                );
+
+                                let _ = client.send_packet(&Poto::from_error(&e).bytes()).await;
+
+                                return Err(e);
+                            }
+                        }
+                    }
+
                     processor.observer().on_pen_start(
                         &client.peer_addr()?,
                         &avisit.local_addr()?,
@@ -529,21 +1807,37 @@ where
                     );
 
                     log::info!(
-                        "client is {} and the server is {}",
-                        client.peer_addr()?,
+                        cid = &*cid, peer_addr = client.peer_addr()?.to_string();
+                        "client connected and the server is {}",
                         aclient.local_addr()?
                     );
 
-                    log::info!("please visit {} for port mapping", avisit.local_addr()?);
+                    log::info!(
+                        cid = &*cid;
+                        "please visit {} for port mapping",
+                        avisit.local_addr()?
+                    );
 
-                    Ok(PenetrateGenerator(Penetrate::new(
-                        config,
-                        peer_provider,
-                        processor,
-                        client.peer_addr()?,
-                        client,
-                        PenetrateAccepter::new(avisit, aclient),
-                    )))
+                    config.events.emit(Event::Open {
+                        name: config.whoami.clone(),
+                        peer_addr: client.peer_addr()?,
+                        visit_addr: avisit.local_addr()?,
+                    });
+
+                    Ok(PenetrateGenerator {
+                        penetrate: Some(Penetrate::new(
+                            config,
+                            peer_provider,
+                            processor,
+                            client.peer_addr()?,
+                            client,
+                            Lease::new(
+                                PenetrateAccepter::new(avisit, aclient).with_services(services),
+                                session,
+                            ),
+                        )),
+                        linger: None,
+                    })
                 }
             }
         })
@@ -553,7 +1847,7 @@ where
 impl<P, T, A, O> Generator for PenetrateGenerator<P, T, A, O>
 where
     A: Accepter<Stream = T> + Send + Unpin + 'static,
-    T: Stream + Send + Sync + 'static,
+    T: Stream + From<FusoStream> + Send + Sync + 'static,
     O: PenetrateObserver + Sync + Send + 'static,
     P: Send + Sync + 'static,
 {
@@ -563,22 +1857,215 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut std::task::Context,
     ) -> Poll<crate::Result<Self::Output>> {
-        match ready!(Pin::new(&mut self.0).poll_accept(cx)?) {
-            Outcome::Future(fut) => {
-                log::debug!("start a future");
+        if let Some((linger, _)) = self.linger.as_mut() {
+            let _ = ready!(Pin::new(linger).poll(cx));
+            let (_, e) = unsafe { self.linger.take().unwrap_unchecked() };
+            return Poll::Ready(Err(e));
+        }
+
+        let penetrate = match self.penetrate.as_mut() {
+            Some(penetrate) => penetrate,
+            None => return Poll::Ready(Ok(None)),
+        };
+
+        let idle_timeout = penetrate.config.idle_timeout;
+        let max_lifetime = penetrate.config.max_lifetime;
+        let strategy = penetrate.config.forward_strategy;
+        let events = penetrate.config.events.clone();
+        let hooks = penetrate.config.hooks.clone();
+        let name = penetrate.config.whoami.clone();
+        let cid = penetrate.processor.cid().clone();
+
+        let outcome = match ready!(Pin::new(&mut *penetrate).poll_accept(cx)) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                // 到期的映射不再保留监听
+                if ErrorCode::from(&e) == ErrorCode::Expired {
+                    penetrate.accepter.discard();
+                }
+
+                let timeout = penetrate.config.session_timeout;
+                let linger = penetrate.accepter.expire_after(timeout);
+
+                // 释放后监听交还给 `Sessions`, 等待同名客户端重连
+                drop(self.penetrate.take());
+
+                return match linger {
+                    None => Poll::Ready(Err(e)),
+                    Some((name, linger)) => {
+                        log::info!(cid = &*cid; "keep the listener of {} for {:?}", name, timeout);
+                        self.linger = Some((linger, e));
+                        self.poll_generate(cx)
+                    }
+                };
+            }
+        };
+
+        match outcome {
+            Outcome::Future(id, fut) => {
+                log::debug!(cid = &*cid, conv = id; "start a future");
                 Poll::Ready(Ok(Some(fut)))
             }
-            Outcome::Route(s1, s2) => Poll::Ready(Ok(Some(Box::pin(async move {
-                log::debug!("start forwarding");
-                if let Err(e) = io::forward(s1, s2).await {
-                    log::trace!("forward error {}", e);
-                };
-                Ok(())
-            })))),
+            Outcome::Drain => {
+                log::info!(cid = &*cid; "stop accepting visitors");
+
+                // 立即释放名称, 新的客户端可以接替该映射
+                penetrate.accepter.release();
+
+                if let Some(accepter) = penetrate.accepter.get_mut() {
+                    accepter.close_visit();
+                }
+
+                let drained = LeasedPenetrate::<P, T, A, O>::poll_drained_future(
+                    cid,
+                    penetrate.mqueue.clone(),
+                    penetrate.writer.clone(),
+                    penetrate.config.maximum_wait,
+                );
+
+                penetrate.futures.push(Box::pin(drained));
+
+                self.poll_generate(cx)
+            }
+            Outcome::Route(id, s1, s2, traffic, permit, timing) => {
+                Poll::Ready(Ok(Some(Box::pin(async move {
+                    let spawn = timing.as_ref().map(|timing| timing.mapped.elapsed());
+                    let started = Instant::now();
+                    let finish = Finish(traffic.clone());
+                    log::debug!(cid = &*cid, conv = id; "start forwarding");
+                    let peer_addr = s1
+                        .peer_addr()
+                        .unwrap_or_else(|_| Address::One(Socket::default()));
+                    hooks
+                        .connect(Connected {
+                            conv: id,
+                            name: name.clone(),
+                            peer_addr: peer_addr.clone(),
+                        })
+                        .await;
+                    let forward =
+                        io::forward_with_strategy(s1, s2, idle_timeout, traffic.clone(), strategy)
+                            .max_lifetime(max_lifetime);
+                    let result = forward.await;
+                    let closed = io::Closed::of(&result);
+                    match result {
+                        Err(e) if encryption::is_auth_failure(&e) => {
+                            log::warn!(cid = &*cid, conv = id; "forward of {} failed {}", peer_addr, e);
+                        }
+                        Err(e) => log::debug!(cid = &*cid, conv = id; "forward {} {}", closed, e),
+                        Ok(()) => {}
+                    };
+                    log::debug!(
+                        cid = &*cid, conv = id;
+                        "forward finished ({}), rx {}bytes, tx {}bytes",
+                        closed,
+                        traffic.rx_bytes(),
+                        traffic.tx_bytes()
+                    );
+                    events.emit(Event::Finish {
+                        conv: id,
+                        name: name.clone(),
+                        closed,
+                        rx_bytes: traffic.rx_bytes(),
+                        tx_bytes: traffic.tx_bytes(),
+                    });
+                    hooks
+                        .close(Disconnected {
+                            conv: id,
+                            name,
+                            peer_addr,
+                            closed,
+                            rx_bytes: traffic.rx_bytes(),
+                            tx_bytes: traffic.tx_bytes(),
+                        })
+                        .await;
+                    if let (Some(timing), Some(spawn)) = (timing, spawn) {
+                        let first_byte = traffic.first_tx().map(|at| at.duration_since(started));
+                        log::debug!(
+                            cid = &*cid, conv = id;
+                            "timing: handshake {:?}, map {:?}, spawn {:?}, first byte {:?}",
+                            timing.handshake,
+                            timing.map,
+                            spawn,
+                            first_byte
+                        );
+                        events.emit(Event::Timing {
+                            conv: id,
+                            name: timing.name,
+                            peer_addr: timing.peer_addr,
+                            handshake: timing.handshake,
+                            map: timing.map,
+                            spawn,
+                            first_byte,
+                        });
+                    }
+                    drop(finish);
+                    drop(permit);
+                    Ok(())
+                }))))
+            }
+        }
+    }
+}
+
+impl Config {
+    /// 通过控制连接通知客户端时的写入超时, 不超过等待映射时间的一半,
+    /// 保证在等待映射超时之前发现控制连接已经无法写入
+    fn control_write_timeout(&self) -> Duration {
+        let limit = self.maximum_wait / 2;
+        match self.write_timeout {
+            Some(timeout) => timeout.min(limit),
+            None => limit,
         }
     }
 }
 
+/// 收集所有conv的统计, 已结束的连接在发送最后一次统计后移除
+fn collect_stats(traffics: &Traffics) -> crate::Result<Vec<Poto>> {
+    let mut traffics = traffics.lock()?;
+
+    let stats = traffics
+        .iter()
+        .map(|(id, traffic)| Poto::Stats(*id, traffic.rx_bytes(), traffic.tx_bytes()))
+        .collect();
+
+    traffics.retain(|_, traffic| !traffic.is_finished());
+
+    Ok(stats)
+}
+
+/// 转发的future被提前丢弃时同样标记为结束, 否则统计表中的记录永远不会被移除
+struct Finish(Arc<Traffic>);
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+/// 通过控制连接通知客户端建立映射, 写入失败或超过 `timeout` 没有写完时认为控制连接已经半关闭,
+/// 移除等待中的conv并返回错误, 由调用方关闭整个映射, 不再向失效的连接发送后续的 `Map`.
+/// 超时取消时可能已经写入了部分数据, 控制连接无法继续使用
+async fn notify_map<W, S>(
+    mqueue: &MQueue<S>,
+    id: u32,
+    mut writer: W,
+    packet: Vec<u8>,
+    timeout: Duration,
+) -> crate::Result<()>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let sent = time::wait_for(timeout, async move { writer.send_packet(&packet).await }).await;
+    let result = sent.and_then(|result| result);
+
+    if result.is_err() {
+        mqueue.remove(id).await;
+    }
+
+    result
+}
+
 impl Display for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -588,3 +2075,260 @@ impl Display for Config {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::ErrorKind,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{Connections, MQueue};
+    use crate::{
+        protocol::{ErrorCode, Poto},
+        Processor, Provider, Socket,
+    };
+
+    type BoxedFuture<T> =
+        Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+    /// 前 `fails` 次返回 `kind` 错误
+    struct FailingBind {
+        kind: ErrorKind,
+        fails: usize,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Provider<Socket> for FailingBind {
+        type Output = BoxedFuture<()>;
+
+        fn call(&self, _: Socket) -> Self::Output {
+            let calls = self.calls.fetch_add(1, Ordering::SeqCst);
+            let kind = self.kind;
+            let fails = self.fails;
+            Box::pin(async move {
+                if calls < fails {
+                    Err(std::io::Error::from(kind).into())
+                } else {
+                    Ok(())
+                }
+            })
+        }
+    }
+
+    fn bind(kind: ErrorKind, fails: usize, retries: usize) -> (crate::Result<()>, usize) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let provider = FailingBind {
+            kind,
+            fails,
+            calls: calls.clone(),
+        };
+
+        let processor = Processor::<_, (), ()>::new(Arc::new(provider), None, None);
+
+        let result = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(super::bind_with_retry(processor, Socket::tcp(0), retries));
+
+        (result, calls.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_bind_retry() {
+        let (result, calls) = bind(ErrorKind::AddrInUse, 2, 3);
+        assert!(result.is_ok());
+        assert_eq!(calls, 3);
+
+        let (result, calls) = bind(ErrorKind::AddrInUse, 5, 2);
+        let err = result.unwrap_err();
+        assert!(err.is_addr_in_use());
+        assert!(matches!(
+            err.kind(),
+            crate::Kind::Build(crate::BuildErr::Bind(socket, _)) if socket == &Socket::tcp(0)
+        ));
+        assert_eq!(calls, 3);
+
+        let (result, calls) = bind(ErrorKind::PermissionDenied, 5, 3);
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_route_missing_conv() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mqueue = MQueue::default();
+
+            assert_eq!(mqueue.route(0, "fuso").await, Err("fuso"));
+
+            let (tx, rx) = async_channel::bounded(1);
+            let id = mqueue.push(tx).await;
+            assert_eq!(mqueue.route(id, "fuso").await, Ok(()));
+            assert_eq!(rx.recv().await.unwrap(), "fuso");
+            assert_eq!(mqueue.route(id, "fuso").await, Err("fuso"));
+
+            let (tx, rx) = async_channel::bounded(1);
+            let id = mqueue.push(tx).await;
+            drop(rx);
+            assert_eq!(mqueue.route(id, "fuso").await, Err("fuso"));
+
+            assert!(!mqueue.is_draining());
+            assert!(mqueue.drain());
+            assert!(!mqueue.drain());
+            assert!(mqueue.clone().is_draining());
+        });
+    }
+
+    #[test]
+    fn test_notify_half_closed_client() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mqueue = MQueue::default();
+
+            let timeout = Duration::from_millis(100);
+
+            // 客户端已经不再读取, 写满缓冲区后一直无法写入
+            let (control, _client) = tokio::io::duplex(8);
+            let (tx, _rx) = async_channel::bounded::<()>(1);
+            let id = mqueue.push(tx).await;
+            let result = super::notify_map(&mqueue, id, control, vec![0u8; 64], timeout).await;
+            assert!(result.is_err());
+            assert!(mqueue.remove(id).await.is_none());
+
+            // 客户端的读取端已经关闭
+            let (control, client) = tokio::io::duplex(8);
+            drop(client);
+            let (tx, _rx) = async_channel::bounded::<()>(1);
+            let id = mqueue.push(tx).await;
+            let result = super::notify_map(&mqueue, id, control, vec![0u8; 4], timeout).await;
+            assert!(result.is_err());
+            assert!(mqueue.remove(id).await.is_none());
+
+            let (control, _client) = tokio::io::duplex(8);
+            let (tx, _rx) = async_channel::bounded::<()>(1);
+            let id = mqueue.push(tx).await;
+            let result = super::notify_map(&mqueue, id, control, vec![0u8; 4], timeout).await;
+            assert!(result.is_ok());
+            assert!(mqueue.remove(id).await.is_some());
+        });
+    }
+
+    #[test]
+    fn test_check_bind_host() {
+        let check = |allowed: &[[u8; 4]], socket: Socket| {
+            let allowed = allowed.iter().map(|ip| (*ip).into()).collect::<Vec<_>>();
+            super::check_bind_host(&allowed, &socket).map_err(|e| ErrorCode::from(&e))
+        };
+
+        assert!(check(&[], Socket::tcp(([0, 0, 0, 0], 8080))).is_ok());
+        assert!(check(&[], Socket::tcp(([127, 0, 0, 1], 8080))).is_ok());
+        assert!(check(&[[127, 0, 0, 1]], Socket::tcp(([127, 0, 0, 1], 8080))).is_ok());
+        assert!(check(&[[0, 0, 0, 0]], Socket::tcp(([0, 0, 0, 0], 8080))).is_ok());
+
+        let denied = Err(ErrorCode::BindDenied);
+        assert_eq!(
+            check(&[[127, 0, 0, 1]], Socket::tcp(([192, 0, 2, 1], 8080))),
+            denied
+        );
+        assert_eq!(
+            check(&[[127, 0, 0, 1]], Socket::tcp(([0, 0, 0, 0], 8080))),
+            denied
+        );
+        assert_eq!(
+            check(&[[127, 0, 0, 2]], Socket::tcp(([127, 0, 0, 1], 8080))),
+            denied
+        );
+        assert_eq!(
+            check(&[], Socket::tcp((String::from("fuso.example.com"), 8080))),
+            denied
+        );
+
+        // 不是本机的地址绑定时失败, 同样回复 `BindDenied`
+        let socket = Socket::tcp(([192, 0, 2, 1], 8080));
+        let err = crate::Error::from(std::io::Error::from(std::io::ErrorKind::AddrNotAvailable))
+            .with_bind_addr(&socket);
+        assert_eq!(ErrorCode::from(&err), ErrorCode::BindDenied);
+    }
+
+    #[test]
+    fn test_server_chosen() {
+        let mut socket = Socket::tcp(0).if_stream_mixed(true);
+        assert!(!super::server_chosen(&mut socket));
+        assert!(socket.is_mixed());
+
+        let mut socket = Socket::tcp(([127, 0, 0, 1], 22)).if_stream_mixed(true);
+        assert!(super::server_chosen(&mut socket));
+        assert!(socket.is_default());
+        assert!(socket.is_mixed());
+
+        let mut socket = Socket::tcp((String::from("fuso.example.com"), 0));
+        assert!(super::server_chosen(&mut socket));
+        assert!(socket.is_default());
+    }
+
+    #[test]
+    fn test_connections_limit() {
+        let connections = Connections::new(Some(2));
+
+        let p1 = connections.acquire().unwrap();
+        let p2 = connections.acquire().unwrap();
+        assert!(connections.acquire().is_none());
+        assert_eq!(connections.active(), 2);
+
+        drop(p1);
+        let _p3 = connections.acquire().unwrap();
+        assert!(connections.acquire().is_none());
+
+        drop(p2);
+        assert_eq!(connections.active(), 1);
+
+        let unlimited = Connections::new(None);
+        let permits = (0..100)
+            .map(|_| unlimited.acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(unlimited.active(), permits.len());
+
+        // 客户端未超过限制时仍受服务端限制, 释放时两边一起归还
+        let server = Connections::server(Some(1));
+        let permit = unlimited.acquire().unwrap().join(server.acquire().unwrap());
+        assert!(server.acquire().is_none());
+        assert_eq!(unlimited.active(), 101);
+
+        drop(permit);
+        assert_eq!((server.active(), unlimited.active()), (0, 100));
+        assert!(server.acquire().is_some());
+    }
+
+    #[test]
+    fn test_collect_stats() {
+        let traffics = super::Traffics::default();
+        let running = Arc::new(crate::io::Traffic::default());
+        let finished = Arc::new(crate::io::Traffic::default());
+
+        traffics.lock().unwrap().insert(1, running.clone());
+        traffics.lock().unwrap().insert(2, finished.clone());
+
+        // 转发的future被丢弃时同样标记为结束
+        drop(super::Finish(finished));
+
+        let mut stats = super::collect_stats(&traffics).unwrap();
+        stats.sort_by_key(|poto| format!("{:?}", poto));
+        assert_eq!(stats, vec![Poto::Stats(1, 0, 0), Poto::Stats(2, 0, 0)]);
+
+        // 已结束的连接只在最后一次统计中出现
+        assert_eq!(
+            super::collect_stats(&traffics).unwrap(),
+            vec![Poto::Stats(1, 0, 0)]
+        );
+
+        running.finish();
+        assert_eq!(
+            super::collect_stats(&traffics).unwrap(),
+            vec![Poto::Stats(1, 0, 0)]
+        );
+        assert!(super::collect_stats(&traffics).unwrap().is_empty());
+    }
+}
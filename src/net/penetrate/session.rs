@@ -0,0 +1,318 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::{time, Accepter, Address, Kind, NetSocket};
+
+type Parked = Box<dyn Any + Send>;
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+/// 默认保留监听的时间
+const DEFAULT_PARK_TIMEOUT: Duration = Duration::from_secs(30);
+
+enum Entry {
+    Active(u64),
+    /// 超过期限后不再交给重连的客户端, 在下次占用或保留名称时关闭
+    Parked(u64, Parked, Instant),
+}
+
+/// 按客户端名称记录映射, 客户端断开后暂时保留监听, 同名客户端重连时直接复用
+#[derive(Clone)]
+pub struct Sessions {
+    generation: Arc<AtomicU64>,
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    park_timeout: Duration,
+}
+
+/// 一个正在使用的名称, 未转为 `Lease` 就被释放时移除记录
+pub struct Session {
+    id: u64,
+    name: String,
+    sessions: Sessions,
+}
+
+/// 持有映射的监听, 释放时将监听交还给 `Sessions`
+pub struct Lease<A>
+where
+    A: Send + 'static,
+{
+    accepter: Option<A>,
+    session: Option<Session>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(DEFAULT_PARK_TIMEOUT)
+    }
+}
+
+impl Sessions {
+    /// 客户端断开后最多保留监听 `park_timeout`, 即使等待重连的任务没有运行
+    pub fn new(park_timeout: Duration) -> Self {
+        Self {
+            generation: Default::default(),
+            entries: Default::default(),
+            park_timeout,
+        }
+    }
+
+    /// 占用名称, 如果该名称有保留的监听则一并取回, 名称正在使用时返回错误
+    pub fn claim<A>(&self, name: &str) -> crate::Result<(Session, Option<A>)>
+    where
+        A: Send + 'static,
+    {
+        let mut entries = self.entries.lock()?;
+        let id = self.generation.fetch_add(1, Ordering::Relaxed);
+
+        expire(&mut entries);
+
+        let parked = match entries.insert(name.to_string(), Entry::Active(id)) {
+            None => None,
+            Some(Entry::Parked(_, parked, _)) => parked.downcast::<A>().ok().map(|parked| *parked),
+            Some(active) => {
+                entries.insert(name.to_string(), active);
+                return Err(Kind::Text(format!("the name {} is already in use", name)).into());
+            }
+        };
+
+        Ok((
+            Session {
+                id,
+                name: name.to_string(),
+                sessions: self.clone(),
+            },
+            parked,
+        ))
+    }
+
+    fn park(&self, name: &str, id: u64, parked: Parked) {
+        if let Ok(mut entries) = self.entries.lock() {
+            expire(&mut entries);
+
+            if let Some(entry) = entries.get_mut(name) {
+                if matches!(entry, Entry::Active(active) if *active == id) {
+                    *entry = Entry::Parked(id, parked, Instant::now() + self.park_timeout);
+                }
+            }
+        }
+    }
+
+    fn release(&self, name: &str, id: u64, parked: bool) {
+        if let Ok(mut entries) = self.entries.lock() {
+            let matched = match entries.get(name) {
+                Some(Entry::Active(active)) => !parked && *active == id,
+                Some(Entry::Parked(active, _, _)) => parked && *active == id,
+                None => false,
+            };
+
+            if matched {
+                entries.remove(name);
+            }
+        }
+    }
+}
+
+/// 移除超过期限的监听
+fn expire(entries: &mut HashMap<String, Entry>) {
+    let now = Instant::now();
+    entries.retain(|_, entry| !matches!(entry, Entry::Parked(_, _, deadline) if *deadline <= now));
+}
+
+impl Session {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.sessions.release(&self.name, self.id, false);
+    }
+}
+
+impl<A> Lease<A>
+where
+    A: Send + 'static,
+{
+    pub fn new(accepter: A, session: Option<Session>) -> Self {
+        Self {
+            accepter: Some(accepter),
+            session,
+        }
+    }
+
+    /// 关闭监听并释放名称, 释放时不再交还给 `Sessions`
+    pub fn discard(&mut self) {
+        drop(self.accepter.take());
+        drop(self.session.take());
+    }
+
+    /// 只释放名称, 同名客户端可以立即重新绑定, 监听不再交还给 `Sessions`
+    pub fn release(&mut self) {
+        drop(self.session.take());
+    }
+
+    pub fn get_mut(&mut self) -> Option<&mut A> {
+        self.accepter.as_mut()
+    }
+
+    /// 超时后丢弃保留的监听, 如果期间已被同名客户端取回则什么都不做
+    pub fn expire_after(&self, timeout: Duration) -> Option<(String, BoxedFuture<()>)> {
+        let session = self.session.as_ref()?;
+        let (id, name, sessions) = (session.id, session.name.clone(), session.sessions.clone());
+
+        Some((
+            session.name.clone(),
+            Box::pin(async move {
+                time::sleep(timeout).await;
+                sessions.release(&name, id, true);
+                Ok(())
+            }),
+        ))
+    }
+}
+
+impl<A> Drop for Lease<A>
+where
+    A: Send + 'static,
+{
+    fn drop(&mut self) {
+        if let (Some(session), Some(accepter)) = (self.session.as_ref(), self.accepter.take()) {
+            session
+                .sessions
+                .park(&session.name, session.id, Box::new(accepter));
+        }
+    }
+}
+
+impl<A> NetSocket for Lease<A>
+where
+    A: NetSocket + Send + 'static,
+{
+    fn local_addr(&self) -> crate::Result<Address> {
+        match self.accepter.as_ref() {
+            Some(accepter) => accepter.local_addr(),
+            None => Err(Kind::Once.into()),
+        }
+    }
+
+    fn peer_addr(&self) -> crate::Result<Address> {
+        match self.accepter.as_ref() {
+            Some(accepter) => accepter.peer_addr(),
+            None => Err(Kind::Once.into()),
+        }
+    }
+}
+
+impl<A> Accepter for Lease<A>
+where
+    A: Accepter + Unpin + Send + 'static,
+{
+    type Stream = A::Stream;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<crate::Result<Self::Stream>> {
+        match self.accepter.as_mut() {
+            Some(accepter) => Pin::new(accepter).poll_accept(cx),
+            None => Poll::Ready(Err(Kind::Once.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::{Lease, Sessions};
+
+    /// 被关闭时标记
+    struct Listener(Arc<AtomicBool>);
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_sessions_reclaim() {
+        let sessions = Sessions::default();
+
+        let (session, parked) = sessions.claim::<u16>("web").unwrap();
+        assert!(parked.is_none());
+        assert!(sessions.claim::<u16>("web").is_err());
+
+        drop(Lease::new(8080u16, Some(session)));
+
+        let (session, parked) = sessions.claim::<u16>("web").unwrap();
+        assert_eq!(parked, Some(8080));
+
+        drop(session);
+
+        let (session, parked) = sessions.claim::<u16>("web").unwrap();
+        assert!(parked.is_none());
+
+        let mut lease = Lease::new(8080u16, Some(session));
+        lease.discard();
+        assert!(lease.expire_after(std::time::Duration::ZERO).is_none());
+        drop(lease);
+
+        let (_, parked) = sessions.claim::<u16>("web").unwrap();
+        assert!(parked.is_none());
+    }
+
+    #[test]
+    fn test_sessions_expire() {
+        let sessions = Sessions::new(Duration::from_millis(10));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let (session, _) = sessions.claim::<Listener>("web").unwrap();
+        drop(Lease::new(Listener(closed.clone()), Some(session)));
+        assert!(!closed.load(Ordering::SeqCst));
+
+        // 等待重连的任务没有运行时, 其他客户端占用名称时关闭过期的监听
+        std::thread::sleep(Duration::from_millis(20));
+        let _ = sessions.claim::<Listener>("api").unwrap();
+        assert!(closed.load(Ordering::SeqCst));
+
+        let (_, parked) = sessions.claim::<Listener>("web").unwrap();
+        assert!(parked.is_none());
+    }
+
+    #[test]
+    fn test_lease_release() {
+        let sessions = Sessions::default();
+
+        let (session, _) = sessions.claim::<u16>("web").unwrap();
+        let mut lease = Lease::new(8080u16, Some(session));
+        lease.release();
+
+        // 释放后监听仍然可用, 名称可以被重新占用
+        assert_eq!(lease.get_mut(), Some(&mut 8080));
+        let (session, parked) = sessions.claim::<u16>("web").unwrap();
+        assert!(parked.is_none());
+
+        drop(lease);
+        drop(session);
+
+        let (_, parked) = sessions.claim::<u16>("web").unwrap();
+        assert!(parked.is_none());
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt::Debug;
+
+use crate::{FusoStream, Stream};
+
+#[cfg(feature = "fuso-crypt-aead")]
+use crate::{encryption::ChaCha20Encryptor, ToBoxStream};
+
+/// 按客户端名区分的密钥, 映射的连接在会话加密之外再使用该密钥加密,
+/// 共享服务端时各个客户端的数据互不相同
+#[derive(Clone)]
+pub struct TenantKey([u8; 32]);
+
+impl TenantKey {
+    /// 通过pbkdf2从口令派生密钥, 需要 `fuso-crypt-aead`
+    pub fn new<P: AsRef<[u8]>>(passphrase: P) -> crate::Result<Self> {
+        #[cfg(feature = "fuso-crypt-aead")]
+        {
+            Ok(Self(crate::encryption::derive_key(passphrase.as_ref())))
+        }
+
+        #[cfg(not(feature = "fuso-crypt-aead"))]
+        {
+            let _ = passphrase;
+            Err(crate::EncryptionErr::UnknownCipher(String::from("chacha20")).into())
+        }
+    }
+
+    /// 使用chacha20-poly1305加密映射的连接
+    pub fn wrap<S>(&self, stream: S) -> FusoStream
+    where
+        S: Stream + Send + 'static,
+    {
+        #[cfg(feature = "fuso-crypt-aead")]
+        {
+            let peer = stream.peer_addr().ok();
+            ChaCha20Encryptor::new(stream, self.0)
+                .peer(peer)
+                .into_boxed_stream()
+        }
+
+        // 没有 `fuso-crypt-aead` 时无法创建密钥
+        #[cfg(not(feature = "fuso-crypt-aead"))]
+        {
+            let _ = stream;
+            unreachable!()
+        }
+    }
+}
+
+/// 不输出密钥
+impl Debug for TenantKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TenantKey(..)")
+    }
+}
+
+/// 查找客户端名对应的密钥, 没有时使用默认密钥,
+/// 设置了密钥但没有默认密钥时拒绝未知的客户端
+pub(crate) fn lookup_tenant_key<'a>(
+    keys: &'a [(String, TenantKey)],
+    default: Option<&'a TenantKey>,
+    name: Option<&str>,
+) -> Result<Option<&'a TenantKey>, ()> {
+    let key = name.and_then(|name| {
+        keys.iter()
+            .find(|(tenant, _)| tenant == name)
+            .map(|(_, key)| key)
+    });
+
+    match (key, default) {
+        (Some(key), _) => Ok(Some(key)),
+        (None, Some(default)) => Ok(Some(default)),
+        (None, None) if keys.is_empty() => Ok(None),
+        (None, None) => Err(()),
+    }
+}
+
+#[cfg(all(test, feature = "fuso-crypt-aead"))]
+mod tests {
+    use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+    use super::TenantKey;
+
+    #[test]
+    fn test_lookup_tenant_key() {
+        let keys = vec![(String::from("alice"), TenantKey::new("alice").unwrap())];
+        let default = TenantKey::new("default").unwrap();
+
+        let found = super::lookup_tenant_key(&keys, None, Some("alice")).unwrap();
+        assert_eq!(found.unwrap().0, keys[0].1 .0);
+        assert!(super::lookup_tenant_key(&keys, None, Some("bob")).is_err());
+        assert!(super::lookup_tenant_key(&keys, None, None).is_err());
+
+        let found = super::lookup_tenant_key(&keys, Some(&default), Some("bob")).unwrap();
+        assert_eq!(found.unwrap().0, default.0);
+        assert!(super::lookup_tenant_key(&[], None, Some("bob"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_tenant_key_wrap() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let key = TenantKey::new("fuso").unwrap();
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let (s1, s2) = tokio::join!(tokio::net::TcpStream::connect(addr), async {
+                    listener.accept().await.map(|(s, _)| s)
+                });
+
+                let (mut s1, mut s2) = (key.wrap(s1.unwrap()), key.wrap(s2.unwrap()));
+
+                s1.write_all(b"hello tenant").await.unwrap();
+
+                let mut buf = [0u8; 12];
+                s2.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello tenant");
+            });
+    }
+}
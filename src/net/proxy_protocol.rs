@@ -0,0 +1,92 @@
+use std::net::{IpAddr, SocketAddr};
+
+use bytes::{BufMut, BytesMut};
+
+use crate::{Address, InnerAddr};
+
+/// PROXY protocol v2 签名
+pub const SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+/// version 2, PROXY
+const VERSION_PROXY: u8 = 0x21;
+/// AF_INET, STREAM
+const TCP_OVER_IPV4: u8 = 0x11;
+/// AF_INET6, STREAM
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// 生成PROXY protocol v2头, `src` 为访问者地址, `dst` 为访问者连接的地址,
+/// 两个地址协议不同时ipv4转换为ipv6映射地址
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut header = BytesMut::with_capacity(16 + 36);
+
+    header.put_slice(&SIGNATURE);
+    header.put_u8(VERSION_PROXY);
+
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            header.put_u8(TCP_OVER_IPV4);
+            header.put_u16(12);
+            header.put_slice(&src_ip.octets());
+            header.put_slice(&dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+            let to_ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+
+            header.put_u8(TCP_OVER_IPV6);
+            header.put_u16(36);
+            header.put_slice(&to_ipv6(src_ip).octets());
+            header.put_slice(&to_ipv6(dst_ip).octets());
+        }
+    }
+
+    header.put_u16(src.port());
+    header.put_u16(dst.port());
+
+    header.to_vec()
+}
+
+/// 从 `Address` 中取出ip地址, 域名或多个地址时返回None
+pub fn socket_addr(addr: &Address) -> Option<SocketAddr> {
+    match addr {
+        Address::One(socket) => match socket.inner() {
+            InnerAddr::Socket(addr) => Some(*addr),
+            InnerAddr::Domain(_, _) | InnerAddr::Unix(_) => None,
+        },
+        Address::Many(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_encode_v2() {
+        let src: SocketAddr = "192.168.1.2:51000".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+
+        let header = super::encode_v2(src, dst);
+
+        assert_eq!(header.len(), 28);
+        assert_eq!(&header[..12], &super::SIGNATURE);
+        assert_eq!(&header[12..16], &[0x21, 0x11, 0x00, 0x0C]);
+        assert_eq!(&header[16..20], &[192, 168, 1, 2]);
+        assert_eq!(&header[20..24], &[10, 0, 0, 1]);
+        assert_eq!(&header[24..], &[0xC7, 0x38, 0x1F, 0x90]);
+
+        let src: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let header = super::encode_v2(src, dst);
+
+        assert_eq!(header.len(), 52);
+        assert_eq!(&header[12..16], &[0x21, 0x21, 0x00, 0x24]);
+        assert_eq!(&header[16..18], &[0x20, 0x01]);
+        assert_eq!(header[31], 1);
+        assert_eq!(&header[42..48], &[0xFF, 0xFF, 10, 0, 0, 1]);
+        assert_eq!(&header[48..], &[0x01, 0xBB, 0x1F, 0x90]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{Addr, InnerAddr, InvalidAddr, Provider, WrappedProvider};
+
+type BoxedFuture<T> = Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+/// 带缓存的域名解析, 同一个域名在 `ttl` 内不会重复解析
+#[derive(Clone)]
+pub struct CachedResolver {
+    ttl: Duration,
+    resolver: WrappedProvider<String, IpAddr>,
+    cache: Arc<Mutex<HashMap<String, (IpAddr, Instant)>>>,
+}
+
+impl CachedResolver {
+    /// `resolver` 负责实际的解析, 如 `TokioResolver`
+    pub fn new<R>(resolver: R, ttl: Duration) -> Self
+    where
+        R: Provider<String, Output = BoxedFuture<IpAddr>> + Send + Sync + 'static,
+    {
+        Self {
+            ttl,
+            resolver: WrappedProvider::wrap(resolver),
+            cache: Default::default(),
+        }
+    }
+
+    fn lookup(&self, domain: &str) -> crate::Result<Option<IpAddr>> {
+        let cache = self.cache.lock()?;
+        Ok(cache
+            .get(domain)
+            .filter(|(_, resolved)| resolved.elapsed() < self.ttl)
+            .map(|(ip, _)| *ip))
+    }
+}
+
+impl Provider<Addr> for CachedResolver {
+    type Output = BoxedFuture<SocketAddr>;
+
+    fn call(&self, addr: Addr) -> Self::Output {
+        let this = self.clone();
+        Box::pin(async move {
+            let (domain, port) = match addr.into_inner() {
+                InnerAddr::Socket(addr) => return Ok(addr),
+                InnerAddr::Domain(domain, port) => (domain, port),
+                InnerAddr::Unix(path) => return Err(InvalidAddr::Domain(path).into()),
+            };
+
+            if let Some(ip) = this.lookup(&domain)? {
+                log::trace!("resolve {} from cache {}", domain, ip);
+                return Ok(SocketAddr::new(ip, port));
+            }
+
+            let ip = this.resolver.call(domain.clone()).await?;
+
+            log::debug!("resolve {} to {}", domain, ip);
+
+            let mut cache = this.cache.lock()?;
+            cache.retain(|_, (_, resolved)| resolved.elapsed() < this.ttl);
+            cache.insert(domain, (ip, Instant::now()));
+
+            Ok(SocketAddr::new(ip, port))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::CachedResolver;
+    use crate::{Addr, Provider};
+
+    type BoxedFuture<T> =
+        Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Provider<String> for Counting {
+        type Output = BoxedFuture<IpAddr>;
+
+        fn call(&self, _: String) -> Self::Output {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async move { Ok(IpAddr::from([127, 0, 0, 1])) })
+        }
+    }
+
+    #[test]
+    fn test_cached_resolver() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let count = Arc::new(AtomicUsize::new(0));
+                let resolver =
+                    CachedResolver::new(Counting(count.clone()), Duration::from_millis(200));
+
+                let domain = || Addr::from((String::from("localhost"), 53));
+
+                for _ in 0..3 {
+                    let addr = resolver.call(domain()).await.unwrap();
+                    assert_eq!(addr.to_string(), "127.0.0.1:53");
+                }
+
+                assert_eq!(count.load(Ordering::SeqCst), 1);
+
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                resolver.call(domain()).await.unwrap();
+                assert_eq!(count.load(Ordering::SeqCst), 2);
+            });
+    }
+}
@@ -19,8 +19,7 @@ pub enum S5Authenticate {
         good: bool,
         cmp_user: Option<Vec<u8>>,
         cmp_pass: Option<Vec<u8>>,
-        username: Vec<u8>,
-        password: Vec<u8>,
+        credentials: Vec<(Vec<u8>, Vec<u8>)>,
     },
 }
 
@@ -33,6 +32,16 @@ impl S5Authenticate {
     }
 
     pub fn standard<U: AsRef<[u8]>, P: AsRef<[u8]>>(username: U, password: P) -> Self {
+        Self::with_credentials([(username, password)])
+    }
+
+    /// 用户名/密码认证(RFC 1929), 匹配任意一组即认证成功
+    pub fn with_credentials<I, U, P>(credentials: I) -> Self
+    where
+        I: IntoIterator<Item = (U, P)>,
+        U: AsRef<[u8]>,
+        P: AsRef<[u8]>,
+    {
         Self::Standard {
             rpos: 0,
             wpos: 0,
@@ -42,8 +51,12 @@ impl S5Authenticate {
             init: false,
             cmp_user: None,
             cmp_pass: None,
-            username: username.as_ref().to_vec(),
-            password: password.as_ref().to_vec(),
+            credentials: credentials
+                .into_iter()
+                .map(|(username, password)| {
+                    (username.as_ref().to_vec(), password.as_ref().to_vec())
+                })
+                .collect(),
         }
     }
 }
@@ -82,7 +95,7 @@ where
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context,
         mut stream: std::pin::Pin<&mut S>,
-        _: &[u8],
+        methods: &[u8],
     ) -> std::task::Poll<crate::Result<()>> {
         match &mut *self {
             S5Authenticate::Skip { reply, offset } => loop {
@@ -109,9 +122,12 @@ where
                 good,
                 cmp_user,
                 cmp_pass,
-                username,
-                password,
+                credentials,
             } => {
+                if *wpos == 0 && !*init && cmp_user.is_none() && !methods.contains(&0x02) {
+                    *wbuf = Some([0x05, 0xFF]);
+                }
+
                 let mut do_next = true;
                 while do_next {
                     do_next = false;
@@ -127,7 +143,10 @@ where
 
                         *wpos += n;
 
-                        if *wpos == buf.len() && cmp_pass.is_none() && cmp_user.is_none() {
+                        if *wpos == buf.len() && buf[1] == 0xFF {
+                            log::debug!("socks5 client does not support username/password");
+                            return Poll::Ready(Err(SocksErr::Method(0x02).into()));
+                        } else if *wpos == buf.len() && cmp_pass.is_none() && cmp_user.is_none() {
                             let mut buf = Vec::new();
                             buf.resize(2, 0);
                             drop(std::mem::replace(rbuf, Some(buf)));
@@ -209,7 +228,11 @@ where
 
                     match (&cmp_pass, &cmp_user) {
                         (Some(pass), Some(user)) => {
-                            if password.eq(&pass) && user[..user.len() - 1].eq(username) {
+                            let user = &user[..user.len() - 1];
+                            if credentials
+                                .iter()
+                                .any(|(username, password)| user.eq(username) && password.eq(pass))
+                            {
                                 *wpos = 0;
                                 *good = true;
                                 do_next = true;
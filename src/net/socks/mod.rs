@@ -1,10 +1,13 @@
 mod auth;
 pub use auth::*;
+mod upstream;
 use std::net::SocketAddr;
 use std::{pin::Pin, task::Poll};
+pub use upstream::*;
 
 use std::future::Future;
 
+use crate::acl::Egress;
 use crate::ext::AsyncWriteExt;
 use crate::protocol::{make_packet, Poto, ToBytes};
 use crate::{
@@ -45,6 +48,7 @@ enum State {
     // ver, cmd, rsv, atype, size
     Request(u8, u8, u8, u8, usize),
     Success(Option<Socket>),
+    Denied,
 }
 
 #[repr(C)]
@@ -62,6 +66,7 @@ pub struct Socks5<'a, S, A> {
     read_offset: usize,
     write_offset: usize,
     auth: &'a mut A,
+    egress: Option<&'a Egress>,
     #[pin]
     stream: &'a mut S,
 }
@@ -74,6 +79,7 @@ pub trait Socks: Stream {
     {
         Socks5 {
             auth,
+            egress: None,
             state: State::Handshake,
             read_buf: Default::default(),
             write_buf: Default::default(),
@@ -84,6 +90,14 @@ pub trait Socks: Stream {
     }
 }
 
+impl<'a, S, A> Socks5<'a, S, A> {
+    /// connect的目标不在允许列表中时应答 `connection not allowed by ruleset` 并返回错误
+    pub fn egress(mut self, egress: &'a Egress) -> Self {
+        self.egress = Some(egress).filter(|egress| !egress.is_empty());
+        self
+    }
+}
+
 pub trait Socks5Auth<S> {
     fn poll_auth(
         self: Pin<&mut Self>,
@@ -124,10 +138,6 @@ impl<'a> TryFrom<&'a [u8]> for Head {
 impl<T> Socks for T where T: NetSocket + AsyncRead + AsyncWrite + Unpin {}
 
 fn parse_address(cmd: u8, _: u8, atype: u8, data: &[u8]) -> crate::Result<Socket> {
-    if cmd == 0x02 {
-        return Err(SocksErr::BindNotSupport.into());
-    }
-
     let addr = match atype {
         0x01 => {
             #[repr(C)]
@@ -163,6 +173,7 @@ fn parse_address(cmd: u8, _: u8, atype: u8, data: &[u8]) -> crate::Result<Socket
     Ok({
         match cmd {
             0x01 => Socket::tcp(addr),
+            0x02 => Socket::bind(addr),
             0x03 => Socket::udp(addr),
             _ => return Err(SocksErr::BindNotSupport.into()),
         }
@@ -183,6 +194,7 @@ where
         let this = self.project();
         let mut stream = this.stream;
         let auth = this.auth;
+        let egress = this.egress;
         let read_buf = this.read_buf;
         let write_buf = this.write_buf;
         let state = this.state;
@@ -270,7 +282,18 @@ where
                     *read_offset = 0;
                     read_buf.clear();
 
-                    if socket.is_udp() {
+                    let denied = socket.kind().is_tcp()
+                        && egress.is_some_and(|egress| !egress.is_allowed(socket.addr()));
+
+                    if denied {
+                        log::warn!("socks5 target {} is not allowed", socket);
+
+                        *write_offset = 0;
+                        write_buf.clear();
+                        write_buf
+                            .extend([0x05, 0x02, *rsv, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+                    } else if socket.is_udp() || socket.is_bind() {
+                        // udp与bind的应答在监听成功后发送
                         write_buf.clear();
                     } else {
                         *write_offset = 0;
@@ -279,12 +302,19 @@ where
                             .extend([0x05, 0x00, *rsv, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
                     }
 
-                    let new_state = State::Success(Some(socket));
+                    let new_state = match denied {
+                        true => State::Denied,
+                        false => State::Success(Some(socket)),
+                    };
+
                     drop(std::mem::replace(state, new_state))
                 }
                 State::Success(socket) if write_buf.is_empty() => {
                     break Poll::Ready(Ok(unsafe { socket.take().unwrap_unchecked() }));
                 }
+                State::Denied if write_buf.is_empty() => {
+                    break Poll::Ready(Err(SocksErr::Denied.into()));
+                }
                 _ => {}
             }
 
@@ -316,7 +346,8 @@ where
 
                     *read_offset += n;
 
-                    if *read_offset == buf.len() {
+                    // 前面的适配器可能只回退了部分数据, 需要读满整个缓冲区
+                    if *read_offset == read_buf.len() {
                         break;
                     }
                 }
@@ -334,6 +365,56 @@ where
         .await
 }
 
+/// bind失败, 回复 `general SOCKS server failure`
+pub async fn finish_bind<S>(stream: &mut S) -> crate::Result<()>
+where
+    S: Stream + Send + Unpin,
+{
+    stream
+        .write_all(&[0x05, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+        .await
+}
+
+/// bind的两次应答, 第一次为监听的地址, 第二次为连接进来的地址
+pub async fn send_bind_message<S>(stream: &mut S, addr: Addr) -> crate::Result<()>
+where
+    S: Stream + Send + Unpin,
+{
+    let mut buf = Vec::new();
+
+    buf.extend(&[0x05, 0x00, 0x00]);
+
+    encode_address(&mut buf, addr.inner())?;
+
+    stream.write_all(&buf).await
+}
+
+/// 写入 `ATYP | ADDR | PORT`
+fn encode_address(buf: &mut Vec<u8>, addr: &crate::InnerAddr) -> crate::Result<()> {
+    match addr {
+        crate::InnerAddr::Socket(SocketAddr::V4(v1)) => {
+            buf.push(0x01);
+            buf.extend(&v1.ip().octets());
+            buf.extend(&v1.port().to_be_bytes());
+        }
+        crate::InnerAddr::Socket(SocketAddr::V6(v2)) => {
+            buf.push(0x04);
+            buf.extend(&v2.ip().octets());
+            buf.extend(&v2.port().to_be_bytes());
+        }
+        crate::InnerAddr::Domain(domain, port) if domain.len() <= u8::MAX as usize => {
+            let domain = domain.as_bytes();
+            buf.push(0x03);
+            buf.push(domain.len() as u8);
+            buf.extend(domain);
+            buf.extend(&port.to_be_bytes());
+        }
+        _ => return Err(SocksErr::InvalidAddress.into()),
+    }
+
+    Ok(())
+}
+
 pub async fn send_udp_forward_message<S>(stream: &mut S, addr: SocketAddr) -> crate::Result<()>
 where
     S: Stream + Send + Unpin,
@@ -363,7 +444,12 @@ where
 //  +----+------+------+----------+----------+----------+
 //  | 2  |  1   |  1   | Variable |    2     | Variable |
 //  +----+------+------+----------+----------+----------+
-pub async fn parse_and_forward_data<S>(s1: &mut S, data: &[u8]) -> crate::Result<Addr>
+/// 目标不在 `egress` 中时丢弃数据包并返回None
+pub async fn parse_and_forward_data<S>(
+    s1: &mut S,
+    data: &[u8],
+    egress: &Egress,
+) -> crate::Result<Option<Addr>>
 where
     S: AsyncWrite + Unpin,
 {
@@ -397,6 +483,10 @@ where
 
     let addr = parse_address(0x03, 0, atype, data)?.into_addr();
 
+    if !egress.is_allowed(&addr) {
+        return Ok(None);
+    }
+
     let message = Poto::Forward(addr.clone()).bytes();
 
     s1.write_all(&message).await?;
@@ -407,7 +497,7 @@ where
 
     log::trace!("send forward data success");
 
-    Ok(addr)
+    Ok(Some(addr))
 }
 
 pub async fn send_packed_udp_forward_message<U>(
@@ -442,6 +532,7 @@ where
             buf.extend(domain);
             buf.extend(&port.to_be_bytes());
         }
+        crate::InnerAddr::Unix(_) => return Err(SocksErr::InvalidAddress.into()),
     }
 
     buf.extend(data);
@@ -454,3 +545,115 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ext::{AsyncReadExt, AsyncWriteExt},
+        guard::Fallback,
+    };
+
+    use super::{S5Authenticate, Socks};
+    use crate::{acl::Egress, Kind, SocksErr};
+
+    #[test]
+    fn test_parse_bind() {
+        let socket = super::parse_address(0x02, 0x00, 0x01, &[0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(socket.is_bind());
+
+        let socket = super::parse_address(0x01, 0x00, 0x01, &[127, 0, 0, 1, 0x1a, 0x42]).unwrap();
+        assert!(socket.is_tcp());
+
+        assert!(super::parse_address(0x04, 0x00, 0x01, &[0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_socks5_after_backward() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let client = tokio::spawn(async move {
+                    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+
+                    let mut buf = [0u8; 2];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(buf, [0x05, 0x00]);
+
+                    stream
+                        .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0x1a, 0x42])
+                        .await
+                        .unwrap();
+
+                    let mut buf = [0u8; 10];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(buf[1], 0x00);
+                });
+
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = Fallback::new(stream, false);
+
+                // 模拟前一个适配器只读取了一个字节
+                stream.mark().await.unwrap();
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).await.unwrap();
+                stream.backward().await.unwrap();
+
+                stream.mark().await.unwrap();
+                let socket = stream
+                    .socks5_handshake(&mut S5Authenticate::default())
+                    .await
+                    .unwrap();
+
+                assert!(socket.is_tcp());
+                assert_eq!(socket.addr().to_string(), "127.0.0.1:6722");
+
+                client.await.unwrap();
+            });
+    }
+
+    #[test]
+    fn test_socks5_egress_denied() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let client = tokio::spawn(async move {
+                    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                    stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+
+                    let mut buf = [0u8; 2];
+                    stream.read_exact(&mut buf).await.unwrap();
+
+                    stream
+                        .write_all(&[0x05, 0x01, 0x00, 0x01, 10, 0, 0, 1, 0x1a, 0x42])
+                        .await
+                        .unwrap();
+
+                    let mut buf = [0u8; 10];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    buf[1]
+                });
+
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let egress = Egress::new(vec!["127.0.0.1".parse().unwrap()]);
+
+                let r = stream
+                    .socks5_handshake(&mut S5Authenticate::default())
+                    .egress(&egress)
+                    .await;
+
+                assert!(matches!(
+                    r.unwrap_err().kind(),
+                    Kind::Socks(SocksErr::Denied)
+                ));
+                // connection not allowed by ruleset
+                assert_eq!(client.await.unwrap(), 0x02);
+            });
+    }
+}
@@ -0,0 +1,235 @@
+use std::{fs::File, io::BufReader, pin::Pin, sync::Arc, task::Poll};
+
+use tokio_rustls::{
+    rustls::{
+        Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerConfig,
+        ServerName,
+    },
+    TlsAcceptor, TlsConnector,
+};
+
+use crate::{AsyncRead, AsyncWrite, Kind, NetSocket, ReadBuf};
+
+// tls record: | content type: u8 | version: u16 | length: u16 |, 握手记录的类型为 0x16
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// 将 `AsyncRead` 与 `AsyncWrite` 适配为 tokio 的 io, 交给 rustls 使用
+struct Compat<S>(S);
+
+pub struct TlsStream<S>(tokio_rustls::TlsStream<Compat<S>>);
+
+fn tls_err<T>(reason: String) -> crate::Result<T> {
+    Err(Kind::Tls(reason).into())
+}
+
+fn load_certs(path: &str) -> crate::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader)?;
+
+    if certs.is_empty() {
+        return tls_err(format!("no certificate found in {}", path));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &str) -> crate::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => break Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => break tls_err(format!("no private key found in {}", path)),
+        }
+    }
+}
+
+/// 从pem文件加载服务端证书与私钥
+pub fn server_config(cert: &str, key: &str) -> crate::Result<Arc<ServerConfig>> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .map_err(|e| Kind::Tls(e.to_string()))?;
+
+    Ok(Arc::new(config))
+}
+
+/// 客户端配置, 未指定ca时使用内置的根证书
+pub fn client_config(ca: Option<&str>) -> crate::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+
+    match ca {
+        Some(ca) => {
+            for cert in load_certs(ca)? {
+                roots.add(&cert).map_err(|e| Kind::Tls(e.to_string()))?;
+            }
+        }
+        None => roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    Ok(Arc::new(config))
+}
+
+pub fn server_name(name: &str) -> crate::Result<ServerName> {
+    ServerName::try_from(name).or_else(|_| tls_err(format!("invalid server name {}", name)))
+}
+
+/// 服务端握手, `head` 为预读的第一个字节, 不是tls握手时直接拒绝
+pub async fn accept<S>(
+    config: Arc<ServerConfig>,
+    head: u8,
+    stream: S,
+) -> crate::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if head != TLS_HANDSHAKE {
+        return tls_err(String::from("client did not start a tls handshake"));
+    }
+
+    let stream = TlsAcceptor::from(config).accept(Compat(stream)).await?;
+
+    Ok(TlsStream(stream.into()))
+}
+
+pub async fn connect<S>(
+    config: Arc<ClientConfig>,
+    name: ServerName,
+    stream: S,
+) -> crate::Result<TlsStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = TlsConnector::from(config)
+        .connect(name, Compat(stream))
+        .await?;
+
+    Ok(TlsStream(stream.into()))
+}
+
+impl<S> tokio::io::AsyncRead for Compat<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let mut read_buf = ReadBuf::new(buf.initialize_unfilled());
+        match Pin::new(&mut self.0).poll_read(cx, &mut read_buf) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Ok(n)) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(into_io_error(e))),
+        }
+    }
+}
+
+impl<S> tokio::io::AsyncWrite for Compat<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0)
+            .poll_write(cx, buf)
+            .map_err(into_io_error)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx).map_err(into_io_error)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx).map_err(into_io_error)
+    }
+}
+
+fn into_io_error(e: crate::Error) -> std::io::Error {
+    let kind = match e.kind() {
+        Kind::IO(e) => e.kind(),
+        _ => std::io::ErrorKind::Other,
+    };
+
+    std::io::Error::new(kind, e.to_string())
+}
+
+impl<S> NetSocket for TlsStream<S>
+where
+    S: NetSocket,
+{
+    fn peer_addr(&self) -> crate::Result<crate::Address> {
+        self.0.get_ref().0 .0.peer_addr()
+    }
+
+    fn local_addr(&self) -> crate::Result<crate::Address> {
+        self.0.get_ref().0 .0.local_addr()
+    }
+}
+
+impl<S> AsyncRead for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
@@ -0,0 +1,588 @@
+use std::{pin::Pin, task::Poll};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    ext::{AsyncReadExt, AsyncWriteExt},
+    guard::buffer::Buffer,
+    ready, AsyncRead, AsyncWrite, NetSocket, ReadBuf, WebSocketErr,
+};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// 默认允许的http请求头长度
+pub const MAX_HEAD_LEN: usize = 8 * 1024;
+const MAX_WRITE_LEN: usize = 64 * 1024;
+const MAX_PAYLOAD_LEN: u64 = 16 * 1024 * 1024;
+pub(crate) const BAD_REQUEST: &[u8] =
+    b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+const OP_CONTINUATION: u8 = 0x00;
+const OP_TEXT: u8 = 0x01;
+const OP_BINARY: u8 = 0x02;
+const OP_CLOSE: u8 = 0x08;
+const OP_PING: u8 = 0x09;
+const OP_PONG: u8 = 0x0A;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// 服务端发出的帧不掩码, 收到的帧必须掩码
+    Server,
+    /// 客户端发出的帧必须掩码
+    Client,
+}
+
+/// rfc6455 websocket, 写入的数据以二进制帧发送, 读取时自动处理 ping/pong/close
+pub struct WebSocket<S> {
+    target: S,
+    role: Role,
+    ws_rbuf: Vec<u8>,
+    ws_dbuf: Buffer<u8>,
+    ws_cbuf: Vec<u8>,
+    ws_cpos: usize,
+    ws_ebuf: Option<Vec<u8>>,
+    ws_epos: usize,
+    ws_elen: usize,
+    closed: bool,
+    close_sent: bool,
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::encode(sha1.finalize())
+}
+
+fn handshake_err<T>(reason: &str) -> crate::Result<T> {
+    Err(WebSocketErr::Handshake(reason.to_string()).into())
+}
+
+fn frame_err<T>(reason: &'static str) -> crate::Result<T> {
+    Err(WebSocketErr::Frame(reason).into())
+}
+
+async fn read_head<S>(stream: &mut S, max_head_len: usize) -> crate::Result<String>
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+
+    // 逐字节读取, 避免读走握手之后的数据
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= max_head_len {
+            return handshake_err("http head too long");
+        }
+
+        stream.read_exact(&mut byte).await?;
+        head.push(byte[0]);
+    }
+
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn parse_headers(head: &str) -> (&str, Vec<(String, &str)>) {
+    let mut lines = head.split("\r\n");
+    let first = lines.next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+        .collect();
+
+    (first, headers)
+}
+
+fn header<'a>(headers: &'a [(String, &'a str)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| *value)
+}
+
+fn has_token(value: Option<&str>, token: &str) -> bool {
+    value.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+fn check_request(head: &str) -> Result<String, &'static str> {
+    let (request_line, headers) = parse_headers(head);
+    let mut request_line = request_line.split_whitespace();
+
+    if request_line.next() != Some("GET") {
+        return Err("method is not GET");
+    }
+
+    match request_line.nth(1) {
+        Some(version) if version.eq_ignore_ascii_case("HTTP/1.1") => {}
+        _ => return Err("http version must be 1.1"),
+    }
+
+    if !has_token(header(&headers, "upgrade"), "websocket") {
+        return Err("missing `Upgrade: websocket`");
+    }
+
+    if !has_token(header(&headers, "connection"), "upgrade") {
+        return Err("missing `Connection: Upgrade`");
+    }
+
+    if header(&headers, "sec-websocket-version") != Some("13") {
+        return Err("unsupported Sec-WebSocket-Version");
+    }
+
+    match header(&headers, "sec-websocket-key") {
+        Some(key) if base64::decode(key).is_ok_and(|key| key.len() == 16) => Ok(key.to_string()),
+        _ => Err("invalid Sec-WebSocket-Key"),
+    }
+}
+
+/// 服务端握手, 校验 `Sec-WebSocket-Key` 并回复 `101 Switching Protocols`
+pub async fn server_upgrade<S>(stream: S) -> crate::Result<WebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    server_upgrade_with_limit(stream, MAX_HEAD_LEN).await
+}
+
+/// 同 `server_upgrade`, 请求头超过 `max_head_len` 时拒绝升级
+pub async fn server_upgrade_with_limit<S>(
+    stream: S,
+    max_head_len: usize,
+) -> crate::Result<WebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    server_upgrade_with_reject(stream, max_head_len, BAD_REQUEST).await
+}
+
+/// 同 `server_upgrade_with_limit`, 不是websocket请求时回复 `reject` 而不是400
+pub async fn server_upgrade_with_reject<S>(
+    mut stream: S,
+    max_head_len: usize,
+    reject: &[u8],
+) -> crate::Result<WebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let head = read_head(&mut stream, max_head_len).await?;
+
+    let key = match check_request(&head) {
+        Ok(key) => key,
+        Err(reason) => {
+            log::debug!("reject websocket request: {}", reason);
+            let _ = stream.write_all(reject).await;
+            return handshake_err(reason);
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+
+    Ok(WebSocket::new(stream, Role::Server))
+}
+
+/// 客户端握手, `host` 与 `path` 用于http请求行与Host头
+pub async fn client_upgrade<S>(mut stream: S, host: &str, path: &str) -> crate::Result<WebSocket<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut key = [0u8; 16];
+    key.fill_with(rand::random);
+    let key = base64::encode(key);
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    );
+
+    stream.write_all(request.as_bytes()).await?;
+
+    let head = read_head(&mut stream, MAX_HEAD_LEN).await?;
+    let (status_line, headers) = parse_headers(&head);
+
+    if status_line.split_whitespace().nth(1) != Some("101") {
+        return handshake_err(&format!("unexpected response `{}`", status_line));
+    }
+
+    if header(&headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return handshake_err("Sec-WebSocket-Accept mismatch");
+    }
+
+    Ok(WebSocket::new(stream, Role::Client))
+}
+
+fn encode_frame(opcode: u8, payload: &[u8], masked: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    let mask_bit = if masked { 0x80 } else { 0x00 };
+
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        len if len < 126 => frame.push(mask_bit | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    if masked {
+        let mut mask = [0u8; 4];
+        mask.fill_with(rand::random);
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+
+    frame
+}
+
+impl<S> WebSocket<S> {
+    pub fn new(target: S, role: Role) -> Self {
+        Self {
+            target,
+            role,
+            ws_rbuf: Vec::new(),
+            ws_dbuf: Default::default(),
+            ws_cbuf: Vec::new(),
+            ws_cpos: 0,
+            ws_ebuf: None,
+            ws_epos: 0,
+            ws_elen: 0,
+            closed: false,
+            close_sent: false,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.target
+    }
+
+    fn queue_control(&mut self, opcode: u8, payload: &[u8]) {
+        let frame = encode_frame(opcode, payload, self.role == Role::Client);
+        self.ws_cbuf.extend(frame);
+    }
+
+    /// 从读缓冲中解析一个完整的帧, 数据不足时返回 `false`
+    fn parse_frame(&mut self) -> crate::Result<bool> {
+        let rbuf = &self.ws_rbuf;
+
+        if rbuf.len() < 2 {
+            return Ok(false);
+        }
+
+        let fin = rbuf[0] & 0x80 != 0;
+        let opcode = rbuf[0] & 0x0F;
+        let masked = rbuf[1] & 0x80 != 0;
+
+        if rbuf[0] & 0x70 != 0 {
+            return frame_err("reserved bits are set");
+        }
+
+        if masked != (self.role == Role::Server) {
+            return frame_err("unexpected mask bit");
+        }
+
+        let (len, mut offset) = match rbuf[1] & 0x7F {
+            126 if rbuf.len() >= 4 => (u16::from_be_bytes([rbuf[2], rbuf[3]]) as u64, 4),
+            127 if rbuf.len() >= 10 => {
+                let mut len = [0u8; 8];
+                len.copy_from_slice(&rbuf[2..10]);
+                (u64::from_be_bytes(len), 10)
+            }
+            126 | 127 => return Ok(false),
+            len => (len as u64, 2),
+        };
+
+        if len > MAX_PAYLOAD_LEN {
+            return frame_err("payload too large");
+        }
+
+        if opcode >= OP_CLOSE && (len > 125 || !fin) {
+            return frame_err("bad control frame");
+        }
+
+        let mask = if masked {
+            if rbuf.len() < offset + 4 {
+                return Ok(false);
+            }
+            let mask = [
+                rbuf[offset],
+                rbuf[offset + 1],
+                rbuf[offset + 2],
+                rbuf[offset + 3],
+            ];
+            offset += 4;
+            Some(mask)
+        } else {
+            None
+        };
+
+        let len = len as usize;
+
+        if rbuf.len() < offset + len {
+            return Ok(false);
+        }
+
+        let mut payload: Vec<u8> = self.ws_rbuf.drain(..offset + len).skip(offset).collect();
+
+        if let Some(mask) = mask {
+            payload
+                .iter_mut()
+                .enumerate()
+                .for_each(|(i, b)| *b ^= mask[i % 4]);
+        }
+
+        match opcode {
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => self.ws_dbuf.push_back(&payload),
+            OP_PING => self.queue_control(OP_PONG, &payload),
+            OP_PONG => {}
+            OP_CLOSE => {
+                log::debug!("websocket closed by peer");
+                self.closed = true;
+                if !self.close_sent {
+                    self.close_sent = true;
+                    let code = payload.get(..2).unwrap_or(&[0x03, 0xE8]).to_vec();
+                    self.queue_control(OP_CLOSE, &code);
+                }
+            }
+            _ => return frame_err("unknown opcode"),
+        }
+
+        Ok(true)
+    }
+}
+
+impl<S> WebSocket<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write_control(&mut self, cx: &mut std::task::Context<'_>) -> Poll<crate::Result<()>> {
+        while self.ws_cpos < self.ws_cbuf.len() {
+            let n =
+                ready!(Pin::new(&mut self.target).poll_write(cx, &self.ws_cbuf[self.ws_cpos..]))?;
+
+            if n == 0 {
+                return Poll::Ready(Err(
+                    std::io::Error::from(std::io::ErrorKind::WriteZero).into()
+                ));
+            }
+
+            self.ws_cpos += n;
+        }
+
+        self.ws_cpos = 0;
+        self.ws_cbuf.clear();
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> NetSocket for WebSocket<S>
+where
+    S: NetSocket,
+{
+    fn peer_addr(&self) -> crate::Result<crate::Address> {
+        self.target.peer_addr()
+    }
+
+    fn local_addr(&self) -> crate::Result<crate::Address> {
+        self.target.local_addr()
+    }
+}
+
+impl<S> AsyncRead for WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<crate::Result<usize>> {
+        loop {
+            // 尽量把 pong/close 发出去, 写不出去时不影响读取
+            if let Poll::Ready(Err(e)) = self.poll_write_control(cx) {
+                return Poll::Ready(Err(e));
+            }
+
+            if !self.ws_dbuf.is_empty() {
+                let n = self.ws_dbuf.read_to_buffer(buf.initialize_unfilled());
+                buf.advance(n);
+                return Poll::Ready(Ok(n));
+            }
+
+            if self.closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            if self.parse_frame()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let mut read_buf = ReadBuf::new(&mut chunk);
+            let n = ready!(Pin::new(&mut self.target).poll_read(cx, &mut read_buf))?;
+
+            if n == 0 && self.ws_rbuf.is_empty() {
+                self.closed = true;
+            } else if n == 0 {
+                return Poll::Ready(Err(
+                    std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+                ));
+            } else {
+                self.ws_rbuf.extend_from_slice(&chunk[..n]);
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocket<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<crate::Result<usize>> {
+        ready!(self.poll_write_control(cx))?;
+
+        let ebuf = match self.ws_ebuf.take() {
+            Some(ebuf) => ebuf,
+            None if buf.is_empty() => return Poll::Ready(Ok(0)),
+            None => {
+                let data = &buf[..buf.len().min(MAX_WRITE_LEN)];
+                self.ws_epos = 0;
+                self.ws_elen = data.len();
+                encode_frame(OP_BINARY, data, self.role == Role::Client)
+            }
+        };
+
+        loop {
+            let epos = self.ws_epos;
+            match Pin::new(&mut self.target).poll_write(cx, &ebuf[epos..])? {
+                Poll::Ready(0) => break Poll::Ready(Ok(0)),
+                Poll::Ready(n) => {
+                    self.ws_epos += n;
+                    if self.ws_epos == ebuf.len() {
+                        break Poll::Ready(Ok(self.ws_elen));
+                    }
+                }
+                Poll::Pending => {
+                    self.ws_ebuf = Some(ebuf);
+                    break Poll::Pending;
+                }
+            }
+        }
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        ready!(self.poll_write_control(cx))?;
+        Pin::new(&mut self.target).poll_flush(cx)
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<crate::Result<()>> {
+        if !self.close_sent {
+            self.close_sent = true;
+            self.queue_control(OP_CLOSE, &1000u16.to_be_bytes());
+        }
+
+        ready!(self.poll_write_control(cx))?;
+        Pin::new(&mut self.target).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{accept_key, client_upgrade, server_upgrade, server_upgrade_with_limit};
+
+    #[test]
+    fn test_accept_key() {
+        // rfc6455 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn test_websocket_frame() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let (c, s) = tokio::io::duplex(1024);
+                let (client, server) =
+                    tokio::join!(client_upgrade(c, "fuso", "/"), server_upgrade(s));
+
+                let (mut client, mut server) = (client.unwrap(), server.unwrap());
+                let mut buf = [0u8; 10];
+
+                client.write_all(b"hello fuso").await.unwrap();
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello fuso");
+
+                let data = vec![7u8; 70000];
+                let mut recv = vec![0u8; data.len()];
+                let (w, r) = tokio::join!(server.write_all(&data), client.read_exact(&mut recv));
+                w.unwrap();
+                r.unwrap();
+                assert_eq!(recv, data);
+
+                client.close().await.unwrap();
+                assert_eq!(server.read(&mut buf).await.unwrap(), 0);
+
+                let (mut c, s) = tokio::io::duplex(1024);
+                c.write_all(b"GET / HTTP/1.1\r\nHost: fuso\r\n\r\n")
+                    .await
+                    .unwrap();
+                assert!(server_upgrade(s).await.is_err());
+            });
+    }
+
+    #[test]
+    fn test_websocket_head_limit() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let cookie = format!("Cookie: {}\r\n", "a".repeat(super::MAX_HEAD_LEN));
+
+                let request = |key: &str| {
+                    format!(
+                        "GET / HTTP/1.1\r\nHost: fuso\r\n{}Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+                        cookie, key
+                    )
+                };
+
+                let key = "dGhlIHNhbXBsZSBub25jZQ==";
+
+                let (mut c, s) = tokio::io::duplex(64 * 1024);
+                c.write_all(request(key).as_bytes()).await.unwrap();
+                assert!(server_upgrade(s).await.is_err());
+
+                let (mut c, s) = tokio::io::duplex(64 * 1024);
+                c.write_all(request(key).as_bytes()).await.unwrap();
+                assert!(server_upgrade_with_limit(s, 4 * super::MAX_HEAD_LEN)
+                    .await
+                    .is_ok());
+            });
+    }
+}
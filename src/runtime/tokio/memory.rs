@@ -0,0 +1,559 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::{io::DuplexStream, sync::mpsc};
+
+use crate::{Accepter, Address, FusoStream, InvalidAddr, NetSocket, Provider, Socket, ToBoxStream};
+
+type BoxedFuture<O> = Pin<Box<dyn std::future::Future<Output = crate::Result<O>> + Send + 'static>>;
+
+/// 每个方向缓冲的数据量, 写满后等待对端读取
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// 自动分配的端口从动态端口开始
+const EPHEMERAL_PORT: u16 = 49152;
+
+/// 动态端口的数量
+const EPHEMERAL_PORTS: u16 = 16384;
+
+/// 内存中的双向连接, 读写与tcp连接一致但不占用端口,
+/// 用于在测试中驱动握手, 选择器与转发
+pub struct MemoryStream {
+    io: DuplexStream,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    /// 发起连接的一端占用的本地端口
+    port: Option<Port>,
+}
+
+/// 连接占用的本地地址, 连接关闭后释放
+struct Port(MemoryNetwork, SocketAddr);
+
+/// 内存中的网络, 监听与连接需要使用同一个实例, 地址只用于匹配监听,
+/// 监听端口为0或连接时从动态端口中分配未被占用的端口
+#[derive(Default, Clone)]
+pub struct MemoryNetwork(Arc<Network>);
+
+#[derive(Default)]
+struct Network {
+    listeners: Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<MemoryStream>>>,
+    /// 正在连接中的本地地址, 分配端口时跳过
+    connections: Mutex<HashSet<SocketAddr>>,
+    next_port: AtomicU16,
+}
+
+/// 关闭后从网络中移除, 之后的连接被拒绝
+pub struct MemoryListener {
+    network: MemoryNetwork,
+    local_addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<MemoryStream>,
+}
+
+/// 连接同一个 `MemoryNetwork` 中的监听
+#[derive(Clone)]
+pub struct MemoryConnector(MemoryNetwork);
+
+impl MemoryStream {
+    /// 创建一对连接, 第一个的本地地址为 `local`, 第二个的本地地址为 `peer`
+    pub fn pair(local: SocketAddr, peer: SocketAddr) -> (Self, Self) {
+        let (a, b) = tokio::io::duplex(BUFFER_SIZE);
+
+        (
+            Self {
+                io: a,
+                local_addr: local,
+                peer_addr: peer,
+                port: None,
+            },
+            Self {
+                io: b,
+                local_addr: peer,
+                peer_addr: local,
+                port: None,
+            },
+        )
+    }
+}
+
+impl MemoryNetwork {
+    pub fn connector(&self) -> MemoryConnector {
+        MemoryConnector(self.clone())
+    }
+
+    /// 跳过已被监听或连接占用的端口, 都被占用时返回 `AddrNotAvailable`
+    fn ephemeral_addr<T>(
+        &self,
+        ip: IpAddr,
+        listeners: &HashMap<SocketAddr, T>,
+        connections: &HashSet<SocketAddr>,
+    ) -> crate::Result<SocketAddr> {
+        (0..EPHEMERAL_PORTS)
+            .map(|_| {
+                let port = self.0.next_port.fetch_add(1, Ordering::SeqCst) % EPHEMERAL_PORTS;
+                SocketAddr::new(ip, EPHEMERAL_PORT + port)
+            })
+            .find(|addr| !listeners.contains_key(addr) && !connections.contains(addr))
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::AddrNotAvailable).into())
+    }
+
+    fn bind(&self, mut addr: SocketAddr) -> crate::Result<MemoryListener> {
+        let mut listeners = self.0.listeners.lock()?;
+        let connections = self.0.connections.lock()?;
+
+        if addr.port() == 0 {
+            addr = self.ephemeral_addr(addr.ip(), &listeners, &connections)?;
+        }
+
+        if listeners.contains_key(&addr) || connections.contains(&addr) {
+            return Err(std::io::Error::from(std::io::ErrorKind::AddrInUse).into());
+        }
+
+        let (sender, incoming) = mpsc::unbounded_channel();
+
+        listeners.insert(addr, sender);
+
+        Ok(MemoryListener {
+            network: self.clone(),
+            local_addr: addr,
+            incoming,
+        })
+    }
+
+    fn connect(&self, addr: SocketAddr) -> crate::Result<MemoryStream> {
+        let listeners = self.0.listeners.lock()?;
+
+        // 与系统一致, 监听未指定地址(0.0.0.0或::)时接受发往任意地址的连接
+        let sender = [
+            addr.ip(),
+            Ipv4Addr::UNSPECIFIED.into(),
+            Ipv6Addr::UNSPECIFIED.into(),
+        ]
+        .into_iter()
+        .find_map(|ip| listeners.get(&SocketAddr::new(ip, addr.port())))
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))?;
+
+        let mut connections = self.0.connections.lock()?;
+        let local = self.ephemeral_addr(addr.ip(), &listeners, &connections)?;
+
+        connections.insert(local);
+        drop(connections);
+
+        let (mut stream, accepted) = MemoryStream::pair(local, addr);
+        stream.port = Some(Port(self.clone(), local));
+
+        sender
+            .send(accepted)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::ConnectionRefused))?;
+
+        Ok(stream)
+    }
+}
+
+fn socket_addr(socket: &Socket) -> crate::Result<SocketAddr> {
+    socket
+        .as_string()
+        .parse()
+        .map_err(|_| InvalidAddr::Domain(socket.as_string()).into())
+}
+
+impl Provider<Socket> for MemoryNetwork {
+    type Output = BoxedFuture<MemoryListener>;
+
+    fn call(&self, socket: Socket) -> Self::Output {
+        let listener = socket_addr(&socket).and_then(|addr| self.bind(addr));
+        Box::pin(async move { listener })
+    }
+}
+
+impl Provider<Socket> for MemoryConnector {
+    type Output = BoxedFuture<FusoStream>;
+
+    fn call(&self, socket: Socket) -> Self::Output {
+        let stream = socket_addr(&socket).and_then(|addr| self.0.connect(addr));
+        Box::pin(async move { stream.map(ToBoxStream::into_boxed_stream) })
+    }
+}
+
+impl NetSocket for MemoryStream {
+    fn peer_addr(&self) -> crate::Result<Address> {
+        Ok(Address::One(Socket::tcp(self.peer_addr)))
+    }
+
+    fn local_addr(&self) -> crate::Result<Address> {
+        Ok(Address::One(Socket::tcp(self.local_addr)))
+    }
+}
+
+impl tokio::io::AsyncRead for MemoryStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for MemoryStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+impl NetSocket for MemoryListener {
+    fn peer_addr(&self) -> crate::Result<Address> {
+        Ok(Address::One(Socket::tcp(self.local_addr)))
+    }
+
+    fn local_addr(&self) -> crate::Result<Address> {
+        Ok(Address::One(Socket::tcp(self.local_addr)))
+    }
+}
+
+impl Accepter for MemoryListener {
+    type Stream = FusoStream;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<crate::Result<Self::Stream>> {
+        match self.incoming.poll_recv(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Some(stream)) => Poll::Ready(Ok(stream.into_boxed_stream())),
+            Poll::Ready(None) => Poll::Ready(Err(std::io::Error::from(
+                std::io::ErrorKind::NotConnected,
+            )
+            .into())),
+        }
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        if let Ok(mut connections) = self.0 .0.connections.lock() {
+            connections.remove(&self.1);
+        }
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = self.network.0.listeners.lock() {
+            listeners.remove(&self.local_addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        client::{ClientBuilder, Route},
+        ext::{AsyncReadExt, AsyncWriteExt},
+        proxy_protocol,
+        server::ServerBuilder,
+        AccepterExt, Addr, ClientProvider, FusoStream, NetSocket, Provider, Socket, TokioExecutor,
+    };
+
+    use super::{BoxedFuture, MemoryConnector, MemoryNetwork};
+
+    /// 客户端直接连接转发目标
+    struct Forward(MemoryConnector);
+
+    impl Provider<Socket> for Forward {
+        type Output = BoxedFuture<Route<FusoStream>>;
+
+        fn call(&self, socket: Socket) -> Self::Output {
+            let stream = self.0.call(socket);
+            Box::pin(async move { stream.await.map(Route::Forward) })
+        }
+    }
+
+    /// 在 `network` 中监听 `addr`, 回复收到的数据并加上 `prefix`
+    async fn echo(network: &MemoryNetwork, addr: Socket, prefix: &'static [u8]) {
+        let mut listener = network.call(addr).await.unwrap();
+
+        tokio::spawn(async move {
+            while let Ok(mut stream) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 4];
+                    while stream.read_exact(&mut buf).await.is_ok() {
+                        let mut response = prefix.to_vec();
+                        response.extend_from_slice(&buf);
+                        if stream.write_all(&response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_memory_network() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let network = MemoryNetwork::default();
+                let bind_socket = Socket::tcp(([127, 0, 0, 1], 8080));
+                let mut listener = network.call(bind_socket.clone()).await.unwrap();
+
+                assert!(network.call(bind_socket.clone()).await.is_err());
+
+                let random = network
+                    .call(Socket::tcp(([127, 0, 0, 1], 0)))
+                    .await
+                    .unwrap();
+                assert_ne!(
+                    random.local_addr().unwrap(),
+                    crate::Address::One(Socket::tcp(([127, 0, 0, 1], 0)))
+                );
+
+                let mut client = network.connector().call(bind_socket.clone()).await.unwrap();
+                let mut server = listener.accept().await.unwrap();
+
+                assert_eq!(
+                    client.peer_addr().unwrap(),
+                    crate::Address::One(bind_socket.clone())
+                );
+                assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+
+                client.write_all(b"hello memory").await.unwrap();
+
+                let mut buf = [0u8; 12];
+                server.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello memory");
+
+                drop(listener);
+                assert!(network.connector().call(bind_socket).await.is_err());
+            });
+    }
+
+    #[test]
+    fn test_memory_ephemeral_port() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let network = MemoryNetwork::default();
+                let bind_socket = Socket::tcp(([127, 0, 0, 1], 8080));
+                let _listener = network.call(bind_socket.clone()).await.unwrap();
+
+                // 下一个动态端口已被监听占用, 分配时跳过
+                let occupied = network
+                    .call(Socket::tcp(([127, 0, 0, 1], 49153)))
+                    .await
+                    .unwrap();
+
+                let first = network.connector().call(bind_socket.clone()).await.unwrap();
+                let second = network.connector().call(bind_socket.clone()).await.unwrap();
+
+                assert_ne!(first.local_addr().unwrap(), occupied.local_addr().unwrap());
+                assert_ne!(second.local_addr().unwrap(), occupied.local_addr().unwrap());
+                assert_ne!(first.local_addr().unwrap(), second.local_addr().unwrap());
+
+                // 所有动态端口都被占用时不再分配重复的端口
+                let mut streams = Vec::new();
+                while let Ok(stream) = network.connector().call(bind_socket.clone()).await {
+                    streams.push(stream);
+                }
+                assert_eq!(streams.len(), 16384 - 3);
+
+                // 连接关闭后释放端口
+                drop(first);
+                assert!(network.connector().call(bind_socket).await.is_ok());
+            });
+    }
+
+    #[test]
+    fn test_memory_penetrate_route() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let network = MemoryNetwork::default();
+                let server_socket = Socket::tcp(([127, 0, 0, 1], 6722));
+
+                let server = ServerBuilder {
+                    executor: TokioExecutor,
+                    is_mixed: false,
+                    observer: Some(Arc::new(())),
+                    handshake: None,
+                    server_provider: Arc::new(network.clone()),
+                }
+                .using_penetrate()
+                .using_adapter()
+                .using_direct()
+                .build()
+                .bind(server_socket.clone())
+                .run();
+
+                let server = tokio::spawn(server);
+
+                // 两个客户端各自的访问端口只转发到自己的目标
+                let mut clients = Vec::new();
+                for (name, visit, target, prefix) in
+                    [("a", 8080, 9080, &b"a:"[..]), ("b", 8081, 9081, &b"b:"[..])]
+                {
+                    let target = Socket::tcp(([127, 0, 0, 1], target));
+                    echo(&network, target.clone(), prefix).await;
+
+                    let client = ClientBuilder {
+                        executor: TokioExecutor,
+                        retry_delay: Some(Duration::from_millis(10)),
+                        retry_max_delay: None,
+                        retry_jitter: 0.0,
+                        maximum_retries: None,
+                        handshake: None,
+                        client_provider: ClientProvider {
+                            server_address: Default::default(),
+                            connect_provider: Arc::new(network.connector()),
+                        },
+                    }
+                    .using_penetrate(Socket::tcp(([127, 0, 0, 1], visit)), target)
+                    .set_name(String::from(name))
+                    .build(server_socket.clone(), Forward(network.connector()))
+                    .run();
+
+                    clients.push(tokio::spawn(client));
+                }
+
+                for (visit, prefix) in [(8080, b"a:"), (8081, b"b:")] {
+                    let visit = Socket::tcp(([127, 0, 0, 1], visit));
+
+                    let mut visitor = loop {
+                        match network.connector().call(visit.clone()).await {
+                            Ok(visitor) => break visitor,
+                            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                        }
+                    };
+
+                    visitor.write_all(b"fuso").await.unwrap();
+
+                    let mut buf = [0u8; 6];
+                    tokio::time::timeout(Duration::from_secs(5), visitor.read_exact(&mut buf))
+                        .await
+                        .unwrap()
+                        .unwrap();
+
+                    assert_eq!(&buf[..2], prefix);
+                    assert_eq!(&buf[2..], b"fuso");
+                }
+
+                for client in clients {
+                    client.abort();
+                }
+
+                server.abort();
+            });
+    }
+
+    #[test]
+    fn test_memory_penetrate_service() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let network = MemoryNetwork::default();
+                let server_socket = Socket::tcp(([127, 0, 0, 1], 6722));
+
+                let server = ServerBuilder {
+                    executor: TokioExecutor,
+                    is_mixed: false,
+                    observer: Some(Arc::new(())),
+                    handshake: None,
+                    server_provider: Arc::new(network.clone()),
+                }
+                .using_penetrate()
+                .proxy_protocol(true)
+                .using_adapter()
+                .using_direct()
+                .build()
+                .bind(server_socket.clone())
+                .run();
+
+                let server = tokio::spawn(server);
+
+                let mut target = network
+                    .call(Socket::tcp(([127, 0, 0, 1], 9082)))
+                    .await
+                    .unwrap();
+
+                let client = ClientBuilder {
+                    executor: TokioExecutor,
+                    retry_delay: Some(Duration::from_millis(10)),
+                    retry_max_delay: None,
+                    retry_jitter: 0.0,
+                    maximum_retries: None,
+                    handshake: None,
+                    client_provider: ClientProvider {
+                        server_address: Default::default(),
+                        connect_provider: Arc::new(network.connector()),
+                    },
+                }
+                .using_penetrate(
+                    Socket::tcp(([127, 0, 0, 1], 8080)),
+                    Socket::tcp(([127, 0, 0, 1], 9080)),
+                )
+                .add_services(vec![(
+                    String::from("ssh"),
+                    8082,
+                    Addr::from(([127, 0, 0, 1], 9082)),
+                )])
+                .build(server_socket.clone(), Forward(network.connector()))
+                .run();
+
+                let client = tokio::spawn(client);
+
+                let service = Socket::tcp(([127, 0, 0, 1], 8082));
+
+                let mut visitor = loop {
+                    match network.connector().call(service.clone()).await {
+                        Ok(visitor) => break visitor,
+                        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+
+                visitor.write_all(b"fuso").await.unwrap();
+
+                let mut stream = tokio::time::timeout(Duration::from_secs(5), target.accept())
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                // 其他服务与访问者走同一个流程, 同样先发送PROXY protocol头
+                let mut header = [0u8; 28];
+                tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut header))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&header[..12], &proxy_protocol::SIGNATURE);
+                assert_eq!(u16::from_be_bytes([header[26], header[27]]), 8082);
+
+                let mut buf = [0u8; 4];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"fuso");
+
+                client.abort();
+                server.abort();
+            });
+    }
+}
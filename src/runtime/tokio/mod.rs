@@ -1,32 +1,73 @@
 mod penetrate;
 pub use penetrate::connector::*;
 
-use std::{net::SocketAddr, pin::Pin, sync::Arc, task::Poll};
+mod memory;
+pub use memory::*;
+
+#[cfg(target_os = "linux")]
+mod splice;
+#[cfg(target_os = "linux")]
+pub(crate) use splice::*;
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::Poll,
+};
 
 use async_mutex::Mutex;
-use tokio::net::TcpListener;
+use tokio::net::TcpSocket;
 
 use crate::{
     client::{self},
     kcp::{self},
-    ready, server, Accepter, Address, ClientProvider, Executor, FusoStream, Kind, NetSocket,
-    Observer, Provider, Socket, SocketErr, Task, ToBoxStream, UdpSocket,
+    ready, server, Accepter, Address, ClientProvider, Executor, FusoStream, InvalidAddr, Kind,
+    NetSocket, Observer, Provider, Socket, SocketErr, Task, ToBoxStream, UdpSocket,
 };
 
 type BoxedFuture<O> = Pin<Box<dyn std::future::Future<Output = crate::Result<O>> + Send + 'static>>;
 
+const DEFAULT_BACKLOG: u32 = 1024;
+
+/// windows上 `SO_REUSEADDR` 允许抢占其他进程正在监听的端口, 只在其他平台上默认开启
+const DEFAULT_REUSE_ADDR: bool = cfg!(not(windows));
+
 #[derive(Clone, Copy)]
 pub struct TokioExecutor;
-pub struct TokioTcpListener(tokio::net::TcpListener);
-pub struct TokioAccepter;
+/// 第二个字段为创建监听时的选项, 用于设置接受的连接
+pub struct TokioTcpListener(tokio::net::TcpListener, TokioAccepter);
+
+/// tcp监听, 可在监听前设置 `SO_REUSEADDR` 与 `SO_REUSEPORT`
+#[derive(Clone, Copy)]
+pub struct TokioAccepter {
+    reuse_addr: bool,
+    reuse_port: bool,
+    backlog: Option<u32>,
+    nodelay: bool,
+    dscp: Option<u8>,
+    freebind: bool,
+}
+
+/// 第二个字段为是否对tcp连接设置 `TCP_NODELAY`, 第三个字段为DSCP标记
 pub struct TokioConnector(
     Arc<Mutex<Option<kcp::KcpConnector<Arc<tokio::net::UdpSocket>, TokioExecutor>>>>,
+    bool,
+    Option<u8>,
 );
 
 pub struct TokioUdpSocket;
 
 pub struct TokioUdpServerProvider;
-pub struct UdpForwardProvider;
+/// socks5 udp中继, 监听在给定的地址上, 可设置中继socket的收发缓冲区
+#[derive(Default, Clone, Copy)]
+pub struct UdpForwardProvider {
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+}
+
+/// 使用系统配置的dns异步解析域名
+pub struct TokioResolver;
 
 impl Executor for TokioExecutor {
     fn spawn<F, O>(&self, fut: F) -> Task<O>
@@ -46,17 +87,341 @@ impl Executor for TokioExecutor {
     }
 }
 
+/// DSCP只有6位, 超出时截断
+#[cfg(unix)]
+pub(crate) fn checked_dscp(dscp: Option<u8>) -> Option<u8> {
+    dscp.map(|dscp| dscp & 0x3f)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn checked_dscp(dscp: Option<u8>) -> Option<u8> {
+    if dscp.is_some() {
+        log::warn!("dscp marking is not supported on this platform, ignore it");
+    }
+
+    None
+}
+
+/// 设置ip头的DSCP标记, 失败时只记录警告, 不影响转发
+#[cfg(unix)]
+pub(crate) fn set_dscp(tcp: &tokio::net::TcpStream, dscp: u8) {
+    use std::os::unix::io::AsRawFd;
+
+    let tos = (dscp as libc::c_int) << 2;
+
+    let setsockopt = |level, name| unsafe {
+        libc::setsockopt(
+            tcp.as_raw_fd(),
+            level,
+            name,
+            &tos as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    let ret = match tcp.local_addr() {
+        // 双栈监听时ipv4连接的地址为ipv4映射的ipv6地址, 实际使用 `IP_TOS`
+        Ok(SocketAddr::V6(addr)) if addr.ip().to_ipv4_mapped().is_none() => {
+            setsockopt(libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+        }
+        _ => setsockopt(libc::IPPROTO_IP, libc::IP_TOS),
+    };
+
+    if ret != 0 {
+        log::warn!(
+            "failed to set dscp {} err={}",
+            dscp,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_dscp(_: &tokio::net::TcpStream, _: u8) {}
+
+/// ipv6的监听同样由 `IP_FREEBIND` 控制
+#[cfg(target_os = "linux")]
+fn set_freebind(tcp: &TcpSocket) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let enable: libc::c_int = 1;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            tcp.as_raw_fd(),
+            libc::IPPROTO_IP,
+            libc::IP_FREEBIND,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// 设置 `SO_RCVBUF` 或 `SO_SNDBUF` 并返回实际生效的大小,
+/// 系统可能调整该值, 如linux会加倍并受 `net.core.rmem_max` 限制
+#[cfg(unix)]
+fn set_buffer_size(
+    udp: &tokio::net::UdpSocket,
+    name: libc::c_int,
+    size: usize,
+) -> std::io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+
+    let ret = unsafe {
+        libc::setsockopt(
+            udp.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            &size as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut effective: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            udp.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            &mut effective as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    match ret {
+        0 => Ok(effective as usize),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+impl UdpForwardProvider {
+    /// 中继socket的接收缓冲区(`SO_RCVBUF`), 突发的udp流量超过缓冲区时会丢包, 默认使用系统设置
+    pub fn recv_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// 中继socket的发送缓冲区(`SO_SNDBUF`), 默认使用系统设置
+    pub fn send_buffer_size(mut self, size: Option<usize>) -> Self {
+        self.send_buffer_size = size;
+        self
+    }
+
+    /// 设置失败时只记录警告, 不影响转发
+    fn apply(&self, udp: &tokio::net::UdpSocket) {
+        #[cfg(unix)]
+        {
+            let options = [
+                ("SO_RCVBUF", libc::SO_RCVBUF, self.recv_buffer_size),
+                ("SO_SNDBUF", libc::SO_SNDBUF, self.send_buffer_size),
+            ];
+
+            for (option, name, size) in options {
+                let size = match size {
+                    Some(size) => size,
+                    None => continue,
+                };
+
+                match set_buffer_size(udp, name, size) {
+                    Err(e) => log::warn!("failed to set {} to {} err={}", option, size, e),
+                    Ok(effective) if effective < size => {
+                        log::warn!(
+                            "{} {} is clamped to {} by the system",
+                            option,
+                            size,
+                            effective
+                        )
+                    }
+                    Ok(effective) => log::debug!("{} {}, effective {}", option, size, effective),
+                }
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = udp;
+
+            if self.recv_buffer_size.is_some() || self.send_buffer_size.is_some() {
+                log::warn!("udp buffer size is not supported on this platform, ignore it");
+            }
+        }
+    }
+}
+
+/// 在指定的tokio运行时上执行任务, 用于嵌入已有的运行时
+impl Executor for tokio::runtime::Handle {
+    fn spawn<F, O>(&self, fut: F) -> Task<O>
+    where
+        F: std::future::Future<Output = O> + Send + 'static,
+        O: Send + 'static,
+    {
+        let task = tokio::runtime::Handle::spawn(self, fut);
+
+        Task {
+            detach_fn: None,
+            abort_fn: Some(Box::new(move || {
+                task.abort();
+            })),
+            _marked: std::marker::PhantomData,
+        }
+    }
+}
+
+/// 兼容原来的单元结构体, `TokioAccepter` 仍然可以直接作为默认的监听使用
+#[allow(non_upper_case_globals)]
+pub const TokioAccepter: TokioAccepter = TokioAccepter::new();
+
+impl Default for TokioAccepter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TokioAccepter {
+    pub const fn new() -> Self {
+        Self {
+            reuse_addr: DEFAULT_REUSE_ADDR,
+            reuse_port: false,
+            backlog: None,
+            nodelay: false,
+            dscp: None,
+            freebind: false,
+        }
+    }
+
+    /// 允许绑定处于TIME_WAIT状态的端口, 服务重启后可以立即监听, 非windows平台默认开启
+    pub fn reuse_addr(mut self, reuse: bool) -> Self {
+        self.reuse_addr = reuse;
+        self
+    }
+
+    /// 允许多个进程监听同一个端口, 由系统分配连接, 不支持的平台上忽略
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.reuse_port = reuse;
+        self
+    }
+
+    /// 监听的等待队列长度, 默认1024, 连接突增时队列满了会丢弃SYN.
+    /// 系统会限制最大值: linux为 `net.core.somaxconn` (5.4之前默认128, 之后4096),
+    /// macos与bsd为 `kern.ipc.somaxconn` (默认128), windows为 `SOMAXCONN` (约200)
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = Some(backlog);
+        self
+    }
+
+    /// 接受的连接设置 `TCP_NODELAY`, 关闭nagle算法, 降低小数据包的延迟
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// 接受的连接设置DSCP标记(0-63), 用于网络中的QoS, 不支持的平台上忽略
+    pub fn dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = checked_dscp(dscp);
+        self
+    }
+
+    /// 设置 `IP_FREEBIND`, 允许监听本机还没有的地址(如keepalived切换后才分配的虚拟ip),
+    /// 只支持linux, 其他平台上忽略
+    pub fn freebind(mut self, freebind: bool) -> Self {
+        self.freebind = freebind;
+        self
+    }
+
+    fn bind(&self, addr: SocketAddr) -> crate::Result<tokio::net::TcpListener> {
+        let tcp = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        if self.reuse_addr {
+            tcp.set_reuseaddr(true)?;
+        }
+
+        #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+        if self.reuse_port {
+            tcp.set_reuseport(true)?;
+        }
+
+        #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+        if self.reuse_port {
+            log::warn!("SO_REUSEPORT is not supported on this platform, ignore it");
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.freebind {
+            set_freebind(&tcp)?;
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        if self.freebind {
+            log::warn!("IP_FREEBIND is not supported on this platform, ignore it");
+        }
+
+        tcp.bind(addr)?;
+
+        let backlog = self.backlog.unwrap_or(DEFAULT_BACKLOG);
+
+        #[cfg(target_os = "linux")]
+        if let Some(max) = std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+            .ok()
+            .and_then(|max| max.trim().parse::<u32>().ok())
+            .filter(|max| backlog > *max)
+        {
+            log::warn!(
+                "listen backlog {} on {} is clamped to net.core.somaxconn {}",
+                backlog,
+                addr,
+                max
+            );
+        }
+
+        Ok(tcp.listen(backlog)?)
+    }
+}
+
 impl Provider<Socket> for TokioAccepter {
     type Output = BoxedFuture<TokioTcpListener>;
 
     fn call(&self, socket: Socket) -> Self::Output {
+        let accepter = *self;
         if socket.is_tcp() || socket.is_mixed() {
             Box::pin(async move {
-                Ok({
-                    TcpListener::bind(socket.as_string())
-                        .await
-                        .map(|tcp| TokioTcpListener(tcp))?
-                })
+                let mut last_err = None;
+
+                // 域名可能解析出多个地址(如同时有ipv4与ipv6), 依次尝试直到有一个监听成功
+                for addr in tokio::net::lookup_host(socket.as_string()).await? {
+                    // systemd socket activation传递的监听, 由systemd绑定, 不再设置监听选项
+                    if let Some(listener) = crate::systemd::take(addr) {
+                        log::info!("use the listener on {} passed by systemd", addr);
+                        let listener = tokio::net::TcpListener::from_std(listener)?;
+                        return Ok(TokioTcpListener(listener, accepter));
+                    }
+
+                    match accepter.bind(addr) {
+                        Ok(listener) => return Ok(TokioTcpListener(listener, accepter)),
+                        Err(e) => {
+                            log::debug!("failed to listen on {}: {}", addr, e);
+                            last_err = Some(e);
+                        }
+                    }
+                }
+
+                Err(last_err.unwrap_or_else(|| InvalidAddr::Domain(socket.as_string()).into()))
             })
         } else {
             Box::pin(async move { Err(Kind::Unsupported(socket).into()) })
@@ -72,6 +437,31 @@ impl NetSocket for tokio::net::TcpStream {
     fn local_addr(&self) -> crate::Result<Address> {
         Ok(Address::One(Socket::tcp(self.local_addr()?)))
     }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(std::os::unix::io::AsRawFd::as_raw_fd(self))
+    }
+}
+
+/// 未绑定路径的一端(如主动连接方)返回空路径
+#[cfg(unix)]
+impl NetSocket for tokio::net::UnixStream {
+    fn peer_addr(&self) -> crate::Result<Address> {
+        let addr = self.peer_addr()?;
+        let path = addr.as_pathname().map(|path| path.to_string_lossy());
+        Ok(Address::One(Socket::tcp(crate::Addr::unix(
+            path.unwrap_or_default(),
+        ))))
+    }
+
+    fn local_addr(&self) -> crate::Result<Address> {
+        let addr = self.local_addr()?;
+        let path = addr.as_pathname().map(|path| path.to_string_lossy());
+        Ok(Address::One(Socket::tcp(crate::Addr::unix(
+            path.unwrap_or_default(),
+        ))))
+    }
 }
 
 impl NetSocket for TokioTcpListener {
@@ -96,6 +486,16 @@ impl Accepter for TokioTcpListener {
             Poll::Ready(Ok((tcp, addr))) => {
                 log::debug!("accept connection from {}", addr);
 
+                if self.1.nodelay {
+                    if let Err(e) = tcp.set_nodelay(true) {
+                        log::warn!("failed to set TCP_NODELAY on {} err={}", addr, e);
+                    }
+                }
+
+                if let Some(dscp) = self.1.dscp {
+                    set_dscp(&tcp, dscp);
+                }
+
                 Poll::Ready(Ok(tcp.into_boxed_stream()))
             }
         }
@@ -107,18 +507,39 @@ impl Provider<Socket> for TokioConnector {
 
     fn call(&self, socket: Socket) -> Self::Output {
         let kcp = self.0.clone();
+        let nodelay = self.1;
+        let dscp = self.2;
         Box::pin(async move {
             Ok({
                 if socket.is_tcp() {
-                    tokio::net::TcpStream::connect(socket.as_string())
-                        .await?
-                        .into_boxed_stream()
+                    let tcp = tokio::net::TcpStream::connect(socket.as_string()).await?;
+
+                    if nodelay {
+                        tcp.set_nodelay(true)?;
+                    }
+
+                    if let Some(dscp) = dscp {
+                        set_dscp(&tcp, dscp);
+                    }
+
+                    tcp.into_boxed_stream()
                 } else if socket.is_kcp() {
                     let mut kcp = kcp.lock().await;
 
                     if kcp.is_none() {
-                        let udp = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
-                        udp.connect(socket.as_string()).await?;
+                        // 与服务端地址的协议族一致, ipv6的服务端不能使用ipv4的socket
+                        let server = tokio::net::lookup_host(socket.as_string())
+                            .await?
+                            .next()
+                            .ok_or_else(|| InvalidAddr::Domain(socket.as_string()))?;
+
+                        let bind = match server {
+                            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+                        };
+
+                        let udp = tokio::net::UdpSocket::bind(bind).await?;
+                        udp.connect(server).await?;
                         *kcp = Some(kcp::KcpConnector::new(Arc::new(udp), TokioExecutor));
                     }
 
@@ -135,7 +556,7 @@ impl ClientProvider<TokioConnector> {
     pub fn with_tokio() -> Self {
         ClientProvider {
             server_address: Default::default(),
-            connect_provider: Arc::new(TokioConnector(Default::default())),
+            connect_provider: Arc::new(TokioConnector(Default::default(), false, None)),
         }
     }
 }
@@ -151,7 +572,45 @@ where
         executor: TokioExecutor,
         handshake: None,
         observer: Some(Arc::new(observer)),
-        server_provider: Arc::new(TokioAccepter),
+        server_provider: Arc::new(TokioAccepter::default()),
+    }
+}
+
+impl<O> server::ServerBuilder<TokioExecutor, TokioAccepter, FusoStream, O> {
+    /// 服务端与客户端映射的监听都设置 `SO_REUSEADDR`
+    pub fn reuse_addr(mut self, reuse: bool) -> Self {
+        self.server_provider = Arc::new(self.server_provider.reuse_addr(reuse));
+        self
+    }
+
+    /// 服务端与客户端映射的监听都设置 `SO_REUSEPORT`
+    pub fn reuse_port(mut self, reuse: bool) -> Self {
+        self.server_provider = Arc::new(self.server_provider.reuse_port(reuse));
+        self
+    }
+
+    /// 服务端与客户端映射的监听的等待队列长度, 会被系统的上限截断
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.server_provider = Arc::new(self.server_provider.backlog(backlog));
+        self
+    }
+
+    /// 服务端接受的所有tcp连接(客户端, 映射, 访问者)都设置 `TCP_NODELAY`
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.server_provider = Arc::new(self.server_provider.nodelay(nodelay));
+        self
+    }
+
+    /// 服务端接受的所有tcp连接都设置DSCP标记, 不支持的平台上忽略
+    pub fn dscp(mut self, dscp: Option<u8>) -> Self {
+        self.server_provider = Arc::new(self.server_provider.dscp(dscp));
+        self
+    }
+
+    /// 服务端与客户端映射的监听都设置 `IP_FREEBIND`, 只支持linux
+    pub fn freebind(mut self, freebind: bool) -> Self {
+        self.server_provider = Arc::new(self.server_provider.freebind(freebind));
+        self
     }
 }
 
@@ -162,10 +621,30 @@ pub fn builder_client_with_tokio(
         handshake: None,
         client_provider: ClientProvider::with_tokio(),
         retry_delay: None,
+        retry_max_delay: None,
+        retry_jitter: 0.0,
         maximum_retries: None,
     }
 }
 
+impl client::ClientBuilder<TokioExecutor, TokioConnector, FusoStream> {
+    /// 与服务端之间的控制连接与映射连接都设置 `TCP_NODELAY`
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        let TokioConnector(kcp, _, dscp) = &*self.client_provider.connect_provider;
+        self.client_provider.connect_provider =
+            Arc::new(TokioConnector(kcp.clone(), nodelay, *dscp));
+        self
+    }
+
+    /// 与服务端之间的控制连接与映射连接都设置DSCP标记, 不支持的平台上忽略
+    pub fn dscp(mut self, dscp: Option<u8>) -> Self {
+        let TokioConnector(kcp, nodelay, _) = &*self.client_provider.connect_provider;
+        self.client_provider.connect_provider =
+            Arc::new(TokioConnector(kcp.clone(), *nodelay, checked_dscp(dscp)));
+        self
+    }
+}
+
 impl NetSocket for tokio::net::UdpSocket {
     fn peer_addr(&self) -> crate::Result<Address> {
         Ok(Address::One(Socket::udp((*self).peer_addr()?)))
@@ -227,14 +706,18 @@ impl UdpSocket for tokio::net::UdpSocket {
     }
 }
 
-impl Provider<()> for UdpForwardProvider {
+impl Provider<SocketAddr> for UdpForwardProvider {
     type Output = BoxedFuture<(SocketAddr, tokio::net::UdpSocket)>;
 
-    fn call(&self, _: ()) -> Self::Output {
+    fn call(&self, bind: SocketAddr) -> Self::Output {
+        let options = *self;
+
         Box::pin(async move {
-            let udp = tokio::net::UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+            let udp = tokio::net::UdpSocket::bind(bind).await?;
             let addr = udp.local_addr()?;
 
+            options.apply(&udp);
+
             log::debug!("udp listening on {}", addr);
 
             Ok((addr, udp))
@@ -242,6 +725,21 @@ impl Provider<()> for UdpForwardProvider {
     }
 }
 
+impl Provider<String> for TokioResolver {
+    type Output = BoxedFuture<IpAddr>;
+
+    fn call(&self, domain: String) -> Self::Output {
+        Box::pin(async move {
+            let addr = tokio::net::lookup_host((domain.as_str(), 0)).await?.next();
+
+            match addr {
+                Some(addr) => Ok(addr.ip()),
+                None => Err(InvalidAddr::Domain(domain).into()),
+            }
+        })
+    }
+}
+
 impl Provider<Socket> for TokioUdpServerProvider {
     type Output = BoxedFuture<Arc<tokio::net::UdpSocket>>;
 
@@ -264,3 +762,419 @@ impl Provider<Socket> for TokioUdpServerProvider {
         })
     }
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        os::unix::io::AsRawFd,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use crate::{generator::Generator, Executor, Provider, Task};
+
+    type BoxedFuture<T> =
+        Pin<Box<dyn std::future::Future<Output = crate::Result<T>> + Send + 'static>>;
+
+    /// 记录经过的任务数, 实际交给当前的tokio运行时执行
+    #[derive(Clone)]
+    struct Counting(tokio::runtime::Handle, Arc<AtomicUsize>);
+
+    impl Executor for Counting {
+        fn spawn<F, O>(&self, fut: F) -> Task<O>
+        where
+            F: std::future::Future<Output = O> + Send + 'static,
+            O: Send + 'static,
+        {
+            self.1.fetch_add(1, Ordering::SeqCst);
+            Executor::spawn(&self.0, fut)
+        }
+    }
+
+    /// 只产生一个future
+    struct Once(Option<BoxedFuture<()>>);
+
+    impl Generator for Once {
+        type Output = Option<BoxedFuture<()>>;
+
+        fn poll_generate(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+        ) -> Poll<crate::Result<Self::Output>> {
+            Poll::Ready(Ok(self.0.take()))
+        }
+    }
+
+    /// 服务端原样返回读到的4个字节, 客户端写入后把读到的结果发送给测试
+    enum Echo {
+        Server,
+        Client(async_channel::Sender<[u8; 4]>),
+    }
+
+    impl<S, T> Provider<(S, T)> for Echo
+    where
+        S: crate::Stream + Send + 'static,
+    {
+        type Output = BoxedFuture<Once>;
+
+        fn call(&self, (mut stream, _): (S, T)) -> Self::Output {
+            use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+            let reply = match self {
+                Echo::Server => None,
+                Echo::Client(reply) => Some(reply.clone()),
+            };
+
+            Box::pin(async move {
+                Ok(Once(Some(Box::pin(async move {
+                    let mut buf = [0u8; 4];
+                    match reply {
+                        None => {
+                            stream.read_exact(&mut buf).await?;
+                            stream.write_all(&buf).await?;
+                        }
+                        Some(reply) => {
+                            stream.write_all(b"fuso").await?;
+                            stream.read_exact(&mut buf).await?;
+                            let _ = reply.send(buf).await;
+                        }
+                    }
+                    Ok(())
+                }))))
+            })
+        }
+    }
+
+    #[test]
+    fn test_builders_with_executor() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let server_spawned = Arc::new(AtomicUsize::new(0));
+                let client_spawned = Arc::new(AtomicUsize::new(0));
+                let handle = tokio::runtime::Handle::current();
+
+                let port = std::net::TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+                    .port();
+
+                let server = super::builder_server_with_tokio(())
+                    .with_executor(Counting(handle.clone(), server_spawned.clone()))
+                    .build(Echo::Server)
+                    .bind(crate::Socket::tcp(([127, 0, 0, 1], port)))
+                    .run();
+
+                let (reply, replied) = async_channel::bounded(1);
+
+                let server = tokio::spawn(server);
+
+                while tokio::net::TcpStream::connect(("127.0.0.1", port))
+                    .await
+                    .is_err()
+                {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+
+                let client = super::builder_client_with_tokio()
+                    .with_executor(Counting(handle.clone(), client_spawned.clone()))
+                    .build(
+                        crate::Socket::tcp(([127, 0, 0, 1], port)),
+                        Echo::Client(reply),
+                    )
+                    .run();
+
+                let client = tokio::spawn(client);
+
+                let echoed =
+                    crate::time::wait_for(
+                        Duration::from_secs(5),
+                        async move { replied.recv().await },
+                    )
+                    .await
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(&echoed, b"fuso");
+                // 连接的处理与生成的转发都交给了调用者提供的执行器
+                assert!(server_spawned.load(Ordering::SeqCst) >= 2);
+                assert!(client_spawned.load(Ordering::SeqCst) >= 1);
+
+                server.abort();
+                client.abort();
+            });
+    }
+
+    #[test]
+    fn test_penetrate_drain() {
+        use crate::ext::{AsyncReadExt, AsyncWriteExt};
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let free_port = || {
+                    std::net::TcpListener::bind("127.0.0.1:0")
+                        .unwrap()
+                        .local_addr()
+                        .unwrap()
+                        .port()
+                };
+
+                let (server_port, visit_port, admin_port) = (free_port(), free_port(), free_port());
+
+                let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let echo_addr = echo.local_addr().unwrap();
+
+                tokio::spawn(async move {
+                    while let Ok((stream, _)) = echo.accept().await {
+                        tokio::spawn(async move {
+                            let (mut reader, mut writer) = tokio::io::split(stream);
+                            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                        });
+                    }
+                });
+
+                let admin = crate::admin::Admin::default();
+
+                tokio::spawn(crate::admin::serve(
+                    super::TokioAccepter::default(),
+                    crate::Socket::tcp(([127, 0, 0, 1], admin_port)),
+                    admin.clone(),
+                    super::TokioExecutor,
+                ));
+
+                let server = super::builder_server_with_tokio(())
+                    .using_penetrate()
+                    .admin(Some(admin))
+                    .using_adapter()
+                    .using_direct()
+                    .build()
+                    .bind(crate::Socket::tcp(([127, 0, 0, 1], server_port)))
+                    .run();
+
+                let server = tokio::spawn(server);
+
+                let client = super::builder_client_with_tokio()
+                    .using_penetrate(
+                        crate::Socket::tcp(([127, 0, 0, 1], visit_port)),
+                        crate::Socket::tcp(echo_addr),
+                    )
+                    .set_name(String::from("drain"))
+                    .build(
+                        crate::Socket::tcp(([127, 0, 0, 1], server_port)),
+                        super::TokioPenetrateConnector::new().await.unwrap(),
+                    )
+                    .run();
+
+                let client = tokio::spawn(client);
+
+                let visit = || tokio::net::TcpStream::connect(("127.0.0.1", visit_port));
+
+                let mut visitor = loop {
+                    match visit().await {
+                        Ok(visitor) => break visitor,
+                        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+
+                let mut buf = [0u8; 4];
+                visitor.write_all(b"fuso").await.unwrap();
+                visitor.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"fuso");
+
+                let mut command = tokio::net::TcpStream::connect(("127.0.0.1", admin_port))
+                    .await
+                    .unwrap();
+                command.write_all(b"drain drain\n").await.unwrap();
+                let mut response = [0u8; 12];
+                command.read_exact(&mut response).await.unwrap();
+                assert_eq!(&response, b"{\"ok\":true}\n");
+
+                // 不再接受新的访问, 正在转发的连接不受影响
+                let mut retries = 0;
+                while visit().await.is_ok() {
+                    retries += 1;
+                    assert!(retries < 100);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+
+                visitor.write_all(b"rust").await.unwrap();
+                visitor.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"rust");
+
+                // 客户端等待正在转发的连接结束后退出, 不再重连
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                assert!(!client.is_finished());
+
+                drop(visitor);
+
+                tokio::time::timeout(Duration::from_secs(5), client)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .unwrap();
+
+                server.abort();
+            });
+    }
+
+    #[test]
+    fn test_set_dscp() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let tcp = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+                    .await
+                    .unwrap();
+
+                super::set_dscp(&tcp, 46);
+
+                let mut tos: libc::c_int = 0;
+                let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+                let ret = unsafe {
+                    libc::getsockopt(
+                        tcp.as_raw_fd(),
+                        libc::IPPROTO_IP,
+                        libc::IP_TOS,
+                        &mut tos as *mut libc::c_int as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+
+                assert_eq!(ret, 0);
+                assert_eq!(tos, 46 << 2);
+                assert_eq!(super::checked_dscp(Some(0xff)), Some(63));
+            });
+    }
+
+    #[test]
+    fn test_accepter_reuse_addr() {
+        use crate::{Provider, Socket};
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                // 原来的单元结构体写法仍然可用, 默认开启 `SO_REUSEADDR`
+                let listener = super::TokioAccepter
+                    .call(Socket::tcp(([127, 0, 0, 1], 0)))
+                    .await
+                    .unwrap();
+
+                let mut reuse: libc::c_int = 0;
+                let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+                let ret = unsafe {
+                    libc::getsockopt(
+                        listener.0.as_raw_fd(),
+                        libc::SOL_SOCKET,
+                        libc::SO_REUSEADDR,
+                        &mut reuse as *mut libc::c_int as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+
+                assert_eq!(ret, 0);
+                assert_ne!(reuse, 0);
+
+                // 解析出的地址中有可以监听的即可
+                let listener = super::TokioAccepter::new()
+                    .call(Socket::tcp((String::from("localhost"), 0)))
+                    .await;
+                assert!(listener.is_ok());
+            });
+    }
+
+    /// 监听状态的socket中, `TCP_INFO` 的 `tcpi_sacked` 为 `listen()` 传入的等待队列长度
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_accepter_backlog() {
+        use crate::{Provider, Socket};
+
+        fn backlog(listener: &super::TokioTcpListener) -> u32 {
+            let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+            let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+
+            let ret = unsafe {
+                libc::getsockopt(
+                    listener.0.as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    libc::TCP_INFO,
+                    &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+
+            assert_eq!(ret, 0);
+            info.tcpi_sacked
+        }
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                // 与 `--backlog` 相同, 经过服务端的构建器设置
+                let listener = super::builder_server_with_tokio(())
+                    .backlog(7)
+                    .server_provider
+                    .call(Socket::tcp(([127, 0, 0, 1], 0)))
+                    .await
+                    .unwrap();
+
+                assert_eq!(backlog(&listener), 7);
+
+                let somaxconn = std::fs::read_to_string("/proc/sys/net/core/somaxconn")
+                    .unwrap()
+                    .trim()
+                    .parse::<u32>()
+                    .unwrap();
+
+                let listener = super::TokioAccepter::new()
+                    .call(Socket::tcp(([127, 0, 0, 1], 0)))
+                    .await
+                    .unwrap();
+
+                assert_eq!(backlog(&listener), super::DEFAULT_BACKLOG.min(somaxconn));
+            });
+    }
+
+    #[test]
+    fn test_udp_forward_buffer_size() {
+        use crate::Provider;
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let provider = super::UdpForwardProvider::default()
+                    .recv_buffer_size(Some(8192))
+                    .send_buffer_size(Some(8192));
+
+                let (_, udp) = provider.call("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+                for name in [libc::SO_RCVBUF, libc::SO_SNDBUF] {
+                    let mut size: libc::c_int = 0;
+                    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+                    let ret = unsafe {
+                        libc::getsockopt(
+                            udp.as_raw_fd(),
+                            libc::SOL_SOCKET,
+                            name,
+                            &mut size as *mut libc::c_int as *mut libc::c_void,
+                            &mut len,
+                        )
+                    };
+
+                    assert_eq!(ret, 0);
+                    // linux会将设置的值加倍
+                    assert!((8192..=16384).contains(&size));
+                }
+            });
+    }
+}
@@ -1,22 +1,33 @@
 use std::{
-    net::{SocketAddr, ToSocketAddrs},
+    future::Future,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
     pin::Pin,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    task::Poll,
+    time::Duration,
 };
 
-use tokio::net::TcpStream;
+use tokio::net::{TcpSocket, TcpStream};
 
 use crate::{
     client::Route,
     kcp::KcpConnector,
-    penetrate::SocksUdpForwardMock,
+    penetrate::{SocksBindMock, SocksUdpForwardMock},
+    resolver::CachedResolver,
+    socks::Upstream,
     udp::{Datagram, VirtualUdpSocket},
-    Addr, Address, FusoStream, InnerAddr, InvalidAddr, NetSocket, Provider, Socket, SocketErr,
-    SocketKind, ToBoxStream, TokioExecutor, WrappedProvider,
+    Addr, Address, FusoStream, InnerAddr, NetSocket, Provider, Socket, SocketErr, SocketKind,
+    ToBoxStream, TokioAccepter, TokioExecutor, TokioResolver, WrappedProvider,
 };
 
 type BoxedFuture<O> = Pin<Box<dyn std::future::Future<Output = crate::Result<O>> + Send + 'static>>;
 
+const DNS_CACHE_TTL: Duration = Duration::from_secs(60);
+
 pub struct TokioTcpConnector;
 
 pub struct TokioTcpAndKcpConnector {
@@ -25,9 +36,31 @@ pub struct TokioTcpAndKcpConnector {
 
 pub struct TokioPenetrateConnector {
     udp: Arc<Datagram<Arc<tokio::net::UdpSocket>, TokioExecutor>>,
+    /// 本机没有ipv6时为None, 此时无法转发到ipv6的目标
+    udp6: Option<Arc<Datagram<Arc<tokio::net::UdpSocket>, TokioExecutor>>>,
+    resolver: CachedResolver,
+    bind_from: Option<IpAddr>,
+    bind_ports: Option<Arc<SourcePorts>>,
+    nodelay: bool,
+    dscp: Option<u8>,
+    happy_eyeballs: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    upstream: Option<Arc<Upstream>>,
 }
 
-pub struct UdpForwardClientProvider(Arc<Datagram<Arc<tokio::net::UdpSocket>, TokioExecutor>>);
+/// 按目标解析后的协议族选择ipv4或ipv6的udp socket
+pub struct UdpForwardClientProvider(
+    Arc<Datagram<Arc<tokio::net::UdpSocket>, TokioExecutor>>,
+    Option<Arc<Datagram<Arc<tokio::net::UdpSocket>, TokioExecutor>>>,
+    CachedResolver,
+);
+
+/// 转发连接可以使用的源端口, 从上次使用的端口之后开始查找空闲端口, 不会立即复用刚关闭的端口
+#[derive(Debug)]
+struct SourcePorts {
+    range: RangeInclusive<u16>,
+    next: AtomicU32,
+}
 
 impl TokioPenetrateConnector {
     pub async fn new() -> crate::Result<Self> {
@@ -38,8 +71,297 @@ impl TokioPenetrateConnector {
                     TokioExecutor,
                 )?
             }),
+            udp6: match tokio::net::UdpSocket::bind("[::]:0").await {
+                Ok(udp) => Some(Arc::new(Datagram::new(Arc::new(udp), TokioExecutor)?)),
+                Err(e) => {
+                    log::debug!("ipv6 udp is not available, {}", e);
+                    None
+                }
+            },
+            resolver: CachedResolver::new(TokioResolver, DNS_CACHE_TTL),
+            bind_from: None,
+            bind_ports: None,
+            nodelay: false,
+            dscp: None,
+            happy_eyeballs: None,
+            connect_timeout: None,
+            upstream: None,
+        })
+    }
+
+    /// udp转发时解析目标域名使用的解析器, 默认使用系统dns并缓存60秒
+    pub fn using_resolver(mut self, resolver: CachedResolver) -> Self {
+        self.resolver = resolver;
+        self
+    }
+
+    /// 转发连接使用的本地地址, 多网卡时用于选择出口, 默认由系统选择
+    pub fn using_bind_from(mut self, bind_from: Option<IpAddr>) -> Self {
+        self.bind_from = bind_from;
+        self
+    }
+
+    /// 转发连接使用的源端口范围, 用于配合防火墙的出站规则, 范围内没有空闲端口时连接失败,
+    /// 默认由系统选择
+    pub fn using_bind_ports(mut self, ports: Option<RangeInclusive<u16>>) -> Self {
+        self.bind_ports = ports
+            .filter(|ports| !ports.is_empty())
+            .map(|range| Arc::new(SourcePorts::new(range)));
+        self
+    }
+
+    /// 连接转发目标时设置 `TCP_NODELAY`, 适合ssh, rdp等交互式协议
+    pub fn using_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// 连接转发目标时设置DSCP标记(0-63), 不支持的平台上忽略
+    pub fn using_dscp(mut self, dscp: Option<u8>) -> Self {
+        self.dscp = crate::checked_dscp(dscp);
+        self
+    }
+
+    /// 转发目标为域名且同时解析出ipv4与ipv6地址时, 两个协议族并发连接(happy eyeballs),
+    /// 使用先连接成功的一个, `delay` 为第一个协议族领先的时间, 设置了 `bind_from` 或 `bind_ports` 时不生效
+    pub fn using_happy_eyeballs(mut self, delay: Option<Duration>) -> Self {
+        self.happy_eyeballs = delay;
+        self
+    }
+
+    /// 单次连接转发目标的超时时间, 有多个转发目标时每个目标单独计时, 默认由系统决定
+    pub fn using_connect_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// tcp转发目标经过上游socks5代理连接, `bind_from` 与 happy eyeballs 作用于连接代理,
+    /// unix域套接字与udp转发不经过代理
+    pub fn using_upstream(mut self, upstream: Option<Upstream>) -> Self {
+        self.upstream = upstream.map(Arc::new);
+        self
+    }
+}
+
+/// 超时后返回 `TimedOut`, 服务端据此回复 `Unreachable`
+async fn connect_timeout<F, T>(
+    target: &str,
+    timeout: Option<Duration>,
+    connect: F,
+) -> crate::Result<T>
+where
+    F: Future<Output = crate::Result<T>>,
+{
+    let timeout = match timeout {
+        None => return connect.await,
+        Some(timeout) => timeout,
+    };
+
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(result) => result,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("connect to {} timed out after {:?}", target, timeout),
+        )
+        .into()),
+    }
+}
+
+impl SourcePorts {
+    fn new(range: RangeInclusive<u16>) -> Self {
+        Self {
+            range,
+            next: AtomicU32::new(0),
+        }
+    }
+
+    /// 从上次使用的端口之后开始, 依次返回范围内的每个端口
+    fn ports(&self) -> impl Iterator<Item = u16> + '_ {
+        let start = *self.range.start() as u32;
+        let len = *self.range.end() as u32 - start + 1;
+        let next = self.next.load(Ordering::Relaxed);
+
+        (0..len).map(move |i| (start + (next + i) % len) as u16)
+    }
+
+    fn used(&self, port: u16) {
+        let next = (port - self.range.start()) as u32 + 1;
+        self.next.store(next, Ordering::Relaxed);
+    }
+}
+
+/// 绑定本地地址或源端口后再连接, 设置了本地地址时只连接与本地地址同一协议族的目标地址,
+/// 源端口被占用时尝试下一个, 范围内的端口都被占用时返回 `AddrInUse`
+async fn connect_from(
+    target: String,
+    bind_from: Option<IpAddr>,
+    bind_ports: Option<&SourcePorts>,
+) -> crate::Result<TcpStream> {
+    let addr = tokio::net::lookup_host(&target)
+        .await?
+        .find(|addr| match bind_from {
+            Some(bind_from) => addr.is_ipv4() == bind_from.is_ipv4(),
+            None => true,
         })
+        .ok_or_else(|| match bind_from {
+            Some(bind_from) => format!(
+                "no address of {} matches the bind address {}",
+                target, bind_from
+            ),
+            None => format!("no address of {}", target),
+        })?;
+
+    let ip = match (bind_from, addr) {
+        (Some(bind_from), _) => bind_from,
+        (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let bind_ports = match bind_ports {
+        None => return Ok(connect_bound(SocketAddr::new(ip, 0), addr).await?),
+        Some(bind_ports) => bind_ports,
+    };
+
+    for port in bind_ports.ports() {
+        match connect_bound(SocketAddr::new(ip, port), addr).await {
+            Ok(tcp) => {
+                bind_ports.used(port);
+                return Ok(tcp);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => {
+                log::debug!("source port {} is in use, try next", port);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AddrInUse,
+        format!(
+            "no free source port in {}-{} to connect to {}",
+            bind_ports.range.start(),
+            bind_ports.range.end(),
+            target
+        ),
+    )
+    .into())
+}
+
+async fn connect_bound(local: SocketAddr, addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = match local {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+
+    socket.bind(local)?;
+    socket.connect(addr).await
+}
+
+/// 依次连接同一协议族的地址, 全部失败时返回最后一个错误
+async fn connect_sequential(addrs: Vec<SocketAddr>) -> crate::Result<TcpStream> {
+    let mut last_error = None;
+
+    for addr in addrs {
+        match TcpStream::connect(addr).await {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => {
+                log::debug!("failed to connect to {} err={}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(match last_error {
+        Some(e) => e.into(),
+        None => "no address to connect".to_string().into(),
+    })
+}
+
+/// `primary` 先开始, `delay` 后或 `primary` 失败时开始 `secondary`, 返回先成功的一个,
+/// 另一个被丢弃, 正在进行的连接随之取消并关闭
+async fn race<F1, F2, T>(primary: F1, secondary: F2, delay: Duration) -> crate::Result<T>
+where
+    F1: Future<Output = crate::Result<T>>,
+    F2: Future<Output = crate::Result<T>>,
+{
+    let mut primary = Box::pin(primary);
+    let mut secondary = Box::pin(secondary);
+    let mut delay = Box::pin(tokio::time::sleep(delay));
+    let mut primary_failed = false;
+    let mut secondary_failed = false;
+
+    // 两个都失败时返回后失败的一个的错误
+    std::future::poll_fn(|cx| {
+        if !primary_failed {
+            match primary.as_mut().poll(cx) {
+                Poll::Ready(Ok(output)) => return Poll::Ready(Ok(output)),
+                Poll::Ready(Err(e)) if secondary_failed => return Poll::Ready(Err(e)),
+                Poll::Ready(Err(_)) => primary_failed = true,
+                Poll::Pending => {}
+            }
+        }
+
+        let started = primary_failed || delay.as_mut().poll(cx).is_ready();
+
+        if started && !secondary_failed {
+            match secondary.as_mut().poll(cx) {
+                Poll::Ready(Ok(output)) => return Poll::Ready(Ok(output)),
+                Poll::Ready(Err(e)) if primary_failed => return Poll::Ready(Err(e)),
+                Poll::Ready(Err(_)) => secondary_failed = true,
+                Poll::Pending => {}
+            }
+        }
+
+        Poll::Pending
+    })
+    .await
+}
+
+/// 按RFC 8305并发连接域名解析出的ipv4与ipv6地址,
+/// 第一个解析结果所属的协议族领先 `delay` 开始连接
+async fn connect_happy_eyeballs(target: String, delay: Duration) -> crate::Result<TcpStream> {
+    let addrs = tokio::net::lookup_host(&target).await?.collect::<Vec<_>>();
+
+    let first_v6 = match addrs.first() {
+        None => return Err(format!("no address of {}", target).into()),
+        Some(addr) => addr.is_ipv6(),
+    };
+
+    let (primary, secondary): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+
+    if secondary.is_empty() {
+        return connect_sequential(primary).await;
     }
+
+    log::debug!(
+        "happy eyeballs to {}, primary {:?}, secondary {:?}",
+        target,
+        primary,
+        secondary
+    );
+
+    race(
+        connect_sequential(primary),
+        connect_sequential(secondary),
+        delay,
+    )
+    .await
+}
+
+/// 转发目标为unix域套接字时直接连接, 省去本地tcp回环
+#[cfg(unix)]
+async fn connect_unix(socket: Socket) -> crate::Result<Route<FusoStream>> {
+    let path = socket.as_string();
+    let stream = tokio::net::UnixStream::connect(&path).await?;
+    log::debug!("connected to unix socket {}", path);
+    Ok(Route::Forward(stream.into_boxed_stream()))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(socket: Socket) -> crate::Result<Route<FusoStream>> {
+    Err(SocketErr::NotSupport(socket).into())
 }
 
 impl Provider<Socket> for TokioTcpConnector {
@@ -80,20 +402,90 @@ impl Provider<Socket> for TokioPenetrateConnector {
 
     fn call(&self, socket: Socket) -> Self::Output {
         let udp = self.udp.clone();
+        let udp6 = self.udp6.clone();
+        let resolver = self.resolver.clone();
+        let bind_from = self.bind_from;
+        let bind_ports = self.bind_ports.clone();
+        let nodelay = self.nodelay;
+        let dscp = self.dscp;
+        let happy_eyeballs = self.happy_eyeballs;
+        let timeout = self.connect_timeout;
+        let upstream = self.upstream.clone();
         Box::pin(async move {
             match socket.kind() {
-                SocketKind::Tcp => Ok(Route::Forward(
-                    TcpStream::connect(socket.as_string())
-                        .await?
-                        .into_boxed_stream(),
-                )),
+                SocketKind::Tcp if socket.is_unix() => {
+                    connect_timeout(&socket.as_string(), timeout, connect_unix(socket.clone()))
+                        .await
+                }
+                SocketKind::Tcp => {
+                    let target = socket.as_string();
+
+                    // 经过上游代理时连接的是代理
+                    let (dial, is_domain) = match upstream.as_ref() {
+                        None => (target.clone(), socket.is_domain()),
+                        Some(upstream) => {
+                            (upstream.addr().to_string(), upstream.addr().is_domain())
+                        }
+                    };
+
+                    let connect = async {
+                        match (bind_from, bind_ports.as_deref(), happy_eyeballs) {
+                            (None, None, Some(delay)) if is_domain => {
+                                connect_happy_eyeballs(dial.clone(), delay).await
+                            }
+                            (None, None, _) => Ok(TcpStream::connect(&dial).await?),
+                            (bind_from, bind_ports, _) => {
+                                connect_from(dial.clone(), bind_from, bind_ports).await
+                            }
+                        }
+                    };
+
+                    let connect = async {
+                        let mut tcp = connect_timeout(&dial, timeout, connect).await?;
+
+                        if let Some(upstream) = upstream.as_ref() {
+                            let handshake = upstream.connect(&mut tcp, socket.addr());
+                            connect_timeout(&target, timeout, handshake).await?;
+                        }
+
+                        Ok::<_, crate::Error>(tcp)
+                    };
+
+                    let tcp = match connect.await {
+                        Ok(tcp) => tcp,
+                        Err(e) if upstream.is_some() => {
+                            log::warn!(
+                                "failed to connect to {} through upstream socks5 {} err={}",
+                                target,
+                                dial,
+                                e
+                            );
+                            return Err(e);
+                        }
+                        Err(e) => return Err(e),
+                    };
+
+                    if nodelay {
+                        tcp.set_nodelay(true)?;
+                    }
+
+                    if let Some(dscp) = dscp {
+                        crate::set_dscp(&tcp, dscp);
+                    }
+
+                    Ok(Route::Forward(tcp.into_boxed_stream()))
+                }
                 SocketKind::Ufd => {
-                    let provider = WrappedProvider::wrap(UdpForwardClientProvider(udp));
+                    let provider =
+                        WrappedProvider::wrap(UdpForwardClientProvider(udp, udp6, resolver));
 
                     Ok(Route::Provider(WrappedProvider::wrap(
                         SocksUdpForwardMock(provider),
                     )))
                 }
+                SocketKind::Bind => Ok(Route::Provider(WrappedProvider::wrap(SocksBindMock(
+                    WrappedProvider::wrap(TokioAccepter::default()),
+                )))),
                 _ => Err(SocketErr::NotSupport(socket).into()),
             }
         })
@@ -105,15 +497,25 @@ impl Provider<Addr> for UdpForwardClientProvider {
 
     fn call(&self, addr: Addr) -> Self::Output {
         let udp = self.0.clone();
+        let udp6 = self.1.clone();
+        let resolver = self.2.clone();
 
         Box::pin(async move {
             log::debug!("try connect to udp {}", addr);
 
-            let addr = addr
-                .as_string()
-                .to_socket_addrs()?
-                .next()
-                .ok_or(InvalidAddr::Domain(addr.as_string()))?;
+            let addr = resolver.call(addr).await?;
+
+            let udp = match (addr, udp6) {
+                (SocketAddr::V4(_), _) => udp,
+                (SocketAddr::V6(_), Some(udp6)) => udp6,
+                (SocketAddr::V6(_), None) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::AddrNotAvailable,
+                        format!("ipv6 is not available, unable to forward to {}", addr),
+                    )
+                    .into())
+                }
+            };
 
             let udp = udp.connect(addr).await?;
             match udp.local_addr()? {
@@ -127,3 +529,185 @@ impl Provider<Addr> for UdpForwardClientProvider {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// 被丢弃时记录, 用于确认落后的连接被取消
+    struct Dropped(Arc<AtomicBool>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    #[test]
+    fn test_happy_eyeballs_race() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let delay = Duration::from_millis(50);
+                let dropped = Arc::new(AtomicBool::new(false));
+
+                let guard = Dropped(dropped.clone());
+                let slow = async move {
+                    let _guard = guard;
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    Ok(4)
+                };
+
+                let start = Instant::now();
+                let output = super::race(slow, async { Ok(6) }, delay).await.unwrap();
+                assert_eq!(output, 6);
+                assert!(start.elapsed() >= delay && start.elapsed() < Duration::from_secs(5));
+                assert!(dropped.load(Ordering::Acquire));
+
+                // 第一个协议族失败时不再等待
+                let start = Instant::now();
+                let failed = async { Err::<u8, _>("refused".to_string().into()) };
+                let output = super::race(failed, async { Ok(6) }, Duration::from_secs(5));
+                assert_eq!(output.await.unwrap(), 6);
+                assert!(start.elapsed() < Duration::from_secs(5));
+
+                let output = super::race(async { Ok(4) }, async { unreachable!() }, delay);
+                assert_eq!(output.await.unwrap(), 4);
+
+                let failed = || async { Err::<u8, crate::Error>("refused".to_string().into()) };
+                assert!(super::race(failed(), failed(), delay).await.is_err());
+
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let target = format!("localhost:{}", listener.local_addr().unwrap().port());
+                let tcp = super::connect_happy_eyeballs(target, delay).await.unwrap();
+                assert!(tcp.peer_addr().unwrap().ip().is_loopback());
+            });
+    }
+
+    /// 依赖linux在全连接队列已满时丢弃SYN的行为, 其他系统可能直接拒绝连接
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_connect_timeout() {
+        use crate::{Provider, Socket};
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let timeout = Duration::from_millis(200);
+
+                let connector = super::TokioPenetrateConnector::new()
+                    .await
+                    .unwrap()
+                    .using_connect_timeout(Some(timeout));
+
+                // 不使用不可路由的地址, 没有网络的环境中会立即返回错误而不是超时,
+                // 全连接队列已满时握手不会被应答, 同样只能等待超时
+                let listener = tokio::net::TcpSocket::new_v4().unwrap();
+                listener.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+                let listener = listener.listen(0).unwrap();
+                let addr = listener.local_addr().unwrap();
+                let _backlog = tokio::net::TcpStream::connect(addr).await.unwrap();
+
+                let start = Instant::now();
+                let err = connector.call(Socket::tcp(addr)).await.err().unwrap();
+                assert!(start.elapsed() >= timeout && start.elapsed() < Duration::from_secs(5));
+                assert_eq!(
+                    crate::protocol::ErrorCode::from(&err),
+                    crate::protocol::ErrorCode::Unreachable
+                );
+            });
+    }
+
+    #[test]
+    fn test_bind_ports() {
+        use crate::{Provider, Socket};
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let occupied = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+                let busy = occupied.local_addr().unwrap().port();
+
+                let free = {
+                    let released = std::net::TcpListener::bind("0.0.0.0:0").unwrap();
+                    released.local_addr().unwrap().port()
+                };
+
+                let connect = |ports| async move {
+                    super::TokioPenetrateConnector::new()
+                        .await
+                        .unwrap()
+                        .using_bind_ports(Some(ports))
+                        .call(Socket::tcp(addr))
+                        .await
+                };
+
+                // 范围内的端口都被占用
+                let err = connect(busy..=busy).await.err().unwrap();
+                assert!(err.to_string().contains("no free source port"));
+
+                assert!(connect(free..=free).await.is_ok());
+                let (_, peer) = listener.accept().await.unwrap();
+                assert_eq!(peer.port(), free);
+                drop(occupied);
+
+                let ports = super::SourcePorts::new(40000..=40002);
+                ports.used(40001);
+                assert_eq!(ports.ports().collect::<Vec<_>>(), [40002, 40000, 40001]);
+            });
+    }
+
+    #[test]
+    fn test_udp_forward_ipv6() {
+        use crate::{Addr, Provider, UdpReceiverExt};
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let connector = super::TokioPenetrateConnector::new().await.unwrap();
+
+                let provider = super::UdpForwardClientProvider(
+                    connector.udp.clone(),
+                    connector.udp6.clone(),
+                    connector.resolver.clone(),
+                );
+
+                let target = match tokio::net::UdpSocket::bind("[::1]:0").await {
+                    Ok(target) => target,
+                    Err(_) => return,
+                };
+
+                let addr = target.local_addr().unwrap();
+
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 16];
+                    let (n, from) = target.recv_from(&mut buf).await.unwrap();
+                    target.send_to(&buf[..n], from).await.unwrap();
+                });
+
+                let (_, udp) = provider.call(Addr::from(addr)).await.unwrap();
+                udp.send(b"fuso").await.unwrap();
+
+                let mut buf = [0u8; 16];
+                let n = udp.recv(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"fuso");
+
+                let provider = super::UdpForwardClientProvider(
+                    connector.udp.clone(),
+                    None,
+                    connector.resolver.clone(),
+                );
+
+                assert!(provider.call(Addr::from(addr)).await.is_err());
+            });
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+};
+
+use tokio::io::unix::AsyncFd;
+
+/// 使用 `splice(2)` 在两个tcp连接之间单向转发, 数据经过管道在内核中移动, 不复制到用户态
+pub(crate) struct Splice {
+    from: Arc<AsyncFd<OwnedFd>>,
+    to: Arc<AsyncFd<OwnedFd>>,
+    len: usize,
+}
+
+/// 复制文件描述符后注册到tokio, 原连接已经注册过, 不能重复注册同一个描述符.
+/// 返回 fd1 到 fd2 与 fd2 到 fd1 两个方向
+pub(crate) fn splice(fd1: RawFd, fd2: RawFd, len: usize) -> io::Result<(Splice, Splice)> {
+    let fd1 = Arc::new(AsyncFd::new(dup(fd1)?)?);
+    let fd2 = Arc::new(AsyncFd::new(dup(fd2)?)?);
+
+    Ok((
+        Splice {
+            from: fd1.clone(),
+            to: fd2.clone(),
+            len,
+        },
+        Splice {
+            from: fd2,
+            to: fd1,
+            len,
+        },
+    ))
+}
+
+fn dup(fd: RawFd) -> io::Result<OwnedFd> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+
+    match unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }),
+    }
+}
+
+fn splice_to(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+
+    match unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            flags,
+        )
+    } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+impl Splice {
+    /// 读到EOF时只关闭对端的写入, `on_forward` 在每次写入对端后调用
+    pub(crate) async fn copy<F>(self, mut on_forward: F) -> crate::Result<()>
+    where
+        F: FnMut(usize),
+    {
+        let (reader, writer) = pipe()?;
+
+        loop {
+            let n = loop {
+                let mut guard = self.from.readable().await?;
+                match guard.try_io(|from| splice_to(from.as_raw_fd(), writer.as_raw_fd(), self.len))
+                {
+                    Ok(r) => break r?,
+                    Err(_) => continue,
+                }
+            };
+
+            if n == 0 {
+                unsafe { libc::shutdown(self.to.as_raw_fd(), libc::SHUT_WR) };
+                return Ok(());
+            }
+
+            log::trace!("splice {}bytes data", n);
+
+            let mut remaining = n;
+            while remaining > 0 {
+                let mut guard = self.to.writable().await?;
+                match guard.try_io(|to| splice_to(reader.as_raw_fd(), to.as_raw_fd(), remaining)) {
+                    Ok(r) => remaining -= r?,
+                    Err(_) => continue,
+                }
+            }
+
+            on_forward(n);
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use std::{pin::Pin, sync::Arc};
+use std::{pin::Pin, sync::Arc, time::Duration};
 
-use crate::{generator::Generator, Fuso, Provider, Socket, Stream, WrappedProvider, DecorateProvider};
+use crate::{
+    generator::Generator, health::Health, DecorateProvider, Executor, Fuso, Provider, Socket,
+    Stream, WrappedProvider,
+};
 
-use super::{Handshake, Processor, Server};
+use super::{Handshake, Processor, Server, Shutdown};
 
 type BoxedFuture<O> = Pin<Box<dyn std::future::Future<Output = crate::Result<O>> + Send + 'static>>;
 
@@ -26,6 +29,20 @@ where
         self
     }
 
+    /// 使用调用者提供的执行器运行连接任务, 可与应用共用同一个运行时
+    pub fn with_executor<E2>(self, executor: E2) -> ServerBuilder<E2, P, S, O>
+    where
+        E2: Executor + Send + Clone + 'static,
+    {
+        ServerBuilder {
+            executor,
+            is_mixed: self.is_mixed,
+            observer: self.observer,
+            handshake: self.handshake,
+            server_provider: self.server_provider,
+        }
+    }
+
     pub fn build<H, G>(self, handler: H) -> Fuso<Server<E, H, P, S, O>>
     where
         H: Provider<(S, Processor<P, S, O>), Output = BoxedFuture<G>> + Send + Sync + 'static,
@@ -38,6 +55,13 @@ where
             provider: self.server_provider,
             observer: self.observer,
             handshake: self.handshake.map(Arc::new),
+            shutdown: Shutdown::default(),
+            health: Health::default(),
+            grace_period: Duration::from_secs(10),
+            access_control: None,
+            accept_limiter: None,
+            resource_guard: None,
+            handshake_timeout: None,
         })
     }
 }
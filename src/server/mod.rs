@@ -1,13 +1,17 @@
 mod builder;
+mod shutdown;
 
 pub use builder::*;
+pub use shutdown::*;
 
 use crate::{
-    generator::GeneratorEx, DecorateProvider, Observer, Processor, Serve, Socket, WrappedProvider,
+    acl::AccessControl, correlation_id, health::Health, limit::AcceptLimiter, metrics::metrics,
+    resource::ResourceGuard, time, DecorateProvider, Observer, Processor, Serve, Socket,
+    WrappedProvider,
 };
-use std::{pin::Pin, sync::Arc};
+use std::{future::Future, pin::Pin, sync::Arc, task::Poll, time::Duration};
 
-use crate::{generator::Generator, Accepter, AccepterExt, Executor, Fuso, Provider, Stream};
+use crate::{generator::Generator, Accepter, Executor, Fuso, Provider, Stream};
 
 pub type Handshake<S> = WrappedProvider<S, (S, Option<DecorateProvider<S>>)>;
 
@@ -20,6 +24,24 @@ pub struct Server<E, H, P, S, O> {
     pub(crate) provider: Arc<P>,
     pub(crate) observer: Option<Arc<O>>,
     pub(crate) handshake: Option<Arc<Handshake<S>>>,
+    pub(crate) shutdown: Shutdown,
+    pub(crate) health: Health,
+    pub(crate) grace_period: Duration,
+    pub(crate) access_control: Option<Arc<AccessControl>>,
+    pub(crate) accept_limiter: Option<AcceptLimiter>,
+    pub(crate) resource_guard: Option<ResourceGuard>,
+    pub(crate) handshake_timeout: Option<Duration>,
+}
+
+/// 每个阶段单独计时, 进入下一阶段后重新开始
+async fn within<O>(timeout: Option<Duration>, fut: BoxedFuture<O>) -> crate::Result<O>
+where
+    O: Send + 'static,
+{
+    match timeout {
+        None => fut.await,
+        Some(timeout) => time::wait_for(timeout, fut).await?,
+    }
 }
 
 impl<E, H, A, G, P, S, O> Server<E, H, P, S, O>
@@ -33,18 +55,40 @@ where
     P: Provider<Socket, Output = BoxedFuture<A>> + Send + Sync + 'static,
 {
     pub async fn run(self) -> crate::Result<()> {
-        let mut accepter = self.provider.call(self.bind.clone()).await?;
+        let mut accepter = self
+            .provider
+            .call(self.bind.clone())
+            .await
+            .map_err(|e| e.with_bind_addr(&self.bind))?;
 
-        log::info!("the server listens on {}", accepter.local_addr()?);
+        let listener = accepter.local_addr()?.to_string();
+
+        log::info!("the server listens on {}", listener);
+
+        let accepting = self.health.accepting();
+
+        let mut stopped = self.shutdown.wait();
 
         loop {
-            let client = accepter.accept().await?;
+            let client = std::future::poll_fn(|cx| match Pin::new(&mut stopped).poll(cx) {
+                Poll::Ready(()) => Poll::Ready(None),
+                Poll::Pending => Pin::new(&mut accepter).poll_accept(cx).map(Some),
+            })
+            .await;
+
+            let client = match client {
+                None => break,
+                Some(client) => client?,
+            };
 
             let executor = self.executor.clone();
             let handshake = self.handshake.clone();
             let provider = self.provider.clone();
             let handler = self.handler.clone();
             let observer = self.observer.clone();
+            let shutdown = self.shutdown.clone();
+            let listener = listener.clone();
+            let handshake_timeout = self.handshake_timeout;
 
             let client_addr = match client.peer_addr() {
                 Ok(addr) => addr,
@@ -54,7 +98,56 @@ where
                 }
             };
 
+            if let Some(ip) = self
+                .access_control
+                .as_ref()
+                .and_then(|acl| acl.check(&client_addr))
+            {
+                log::warn!(
+                    peer_addr = client_addr.to_string(), listener = listener;
+                    "refused connection from {}",
+                    ip
+                );
+                drop(client);
+                continue;
+            }
+
+            if let Some(limited) = self
+                .accept_limiter
+                .as_ref()
+                .and_then(|limiter| limiter.check(&client_addr))
+            {
+                // 大量连接时只计数, 不逐个输出警告
+                log::debug!(
+                    peer_addr = client_addr.to_string(), listener = listener;
+                    "{}, drop the connection",
+                    limited
+                );
+                metrics().on_rate_limited();
+                drop(client);
+                continue;
+            }
+
+            if let Some(low) = self.resource_guard.as_ref().and_then(|guard| guard.check()) {
+                log::debug!(
+                    peer_addr = client_addr.to_string(), listener = listener;
+                    "{}, drop the connection",
+                    low
+                );
+                metrics().on_resource_rejected();
+                drop(client);
+                continue;
+            }
+
+            let cid = correlation_id();
+
+            log::debug!(
+                cid = &*cid, peer_addr = client_addr.to_string(), listener = listener.clone();
+                "accepted a connection"
+            );
+
             observer.on_connect(&client_addr);
+            metrics().on_connect();
 
             self.executor.spawn(async move {
                 let now = std::time::Instant::now();
@@ -62,8 +155,8 @@ where
                 let client = match handshake.as_ref() {
                     None => Ok((client, None)),
                     Some(provider) => {
-                        log::debug!("start shaking hands");
-                        provider.call(client).await
+                        log::debug!(cid = &*cid; "start shaking hands");
+                        within(handshake_timeout, provider.call(client)).await
                     }
                 };
 
@@ -71,47 +164,85 @@ where
 
                 let generator = match client {
                     Err(e) => {
-                        log::warn!("handshake failed {}", e);
+                        log::warn!(
+                            cid = &*cid, peer_addr = client_addr.to_string(), listener = listener;
+                            "handshake failed {}",
+                            e
+                        );
+                        metrics().on_handshake_failed();
                         Err(e)
                     }
                     Ok((client, decorator)) => {
-                        log::debug!("start processing the connection");
-                        handler
-                            .call((
-                                client,
-                                Processor::new(provider, observer.clone(), decorator),
-                            ))
-                            .await
+                        log::debug!(cid = &*cid; "start processing the connection");
+                        let processor = Processor::new(provider, observer.clone(), decorator)
+                            .with_cid(cid.clone());
+                        within(handshake_timeout, handler.call((client, processor))).await
                     }
                 };
 
                 if generator.is_err() {
-                    log::warn!("failed to handle connection {}", unsafe {
-                        generator.unwrap_err_unchecked()
-                    });
+                    log::warn!(
+                        cid = &*cid, peer_addr = client_addr.to_string(), listener = listener;
+                        "failed to handle connection {}",
+                        unsafe { generator.unwrap_err_unchecked() }
+                    );
                     return;
                 }
 
                 let mut generator = unsafe { generator.unwrap_unchecked() };
 
+                let mut stopped = shutdown.wait();
+
                 loop {
-                    match generator.next().await {
+                    let next = std::future::poll_fn(|cx| match Pin::new(&mut stopped).poll(cx) {
+                        Poll::Ready(()) => Poll::Ready(Ok(None)),
+                        Poll::Pending => Pin::new(&mut generator).poll_generate(cx),
+                    })
+                    .await;
+
+                    match next {
                         Ok(None) => break,
                         Err(e) => {
-                            log::warn!("An error occurred {}", e);
+                            log::warn!(
+                                cid = &*cid, peer_addr = client_addr.to_string(), listener = listener;
+                                "An error occurred {}",
+                                e
+                            );
                             break;
                         }
                         Ok(Some(fut)) => {
-                            executor.spawn(fut);
+                            executor.spawn(shutdown.track(fut));
                         }
                     }
                 }
 
-                log::warn!("stop processing");
+                log::warn!(
+                    cid = &*cid, peer_addr = client_addr.to_string(), listener = listener;
+                    "stop processing"
+                );
 
                 observer.on_stop(now,&client_addr);
             });
         }
+
+        // 先标记为未就绪, 再等待转发结束
+        drop(accepting);
+        drop(accepter);
+
+        log::info!(
+            "the server stops accepting, waiting up to {:?} for {} forwards",
+            self.grace_period,
+            self.shutdown.inflight()
+        );
+
+        if !self.shutdown.drain(self.grace_period).await {
+            log::warn!(
+                "grace period expired, {} forwards dropped",
+                self.shutdown.inflight()
+            );
+        }
+
+        Ok(())
     }
 }
 
@@ -133,9 +264,60 @@ where
             handshake: self.0.handshake,
             handler: self.0.handler,
             observer: self.0.observer,
+            shutdown: self.0.shutdown,
+            health: self.0.health,
+            grace_period: self.0.grace_period,
+            access_control: self.0.access_control,
+            accept_limiter: self.0.accept_limiter,
+            resource_guard: self.0.resource_guard,
+            handshake_timeout: self.0.handshake_timeout,
         })
     }
 
+    /// 关闭句柄, 用于在服务运行后停止服务
+    pub fn shutdown(&self) -> Shutdown {
+        self.0.shutdown.clone()
+    }
+
+    /// 共享的健康状态, 监听后标记为存活, 停止接受新连接时标记为未就绪
+    pub fn health(mut self, health: Health) -> Self {
+        self.0.health = health;
+        self
+    }
+
+    /// 关闭服务时等待转发结束的最长时间
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.0.grace_period = grace_period;
+        self
+    }
+
+    /// 按来源ip过滤连接, 被拒绝的连接在握手之前直接关闭
+    pub fn access_control(mut self, access_control: AccessControl) -> Self {
+        self.0.access_control = Some(access_control)
+            .filter(|acl| !acl.is_empty())
+            .map(Arc::new);
+        self
+    }
+
+    /// 限制接受连接的速率, 超过速率的连接在握手之前直接关闭并计入指标,
+    /// 多个监听使用同一个实例时共享全局限制
+    pub fn accept_limiter(mut self, limiter: AcceptLimiter) -> Self {
+        self.0.accept_limiter = Some(limiter).filter(|limiter| !limiter.is_empty());
+        self
+    }
+
+    /// 可用内存或文件描述符低于下限时, 新连接在握手之前直接关闭并计入指标
+    pub fn resource_guard(mut self, guard: ResourceGuard) -> Self {
+        self.0.resource_guard = Some(guard).filter(|guard| !guard.is_empty());
+        self
+    }
+
+    /// 握手超时, 握手与处理客户端的首个请求分别计时, 超时后关闭连接, 默认不限制
+    pub fn handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.0.handshake_timeout = timeout;
+        self
+    }
+
     pub fn run(self) -> Fuso<Serve> {
         Fuso(Serve {
             fut: Box::pin(self.0.run()),
@@ -0,0 +1,240 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use crate::time;
+
+#[derive(Default)]
+struct Inner {
+    stopped: AtomicBool,
+    inflight: AtomicUsize,
+    /// 每个等待者只保留一个waker, 等待者被丢弃时移除
+    wakers: Mutex<HashMap<usize, Waker>>,
+    next_key: AtomicUsize,
+    /// 等待所有转发结束的waker, 最后一个转发结束时唤醒
+    idle: Mutex<Vec<Waker>>,
+}
+
+/// 服务关闭句柄, 调用 `shutdown` 后服务停止接受新连接,
+/// 并在宽限期内等待正在转发的连接结束
+#[derive(Default, Clone)]
+pub struct Shutdown(Arc<Inner>);
+
+pub struct Wait {
+    inner: Arc<Inner>,
+    key: usize,
+}
+
+pub struct Idle(Arc<Inner>);
+
+#[pin_project::pin_project(PinnedDrop)]
+pub struct Tracked<F> {
+    #[pin]
+    fut: F,
+    inner: Arc<Inner>,
+}
+
+impl Shutdown {
+    pub fn shutdown(&self) {
+        if !self.0.stopped.swap(true, Ordering::SeqCst) {
+            let wakers = std::mem::take(&mut *self.0.wakers.lock().unwrap());
+            for waker in wakers.into_values() {
+                waker.wake();
+            }
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0.stopped.load(Ordering::SeqCst)
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.0.inflight.load(Ordering::SeqCst)
+    }
+
+    pub fn wait(&self) -> Wait {
+        Wait {
+            inner: self.0.clone(),
+            key: self.0.next_key.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn track<F>(&self, fut: F) -> Tracked<F> {
+        self.0.inflight.fetch_add(1, Ordering::SeqCst);
+        Tracked {
+            fut,
+            inner: self.0.clone(),
+        }
+    }
+
+    /// 没有正在转发的连接时完成
+    pub fn idle(&self) -> Idle {
+        Idle(self.0.clone())
+    }
+
+    /// 等待所有转发结束, 超过宽限期返回false
+    pub async fn drain(&self, grace: Duration) -> bool {
+        time::wait_for(grace, self.idle()).await.is_ok()
+    }
+}
+
+impl Future for Idle {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.0.inflight.load(Ordering::SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+
+        let mut wakers = self.0.idle.lock().unwrap();
+
+        // 持有锁之后再次检查, 避免最后一个转发在注册waker之前结束
+        if self.0.inflight.load(Ordering::SeqCst) == 0 {
+            return Poll::Ready(());
+        }
+
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Future for Wait {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = &self.inner;
+
+        if inner.stopped.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+
+        let mut wakers = inner.wakers.lock().unwrap();
+
+        if inner.stopped.load(Ordering::SeqCst) {
+            return Poll::Ready(());
+        }
+
+        match wakers.get_mut(&self.key) {
+            Some(waker) if waker.will_wake(cx.waker()) => {}
+            Some(waker) => *waker = cx.waker().clone(),
+            None => {
+                wakers.insert(self.key, cx.waker().clone());
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Wait {
+    fn drop(&mut self) {
+        if let Ok(mut wakers) = self.inner.wakers.lock() {
+            wakers.remove(&self.key);
+        }
+    }
+}
+
+impl<F> Future for Tracked<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.project().fut.poll(cx)
+    }
+}
+
+#[pin_project::pinned_drop]
+impl<F> PinnedDrop for Tracked<F> {
+    fn drop(self: Pin<&mut Self>) {
+        if self.inner.inflight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            let wakers = std::mem::take(&mut *self.inner.idle.lock().unwrap());
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future::Future, pin::Pin, task::Poll, time::Duration};
+
+    use super::Shutdown;
+
+    #[test]
+    fn test_shutdown_drain() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let shutdown = Shutdown::default();
+
+                let wait = tokio::spawn(shutdown.wait());
+                let forward =
+                    tokio::spawn(shutdown.track(tokio::time::sleep(Duration::from_millis(200))));
+                let stuck = shutdown.track(std::future::pending::<()>());
+
+                assert_eq!(shutdown.inflight(), 2);
+                shutdown.shutdown();
+                wait.await.unwrap();
+
+                assert!(!shutdown.drain(Duration::from_millis(500)).await);
+                drop(stuck);
+
+                assert!(shutdown.drain(Duration::from_secs(1)).await);
+                forward.await.unwrap();
+                assert_eq!(shutdown.inflight(), 0);
+
+                // 最后一个转发结束时唤醒等待者
+                let tracked = shutdown.track(tokio::time::sleep(Duration::from_millis(50)));
+                let idle = tokio::spawn(shutdown.idle());
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                assert!(!idle.is_finished());
+                tracked.await;
+                tokio::time::timeout(Duration::from_millis(100), idle)
+                    .await
+                    .unwrap()
+                    .unwrap();
+            });
+    }
+
+    #[test]
+    fn test_shutdown_wakers_released() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let shutdown = Shutdown::default();
+
+                // 每个连接的处理任务结束后不再保留waker
+                for _ in 0..100 {
+                    let wait = shutdown.wait();
+                    let _ = tokio::time::timeout(Duration::from_millis(1), wait).await;
+                }
+
+                let mut wait = shutdown.wait();
+                std::future::poll_fn(|cx| {
+                    for _ in 0..10 {
+                        assert!(Pin::new(&mut wait).poll(cx).is_pending());
+                    }
+                    Poll::Ready(())
+                })
+                .await;
+
+                assert_eq!(shutdown.0.wakers.lock().unwrap().len(), 1);
+
+                shutdown.shutdown();
+                wait.await;
+                assert!(shutdown.0.wakers.lock().unwrap().is_empty());
+            });
+    }
+}